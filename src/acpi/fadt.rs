@@ -0,0 +1,200 @@
+//! The Fixed ACPI Description Table, which says where the power management registers are and how
+//! to switch the firmware into ACPI mode.
+
+use acpi::sdt::SdtHeader;
+use core::{mem, slice};
+
+/// The address space a `GenericAddress` lives in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    Other(u8),
+}
+
+/// A register address, as described by an ACPI Generic Address Structure.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// Parse a 12 byte Generic Address Structure.
+    pub fn parse(bytes: &[u8]) -> GenericAddress {
+        GenericAddress {
+            space: match bytes[0] {
+                0 => AddressSpace::SystemMemory,
+                1 => AddressSpace::SystemIo,
+                other => AddressSpace::Other(other),
+            },
+            address: read_u64(bytes, 4),
+        }
+    }
+
+    /// Return an I/O port address.
+    fn io(port: u32) -> GenericAddress {
+        GenericAddress {
+            space: AddressSpace::SystemIo,
+            address: port as u64,
+        }
+    }
+}
+
+// Offsets of the fields we use, from the start of the table.
+const SCI_INT: usize = 46;
+const SMI_CMD: usize = 48;
+const ACPI_ENABLE: usize = 52;
+const ACPI_DISABLE: usize = 53;
+const PM1A_CNT_BLK: usize = 64;
+const PM1B_CNT_BLK: usize = 68;
+const FLAGS: usize = 112;
+const RESET_REG: usize = 116;
+const RESET_VALUE: usize = 128;
+const X_PM1A_CNT_BLK: usize = 172;
+const X_PM1B_CNT_BLK: usize = 184;
+
+/// Set in the FADT flags if the reset register is supported.
+const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+
+/// The power management information we need out of the FADT.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    /// The interrupt the SCI is wired to, as an 8259 IRQ number.
+    pub sci_interrupt: u16,
+    /// The I/O port to write `acpi_enable` or `acpi_disable` to, or 0 if the system is always in
+    /// ACPI mode.
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    /// The PM1a control block. Every system has one.
+    pub pm1a_control: GenericAddress,
+    /// The PM1b control block, if there is one.
+    pub pm1b_control: Option<GenericAddress>,
+    /// The register to write `reset_value` to in order to reset the system, if supported.
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    /// Parse a mapped FADT.
+    pub fn new(sdt: &'static SdtHeader) -> Option<Fadt> {
+        let length = (sdt.length as usize).max(mem::size_of::<SdtHeader>());
+        let bytes = unsafe { slice::from_raw_parts(sdt as *const SdtHeader as *const u8, length) };
+        Fadt::parse(bytes)
+    }
+
+    /// Parse a FADT out of `bytes`, the whole table including its header. The ACPI 2.0 extended
+    /// fields are used when the table is long enough to have them and they are set, and the 32-bit
+    /// legacy fields otherwise. Returns `None` if the table is too short to have a PM1a block.
+    pub fn parse(bytes: &[u8]) -> Option<Fadt> {
+        if bytes.len() < PM1B_CNT_BLK + 4 {
+            return None;
+        }
+
+        let extended = |offset: usize| {
+            if bytes.len() < offset + 12 {
+                return None;
+            }
+
+            match GenericAddress::parse(&bytes[offset..offset + 12]) {
+                GenericAddress { address: 0, .. } => None,
+                address => Some(address),
+            }
+        };
+        let legacy = |offset: usize| match read_u32(bytes, offset) {
+            0 => None,
+            port => Some(GenericAddress::io(port)),
+        };
+
+        let pm1a_control = extended(X_PM1A_CNT_BLK).or_else(|| legacy(PM1A_CNT_BLK))?;
+        let pm1b_control = extended(X_PM1B_CNT_BLK).or_else(|| legacy(PM1B_CNT_BLK));
+
+        let reset_supported =
+            bytes.len() > RESET_VALUE && read_u32(bytes, FLAGS) & FLAG_RESET_REG_SUP != 0;
+        let (reset_register, reset_value) = if reset_supported {
+            (extended(RESET_REG), bytes[RESET_VALUE])
+        } else {
+            (None, 0)
+        };
+
+        Some(Fadt {
+            sci_interrupt: read_u16(bytes, SCI_INT),
+            smi_command: read_u32(bytes, SMI_CMD),
+            acpi_enable: bytes[ACPI_ENABLE],
+            acpi_disable: bytes[ACPI_DISABLE],
+            pm1a_control: pm1a_control,
+            pm1b_control: pm1b_control,
+            reset_register: reset_register,
+            reset_value: reset_value,
+        })
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    read_u16(bytes, offset) as u32 | (read_u16(bytes, offset + 2) as u32) << 16
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    read_u32(bytes, offset) as u64 | (read_u32(bytes, offset + 4) as u64) << 32
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "fadt",
+            run: fadt,
+        },
+    ];
+
+    fn fadt() -> Result<(), &'static str> {
+        use acpi::fadt::{AddressSpace, Fadt, GenericAddress};
+
+        let mut table = [0u8; 244];
+        table[..4].copy_from_slice(b"FACP");
+        table[46] = 9; // SCI_INT
+        table[48] = 0xb2; // SMI_CMD
+        table[52] = 0xf0; // ACPI_ENABLE
+        table[53] = 0xf1; // ACPI_DISABLE
+        table[64] = 0x04; // PM1a_CNT_BLK, 0x604
+        table[65] = 0x06;
+        table[113] = 0x04; // FLAGS, RESET_REG_SUP
+        table[116] = 1; // RESET_REG, I/O port 0xcf9
+        table[120] = 0xf9;
+        table[121] = 0x0c;
+        table[128] = 0x06; // RESET_VALUE
+        table[172] = 1; // X_PM1a_CNT_BLK, I/O port 0x1004
+        table[176] = 0x04;
+        table[177] = 0x10;
+
+        let fadt = Fadt::parse(&table).ok_or("could not parse the FADT")?;
+
+        let io = |address| {
+            Some(GenericAddress {
+                space: AddressSpace::SystemIo,
+                address: address,
+            })
+        };
+
+        if fadt.sci_interrupt != 9 || fadt.smi_command != 0xb2 {
+            Err("read the wrong SCI or SMI command port")
+        } else if fadt.acpi_enable != 0xf0 || fadt.acpi_disable != 0xf1 {
+            Err("read the wrong ACPI enable or disable value")
+        } else if Some(fadt.pm1a_control) != io(0x1004) {
+            Err("did not prefer the extended PM1a control block")
+        } else if fadt.pm1b_control.is_some() {
+            Err("found a PM1b control block that is not there")
+        } else if fadt.reset_register != io(0xcf9) || fadt.reset_value != 6 {
+            Err("read the wrong reset register")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! The HPET Description Table, which says where the registers of the High Precision Event Timer
+//! are.
+
+use acpi::fadt::GenericAddress;
+use acpi::sdt::SdtHeader;
+use core::{mem, slice};
+
+// Offsets of the fields we use, from the start of the table.
+const BASE_ADDRESS: usize = 40;
+const HPET_NUMBER: usize = 52;
+const MINIMUM_TICK: usize = 53;
+
+/// The information we need out of the HPET table.
+#[derive(Debug, Clone, Copy)]
+pub struct HpetTable {
+    /// Where the timer block's registers are. This should always be in system memory.
+    pub base_address: GenericAddress,
+    /// The sequence number of this timer block.
+    pub number: u8,
+    /// The smallest periodic interval, in main counter ticks, that does not lose interrupts.
+    pub minimum_tick: u16,
+}
+
+impl HpetTable {
+    /// Parse a mapped HPET table.
+    pub fn new(sdt: &'static SdtHeader) -> Option<HpetTable> {
+        let length = (sdt.length as usize).max(mem::size_of::<SdtHeader>());
+        let bytes = unsafe { slice::from_raw_parts(sdt as *const SdtHeader as *const u8, length) };
+        HpetTable::parse(bytes)
+    }
+
+    /// Parse an HPET table out of `bytes`, the whole table including its header. Returns `None` if
+    /// the table is too short.
+    pub fn parse(bytes: &[u8]) -> Option<HpetTable> {
+        if bytes.len() < MINIMUM_TICK + 2 {
+            return None;
+        }
+
+        Some(HpetTable {
+            base_address: GenericAddress::parse(&bytes[BASE_ADDRESS..BASE_ADDRESS + 12]),
+            number: bytes[HPET_NUMBER],
+            minimum_tick: bytes[MINIMUM_TICK] as u16 | (bytes[MINIMUM_TICK + 1] as u16) << 8,
+        })
+    }
+}
@@ -1,5 +1,4 @@
 use acpi::sdt::SdtHeader;
-use arch::memory::paging::ActivePageTable;
 use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use core::mem;
 use spin::Mutex;
@@ -21,14 +20,12 @@ pub struct Madt {
 
 impl Madt {
     /// Initialise all the MADT entries.
-    pub fn init(&mut self, active_table: &mut ActivePageTable) {
-        let mut local_apics: StaticVec<&'static LapicEntry, [&'static LapicEntry; 20]>
-            = StaticVec::new();
-        let mut nmis: StaticVec<&'static ApicNMI, [&'static ApicNMI; 10]> = StaticVec::new();
-        let mut io_apics: StaticVec<&'static IoApic, [&'static IoApic; 10]> = StaticVec::new();
-        let mut isos: StaticVec<&'static InterruptSourceOverride,
-                    [&'static InterruptSourceOverride; 10]>
-            = StaticVec::new();
+    pub fn init(&mut self) {
+        let mut local_apics: StaticVec<LapicEntry, [LapicEntry; 20]> = StaticVec::new();
+        let mut nmis: StaticVec<ApicNMI, [ApicNMI; 10]> = StaticVec::new();
+        let mut io_apics: StaticVec<IoApic, [IoApic; 10]> = StaticVec::new();
+        let mut isos: StaticVec<InterruptSourceOverride, [InterruptSourceOverride; 10]> =
+            StaticVec::new();
         
         let mut apic_manager = apic::ApicManager::new();
 
@@ -53,7 +50,7 @@ impl Madt {
                         println!("Found disabled core, id: {}", local_apic.id);
                     }
                     
-                    local_apics.push(local_apic).expect("Failed to push element to static vector");
+                    local_apics.push(*local_apic).expect("Failed to push element to static vector");
                 }
 
                 MadtEntry::IoApic(io_apic) => {
@@ -61,7 +58,7 @@ impl Madt {
                         "[ dev ] Found I/O APIC, id: {}, register base: {:#x}, gsib: {}",
                         io_apic.id, io_apic.address, io_apic.gsib
                     );
-                    io_apics.push(io_apic).expect("Failed to push element to static vector");
+                    io_apics.push(*io_apic).expect("Failed to push element to static vector");
                 }
 
                 MadtEntry::Iso(iso) => {
@@ -69,14 +66,14 @@ impl Madt {
                         "[ dev ] Found interrupt source override,\n overrides IRQ {},\n gsi: {}",
                         iso.irq_source, iso.gsi
                     );
-                    isos.push(iso).expect("Failed to push element to static vector");
+                    isos.push(*iso).expect("Failed to push element to static vector");
                 }
 
                 MadtEntry::Nmi(nmi) => {
                     println!("[ dev ] APIC NMI with flags: {}, LINT: {}",
                              nmi.flags,
                              nmi.lint_no);
-                    nmis.push(nmi).expect("Failed to push element to static vector.");
+                    nmis.push(*nmi).expect("Failed to push element to static vector.");
                 }
 
                 _ => {
@@ -99,7 +96,7 @@ impl Madt {
         unsafe { pic::PICS.lock().init() };
 
         if CpuId::new().get_feature_info().unwrap().has_apic() {
-           apic::init(active_table);
+           apic::init();
         }
 
         println!("[ smp ] Found {} APs", CPUS.load(Ordering::SeqCst));
@@ -125,6 +122,7 @@ impl Madt {
 }
 
 /// The Local APIC.
+#[derive(Clone, Copy)]
 #[repr(packed)]
 pub struct LapicEntry {
    /// The ID of the parent AP.
@@ -135,6 +133,7 @@ pub struct LapicEntry {
     pub flags: u32,
 }
 
+#[derive(Clone, Copy)]
 #[repr(packed)]
 pub struct IoApic {
     /// The ID of this I/O APIC.
@@ -147,6 +146,7 @@ pub struct IoApic {
 }
 
 /// Mapping of IRQ source to interrupt.
+#[derive(Clone, Copy)]
 #[repr(packed)]
 pub struct InterruptSourceOverride {
     pub bus_source: u8,
@@ -156,6 +156,7 @@ pub struct InterruptSourceOverride {
 }
 
 /// Non-maskable interrupts.
+#[derive(Clone, Copy)]
 #[repr(packed)]
 pub struct ApicNMI {
     pub processor_id: u8,
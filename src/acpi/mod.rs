@@ -1,50 +1,106 @@
-use arch::memory::paging::{ActivePageTable, Page, PhysicalAddress, VirtualAddress};
-use arch::memory::Frame;
-use arch::memory::paging::entry::EntryFlags;
-use core::mem;
+use arch::{Arch, MapFlags, ARCH};
+use arch::memory;
+use core::{mem, ptr};
+use multiboot2::BootInformation;
+use spin::Once;
 
 pub mod rsdp;
 pub mod sdt;
 pub mod rsdt;
 pub mod xsdt;
 pub mod madt;
+pub mod fadt;
+pub mod hpet;
+pub mod pm;
 
-/// Retrieve an SDT from a pointer found using the RSDP
-fn get_sdt(address: usize, active_table: &mut ActivePageTable) -> &'static sdt::SdtHeader {
-    {
-        let page = Page::containing_address(VirtualAddress::new(address));
-        if active_table.translate_page(page).is_none() {
-            let frame = Frame::containing_address(PhysicalAddress::new(page.start_address().get()));
-            let result =
-                active_table.map_to(page, frame, EntryFlags::PRESENT | EntryFlags::NO_EXECUTE);
-            result.flush(active_table);
-        }
+pub use self::pm::enable;
+
+/// The FADT, once `init` has found it.
+static FADT: Once<fadt::Fadt> = Once::new();
+
+/// Return the FADT, if `init` found a valid one.
+pub fn fadt() -> Option<&'static fadt::Fadt> {
+    FADT.try()
+}
+
+/// The HPET table, once `init` has found it.
+static HPET: Once<hpet::HpetTable> = Once::new();
+
+/// Return the HPET table, if `init` found a valid one.
+pub fn hpet() -> Option<&'static hpet::HpetTable> {
+    HPET.try()
+}
+
+/// Check an ACPI checksum: every byte of a valid table adds up to zero, modulo 256.
+pub fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Virtual address of the two pages `read_physical` maps physical memory at.
+const READ_WINDOW: usize = 0xffff_fe00_0000_0000;
+
+/// Copy the `T` at physical address `address` out of memory. The frames covering it are mapped at
+/// a scratch window for the duration of the copy, so `T` may straddle a page boundary.
+///
+/// # Panics
+///
+/// Panics if `T` is larger than a page, or the window is already in use.
+pub fn read_physical<T: Copy>(address: usize) -> T {
+    let page_size = ARCH.page_size();
+    let size = mem::size_of::<T>();
+    assert!(size <= page_size, "read_physical can read at most a page");
+
+    let offset = address % page_size;
+    let first = address - offset;
+    let pages = (offset + size.max(1) + page_size - 1) / page_size;
+
+    for i in 0..pages {
+        ARCH.map(READ_WINDOW + i * page_size, first + i * page_size, MapFlags::empty())
+            .expect("The read_physical window is already mapped.");
     }
 
-    let sdt = unsafe { &*(address as *const sdt::SdtHeader) };
-
-    {
-        // Map next page, and all pages within the range occupied by the data table.
-        let start_page = Page::containing_address(VirtualAddress::new(address + 4096));
-        let end_page = Page::containing_address(VirtualAddress::new(address + sdt.length as usize));
-        for page in Page::range_inclusive(start_page, end_page) {
-            // Check if this page has already been mapped to a frame.
-            if active_table.translate_page(page).is_none() {
-                let frame =
-                    Frame::containing_address(PhysicalAddress::new(page.start_address().get()));
-                let result =
-                    active_table.map_to(page, frame, EntryFlags::PRESENT | EntryFlags::NO_EXECUTE);
-                result.flush(active_table);
-            }
+    let value = unsafe { ptr::read_unaligned((READ_WINDOW + offset) as *const T) };
+
+    // `unmap` leaves the frames alone, which is what we want for memory we do not own.
+    for i in 0..pages {
+        ARCH.unmap(READ_WINDOW + i * page_size);
+    }
+
+    value
+}
+
+/// Retrieve an SDT from a pointer found using the RSDP. The whole table is identity mapped, so the
+/// returned reference stays valid until `reclaim` is called.
+fn get_sdt(address: usize) -> &'static sdt::SdtHeader {
+    let header: sdt::SdtHeader = read_physical(address);
+
+    let page_size = ARCH.page_size();
+    let first = address / page_size;
+    let last = (address + (header.length as usize).max(mem::size_of::<sdt::SdtHeader>()) - 1)
+        / page_size;
+    for page in (first..last + 1).map(|number| number * page_size) {
+        // Check if this page has already been mapped to a frame.
+        if ARCH.translate(page).is_none() {
+            ARCH.map(page, page, MapFlags::empty())
+                .expect("Could not identity map an ACPI table.");
         }
     }
 
-    sdt
+    unsafe { &*(address as *const sdt::SdtHeader) }
 }
 
-pub unsafe fn init(active_table: &mut ActivePageTable) {
-    let rsdp = rsdp::RsdpDescriptor::init(active_table).expect("Could not find rsdp, aborting ...");
-    let sdt = get_sdt(rsdp.sdt(), active_table);
+/// Find and parse the ACPI tables. The page table is only locked while tables and registers are
+/// being mapped, so that enabling ACPI mode and setting up the APICs run without holding it.
+pub unsafe fn init(boot_info: &BootInformation) {
+    // Prefer the RSDP handed to us by the bootloader, and only scan the BIOS area without one.
+    let rsdp = rsdp::RsdpDescriptor::from_multiboot(boot_info)
+        .or_else(rsdp::RsdpDescriptor::init)
+        .expect("Could not find rsdp, aborting ...");
+    let sdt = get_sdt(rsdp.sdt());
+    if !sdt.checksum_ok() {
+        println!("[ acpi ] WARNING: RSDT checksum is invalid, ignoring ACPI tables.");
+        return;
+    }
     let rsdt = rsdt::Rsdt::new(sdt);
 
     println!(
@@ -63,6 +119,35 @@ pub unsafe fn init(active_table: &mut ActivePageTable) {
         rsdt.other_entries.len()
     );
 
+    // Map every table the RSDT points to, so that they can be parsed.
+    for &address in rsdt.other_entries.iter() {
+        get_sdt(address as usize);
+    }
+
+    match rsdt.find_sdt(b"FACP") {
+        Some(rsdt::TableType::Fadt(fadt)) => {
+            println!(
+                "[ acpi ] Found FADT, SCI on IRQ {}, PM1a control at {:#x}",
+                fadt.sci_interrupt, fadt.pm1a_control.address
+            );
+            FADT.call_once(|| fadt);
+
+            match enable() {
+                Ok(()) => println!("[ acpi ] ACPI mode enabled."),
+                Err(err) => println!("[ acpi ] Could not enable ACPI mode: {}", err),
+            }
+        }
+        _ => println!("[ acpi ] Could not find FADT."),
+    }
+
+    match rsdt.find_sdt(b"HPET") {
+        Some(rsdt::TableType::Hpet(hpet)) => {
+            println!("[ acpi ] Found HPET, registers at {:#x}", hpet.base_address.address);
+            HPET.call_once(|| hpet);
+        }
+        _ => println!("[ acpi ] Could not find HPET."),
+    }
+
     // let mut madt: madt::Madt = unsafe { *(&*(0 as *const madt::Madt)) };
     match rsdt.find_sdt(b"APIC") {
         Some(rsdt::TableType::Madt(mut m)) => {
@@ -71,8 +156,104 @@ pub unsafe fn init(active_table: &mut ActivePageTable) {
                 m.sdt as *const sdt::SdtHeader as usize
             );
 
-            m.init(active_table);
+            m.init();
         }
         _ => println!("Could not find MADT."),
     }
 }
+
+/// Hand the ACPI reclaimable memory to the frame allocator as new areas, once everything needed
+/// from the tables has been copied out. The MADT entries are copied into the APIC manager by
+/// `init`, and nothing else keeps a reference to the tables, so this must be called after `init`
+/// and before any new table is looked up.
+pub fn reclaim(boot_info: &BootInformation) {
+    use arch::multiboot::{self, MemoryAreaType};
+
+    let entries = match multiboot::memory_map(boot_info) {
+        Some(entries) => entries,
+        None => return,
+    };
+
+    let mut count = 0;
+
+    let page_size = ARCH.page_size();
+    for entry in entries.filter(|entry| entry.typ == MemoryAreaType::AcpiReclaimable) {
+        // `get_sdt` identity maps the tables it reads. Only frames that lie entirely within the
+        // region are reclaimed.
+        let first = (entry.base as usize + page_size - 1) / page_size;
+        let last = (entry.base + entry.length) as usize / page_size;
+        for address in (first..last).map(|number| number * page_size) {
+            if ARCH.translate(address) == Some(address) {
+                ARCH.unmap(address);
+            }
+        }
+
+        // Handing memory to the frame allocator is up to the port.
+        let mut active_table = memory::active_table();
+        count += memory::add_area(&mut active_table, entry.base as usize, entry.length as usize);
+    }
+
+    println!("[ acpi ] Reclaimed {} frames of ACPI memory.", count);
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "read_physical",
+            run: read_physical,
+        },
+        KTest {
+            name: "acpi_checksum",
+            run: acpi_checksum,
+        },
+    ];
+
+    fn read_physical() -> Result<(), &'static str> {
+        use arch::memory::{allocate_frames, deallocate_frame, phys_to_virt, PAGE_SIZE};
+        use arch::memory::paging::PhysicalAddress;
+        use core::ptr;
+        use ktest::frame_at;
+
+        let first = allocate_frames(2).ok_or("could not allocate two contiguous frames")?;
+        let start = first.start_address().get();
+
+        // Place the value so that it straddles the boundary between the two frames.
+        let address = start + PAGE_SIZE - 4;
+        let expected: u64 = 0x0123_4567_89ab_cdef;
+        let pointer = phys_to_virt(PhysicalAddress::new(address)).as_mut_ptr();
+        unsafe { ptr::write_unaligned(pointer, expected) };
+
+        let value: u64 = ::acpi::read_physical(address);
+
+        deallocate_frame(first);
+        deallocate_frame(frame_at(start + PAGE_SIZE));
+
+        if value == expected {
+            Ok(())
+        } else {
+            Err("read the wrong value across the page boundary")
+        }
+    }
+
+    fn acpi_checksum() -> Result<(), &'static str> {
+        use acpi::checksum_ok;
+
+        // The last byte makes the sum wrap around to zero.
+        let mut table = [0x41u8, 0x50, 0x49, 0x43, 0x10, 0x00, 0x00, 0x00, 0x00];
+        table[8] = 0u8.wrapping_sub(table.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+
+        if !checksum_ok(&table) {
+            return Err("valid table was rejected");
+        }
+
+        table[4] ^= 0xff;
+        if checksum_ok(&table) {
+            Err("corrupted table was accepted")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Switching the firmware into ACPI mode, which has to happen before the PM1 registers can be used
+//! for shutdown or reboot.
+
+use acpi::fadt::{AddressSpace, Fadt};
+use device::Port;
+
+/// Set in PM1 control once the system is in ACPI mode.
+const SCI_EN: u16 = 1 << 0;
+
+/// How many times to poll SCI_EN after asking the firmware to hand over. Firmware can take a while,
+/// and the PIT may not be running yet, so this is a count rather than a time.
+const ENABLE_POLLS: usize = 1_000_000;
+
+/// The port accesses the ACPI handoff needs, so that it can be run against fake hardware.
+pub trait PortIo {
+    fn inw(&mut self, port: u16) -> u16;
+    fn outb(&mut self, port: u16, value: u8);
+}
+
+/// Real port I/O.
+pub struct Hardware;
+
+impl PortIo for Hardware {
+    fn inw(&mut self, port: u16) -> u16 {
+        unsafe { Port::<u16>::new(port) }.read()
+    }
+
+    fn outb(&mut self, port: u16, value: u8) {
+        unsafe { Port::<u8>::new(port) }.write(value)
+    }
+}
+
+/// Switch the system into ACPI mode using the FADT found by `acpi::init`, if it is not already.
+pub fn enable() -> Result<(), &'static str> {
+    let fadt = super::fadt().ok_or("no FADT")?;
+    enable_with(fadt, &mut Hardware)
+}
+
+/// Switch the system described by `fadt` into ACPI mode through `io`. Does nothing if SCI_EN is
+/// already set, or if there is no SMI command port, which means the system is always in ACPI mode.
+pub fn enable_with<P: PortIo>(fadt: &Fadt, io: &mut P) -> Result<(), &'static str> {
+    if fadt.pm1a_control.space != AddressSpace::SystemIo {
+        return Err("PM1a control block is not in I/O space");
+    }
+    let pm1a_control = fadt.pm1a_control.address as u16;
+
+    if io.inw(pm1a_control) & SCI_EN != 0 {
+        return Ok(());
+    }
+
+    if fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        // Hardware-reduced systems have no legacy mode to leave.
+        return Ok(());
+    }
+
+    io.outb(fadt.smi_command as u16, fadt.acpi_enable);
+
+    for _ in 0..ENABLE_POLLS {
+        if io.inw(pm1a_control) & SCI_EN != 0 {
+            return Ok(());
+        }
+    }
+
+    Err("firmware did not set SCI_EN")
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use alloc::Vec;
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "acpi_enable",
+            run: acpi_enable,
+        },
+    ];
+
+    fn acpi_enable() -> Result<(), &'static str> {
+        use acpi::fadt::{AddressSpace, Fadt, GenericAddress};
+        use acpi::pm::{enable_with, PortIo};
+
+        /// Firmware that sets SCI_EN once the enable value is written to its SMI port.
+        struct FakeFirmware {
+            writes: Vec<(u16, u8)>,
+            pm1a_control: u16,
+        }
+
+        impl PortIo for FakeFirmware {
+            fn inw(&mut self, port: u16) -> u16 {
+                if port == 0x604 {
+                    self.pm1a_control
+                } else {
+                    0xffff
+                }
+            }
+
+            fn outb(&mut self, port: u16, value: u8) {
+                self.writes.push((port, value));
+                if port == 0xb2 && value == 0xf0 {
+                    self.pm1a_control |= 1;
+                }
+            }
+        }
+
+        let mut fadt = Fadt {
+            sci_interrupt: 9,
+            smi_command: 0xb2,
+            acpi_enable: 0xf0,
+            acpi_disable: 0xf1,
+            pm1a_control: GenericAddress {
+                space: AddressSpace::SystemIo,
+                address: 0x604,
+            },
+            pm1b_control: None,
+            reset_register: None,
+            reset_value: 0,
+        };
+
+        let mut firmware = FakeFirmware {
+            writes: Vec::new(),
+            pm1a_control: 0,
+        };
+        enable_with(&fadt, &mut firmware)?;
+        if firmware.writes != [(0xb2, 0xf0)] {
+            return Err("did not write the enable value to the SMI port exactly once");
+        }
+
+        // Nothing should be written once ACPI mode is on, or without an SMI port.
+        enable_with(&fadt, &mut firmware)?;
+        fadt.smi_command = 0;
+        firmware.pm1a_control = 0;
+        enable_with(&fadt, &mut firmware)?;
+
+        if firmware.writes.len() == 1 {
+            Ok(())
+        } else {
+            Err("wrote to the SMI port when no handoff was needed")
+        }
+    }
+}
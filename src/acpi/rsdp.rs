@@ -1,7 +1,7 @@
-use arch::memory::Frame;
-use arch::memory::paging::{Page, PhysicalAddress, VirtualAddress};
-use arch::memory::paging::ActivePageTable;
-use arch::memory::paging::entry::EntryFlags;
+use arch::{Arch, MapFlags, ARCH};
+use arch::multiboot;
+use multiboot2::BootInformation;
+use core::{mem, ptr, slice};
 
 #[derive(Copy, Clone, Debug)]
 #[repr(packed)]
@@ -17,37 +17,93 @@ pub struct RsdpDescriptor {
     reserved: [u8; 3],
 }
 
+/// The length of the ACPI 1.0 part of the RSDP, which the first checksum covers.
+const RSDP_V1_LENGTH: usize = 20;
+
 impl RsdpDescriptor {
     /// Map RSDP address space, search for RSDP.
-    pub fn init(active_table: &mut ActivePageTable) -> Option<Self> {
+    pub fn init() -> Option<Self> {
         // TODO: Search in EBDA as well.
 
         let rsdp_start: usize = 0xe0000;
         let rsdp_end: usize = 0xf_ffff;
 
         // Map address space.
-        {
-            let start_frame = Frame::containing_address(PhysicalAddress::new(rsdp_start));
-            let end_frame = Frame::containing_address(PhysicalAddress::new(rsdp_end));
-
-            for frame in Frame::range_inclusive(start_frame, end_frame) {
-                let page =
-                    Page::containing_address(VirtualAddress::new(frame.start_address().get()));
-                let res =
-                    active_table.map_to(page, frame, EntryFlags::PRESENT | EntryFlags::NO_EXECUTE);
-
-                res.flush(active_table);
+        let page_size = ARCH.page_size();
+        for page in (rsdp_start / page_size..rsdp_end / page_size + 1).map(|n| n * page_size) {
+            if ARCH.translate(page).is_none() {
+                ARCH.map(page, page, MapFlags::empty()).ok()?;
             }
         }
 
         RsdpDescriptor::search(rsdp_start, rsdp_end)
     }
 
+    /// Take the RSDP copy the bootloader placed in the multiboot information structure, preferring
+    /// the ACPI 2.0+ tag over the 1.0 one. This is the only reliable source on UEFI systems, which
+    /// have no RSDP in the BIOS area.
+    pub fn from_multiboot(boot_info: &BootInformation) -> Option<Self> {
+        let tag = multiboot::find_tag(boot_info, multiboot::TAG_RSDP_V2)
+            .or_else(|| multiboot::find_tag(boot_info, multiboot::TAG_RSDP_V1))?;
+
+        let rsdp = RsdpDescriptor::from_bytes(unsafe { tag.data() })?;
+        println!(
+            "[ acpi ] Found RSDP revision {} in multiboot tag {}",
+            rsdp.revision, tag.typ
+        );
+        Some(rsdp)
+    }
+
+    /// Parse an RSDP out of `bytes`. An ACPI 1.0 RSDP is only 20 bytes long, in which case the
+    /// extended fields are left zeroed.
+    fn from_bytes(bytes: &[u8]) -> Option<RsdpDescriptor> {
+        let mut rsdp = RsdpDescriptor {
+            signature: [0; 8],
+            checksum: 0,
+            oem_id: [0; 6],
+            revision: 0,
+            rsdt_address: 0,
+            length: 0,
+            xsdt_address: 0,
+            extended_checksum: 0,
+            reserved: [0; 3],
+        };
+
+        let len = bytes.len().min(mem::size_of::<RsdpDescriptor>());
+        unsafe {
+            ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                &mut rsdp as *mut RsdpDescriptor as *mut u8,
+                len,
+            );
+        }
+
+        if &rsdp.signature == b"RSD PTR " && RsdpDescriptor::checksums_ok(bytes) {
+            Some(rsdp)
+        } else {
+            None
+        }
+    }
+
     /// Find and parse the RSDP.
     fn search(start_addr: usize, end_addr: usize) -> Option<RsdpDescriptor> {
         for i in 0..(end_addr + 1 - start_addr) / 16 {
             let rsdp = unsafe { &*((start_addr + i * 16) as *const RsdpDescriptor) };
-            if &rsdp.signature == b"RSD PTR " {
+            if &rsdp.signature != b"RSD PTR " {
+                continue;
+            }
+
+            // The extended checksum may cover more than the structure, up to `length` bytes.
+            let len = if rsdp.revision >= 2 {
+                (rsdp.length as usize).max(mem::size_of::<RsdpDescriptor>())
+            } else {
+                RSDP_V1_LENGTH
+            };
+            let bytes = unsafe {
+                slice::from_raw_parts(rsdp as *const RsdpDescriptor as *const u8, len)
+            };
+
+            if RsdpDescriptor::checksums_ok(bytes) {
                 println!(
                     "[ acpi ] Found RSDP at {:#x}",
                     rsdp as *const RsdpDescriptor as usize
@@ -59,6 +115,36 @@ impl RsdpDescriptor {
         None
     }
 
+    /// Check the checksums of the RSDP in `bytes`: the ACPI 1.0 one over the first 20 bytes and,
+    /// from revision 2, the extended one over `length` bytes. Logs a warning on failure.
+    fn checksums_ok(bytes: &[u8]) -> bool {
+        if bytes.len() < RSDP_V1_LENGTH || !super::checksum_ok(&bytes[..RSDP_V1_LENGTH]) {
+            println!("[ acpi ] WARNING: Ignoring RSDP with an invalid checksum.");
+            return false;
+        }
+
+        // The revision is at offset 15, and the length of the extended structure at offset 20.
+        if bytes[15] < 2 {
+            return true;
+        }
+        if bytes.len() < RSDP_V1_LENGTH + 4 {
+            println!("[ acpi ] WARNING: Ignoring truncated ACPI 2.0 RSDP.");
+            return false;
+        }
+
+        let length = bytes[20..24]
+            .iter()
+            .rev()
+            .fold(0, |length, &byte| length << 8 | byte as usize)
+            .min(bytes.len());
+        if length < RSDP_V1_LENGTH || !super::checksum_ok(&bytes[..length]) {
+            println!("[ acpi ] WARNING: Ignoring RSDP with an invalid extended checksum.");
+            return false;
+        }
+
+        true
+    }
+
     /// Dependent on ACPI version, return the address of the XSDT/RSDT.
     pub fn sdt(&self) -> usize {
         if self.revision >= 2 {
@@ -68,3 +154,65 @@ impl RsdpDescriptor {
         }
     }
 }
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "rsdp_multiboot",
+            run: rsdp_multiboot,
+        },
+    ];
+
+    /// A revision 2 RSDP pointing at `rsdt` and `xsdt`, with both checksums set.
+    fn rsdp_v2(rsdt: u32, xsdt: u64) -> [u8; 36] {
+        let mut bytes = [0; 36];
+        bytes[..8].copy_from_slice(b"RSD PTR ");
+        bytes[15] = 2;
+        for i in 0..4 {
+            bytes[16 + i] = (rsdt >> (i * 8)) as u8;
+        }
+        bytes[20] = 36;
+        for i in 0..8 {
+            bytes[24 + i] = (xsdt >> (i * 8)) as u8;
+        }
+
+        let sum = |bytes: &[u8]| bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        bytes[8] = 0u8.wrapping_sub(sum(&bytes[..20]));
+        bytes[32] = 0u8.wrapping_sub(sum(&bytes[..]));
+        bytes
+    }
+
+    fn rsdp_multiboot() -> Result<(), &'static str> {
+        use acpi::rsdp::RsdpDescriptor;
+        use arch::multiboot::{TAG_RSDP_V1, TAG_RSDP_V2};
+        use ktest::multiboot_info;
+
+        // The 1.0 tag comes first and points elsewhere, so only the 2.0 one can give this XSDT.
+        // Dropping the revision to 0 takes 2 off the sum the first checksum covers.
+        let mut v1 = rsdp_v2(0x000e_1000, 0);
+        v1[15] = 0;
+        v1[8] = v1[8].wrapping_add(2);
+        let v2 = rsdp_v2(0x000e_2000, 0x1_2345_6000);
+
+        let mut buffer = [0u64; 16];
+        let tags: [(u32, &[u8]); 2] = [(TAG_RSDP_V1, &v1[..20]), (TAG_RSDP_V2, &v2)];
+        let boot_info = unsafe { ::multiboot2::load(multiboot_info(&mut buffer, &tags)) };
+        let rsdp = RsdpDescriptor::from_multiboot(&boot_info).ok_or("the RSDP tags were ignored")?;
+        if rsdp.revision != 2 || rsdp.sdt() != 0x1_2345_6000 {
+            return Err("the pointer in the RSDP v2 tag was not used");
+        }
+
+        let mut corrupt = v2;
+        corrupt[24] ^= 1;
+        let tags: [(u32, &[u8]); 1] = [(TAG_RSDP_V2, &corrupt)];
+        let boot_info = unsafe { ::multiboot2::load(multiboot_info(&mut buffer, &tags)) };
+        if RsdpDescriptor::from_multiboot(&boot_info).is_some() {
+            Err("an RSDP with a bad extended checksum was used")
+        } else {
+            Ok(())
+        }
+    }
+}
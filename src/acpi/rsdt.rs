@@ -1,7 +1,9 @@
 use super::sdt::SdtHeader;
-use core::slice;
+use core::{slice, str};
 
 use super::madt::Madt;
+use super::fadt::Fadt;
+use super::hpet::HpetTable;
 
 #[derive(Debug)]
 pub struct Rsdt<'a> {
@@ -35,10 +37,18 @@ impl<'a> Rsdt<'a> {
 
             if sig != signature {
                 continue;
+            } else if !sdt.checksum_ok() {
+                println!(
+                    "[ acpi ] WARNING: Ignoring {} table with an invalid checksum.",
+                    str::from_utf8(sig).unwrap_or("????")
+                );
+                continue;
             } else {
                 match signature {
                     // TODO: Support more tables.
                     b"APIC" => return Some(TableType::Madt(Madt::new(sdt))),
+                    b"FACP" => return Fadt::new(sdt).map(TableType::Fadt),
+                    b"HPET" => return HpetTable::new(sdt).map(TableType::Hpet),
                     _ => return None,
                 }
             }
@@ -56,6 +66,6 @@ impl<'a> Rsdt<'a> {
 
 pub enum TableType {
     Madt(Madt),
-    Facp,
-    Hpet,
+    Fadt(Fadt),
+    Hpet(HpetTable),
 }
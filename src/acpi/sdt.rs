@@ -44,4 +44,11 @@ impl SdtHeader {
     pub unsafe fn data(&self) -> &[u8] {
         slice::from_raw_parts(self.data_address() as *const u8, self.data_len())
     }
+
+    /// Check the checksum over the whole table, header included. The table must be mapped.
+    pub fn checksum_ok(&self) -> bool {
+        let length = (self.length as usize).max(mem::size_of::<Self>());
+        let bytes = unsafe { slice::from_raw_parts(self as *const _ as *const u8, length) };
+        super::checksum_ok(bytes)
+    }
 }
@@ -6,7 +6,11 @@ pub struct Xsdt(&'static SdtHeader);
 impl Xsdt {
     pub fn new(sdt: &'static SdtHeader) -> Option<Xsdt> {
         match &sdt.signature {
-            b"XSDT" => Some(Xsdt(sdt)),
+            b"XSDT" if sdt.checksum_ok() => Some(Xsdt(sdt)),
+            b"XSDT" => {
+                println!("[ acpi ] WARNING: XSDT checksum is invalid, ignoring it.");
+                None
+            }
             _ => None,
         }
     }
@@ -1,5 +1,86 @@
+//! Architecture support. Each port lives in its own module and provides an implementation of
+//! `Arch`, exported as `arch::ARCH`, through which generic code does the few things it needs the
+//! hardware for: mapping pages, masking interrupts, halting and port and MMIO access.
+//!
+//! Generic code goes through `Arch` for those. It still reaches into the x86_64 port for what
+//! `Arch` does not cover yet:
+//!
+//! - Drivers for x86-only hardware: the APIC and its MSRs, the PIC and the PS/2 controller.
+//! - The memory manager: task stacks, DMA buffers, handing frames to the frame allocator, and the
+//!   heap and frame statistics shown by procfs and `top`.
+//! - Task switching: the kernel stack in the TSS, and `disable_interrupts_and_then`, which also
+//!   masks the PIC.
+//! - Fatal error handling, and the fixtures of the self-tests.
+//!
+//! Each of these needs a matching interface before a second port can build.
+
 #[cfg(target_arch = "x86_64")]
 pub mod x86_64;
 
 #[cfg(target_arch = "x86_64")]
 pub use self::x86_64::*;
+
+bitflags! {
+    /// How a page mapped through `Arch::map` may be used. Pages are always readable.
+    pub struct MapFlags: u8 {
+        const WRITABLE =   1 << 0;
+        const EXECUTABLE = 1 << 1;
+        /// For device registers, which must not be cached.
+        const NO_CACHE =   1 << 2;
+    }
+}
+
+/// The operations generic kernel code needs from an architecture. Addresses are plain `usize`s,
+/// since the address types of each port are its own.
+pub trait Arch {
+    /// The size of the pages `map` and `unmap` work on, in bytes.
+    fn page_size(&self) -> usize;
+
+    /// Map the page containing `virt` to the frame containing `phys` in the active address space.
+    /// Fails if the page is already mapped.
+    fn map(&self, virt: usize, phys: usize, flags: MapFlags) -> Result<(), &'static str>;
+
+    /// Unmap the page containing `virt`, and return the physical address it was mapped to. The
+    /// frame is not freed.
+    fn unmap(&self, virt: usize) -> Option<usize>;
+
+    /// Return the physical address `virt` is mapped to, if it is mapped.
+    fn translate(&self, virt: usize) -> Option<usize>;
+
+    /// Disable maskable interrupts, and return whether they were enabled before.
+    fn disable_interrupts(&self) -> bool;
+
+    /// Enable interrupts again if `enabled`, the value returned by `disable_interrupts`.
+    fn restore_interrupts(&self, enabled: bool);
+
+    /// Enable interrupts and sleep until the next one arrives.
+    fn halt(&self);
+
+    /// Read a byte from I/O port `port`.
+    unsafe fn read_port(&self, port: u16) -> u8;
+
+    /// Write a byte to I/O port `port`.
+    unsafe fn write_port(&self, port: u16, value: u8);
+
+    /// Read the 32-bit device register mapped at `address`.
+    unsafe fn read_mmio(&self, address: usize) -> u32;
+
+    /// Write the 32-bit device register mapped at `address`.
+    unsafe fn write_mmio(&self, address: usize, value: u32);
+}
+
+/// Run `f` with interrupts disabled, then put them back the way they were, through `ARCH`. This
+/// never enables interrupts that were not already enabled, so it is safe to call from an interrupt
+/// handler or with a lock held that interrupt handlers take.
+pub fn without_interrupts<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let enabled = ARCH.disable_interrupts();
+
+    let result = f();
+
+    ARCH.restore_interrupts(enabled);
+
+    result
+}
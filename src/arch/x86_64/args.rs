@@ -0,0 +1,73 @@
+//! Kernel arguments, as passed on the command line by the bootloader. Arguments are separated by
+//! whitespace and are either bare flags (`checkpaging`) or `key=value` pairs (`hz=1000`).
+
+use core::str::{self, SplitWhitespace};
+use spin::Once;
+
+/// Maximum length of the command line we keep around. Anything past this is dropped.
+const MAX_COMMAND_LINE: usize = 256;
+
+/// A copy of the kernel command line. We copy it out of the multiboot structure so that it stays
+/// valid after the boot memory is reclaimed.
+pub struct KernelArgs {
+    buffer: [u8; MAX_COMMAND_LINE],
+    len: usize,
+}
+
+impl KernelArgs {
+    /// Copy `command_line` into a new `KernelArgs`, truncating at a character boundary if needed.
+    pub fn parse(command_line: &str) -> KernelArgs {
+        let mut len = command_line.len().min(MAX_COMMAND_LINE);
+        while !command_line.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        let mut args = KernelArgs {
+            buffer: [0; MAX_COMMAND_LINE],
+            len: len,
+        };
+        args.buffer[..len].copy_from_slice(&command_line.as_bytes()[..len]);
+        args
+    }
+
+    /// Return the whole command line.
+    pub fn as_str(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.buffer[..self.len]) }
+    }
+
+    /// Return an iterator over every argument.
+    pub fn iter(&self) -> SplitWhitespace {
+        self.as_str().split_whitespace()
+    }
+
+    /// Check if the bare flag `flag` was passed.
+    pub fn has(&self, flag: &str) -> bool {
+        self.iter().any(|arg| arg == flag)
+    }
+
+    /// Return the value of a `key=value` argument.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.iter()
+            .filter_map(|arg| {
+                let mut split = arg.splitn(2, '=');
+                match (split.next(), split.next()) {
+                    (Some(k), Some(v)) if k == key => Some(v),
+                    _ => None,
+                }
+            })
+            .next()
+    }
+}
+
+static ARGS: Once<KernelArgs> = Once::new();
+
+/// Save the kernel command line. Only the first call has any effect.
+pub fn init(command_line: &str) {
+    let args = ARGS.call_once(|| KernelArgs::parse(command_line));
+    println!("[ INFO ] Kernel arguments: \"{}\"", args.as_str());
+}
+
+/// Return the kernel arguments, or an empty set if `init` has not been called yet.
+pub fn get() -> &'static KernelArgs {
+    ARGS.call_once(|| KernelArgs::parse(""))
+}
@@ -1,4 +1,6 @@
 global start
+global stack_top
+global boot_stack_size
 extern long_mode_start
 
 section .text
@@ -180,9 +182,11 @@ p3_table:
     resb 4096
 p2_table:
     resb 4096
+; Exported so that the kernel can find the bottom of the stack and put a guard page below it.
+boot_stack_size equ 4096 * 8
 stack_bottom:
     ; Reserve a 32 KiB stack for the kernel main function.
-    resb 4096 * 8
+    resb boot_stack_size
 stack_top:
 
 section .rodata
@@ -0,0 +1,54 @@
+global timer_entry
+global interrupt_return
+extern timer_interrupt
+
+section .text
+bits 64
+; The timer interrupt. Every general purpose register is saved below the frame the CPU pushed,
+; and `timer_interrupt` is handed a pointer to the lot. It returns the stack pointer to restore
+; from, which is the same one unless the scheduler switched to a process the timer switched out
+; earlier, in which case it is that process's saved registers and frame.
+timer_entry:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    ; The CPU aligned the stack before pushing its frame, and the 20 words pushed since keep it
+    ; aligned for the call.
+    mov rdi, rsp
+    cld
+    call timer_interrupt
+    mov rsp, rax
+
+; Restore the registers saved by `timer_entry` at RSP and return from the interrupt. The frame
+; restores RIP, CS, RFLAGS, RSP and SS, so a process interrupted in ring 3 goes back to its user
+; stack. `Context::switch_to_frame` jumps here to resume such a process.
+interrupt_return:
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    iretq
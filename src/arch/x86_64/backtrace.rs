@@ -0,0 +1,35 @@
+//! A best-effort backtrace, made by walking the chain of saved frame pointers. The target spec
+//! keeps frame pointers, so each frame starts with the caller's `rbp` followed by the return
+//! address.
+
+use arch::cpuid;
+use arch::memory::paging::is_canonical;
+
+/// Print up to `max_frames` return addresses, starting with the caller of this function. The walk
+/// stops early if the chain looks corrupt.
+pub fn print(max_frames: usize) {
+    let mut rbp: usize;
+    unsafe { asm!("mov $0, rbp" : "=r"(rbp) : : : "intel", "volatile") };
+
+    let bits = cpuid::virtual_address_bits();
+
+    println!("Backtrace:");
+    for depth in 0..max_frames {
+        if rbp == 0 || rbp % 8 != 0 || !is_canonical(rbp, bits) {
+            break;
+        }
+
+        let return_address = unsafe { *((rbp + 8) as *const usize) };
+        if return_address == 0 {
+            break;
+        }
+        println!("    {:>2}: {:#x}", depth, return_address);
+
+        // The stack grows down, so callers' frames are always at higher addresses.
+        let next = unsafe { *(rbp as *const usize) };
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
@@ -0,0 +1,166 @@
+//! Raw CPUID queries that `raw_cpuid` does not cover: address widths and hypervisor detection.
+
+use spin::Once;
+
+/// Execute CPUID with the given leaf, returning `(eax, ebx, ecx, edx)`.
+pub fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+             : "{eax}"(leaf), "{ecx}"(0)
+             :: "volatile");
+    }
+    (eax, ebx, ecx, edx)
+}
+
+/// The physical and linear address widths reported by the CPU, cached since they are needed for
+/// every address check.
+static ADDRESS_BITS: Once<(u8, u8)> = Once::new();
+
+/// Read the physical and linear address widths from leaf 0x80000008, falling back to 36 and 48
+/// bits on CPUs without that leaf.
+fn address_bits() -> (u8, u8) {
+    *ADDRESS_BITS.call_once(|| {
+        let (max_extended_leaf, _, _, _) = cpuid(0x8000_0000);
+        if max_extended_leaf < 0x8000_0008 {
+            return (36, 48);
+        }
+
+        let (eax, _, _, _) = cpuid(0x8000_0008);
+        (eax as u8, (eax >> 8) as u8)
+    })
+}
+
+/// The number of physical address bits the CPU supports.
+pub fn phys_addr_bits() -> u8 {
+    address_bits().0
+}
+
+/// The number of linear (virtual) address bits the CPU supports.
+pub fn linear_addr_bits() -> u8 {
+    address_bits().1
+}
+
+/// Check if 5-level paging is in use: the CPU supports 57-bit linear addresses and CR4.LA57 is set.
+pub fn five_level_paging() -> bool {
+    use x86_64::registers::control_regs::cr4;
+
+    linear_addr_bits() >= 57 && cr4().bits() & (1 << 12) != 0
+}
+
+/// Return the width of canonical virtual addresses under the paging mode in use.
+pub fn virtual_address_bits() -> u8 {
+    if five_level_paging() {
+        57
+    } else {
+        linear_addr_bits().min(48)
+    }
+}
+
+/// Whether the CPU supports the no-execute bit, cached since every page table entry written
+/// depends on it.
+static NX: Once<bool> = Once::new();
+
+/// Check if the CPU supports the no-execute page table bit, from leaf 0x80000001. Without it, bit
+/// 63 of an entry is reserved.
+pub fn has_nx() -> bool {
+    *NX.call_once(|| {
+        let (max_extended_leaf, _, _, _) = cpuid(0x8000_0000);
+        if max_extended_leaf < 0x8000_0001 {
+            return false;
+        }
+
+        let (_, _, _, edx) = cpuid(0x8000_0001);
+        edx & (1 << 20) != 0
+    })
+}
+
+/// A hypervisor we know the CPUID signature of.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hypervisor {
+    Kvm,
+    /// QEMU without KVM, using the tiny code generator.
+    QemuTcg,
+    VMware,
+    HyperV,
+    Xen,
+    VirtualBox,
+    Unknown,
+}
+
+impl Hypervisor {
+    /// Identify a hypervisor from the 12 byte signature in EBX, ECX, EDX of leaf 0x40000000.
+    pub fn from_signature(signature: &[u8; 12]) -> Hypervisor {
+        match signature {
+            b"KVMKVMKVM\0\0\0" => Hypervisor::Kvm,
+            b"TCGTCGTCGTCG" => Hypervisor::QemuTcg,
+            b"VMwareVMware" => Hypervisor::VMware,
+            b"Microsoft Hv" => Hypervisor::HyperV,
+            b"XenVMMXenVMM" => Hypervisor::Xen,
+            b"VBoxVBoxVBox" => Hypervisor::VirtualBox,
+            _ => Hypervisor::Unknown,
+        }
+    }
+}
+
+/// Check if we are running under a hypervisor, which sets bit 31 of ECX in leaf 1.
+pub fn hypervisor_present() -> bool {
+    let (_, _, ecx, _) = cpuid(1);
+    ecx & (1 << 31) != 0
+}
+
+/// Return the raw vendor signature of the hypervisor, if there is one.
+pub fn hypervisor_signature() -> Option<[u8; 12]> {
+    if !hypervisor_present() {
+        return None;
+    }
+
+    let (_, ebx, ecx, edx) = cpuid(0x4000_0000);
+    Some(signature(ebx, ecx, edx))
+}
+
+/// Lay out the signature returned in EBX, ECX and EDX as the 12 bytes it spells, low byte first.
+fn signature(ebx: u32, ecx: u32, edx: u32) -> [u8; 12] {
+    let mut signature = [0; 12];
+    for (i, register) in [ebx, ecx, edx].iter().enumerate() {
+        for byte in 0..4 {
+            signature[i * 4 + byte] = (register >> (byte * 8)) as u8;
+        }
+    }
+
+    signature
+}
+
+/// Return the hypervisor we are running under, if any.
+pub fn hypervisor_vendor() -> Option<Hypervisor> {
+    hypervisor_signature().map(|signature| Hypervisor::from_signature(&signature))
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "hypervisor_signature",
+            run: hypervisor_signature,
+        },
+    ];
+
+    fn hypervisor_signature() -> Result<(), &'static str> {
+        use super::{signature, Hypervisor};
+
+        // What KVM returns from leaf 0x40000000: "KVMK", "VMKV", "M\0\0\0".
+        let kvm = signature(0x4b4d_564b, 0x564b_4d56, 0x0000_004d);
+        if &kvm != b"KVMKVMKVM\0\0\0" {
+            Err("the signature registers were laid out in the wrong order")
+        } else if Hypervisor::from_signature(&kvm) != Hypervisor::Kvm {
+            Err("the KVM signature was not recognised")
+        } else if Hypervisor::from_signature(b"KVMKVMKVMKVM") != Hypervisor::Unknown {
+            Err("a signature that is only close to KVM's was recognised")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -14,30 +14,70 @@ pub unsafe fn init(multiboot_info: usize) {
 
     asm!("cli");
     {
-        device::vga::buffer::clear_screen();
+        let boot_info = ::multiboot2::load(multiboot_info);
+        super::args::init(super::multiboot::command_line(&boot_info).unwrap_or(""));
+
+        // Machines without legacy VGA text mode may have something else at 0xb8000.
+        if super::args::get().has("novga") {
+            device::vga::vga::set_enabled(false);
+            device::console::set_mirror(false);
+        } else {
+            device::vga::buffer::clear_screen();
+        }
         println!("[ INFO ] lambdaOS: Begin init.");
+        if !device::vga::vga::enabled() {
+            println!("[ INFO ] VGA text mode disabled, logging to serial only.");
+        }
 
-        let boot_info = ::multiboot2::load(multiboot_info);
+        match super::cpuid::hypervisor_vendor() {
+            Some(vendor) => println!("[ INFO ] Running under hypervisor: {:?}", vendor),
+            None => println!("[ INFO ] No hypervisor detected."),
+        }
+
+        // Before anything allocates frames, so that the frame magazines can tell CPUs apart.
+        super::percpu::init();
 
         // Set safety bits in certain registers.
         enable_nxe_bit();
         enable_write_protect_bit();
 
         // Setup memory management.
-        let mut memory_controller = memory::init(&boot_info);
-        interrupts::init(&mut memory_controller);
+        memory::init(&boot_info);
+        interrupts::init();
+
+        ::acpi::init(&boot_info);
 
         // Setup hardware devices.
         device::init();
+
+        ::fs::init();
+
+        // The APIC manager has its own copy of the MADT entries by now.
+        ::acpi::reclaim(&boot_info);
+
+        // Nothing uses the multiboot structure past this point.
+        memory::reclaim_boot_memory();
+
+        println!("[ mem ] {}", memory::with_controller(|controller| controller.stats()));
     }
     asm!("sti");
 
+    ::ktest::run_from_args();
+    ::top::run_from_args();
+
     println!("[ OK ] Init successful, you may now type.")
 }
 
+/// Enable the no-execute bit, if the CPU has one. Without it, page table entries are written with
+/// `NO_EXECUTE` cleared.
 pub fn enable_nxe_bit() {
     use x86_64::registers::msr::{rdmsr, wrmsr, IA32_EFER};
 
+    if !super::cpuid::has_nx() {
+        println!("[ INFO ] The CPU does not support NX, mapping every page executable.");
+        return;
+    }
+
     let nxe_bit = 1 << 11;
     unsafe {
         let efer = rdmsr(IA32_EFER);
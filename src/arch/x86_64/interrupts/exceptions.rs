@@ -1,16 +1,20 @@
 //! Handlers for internal CPU exceptions. Currently, when an exception occurs, we just print some
-//! debug information and then spin the CPU. TODO: Figure out which exceptions are safe to return
-//! from.
+//! debug information and then take the configured `FatalAction`, see `fatal`. TODO: Figure out
+//! which exceptions are safe to return from.
 
-use x86_64::structures::idt::{ExceptionStackFrame, PageFaultErrorCode};
+use core::fmt;
+use x86_64::structures::idt::PageFaultErrorCode;
+use super::{ExceptionStackFrame, ExceptionStackFrameWithErrorCode};
 use super::disable_interrupts_and_then;
+use super::guard::report_fault_in_handler;
 
 /// Handler for the #DE Exception. This exception occurs when divinding any number by zero using
 /// either the DIV or IDIV instructions.
 pub extern "x86-interrupt" fn divide_by_zero_handler(stack_frame: &mut ExceptionStackFrame) {
     disable_interrupts_and_then(|| {
+        report_fault_in_handler("DIVIDE BY ZERO");
         println!("\nEXCEPTION: DIVIDE BY ZERO\n{:#?}", stack_frame);
-        loop {}
+        super::fatal::die()
     });
 }
 
@@ -23,8 +27,9 @@ pub extern "x86-interrupt" fn divide_by_zero_handler(stack_frame: &mut Exception
 /// - Task switch (Trap).
 pub extern "x86-interrupt" fn debug_handler(stack_frame: &mut ExceptionStackFrame) {
     disable_interrupts_and_then(|| {
+        report_fault_in_handler("DEBUG");
         println!("\nEXCEPTION: DEBUG\n{:#?}", stack_frame);
-        loop {}
+        super::fatal::die()
     });
 }
 
@@ -34,8 +39,9 @@ pub extern "x86-interrupt" fn debug_handler(stack_frame: &mut ExceptionStackFram
 /// which piece of hardware is faulty.
 pub extern "x86-interrupt" fn nmi_handler(stack_frame: &mut ExceptionStackFrame) {
     disable_interrupts_and_then(|| {
+        report_fault_in_handler("NON-MASKABLE INTERRUPT");
         println!("\nEXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}", stack_frame);
-        loop {}
+        super::fatal::die()
     });
 }
 
@@ -43,7 +49,7 @@ pub extern "x86-interrupt" fn nmi_handler(stack_frame: &mut ExceptionStackFrame)
 pub extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut ExceptionStackFrame) {
     println!(
         "\nEXCEPTION: BREAKPOINT at {:#x}\n{:#?}",
-        stack_frame.instruction_pointer, stack_frame
+        stack_frame.rip, stack_frame
     );
 }
 
@@ -52,8 +58,9 @@ pub extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut ExceptionStac
 /// than the maximum value of a 64-bit integer.
 pub extern "x86-interrupt" fn overflow_handler(stack_frame: &mut ExceptionStackFrame) {
     disable_interrupts_and_then(|| {
+        report_fault_in_handler("OVERFLOW");
         println!("\nEXCEPTION: OVERFLOW\n{:#?}", stack_frame);
-        loop {}
+        super::fatal::die()
     });
 }
 
@@ -62,8 +69,9 @@ pub extern "x86-interrupt" fn overflow_handler(stack_frame: &mut ExceptionStackF
 /// upper and lower bounds of the array. If the index is out of bounds, this exception is thrown.
 pub extern "x86-interrupt" fn bound_range_handler(stack_frame: &mut ExceptionStackFrame) {
     disable_interrupts_and_then(|| {
+        report_fault_in_handler("BOUND RANGE EXCEEDED");
         println!("\nEXCEPTION: BOUND RANGE EXCEEDED\n{:#?}", stack_frame);
-        loop {}
+        super::fatal::die()
     });
 }
 
@@ -71,11 +79,12 @@ pub extern "x86-interrupt" fn bound_range_handler(stack_frame: &mut ExceptionSta
 /// the instruction exceeds 15 bytes), an `INVALID OPCODE` exception is thrown.
 pub extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: &mut ExceptionStackFrame) {
     disable_interrupts_and_then(|| {
+        report_fault_in_handler("INVALID OPCODE");
         println!(
             "\nEXCEPTION: INVALID OPCODE at {:#x}\n{:#?}",
-            stack_frame.instruction_pointer, stack_frame
+            stack_frame.rip, stack_frame
         );
-        loop {}
+        super::fatal::die()
     });
 }
 
@@ -84,21 +93,35 @@ pub extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: &mut Exception
 /// FPU.
 pub extern "x86-interrupt" fn device_not_available_handler(stack_frame: &mut ExceptionStackFrame) {
     disable_interrupts_and_then(|| {
+        report_fault_in_handler("FPU NOT AVAILABLE");
         println!("\nEXCEPTION: FPU NOT AVAILABLE\n{:#?}", stack_frame);
-        loop {}
+        super::fatal::die()
     });
 }
 
 /// A Double Fault occurs when a) an exception is unhandled, b) when an exception occurs whilst the
 /// CPU is in the process of calling the exception handler for the first exception. This is an
-/// Abort, meaning it is not possible to recover from a Double Fault.
+/// Abort, meaning it is not possible to recover from a Double Fault, so the configured
+/// `FatalAction` is taken.
 pub extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut ExceptionStackFrame,
-    _error_code: u64,
+    error_code: u64,
 ) {
     disable_interrupts_and_then(|| {
-        println!("\nEXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-        loop {}
+        use arch::memory::paging::VirtualAddress;
+        use x86_64::registers::control_regs;
+
+        report_fault_in_handler("DOUBLE FAULT");
+        let frame = ExceptionStackFrameWithErrorCode::new(stack_frame, error_code);
+        println!("\nEXCEPTION: DOUBLE FAULT\n{:#?}", frame);
+
+        // A page fault on a stack's guard page cannot be pushed onto that stack, so a stack
+        // overflow usually ends up here rather than in the page fault handler.
+        let address = control_regs::cr2().0;
+        if let Some(bottom) = ::arch::memory::guarded_stack(VirtualAddress::new(address)) {
+            println!("Stack overflow in the stack starting at {:#x}", bottom);
+        }
+        super::fatal::die()
     });
 }
 
@@ -109,11 +132,10 @@ pub extern "x86-interrupt" fn invalid_tss_handler(
     error_code: u64,
 ) {
     disable_interrupts_and_then(|| {
-        println!(
-            "\nEXCEPTION: INVALID TSS with code: {:?}\n{:#?}",
-            error_code, stack_frame
-        );
-        loop {}
+        report_fault_in_handler("INVALID TSS");
+        let frame = ExceptionStackFrameWithErrorCode::new(stack_frame, error_code);
+        println!("\nEXCEPTION: INVALID TSS\n{:#?}", frame);
+        super::fatal::die()
     });
 }
 
@@ -124,13 +146,10 @@ pub extern "x86-interrupt" fn seg_not_present_handler(
     error_code: u64,
 ) {
     disable_interrupts_and_then(|| {
-        println!(
-            "\nEXCEPTION: SEGMENT NOT PRESENT\nerror code: \
-             {:?}\n{:#?}",
-            error_code, stack_frame
-        );
-
-        loop {}
+        report_fault_in_handler("SEGMENT NOT PRESENT");
+        let frame = ExceptionStackFrameWithErrorCode::new(stack_frame, error_code);
+        println!("\nEXCEPTION: SEGMENT NOT PRESENT\n{:#?}", frame);
+        super::fatal::die()
     });
 }
 
@@ -143,13 +162,10 @@ pub extern "x86-interrupt" fn stack_seg_fault_handler(
     error_code: u64,
 ) {
     disable_interrupts_and_then(|| {
-        println!(
-            "\nEXCEPTION: STACK SEGMENT FAULT\nerror code: \
-             {:?}\n{:#?}",
-            error_code, stack_frame
-        );
-
-        loop {}
+        report_fault_in_handler("STACK SEGMENT FAULT");
+        let frame = ExceptionStackFrameWithErrorCode::new(stack_frame, error_code);
+        println!("\nEXCEPTION: STACK SEGMENT FAULT\n{:#?}", frame);
+        super::fatal::die()
     });
 }
 
@@ -160,10 +176,12 @@ pub extern "x86-interrupt" fn stack_seg_fault_handler(
 /// - Referencing the null segment descriptor.
 /// - Trying to access an unimplemented register (i.e in Protected Mode: `mov cr6, eax` is
 /// illegal).
-pub extern "x86-interrupt" fn gpf_handler(stack_frame: &mut ExceptionStackFrame, _error_code: u64) {
+pub extern "x86-interrupt" fn gpf_handler(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
     disable_interrupts_and_then(|| {
-        println!("\nEXCEPTION: GPF\n{:#?}", stack_frame);
-        loop {}
+        report_fault_in_handler("GPF");
+        let frame = ExceptionStackFrameWithErrorCode::new(stack_frame, error_code);
+        println!("\nEXCEPTION: GPF\n{:#?}", frame);
+        super::fatal::die()
     });
 }
 
@@ -178,26 +196,70 @@ pub extern "x86-interrupt" fn page_fault_handler(
     error_code: PageFaultErrorCode,
 ) {
     disable_interrupts_and_then(|| {
+        use arch::memory::paging::VirtualAddress;
         use x86_64::registers::control_regs;
+
+        report_fault_in_handler("PAGE FAULT");
+        let address = control_regs::cr2().0;
         println!(
-            "\nEXCEPTION: PAGE FAULT while accessing {:#x}\nerror code: \
-             {:?}\n{:#?}",
-            control_regs::cr2(),
-            error_code,
+            "\nEXCEPTION: PAGE FAULT, {} at {:#x}\nerror code: {:#x}\n{:#?}",
+            PageFaultCause(error_code.bits()),
+            address,
+            error_code.bits(),
             stack_frame
         );
-        loop {}
+
+        if let Some(bottom) = ::arch::memory::guarded_stack(VirtualAddress::new(address)) {
+            println!("Stack overflow in the stack starting at {:#x}", bottom);
+        }
+        super::fatal::die()
     });
 }
 
+// Page fault error code bits.
+const PF_PROTECTION: u64 = 1 << 0;
+const PF_WRITE: u64 = 1 << 1;
+const PF_USER: u64 = 1 << 2;
+const PF_RESERVED: u64 = 1 << 3;
+const PF_INSTRUCTION: u64 = 1 << 4;
+
+/// A page fault error code, displayed as what was being done and why it faulted, such as
+/// "kernel-mode write to a non-present page".
+pub struct PageFaultCause(pub u64);
+
+impl fmt::Display for PageFaultCause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+
+        let mode = if code & PF_USER != 0 { "user" } else { "kernel" };
+        let access = if code & PF_INSTRUCTION != 0 {
+            "instruction fetch"
+        } else if code & PF_WRITE != 0 {
+            "write"
+        } else {
+            "read"
+        };
+        let reason = if code & PF_RESERVED != 0 {
+            "with a reserved bit set in the page tables"
+        } else if code & PF_PROTECTION != 0 {
+            "violating the page's protection"
+        } else {
+            "to a non-present page"
+        };
+
+        write!(f, "{}-mode {} {}", mode, access, reason)
+    }
+}
+
 /// An x87-floating point exception occurs when any waiting floating point instruction (e.g, FWAIT
 /// or WAIT.), and the following conditions are true:
 /// - CR0.NE = 1,
 /// - an unmasked x87 floating point exception is pending.
 pub extern "x86-interrupt" fn x87_fp_exception_handler(stack_frame: &mut ExceptionStackFrame) {
     disable_interrupts_and_then(|| {
+        report_fault_in_handler("X87 FLOATING POINT EXCEPTION");
         println!("\nX87 FLOATING POINT EXCEPTION\n{:#?}", stack_frame);
-        loop {}
+        super::fatal::die()
     });
 }
 
@@ -206,11 +268,13 @@ pub extern "x86-interrupt" fn x87_fp_exception_handler(stack_frame: &mut Excepti
 /// `CPL = 3`.
 pub extern "x86-interrupt" fn alignment_check_handler(
     stack_frame: &mut ExceptionStackFrame,
-    _error_code: u64,
+    error_code: u64,
 ) {
     disable_interrupts_and_then(|| {
-        println!("\nEXCEPTION: ALIGNMENT CHECK\n{:#?}", stack_frame);
-        loop {}
+        report_fault_in_handler("ALIGNMENT CHECK");
+        let frame = ExceptionStackFrameWithErrorCode::new(stack_frame, error_code);
+        println!("\nEXCEPTION: ALIGNMENT CHECK\n{:#?}", frame);
+        super::fatal::die()
     });
 }
 
@@ -219,9 +283,10 @@ pub extern "x86-interrupt" fn alignment_check_handler(
 /// placed in the model-specific registers.
 pub extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut ExceptionStackFrame) {
     disable_interrupts_and_then(|| {
+        report_fault_in_handler("MACHINE CHECK");
         // TODO: use the MSRs to get error information about the MC.
         println!("\nEXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
-        loop {}
+        super::fatal::die()
     });
 }
 
@@ -229,10 +294,43 @@ pub extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut ExceptionS
 /// will cause this exception. Otherwise, an `Invalid Opcode` exception occurs.
 pub extern "x86-interrupt" fn simd_fp_exception_handler(stack_frame: &mut ExceptionStackFrame) {
     disable_interrupts_and_then(|| {
+        report_fault_in_handler("SIMD FLOATING POINT EXCEPTION");
         println!(
             "\nEXCEPTION: SIMD FLOATING POINT EXCEPTION\n{:#?}",
             stack_frame
         );
-        loop {}
+        super::fatal::die()
     });
 }
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "page_fault_cause",
+            run: page_fault_cause,
+        },
+    ];
+
+    fn page_fault_cause() -> Result<(), &'static str> {
+        use arch::interrupts::exceptions::PageFaultCause;
+
+        let describe = |code| format!("{}", PageFaultCause(code));
+        let execute_protected = "kernel-mode instruction fetch violating the page's protection";
+        let reserved_bit = "kernel-mode read with a reserved bit set in the page tables";
+
+        if describe(0b00010) != "kernel-mode write to a non-present page" {
+            Err("a kernel write to an unmapped page was described wrongly")
+        } else if describe(0b00101) != "user-mode read violating the page's protection" {
+            Err("a user read of a kernel page was described wrongly")
+        } else if describe(0b10001) != execute_protected {
+            Err("an instruction fetch from a NO_EXECUTE page was described wrongly")
+        } else if describe(0b01001) != reserved_bit {
+            Err("a reserved bit fault was described wrongly")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! What to do once the kernel hits an error it cannot recover from, such as a double fault. During
+//! development the machine should stop so that the state can be inspected, but a CI run wants
+//! QEMU to exit with a failure code rather than hang until it times out. The action is taken from
+//! the `fatal` kernel argument: `fatal=halt`, `fatal=reboot` or `fatal=qemu-exit`.
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+/// The action taken on a fatal error.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FatalAction {
+    /// Halt the CPU with interrupts disabled.
+    Halt,
+    /// Reset the machine.
+    Reboot,
+    /// Exit QEMU with the failure code, through its `isa-debug-exit` device.
+    QemuExit,
+}
+
+/// The configured action, as an index into `ACTIONS`. `Halt` by default.
+static ACTION: AtomicUsize = ATOMIC_USIZE_INIT;
+
+const ACTIONS: [FatalAction; 3] = [FatalAction::Halt, FatalAction::Reboot, FatalAction::QemuExit];
+
+/// Set the action taken on a fatal error.
+pub fn set_fatal_action(action: FatalAction) {
+    let index = ACTIONS.iter().position(|&a| a == action).unwrap();
+    ACTION.store(index, Ordering::SeqCst);
+}
+
+/// Return the action taken on a fatal error.
+pub fn fatal_action() -> FatalAction {
+    ACTIONS[ACTION.load(Ordering::SeqCst)]
+}
+
+/// Set the action from the `fatal` kernel argument, if one is given.
+pub fn init_from_args() {
+    let action = match ::arch::args::get().get("fatal") {
+        Some("halt") | None => return,
+        Some("reboot") => FatalAction::Reboot,
+        Some("qemu-exit") => FatalAction::QemuExit,
+        Some(other) => {
+            println!("[ interrupts ] Unknown fatal action {}, halting on fatal errors.", other);
+            return;
+        }
+    };
+
+    set_fatal_action(action);
+    println!("[ interrupts ] Fatal errors will {:?}.", action);
+}
+
+/// Halt forever with interrupts disabled.
+fn halt() -> ! {
+    loop {
+        unsafe { asm!("cli; hlt" :::: "volatile") };
+    }
+}
+
+/// Reset the machine by pulsing the reset line through the PS/2 controller. If that does nothing,
+/// load an empty IDT and raise an exception, which triple faults.
+fn reboot() -> ! {
+    use device::ps2::Ps2Controller;
+    use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+
+    // Not through `PS2`, since whatever faulted may be holding its lock.
+    unsafe { Ps2Controller::new(0x64, 0x60).pulse_reset() };
+
+    unsafe {
+        lidt(&DescriptorTablePointer { limit: 0, base: 0 });
+        asm!("int3" :::: "volatile");
+    }
+
+    halt()
+}
+
+/// Take the configured action. Interrupts should already be disabled.
+pub fn die() -> ! {
+    match fatal_action() {
+        FatalAction::Halt => halt(),
+        FatalAction::Reboot => reboot(),
+        FatalAction::QemuExit => ::device::qemu::exit(::device::qemu::ExitCode::Failure),
+    }
+}
+
+/// Cause a double fault, for testing the fatal action. This loads an IDT with nothing but the
+/// double fault handler and reads a non-canonical address. The general protection fault that
+/// raises has no handler, so delivering it faults again, which is a double fault.
+pub fn trigger_double_fault() -> ! {
+    use super::exceptions::double_fault_handler;
+    use super::frame::handler_with_err_code;
+    use super::DOUBLE_FAULT_IST_INDEX;
+    use x86_64::structures::idt::Idt;
+
+    lazy_static! {
+        static ref DOUBLE_FAULT_ONLY: Idt = {
+            let mut idt = Idt::new();
+            unsafe {
+                idt.double_fault.set_handler_fn(handler_with_err_code(double_fault_handler))
+                    .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
+            }
+            idt
+        };
+    }
+
+    unsafe {
+        asm!("cli" :::: "volatile");
+        DOUBLE_FAULT_ONLY.load();
+        let _ = ::core::ptr::read_volatile(0x8000_0000_0000_0000 as *const u64);
+    }
+
+    halt()
+}
+
+/// Self-tests that end the session, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const FATAL_TESTS: &[KTest] = &[
+        KTest {
+            name: "double_fault_exit",
+            run: double_fault_exit,
+        },
+        KTest {
+            name: "stack_overflow_exit",
+            run: stack_overflow_exit,
+        },
+    ];
+
+    /// With `FatalAction::QemuExit`, QEMU should exit with the failure code, 35 with the usual
+    /// `isa-debug-exit` setup. Run with `ktest=double_fault_exit`.
+    fn double_fault_exit() -> Result<(), &'static str> {
+        use arch::interrupts::{fatal, set_fatal_action, FatalAction};
+
+        set_fatal_action(FatalAction::QemuExit);
+        fatal::trigger_double_fault()
+    }
+
+    /// Overflow the kernel stack into its guard page. Tests run on the boot stack, so this is the
+    /// guard page `paging::init` puts below it. The page fault cannot be pushed onto the
+    /// overflowed stack, so the CPU raises a double fault, which must reach its handler on the IST
+    /// stack, report the overflow and exit QEMU with the failure code. Run with
+    /// `ktest=stack_overflow_exit`.
+    fn stack_overflow_exit() -> Result<(), &'static str> {
+        use arch::interrupts::{set_fatal_action, FatalAction};
+        use core::ptr;
+
+        fn recurse(depth: usize) -> usize {
+            let frame = [depth; 64];
+            let below = recurse(depth + 1);
+            unsafe { ptr::read_volatile(&frame[depth % 64]) + below }
+        }
+
+        set_fatal_action(FatalAction::QemuExit);
+        recurse(0);
+
+        Err("the stack overflow returned")
+    }
+}
@@ -0,0 +1,169 @@
+//! Typed views of what the CPU pushes onto the stack when it takes an interrupt or exception.
+
+use x86_64::structures::idt::{self, PageFaultErrorCode};
+use core::{fmt, mem};
+
+/// The frame pushed by the CPU for every interrupt and exception, lowest address first.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct ExceptionStackFrame {
+    /// The instruction that was executing, or the one after it for traps.
+    pub rip: u64,
+    /// The code segment selector of the interrupted code.
+    pub cs: u64,
+    /// RFLAGS at the time of the interrupt.
+    pub rflags: u64,
+    /// The stack pointer of the interrupted code.
+    pub rsp: u64,
+    /// The stack segment selector of the interrupted code.
+    pub ss: u64,
+}
+
+impl fmt::Debug for ExceptionStackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ExceptionStackFrame")
+            .field("rip", &format_args!("{:#x}", self.rip))
+            .field("cs", &format_args!("{:#x}", self.cs))
+            .field("rflags", &format_args!("{:#x}", self.rflags))
+            .field("rsp", &format_args!("{:#x}", self.rsp))
+            .field("ss", &format_args!("{:#x}", self.ss))
+            .finish()
+    }
+}
+
+/// The frame pushed by the CPU for exceptions that come with an error code. The error code is
+/// pushed last, so it sits just below the regular frame.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct ExceptionStackFrameWithErrorCode {
+    pub error_code: u64,
+    pub frame: ExceptionStackFrame,
+}
+
+impl ExceptionStackFrameWithErrorCode {
+    /// Rebuild the pushed frame from the two halves the `x86-interrupt` ABI hands a handler.
+    pub fn new(frame: &ExceptionStackFrame, error_code: u64) -> Self {
+        ExceptionStackFrameWithErrorCode {
+            error_code: error_code,
+            frame: *frame,
+        }
+    }
+}
+
+impl fmt::Debug for ExceptionStackFrameWithErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ExceptionStackFrameWithErrorCode")
+            .field("error_code", &format_args!("{:#x}", self.error_code))
+            .field("frame", &self.frame)
+            .finish()
+    }
+}
+
+/// What `timer_entry` leaves on the stack: every general purpose register, lowest address first,
+/// below the frame the CPU pushed.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct InterruptFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub frame: ExceptionStackFrame,
+}
+
+/// An interrupt or exception handler without an error code.
+pub type HandlerFunc = extern "x86-interrupt" fn(&mut ExceptionStackFrame);
+/// An exception handler with an error code.
+pub type HandlerFuncWithErrCode = extern "x86-interrupt" fn(&mut ExceptionStackFrame, u64);
+/// The page fault handler.
+pub type PageFaultHandlerFunc =
+    extern "x86-interrupt" fn(&mut ExceptionStackFrame, PageFaultErrorCode);
+
+// The IDT only accepts handlers taking the `x86_64` crate's frame type. It has the same layout as
+// ours, so the handlers can be passed to it unchanged.
+
+/// Convert `handler` for `Idt::set_handler_fn`.
+pub fn handler(handler: HandlerFunc) -> idt::HandlerFunc {
+    unsafe { mem::transmute(handler) }
+}
+
+/// Convert `handler` for `Idt::set_handler_fn`.
+pub fn handler_with_err_code(handler: HandlerFuncWithErrCode) -> idt::HandlerFuncWithErrCode {
+    unsafe { mem::transmute(handler) }
+}
+
+/// Convert `handler` for `Idt::set_handler_fn`.
+pub fn page_fault_handler(handler: PageFaultHandlerFunc) -> idt::PageFaultHandlerFunc {
+    unsafe { mem::transmute(handler) }
+}
+
+/// Convert an entry point written in assembly, which saves and restores registers itself, for
+/// `Idt::set_handler_fn`.
+pub fn entry(entry: unsafe extern "C" fn()) -> idt::HandlerFunc {
+    unsafe { mem::transmute(entry) }
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "frame_layout",
+            run: frame_layout,
+        },
+    ];
+
+    fn frame_layout() -> Result<(), &'static str> {
+        use core::mem;
+        use super::{ExceptionStackFrame, ExceptionStackFrameWithErrorCode, InterruptFrame};
+        use x86_64::structures::idt;
+
+        // What the CPU pushes for an exception with an error code, lowest address first.
+        let pushed: [u64; 6] = [
+            0x14,
+            0xffff_8000_0010_2030,
+            0x08,
+            0x202,
+            0xffff_8000_0020_0ff8,
+            0x10,
+        ];
+        let with_error = unsafe { &*(pushed.as_ptr() as *const ExceptionStackFrameWithErrorCode) };
+        let frame = with_error.frame;
+
+        if mem::size_of::<ExceptionStackFrame>() != mem::size_of::<idt::ExceptionStackFrame>() {
+            return Err("the frame does not match the one the IDT handlers are declared with");
+        }
+        if with_error.error_code != 0x14 || frame.rip != pushed[1] || frame.cs != 0x08 {
+            return Err("the error code, RIP or CS was read from the wrong slot");
+        }
+        if frame.rflags != 0x202 || frame.rsp != pushed[4] || frame.ss != 0x10 {
+            return Err("RFLAGS, RSP or SS was read from the wrong slot");
+        }
+
+        // What `timer_entry` leaves: 15 registers from R15 up to RAX, then the CPU's frame.
+        let mut saved = [0u64; 20];
+        for (i, slot) in saved.iter_mut().enumerate() {
+            *slot = i as u64;
+        }
+        let registers = unsafe { &*(saved.as_ptr() as *const InterruptFrame) };
+        if registers.r15 != 0 || registers.rbx != 13 || registers.rax != 14 {
+            Err("the saved registers are not in the order timer_entry pushes them")
+        } else if registers.frame.rip != 15 || registers.frame.ss != 19 {
+            Err("the CPU's frame does not follow the saved registers")
+        } else {
+            Ok(())
+        }
+    }
+}
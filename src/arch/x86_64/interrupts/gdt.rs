@@ -94,3 +94,172 @@ bitflags! {
         const LONG_MODE         = 1 << 53;
     }
 }
+
+/// The decoded fields of a single GDT descriptor.
+#[derive(Debug, Clone, Copy)]
+pub struct DescriptorInfo {
+    pub base: u64,
+    pub limit: u32,
+    /// The 4-bit segment type.
+    pub typ: u8,
+    pub dpl: u8,
+    pub present: bool,
+    /// Code or data segment, as opposed to a system segment such as a TSS.
+    pub user_segment: bool,
+    pub long_mode: bool,
+}
+
+impl DescriptorInfo {
+    /// Decode a descriptor. `high` is the second half of a 16-byte system segment descriptor and
+    /// supplies the top 32 bits of its base.
+    pub fn parse(low: u64, high: Option<u64>) -> DescriptorInfo {
+        use bit_field::BitField;
+
+        let mut base = low.get_bits(16..40) | (low.get_bits(56..64) << 24);
+        if let Some(high) = high {
+            base |= high.get_bits(0..32) << 32;
+        }
+
+        DescriptorInfo {
+            base: base,
+            limit: (low.get_bits(0..16) | (low.get_bits(48..52) << 16)) as u32,
+            typ: low.get_bits(40..44) as u8,
+            dpl: low.get_bits(45..47) as u8,
+            present: low.get_bit(47),
+            user_segment: low.get_bit(44),
+            long_mode: low.get_bit(53),
+        }
+    }
+
+    /// Check if this is a 16-byte system segment descriptor.
+    pub fn is_system_segment(&self) -> bool {
+        self.present && !self.user_segment
+    }
+}
+
+/// Decode every descriptor in `table`, calling `f` with its index and fields. The null descriptor
+/// and empty slots are skipped.
+pub fn parse_table<F: FnMut(usize, DescriptorInfo)>(table: &[u64], mut f: F) {
+    let mut index = 1;
+    while index < table.len() {
+        let info = DescriptorInfo::parse(table[index], None);
+
+        if info.is_system_segment() {
+            let high = table.get(index + 1).cloned();
+            f(index, DescriptorInfo::parse(table[index], high));
+            index += 2;
+        } else {
+            if table[index] != 0 {
+                f(index, info);
+            }
+            index += 1;
+        }
+    }
+}
+
+/// Print every descriptor of the live GDT, and the stack pointers of the loaded TSS.
+pub fn dump() {
+    use x86_64::instructions::tables::DescriptorTablePointer;
+    use core::{mem, slice};
+
+    let mut gdtr = DescriptorTablePointer { limit: 0, base: 0 };
+    let tr: u16;
+    unsafe {
+        asm!("sgdt ($0)" :: "r"(&mut gdtr) : "memory");
+        asm!("str $0" : "=r"(tr));
+    }
+
+    let entries = (gdtr.limit as usize + 1) / mem::size_of::<u64>();
+    let table = unsafe { slice::from_raw_parts(gdtr.base as *const u64, entries) };
+
+    println!(
+        "[ tables ] GDT at {:#x}, {} entries, TR selector {:#x}",
+        gdtr.base, entries, tr
+    );
+
+    let mut tss_base = None;
+    parse_table(table, |index, info| {
+        println!(
+            "[ tables ]   {}: base {:#x} limit {:#x} type {:#x} dpl {} {}{}{}",
+            index,
+            info.base,
+            info.limit,
+            info.typ,
+            info.dpl,
+            if info.user_segment { "user" } else { "system" },
+            if info.present { "" } else { " not-present" },
+            if info.long_mode { " long" } else { "" }
+        );
+
+        if index == (tr >> 3) as usize {
+            tss_base = Some(info.base);
+        }
+    });
+
+    match tss_base {
+        Some(base) if base != 0 => {
+            let tss = unsafe { &*(base as *const TaskStateSegment) };
+            for (i, rsp) in tss.privilege_stack_table.iter().enumerate() {
+                println!("[ tables ] TSS RSP{}: {:#x}", i, rsp.0);
+            }
+            for (i, ist) in tss.interrupt_stack_table.iter().enumerate() {
+                println!("[ tables ] TSS IST{}: {:#x}", i + 1, ist.0);
+            }
+        }
+        _ => println!("[ tables ] No TSS loaded."),
+    }
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "gdt_parse",
+            run: gdt_parse,
+        },
+    ];
+
+    fn gdt_parse() -> Result<(), &'static str> {
+        use alloc::Vec;
+        use bit_field::BitField;
+        use super::{parse_table, Descriptor, DescriptorFlags, Gdt};
+
+        // A ring 3 data segment, and a TSS descriptor with a base that needs the upper half.
+        let data = DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT;
+        let mut user_data = data.bits();
+        user_data.set_bits(45..47, 3);
+
+        let base = 0xffff_8000_1234_5678u64;
+        let mut tss_low = DescriptorFlags::PRESENT.bits();
+        tss_low.set_bits(16..40, base.get_bits(0..24));
+        tss_low.set_bits(56..64, base.get_bits(24..32));
+        tss_low.set_bits(0..16, 0x67);
+        tss_low.set_bits(40..44, 0b1001);
+
+        let mut gdt = Gdt::new();
+        gdt.add_entry(Descriptor::kernel_code_segment());
+        gdt.add_entry(Descriptor::UserSegment(user_data));
+        gdt.add_entry(Descriptor::SystemSegment(tss_low, base.get_bits(32..64)));
+
+        let mut entries = Vec::new();
+        parse_table(&gdt.table, |index, info| entries.push((index, info)));
+
+        if entries.iter().map(|&(index, _)| index).collect::<Vec<_>>() != [1, 2, 3] {
+            return Err("the descriptors were not found at their indices");
+        }
+        let (code, user, tss) = (entries[0].1, entries[1].1, entries[2].1);
+        if !code.user_segment || !code.long_mode || code.typ & 0b1000 == 0 || code.dpl != 0 {
+            return Err("the kernel code segment was decoded wrongly");
+        }
+        if !user.user_segment || user.long_mode || user.typ & 0b1000 != 0 || user.dpl != 3 {
+            return Err("the ring 3 data segment was decoded wrongly");
+        }
+        if !tss.is_system_segment() || tss.typ != 0b1001 || tss.limit != 0x67 || tss.base != base {
+            Err("the TSS descriptor was decoded wrongly")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Tracking of which interrupt handler is running, so that an exception raised by a buggy handler
+//! can be reported as such instead of looking like a fault in whatever code was interrupted. Each
+//! handler run is also counted per vector.
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use spin::Mutex;
+
+/// The vector of the handler currently running plus one, or 0 if no handler is running.
+static CURRENT_HANDLER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The number of times the handler for each vector has run. Only locked with interrupts disabled.
+static COUNTS: Mutex<[usize; 256]> = Mutex::new([0; 256]);
+
+/// Marks an interrupt handler as in progress for as long as it is alive.
+pub struct HandlerGuard {
+    previous: usize,
+}
+
+impl HandlerGuard {
+    /// Mark the handler for `vector` as running.
+    pub fn enter(vector: u8) -> HandlerGuard {
+        // Handlers run with interrupts disabled, so the lock can only be held by another CPU.
+        if let Some(mut counts) = COUNTS.try_lock() {
+            counts[vector as usize] += 1;
+        }
+
+        HandlerGuard {
+            previous: CURRENT_HANDLER.swap(vector as usize + 1, Ordering::SeqCst),
+        }
+    }
+}
+
+impl Drop for HandlerGuard {
+    fn drop(&mut self) {
+        CURRENT_HANDLER.store(self.previous, Ordering::SeqCst);
+    }
+}
+
+/// Return the vector of the interrupt handler currently running, if any.
+pub fn current_handler() -> Option<u8> {
+    match CURRENT_HANDLER.load(Ordering::SeqCst) {
+        0 => None,
+        vector => Some((vector - 1) as u8),
+    }
+}
+
+/// Return the number of times the handler for each vector has run.
+pub fn counts() -> [usize; 256] {
+    super::without_interrupts(|| *COUNTS.lock())
+}
+
+/// If an interrupt handler was running when `exception` was raised, say so. Called at the start of
+/// the exception handlers.
+pub fn report_fault_in_handler(exception: &str) {
+    if let Some(vector) = current_handler() {
+        println!(
+            "\n{} raised inside the interrupt handler for vector {:#x}",
+            exception, vector
+        );
+    }
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "handler_guard",
+            run: handler_guard,
+        },
+    ];
+
+    pub const FATAL_TESTS: &[KTest] = &[
+        KTest {
+            name: "handler_fault_exit",
+            run: handler_fault_exit,
+        },
+    ];
+
+    /// The vector the faulting test handler is installed at, which nothing else uses.
+    const TEST_VECTOR: u8 = 0x40;
+
+    fn handler_guard() -> Result<(), &'static str> {
+        use super::{counts, current_handler, HandlerGuard};
+
+        let before = counts()[TEST_VECTOR as usize];
+        let outer = current_handler();
+
+        let (inner, nested) = {
+            let _guard = HandlerGuard::enter(TEST_VECTOR);
+            let inner = current_handler();
+            let nested = {
+                let _guard = HandlerGuard::enter(TEST_VECTOR + 1);
+                current_handler()
+            };
+            (inner, (nested, current_handler()))
+        };
+
+        if outer.is_some() {
+            Err("a handler was marked as running outside of any interrupt")
+        } else if inner != Some(TEST_VECTOR) {
+            Err("entering a handler did not mark it as running")
+        } else if nested != (Some(TEST_VECTOR + 1), Some(TEST_VECTOR)) {
+            Err("leaving a nested handler did not restore the outer one")
+        } else if current_handler().is_some() {
+            Err("leaving the handler did not clear it")
+        } else if counts()[TEST_VECTOR as usize] != before + 1 {
+            Err("the handler run was not counted")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Install a handler that dereferences null and raise its vector. The page fault handler
+    /// should print "PAGE FAULT raised inside the interrupt handler for vector 0x40" before the
+    /// usual report, then exit QEMU with the failure code. Run with `ktest=handler_fault_exit`.
+    fn handler_fault_exit() -> Result<(), &'static str> {
+        use arch::interrupts::{set_fatal_action, ExceptionStackFrame, FatalAction};
+        use arch::interrupts::DOUBLE_FAULT_IST_INDEX;
+        use arch::interrupts::exceptions::{double_fault_handler, page_fault_handler};
+        use arch::interrupts::frame::{self, handler, handler_with_err_code};
+        use core::ptr;
+        use super::HandlerGuard;
+        use x86_64::structures::idt::Idt;
+
+        extern "x86-interrupt" fn null_handler(_stack_frame: &mut ExceptionStackFrame) {
+            let _guard = HandlerGuard::enter(TEST_VECTOR);
+            unsafe { ptr::read_volatile(0 as *const u64) };
+        }
+
+        lazy_static! {
+            static ref FAULTING_HANDLER: Idt = {
+                let mut idt = Idt::new();
+                unsafe {
+                    idt.double_fault.set_handler_fn(handler_with_err_code(double_fault_handler))
+                        .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
+                }
+                idt.page_fault.set_handler_fn(frame::page_fault_handler(page_fault_handler));
+                idt.interrupts[TEST_VECTOR as usize - 0x20].set_handler_fn(handler(null_handler));
+                idt
+            };
+        }
+
+        set_fatal_action(FatalAction::QemuExit);
+        unsafe {
+            asm!("cli" :::: "volatile");
+            FAULTING_HANDLER.load();
+            asm!("int 0x40" :::: "volatile" "intel");
+        }
+
+        Err("the faulting handler returned")
+    }
+}
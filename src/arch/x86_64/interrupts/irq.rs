@@ -1,39 +1,115 @@
 use device::pic::PICS;
-use device::keyboard::ps2_keyboard::parse_key;
-use device::ps2_8042::read_char;
-use x86_64::structures::idt::ExceptionStackFrame;
-use super::disable_interrupts_and_then;
+use device::keyboard;
+use device::ps2::read_char;
+use super::{ExceptionStackFrame, InterruptFrame};
 use device::apic;
+use super::guard::HandlerGuard;
+use super::softirq;
 
-/// Timer handler checks the tick counter and if it exceeds 10, performs a round-robin context
-/// switch to the next process.
-pub extern "x86-interrupt" fn timer_handler(_stack_frame: &mut ExceptionStackFrame) {
+// The vectors the IRQ handlers are installed at.
+const TIMER_VECTOR: u8 = 0x30;
+const KEYBOARD_VECTOR: u8 = 0x31;
+const SERIAL_VECTOR: u8 = 0x34;
+const MOUSE_VECTOR: u8 = 0x3c;
+const ATA_PRIMARY_VECTOR: u8 = 0x3e;
+
+extern "C" {
+    /// The timer's entry point, in `interrupt_entry.asm`. It saves every general purpose register
+    /// and calls `timer_interrupt`.
+    pub fn timer_entry();
+}
+
+/// Called by `timer_entry` with the registers and frame of the interrupted code at `frame`. It
+/// counts the tick, and once the time slice is used up (~20ms), performs a round-robin context
+/// switch to the next process. Returns the stack pointer `timer_entry` restores the registers and
+/// frame from.
+///
+/// A process the timer switched out before is resumed by returning its saved frame instead of
+/// `frame`, so this interrupt returns straight into it, with every register as it was. If it was
+/// interrupted in ring 3, its frame is on its kernel stack, since `resched` points RSP0 there, and
+/// its user SS and RSP come back with the rest.
+#[no_mangle]
+pub extern "C" fn timer_interrupt(frame: &mut InterruptFrame) -> usize {
     use core::sync::atomic::Ordering;
-    use device::pit::PIT_TICKS;
-    use task::{Scheduling, SCHEDULER};
+    use device::pit::{self, PIT_TICKS};
+    use task::SCHEDULER;
+
+    let frame = frame as *mut InterruptFrame as usize;
+    let guard = HandlerGuard::enter(TIMER_VECTOR);
 
-    println!("timer interrupt.");
+    pit::tick();
+    SCHEDULER.tick();
 
     apic::eoi();
-    
+
+    // Rescheduling may switch away from this handler, so it no longer counts as running.
+    drop(guard);
+
+    softirq::run_pending();
+
     // Check if allocated timeslice finished (~20ms).
     if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 10 {
+        // Reset before switching, since a switch may only return once this process runs again.
         PIT_TICKS.store(0, Ordering::SeqCst);
 
-        unsafe {
-            // Call scheduler.
-            disable_interrupts_and_then(|| {
-                SCHEDULER.resched();
-            });
+        // Interrupts are still disabled: the CPU took the interrupt with them off, and
+        // `run_pending` turns them off again. They must stay off until the switch is done.
+        match unsafe { SCHEDULER.preempt(frame) } {
+            Some(next) => return next,
+            // The scheduler's locks were held, so try again on the next tick.
+            None => PIT_TICKS.store(10, Ordering::SeqCst),
         }
     }
+
+    frame
 }
 
 pub extern "x86-interrupt" fn keyboard_handler(_stack_frame: &mut ExceptionStackFrame) {
-    println!("keyboard interrupt.");
-    let code = read_char();
+    let guard = HandlerGuard::enter(KEYBOARD_VECTOR);
+
+    keyboard::handle_interrupt();
+
+    apic::eoi();
+
+    drop(guard);
+    softirq::run_pending();
+}
+
+pub extern "x86-interrupt" fn mouse_handler(_stack_frame: &mut ExceptionStackFrame) {
+    use device::mouse;
+
+    let guard = HandlerGuard::enter(MOUSE_VECTOR);
+
+    mouse::handle_byte(read_char());
 
-    parse_key(code);
-        
     apic::eoi();
+
+    drop(guard);
+    softirq::run_pending();
+}
+
+pub extern "x86-interrupt" fn serial_handler(_stack_frame: &mut ExceptionStackFrame) {
+    use device::serial;
+
+    let guard = HandlerGuard::enter(SERIAL_VECTOR);
+
+    serial::handle_interrupt();
+
+    apic::eoi();
+
+    drop(guard);
+    softirq::run_pending();
+}
+
+pub extern "x86-interrupt" fn ata_primary_handler(_stack_frame: &mut ExceptionStackFrame) {
+    use device::ata;
+
+    let guard = HandlerGuard::enter(ATA_PRIMARY_VECTOR);
+
+    ata::handle_interrupt();
+
+    apic::eoi();
+
+    drop(guard);
+    softirq::run_pending();
 }
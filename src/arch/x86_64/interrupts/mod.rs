@@ -1,56 +1,80 @@
-use arch::memory::MemoryController;
+use arch::memory;
 use x86_64::structures::tss::TaskStateSegment;
-use x86_64::structures::idt::{Idt, ExceptionStackFrame};
+use x86_64::structures::idt::Idt;
 use spin::Once;
 
 pub mod gdt;
+pub mod frame;
+pub mod guard;
 pub mod exceptions;
+pub mod fatal;
 pub mod irq;
+pub mod softirq;
 pub mod utils;
 
 pub use self::utils::*;
+pub use self::fatal::{fatal_action, set_fatal_action, FatalAction};
+pub use self::frame::{ExceptionStackFrame, ExceptionStackFrameWithErrorCode, InterruptFrame};
 
+/// The Interrupt Stack Table slot of the double fault stack. The CPU switches to it before pushing
+/// anything, so a kernel stack overflow that hits a guard page still reaches the handler instead
+/// of triple faulting.
 const DOUBLE_FAULT_IST_INDEX: usize = 0;
+/// The size of the double fault stack, in pages. The handler formats the whole frame, which does
+/// not fit in one.
+const DOUBLE_FAULT_STACK_PAGES: usize = 4;
 
 lazy_static! {
     static ref IDT: Idt = {
+        use self::frame::{entry, handler, handler_with_err_code, page_fault_handler};
+
         let mut idt = Idt::new();
 
         println!("[ interrupts ] Installing exception handlers.");
-        idt.divide_by_zero.set_handler_fn(exceptions::divide_by_zero_handler);
-        idt.debug.set_handler_fn(exceptions::debug_handler);
-        idt.non_maskable_interrupt.set_handler_fn(exceptions::nmi_handler);
-        idt.breakpoint.set_handler_fn(exceptions::breakpoint_handler);
-        idt.overflow.set_handler_fn(exceptions::overflow_handler);
-        idt.bound_range_exceeded.set_handler_fn(exceptions::bound_range_handler);
-        idt.invalid_opcode.set_handler_fn(exceptions::invalid_opcode_handler);
-        idt.device_not_available.set_handler_fn(exceptions::device_not_available_handler);
+        idt.divide_by_zero.set_handler_fn(handler(exceptions::divide_by_zero_handler));
+        idt.debug.set_handler_fn(handler(exceptions::debug_handler));
+        idt.non_maskable_interrupt.set_handler_fn(handler(exceptions::nmi_handler));
+        idt.breakpoint.set_handler_fn(handler(exceptions::breakpoint_handler));
+        idt.overflow.set_handler_fn(handler(exceptions::overflow_handler));
+        idt.bound_range_exceeded.set_handler_fn(handler(exceptions::bound_range_handler));
+        idt.invalid_opcode.set_handler_fn(handler(exceptions::invalid_opcode_handler));
+        idt.device_not_available.set_handler_fn(handler(exceptions::device_not_available_handler));
         unsafe {
-            idt.double_fault.set_handler_fn(exceptions::double_fault_handler)
+            idt.double_fault.set_handler_fn(handler_with_err_code(exceptions::double_fault_handler))
                 .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
         }
-        idt.invalid_tss.set_handler_fn(exceptions::invalid_tss_handler);
-        idt.segment_not_present.set_handler_fn(exceptions::seg_not_present_handler);
-        idt.stack_segment_fault.set_handler_fn(exceptions::stack_seg_fault_handler);
-        idt.general_protection_fault.set_handler_fn(exceptions::gpf_handler);
-        idt.page_fault.set_handler_fn(exceptions::page_fault_handler);
-        idt.x87_floating_point.set_handler_fn(exceptions::x87_fp_exception_handler);
-        idt.alignment_check.set_handler_fn(exceptions::alignment_check_handler);
-        idt.machine_check.set_handler_fn(exceptions::machine_check_handler);
-        idt.simd_floating_point.set_handler_fn(exceptions::simd_fp_exception_handler);
+        idt.invalid_tss.set_handler_fn(handler_with_err_code(exceptions::invalid_tss_handler));
+        idt.segment_not_present.set_handler_fn(handler_with_err_code(
+            exceptions::seg_not_present_handler,
+        ));
+        idt.stack_segment_fault.set_handler_fn(handler_with_err_code(
+            exceptions::stack_seg_fault_handler,
+        ));
+        idt.general_protection_fault.set_handler_fn(handler_with_err_code(exceptions::gpf_handler));
+        idt.page_fault.set_handler_fn(page_fault_handler(exceptions::page_fault_handler));
+        idt.x87_floating_point.set_handler_fn(handler(exceptions::x87_fp_exception_handler));
+        idt.alignment_check.set_handler_fn(handler_with_err_code(
+            exceptions::alignment_check_handler,
+        ));
+        idt.machine_check.set_handler_fn(handler(exceptions::machine_check_handler));
+        idt.simd_floating_point.set_handler_fn(handler(exceptions::simd_fp_exception_handler));
 
         println!("[ interrupts ] Installing IRQs.");
-        idt.interrupts[0].set_handler_fn(irq::timer_handler);
+        idt.interrupts[0].set_handler_fn(entry(irq::timer_entry));
         // idt.interrupts[1].set_handler_fn(irq::keyboard_handler);
         
-        idt.interrupts[0x30 - 0x20].set_handler_fn(irq::timer_handler);
+        idt.interrupts[0x30 - 0x20].set_handler_fn(entry(irq::timer_entry));
+        idt.interrupts[0x30 - 0x20 + 1].set_handler_fn(handler(irq::keyboard_handler));
+        idt.interrupts[0x30 - 0x20 + 4].set_handler_fn(handler(irq::serial_handler));
+        idt.interrupts[0x30 - 0x20 + 12].set_handler_fn(handler(irq::mouse_handler));
+        idt.interrupts[0x30 - 0x20 + 14].set_handler_fn(handler(irq::ata_primary_handler));
         // idt.interrupts[17].set_handler_fn(irq::keyboard_handler);
         
         // APIC NMI.
         for vec in (0x90-0x20)..(0x97-0x20) {
-            idt.interrupts[vec].set_handler_fn(apic_nmi_handler);
+            idt.interrupts[vec].set_handler_fn(handler(apic_nmi_handler));
         }
-        idt.interrupts[0xff - 0x20].set_handler_fn(spurious_interrupt_handler);
+        idt.interrupts[0xff - 0x20].set_handler_fn(handler(spurious_interrupt_handler));
 
         idt
     };
@@ -60,15 +84,17 @@ static TSS: Once<TaskStateSegment> = Once::new();
 static GDT: Once<gdt::Gdt> = Once::new();
 
 /// Loads an IDT, GDT and TSS and reloads code segment registers.
-pub fn init(memory_controller: &mut MemoryController) {
+pub fn init() {
     use x86_64::structures::gdt::SegmentSelector;
     use x86_64::instructions::segmentation::set_cs;
     use x86_64::instructions::tables::load_tss;
     use x86_64::VirtualAddress;
 
-    let double_fault_stack = memory_controller
-        .alloc_stack(1)
-        .expect("could not allocate double fault stack");
+    let double_fault_stack = memory::with_controller(|memory_controller| {
+        memory_controller
+            .alloc_stack(DOUBLE_FAULT_STACK_PAGES)
+            .expect("could not allocate double fault stack")
+    });
 
     let tss = TSS.call_once(|| {
         let mut tss = TaskStateSegment::new();
@@ -103,10 +129,64 @@ pub fn init(memory_controller: &mut MemoryController) {
 
     // Load the IDT
     IDT.load();
-    println!("[ tables ] Successfully loaded IDT.")
+    println!("[ tables ] Successfully loaded IDT.");
+
+    fatal::init_from_args();
+}
+
+/// Set the stack the CPU switches to when an interrupt or syscall arrives from ring 3.
+///
+/// # Panics
+///
+/// Panics if `init` has not been called yet.
+pub fn set_kernel_stack(top: usize) {
+    use x86_64::VirtualAddress;
+
+    let tss = TSS.try().expect("TSS accessed before interrupts::init.");
+
+    // The CPU only reads RSP0 when it changes privilege level, so the loaded TSS can be updated in
+    // place.
+    unsafe {
+        let tss = tss as *const TaskStateSegment as *mut TaskStateSegment;
+        (*tss).privilege_stack_table[0] = VirtualAddress(top);
+    }
+}
+
+/// Return the stack top currently loaded in RSP0, or 0 if there is none.
+pub fn kernel_stack() -> usize {
+    TSS.try().map_or(0, |tss| tss.privilege_stack_table[0].0)
+}
+
+/// Reinitialise the 8259 PICs so that their IRQs start at `master_offset` and `slave_offset`.
+/// Both offsets must be multiples of 8, must not overlap each other, and must lie above the CPU
+/// exception vectors (0-31). The current masks are kept.
+pub fn remap_pics(master_offset: u8, slave_offset: u8) -> Result<(), &'static str> {
+    use device::pic::PICS;
+
+    for &offset in [master_offset, slave_offset].iter() {
+        if offset % 8 != 0 {
+            return Err("PIC vector offsets must be multiples of 8");
+        }
+        if offset < 0x20 {
+            return Err("PIC vector offsets must not overlap the CPU exception vectors");
+        }
+        if offset > 0xff - 7 {
+            return Err("PIC vector offsets must leave room for 8 vectors");
+        }
+    }
+
+    if master_offset == slave_offset {
+        return Err("master and slave PIC vector offsets overlap");
+    }
+
+    // This saves the PIC masks beforehand and restores them afterwards.
+    disable_interrupts_and_then(|| unsafe { PICS.lock().remap(master_offset, slave_offset) });
+
+    Ok(())
 }
 
 pub extern "x86-interrupt" fn apic_nmi_handler(stack_frame: &mut ExceptionStackFrame) {
+    guard::report_fault_in_handler("APIC NMI");
     println!("NON-MASKABLE APIC INTERRUPT!");
     loop {}
 }
@@ -114,3 +194,49 @@ pub extern "x86-interrupt" fn apic_nmi_handler(stack_frame: &mut ExceptionStackF
 pub extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: &mut ExceptionStackFrame) {
     println!("SPURIOUS INTERRUPT!");
 }
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "remap_pics",
+            run: remap_pics,
+        },
+    ];
+
+    fn remap_pics() -> Result<(), &'static str> {
+        use device::pic::PICS;
+
+        let (master, slave) = PICS.lock().offsets();
+
+        for &(bad_master, bad_slave) in [(0x31, 0x38), (0x18, 0x38), (0x30, 0x30)].iter() {
+            if super::remap_pics(bad_master, bad_slave).is_ok() {
+                return Err("invalid PIC vector offsets were accepted");
+            }
+        }
+        if PICS.lock().offsets() != (master, slave) {
+            return Err("rejected offsets changed the PICs");
+        }
+
+        super::remap_pics(0x30, 0x38)?;
+        let (old_handled, new_handled, offsets) = {
+            let pics = PICS.lock();
+            (
+                pics.handles_interrupt(0x20) || pics.handles_interrupt(0x2f),
+                pics.handles_interrupt(0x30) && pics.handles_interrupt(0x3f),
+                pics.offsets(),
+            )
+        };
+        super::remap_pics(master, slave)?;
+
+        if offsets != (0x30, 0x38) {
+            Err("the PICs did not take the new offsets")
+        } else if old_handled || !new_handled {
+            Err("handles_interrupt did not follow the new offsets")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Deferred interrupt work. An interrupt handler should only do what cannot wait, such as reading
+//! a byte out of a device, and `schedule` the rest. Scheduled work runs with interrupts enabled
+//! when the handler calls `run_pending` on its way out, after it has sent its EOI.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use klib::RingBuffer;
+use spin::Mutex;
+use super::{enable_interrupts, interrupts_enabled, without_interrupts};
+
+/// A piece of deferred work.
+pub type Work = fn();
+
+fn nothing() {}
+
+lazy_static! {
+    /// Work waiting to run. Only ever locked with interrupts disabled, so an interrupt handler can
+    /// never spin on a lock held by the code it interrupted.
+    static ref PENDING: Mutex<RingBuffer<Work>> = Mutex::new(RingBuffer::new(nothing as Work));
+}
+
+/// Set while `run_pending` is draining the queue, so that interrupts taken while deferred work
+/// runs do not start draining it again on top of it.
+static RUNNING: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The number of pieces of work dropped because the queue was full.
+static DROPPED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Queue `work` to run after the current interrupt handler returns. Safe to call from interrupt
+/// context. Returns `false`, dropping `work`, if the queue is full.
+pub fn schedule(work: Work) -> bool {
+    let queued = without_interrupts(|| PENDING.lock().push(work));
+    if !queued {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    queued
+}
+
+/// Return the number of pieces of work dropped because the queue was full.
+pub fn dropped() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Run every piece of queued work with interrupts enabled. Interrupt handlers call this last,
+/// after their EOI, and interrupts are disabled again before it returns. Does nothing if there is
+/// no work, so that handlers do not nest on the same stack for nothing, or if deferred work is
+/// already being run further up the stack.
+pub fn run_pending() {
+    if without_interrupts(|| PENDING.lock().is_empty()) {
+        return;
+    }
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let was_enabled = interrupts_enabled();
+    enable_interrupts();
+
+    while let Some(work) = without_interrupts(|| PENDING.lock().pop()) {
+        work();
+    }
+
+    if !was_enabled {
+        unsafe { asm!("cli") };
+    }
+
+    RUNNING.store(false, Ordering::SeqCst);
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "softirq_timer",
+            run: softirq_timer,
+        },
+    ];
+
+    /// How `record` found things when it ran: 0 if it has not run, 1 if interrupts were
+    /// disabled, and 2 if they were enabled.
+    static RAN: AtomicUsize = ATOMIC_USIZE_INIT;
+    /// The tick `record` ran on.
+    static RAN_AT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    fn record() {
+        use device::pit;
+        use super::interrupts_enabled;
+
+        RAN_AT.store(pit::ticks(), Ordering::SeqCst);
+        RAN.store(if interrupts_enabled() { 2 } else { 1 }, Ordering::SeqCst);
+    }
+
+    /// Queue work with interrupts disabled, as a handler would, and let the next timer interrupt
+    /// run it on its way out.
+    fn softirq_timer() -> Result<(), &'static str> {
+        use arch::ARCH;
+        use device::pit;
+        use super::{schedule, without_interrupts};
+
+        RAN.store(0, Ordering::SeqCst);
+
+        let (queued, ran_early, scheduled_at) = without_interrupts(|| {
+            let queued = schedule(record);
+            (queued, RAN.load(Ordering::SeqCst) != 0, pit::ticks())
+        });
+
+        let mut waited = 0;
+        while RAN.load(Ordering::SeqCst) == 0 && waited < 10 {
+            let tick = pit::ticks();
+            while pit::ticks() == tick {
+                ARCH.halt();
+            }
+            waited += 1;
+        }
+
+        if !queued {
+            Err("the work could not be queued")
+        } else if ran_early {
+            Err("the work ran before an interrupt handler returned")
+        } else if RAN.load(Ordering::SeqCst) == 0 {
+            Err("the work did not run after the timer interrupt")
+        } else if RAN_AT.load(Ordering::SeqCst) == scheduled_at {
+            Err("the work ran before the next timer tick")
+        } else if RAN.load(Ordering::SeqCst) != 2 {
+            Err("the work ran with interrupts disabled")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -6,25 +6,26 @@ unsafe fn enable() {
     asm!("sti");
 }
 
-/// Disable all interrupts and save the PIC masks
-pub fn disable_interrupts() -> (u8, u8) {
-    use device::pic::PICS;
+/// Disable maskable interrupts, and return whether they were enabled before, to be handed to
+/// `restore_interrupts`.
+pub fn disable_interrupts() -> bool {
+    let enabled = interrupts_enabled();
 
     unsafe {
         disable();
     }
 
-    let saved_masks: (u8, u8) = {
-        let mask_pic0 = PICS.lock().pics[0].data.read();
-        let mask_pic1 = PICS.lock().pics[1].data.read();
-
-        (mask_pic0, mask_pic1)
-    };
-
-    PICS.lock().pics[0].data.write(0xff);
-    PICS.lock().pics[1].data.write(0xff);
+    enabled
+}
 
-    saved_masks
+/// Put the interrupt flag back the way it was before `disable_interrupts` returned `enabled`.
+/// Interrupts are only enabled if they were enabled then, so this is safe to nest.
+pub fn restore_interrupts(enabled: bool) {
+    if enabled {
+        unsafe {
+            enable();
+        }
+    }
 }
 
 /// Enable all interrupts
@@ -47,37 +48,117 @@ pub fn enable_interrupts() {
     }
 }
 
-/// Restore interrupts to previous state
-pub fn restore_interrupts(saved_masks: (u8, u8)) {
+/// Mask every PIC line, and return the masks that were set before.
+fn save_pic_masks() -> (u8, u8) {
     use device::pic::PICS;
 
-    // Ensure PIC manipulation is not interrupted
-    unsafe {
-        disable();
-    }
+    let saved_masks: (u8, u8) = {
+        let mask_pic0 = PICS.lock().pics[0].data.read();
+        let mask_pic1 = PICS.lock().pics[1].data.read();
+
+        (mask_pic0, mask_pic1)
+    };
+
+    PICS.lock().pics[0].data.write(0xff);
+    PICS.lock().pics[1].data.write(0xff);
+
+    saved_masks
+}
+
+/// Put back the PIC masks returned by `save_pic_masks`.
+fn restore_pic_masks(saved_masks: (u8, u8)) {
+    use device::pic::PICS;
 
     let (mask_pic0, mask_pic1) = saved_masks;
 
     PICS.lock().pics[0].data.write(mask_pic0);
     PICS.lock().pics[1].data.write(mask_pic1);
-
-    unsafe {
-        enable();
-    }
 }
 
 // Stolen from Robert Gries.
-// This function disables interrupts, allows a function to run without them enabled, and then
-// reenables interrupts.
+// This function disables interrupts and masks every PIC line, allows a function to run without
+// them, and then puts the masks and the interrupt flag back the way they were.
 pub fn disable_interrupts_and_then<F, T>(f: F) -> T
 where
     F: FnOnce() -> T,
 {
-    let saved_masks = disable_interrupts();
+    let enabled = disable_interrupts();
+    let saved_masks = save_pic_masks();
 
     let result: T = f();
 
-    restore_interrupts(saved_masks);
+    restore_pic_masks(saved_masks);
+    restore_interrupts(enabled);
 
     result
 }
+
+/// Check whether maskable interrupts are enabled on this CPU.
+pub fn interrupts_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq; pop $0" : "=r"(rflags) ::: "volatile");
+    }
+    rflags & (1 << 9) != 0
+}
+
+/// Run `f` with interrupts disabled, then put the interrupt flag back the way it was. This never
+/// enables interrupts that were not already enabled, so it is safe to call from an interrupt
+/// handler or with a lock held that interrupt handlers take.
+pub fn without_interrupts<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let enabled = disable_interrupts();
+
+    let result = f();
+
+    restore_interrupts(enabled);
+
+    result
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "interrupt_state",
+            run: interrupt_state,
+        },
+    ];
+
+    fn interrupt_state() -> Result<(), &'static str> {
+        use arch::interrupts::{disable_interrupts, disable_interrupts_and_then, interrupts_enabled,
+                               restore_interrupts};
+
+        // The tests run once init has enabled interrupts.
+        if !interrupts_enabled() {
+            return Err("interrupts are not enabled to begin with");
+        }
+
+        let outer = disable_interrupts();
+        let disabled = !interrupts_enabled();
+        let inner = disable_interrupts();
+        restore_interrupts(inner);
+        let still_disabled = !interrupts_enabled();
+        disable_interrupts_and_then(|| {});
+        let masked_disabled = !interrupts_enabled();
+        restore_interrupts(outer);
+
+        if !outer {
+            Err("disable_interrupts did not report that interrupts were enabled")
+        } else if !disabled {
+            Err("disable_interrupts left interrupts enabled")
+        } else if inner || !still_disabled {
+            Err("a nested restore enabled interrupts")
+        } else if !masked_disabled {
+            Err("disable_interrupts_and_then enabled interrupts that were disabled")
+        } else if !interrupts_enabled() {
+            Err("restore_interrupts did not enable interrupts again")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -1,152 +1,920 @@
-use arch::memory::{Frame, FrameAllocator};
-use multiboot2::{MemoryArea, MemoryAreaIter};
+use arch::memory::{Frame, FrameAllocator, FrameIter, PAGE_SIZE};
+use multiboot2::MemoryAreaIter;
+use arch::memory::frame_bitmap::FrameBitmap;
 use arch::memory::paging::PhysicalAddress;
+use heapless::Vec as StaticVec;
+
+/// The maximum number of memory areas the allocator keeps track of.
+pub const MAX_AREAS: usize = 32;
+
+/// A region of usable physical memory. These are copied out of the multiboot memory map, so that
+/// the allocator does not depend on the multiboot information structure staying mapped.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryArea {
+    start_address: usize,
+    size: usize,
+}
+
+impl MemoryArea {
+    pub fn new(start_address: usize, size: usize) -> MemoryArea {
+        MemoryArea {
+            start_address: start_address,
+            size: size,
+        }
+    }
+
+    /// The physical start address of this area.
+    pub fn start_address(&self) -> usize {
+        self.start_address
+    }
+
+    /// The size of this area in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// Return the number of the first frame of the lowest run of `count` frames that lie in `areas`, at
+/// or above frame `from`, none of which `reserved` says are taken. A run does not span two areas.
+pub fn find_contiguous_run<F>(
+    areas: &[MemoryArea],
+    from: usize,
+    count: usize,
+    reserved: F,
+) -> Option<usize>
+where
+    F: Fn(usize) -> bool,
+{
+    areas
+        .iter()
+        .filter_map(|area| {
+            let first = (area.start_address() / PAGE_SIZE).max(from);
+            let last = (area.start_address() + area.size() - 1) / PAGE_SIZE;
+
+            let mut run_start = first;
+            for number in first..last + 1 {
+                if reserved(number) {
+                    run_start = number + 1;
+                } else if number + 1 - run_start == count {
+                    return Some(run_start);
+                }
+            }
+            None
+        })
+        .min()
+}
+
+/// The lowest frame the frame bitmap may be placed at, to keep it clear of the BIOS areas.
+const BITMAP_MIN_FRAME: usize = 0x10_0000 / PAGE_SIZE;
+/// The frame the frame bitmap must end below, so that it lies in the memory the boot page tables
+/// identity map.
+const BITMAP_END_FRAME: usize = 0x4000_0000 / PAGE_SIZE;
+
+/// The maximum number of free ranges kept for the memory the frame bitmap does not cover.
+pub const MAX_RANGES: usize = 64;
+
+/// Free frames kept as a list of ranges, for the memory above the end of the frame bitmap when
+/// there is too much memory for a bitmap covering all of it to fit below 1 GiB. Queries scan the
+/// list, so this is only used where the bitmap cannot be.
+pub struct FrameRanges {
+    /// The first frame of each range, and the frame after its last.
+    ranges: StaticVec<(usize, usize), [(usize, usize); MAX_RANGES]>,
+}
+
+impl FrameRanges {
+    pub fn new() -> FrameRanges {
+        FrameRanges {
+            ranges: StaticVec::new(),
+        }
+    }
+
+    /// Make frames `first` up to, but not including, `end` free, merging them into the ranges
+    /// next to them. Returns `false`, leaving them out, if there is no room for another range.
+    pub fn insert(&mut self, first: usize, end: usize) -> bool {
+        if first >= end {
+            return true;
+        }
+
+        let below = self.ranges.iter().position(|&(_, range_end)| range_end == first);
+        let above = self.ranges.iter().position(|&(range_first, _)| range_first == end);
+
+        match (below, above) {
+            (Some(below), Some(above)) => {
+                self.ranges[below].1 = self.ranges[above].1;
+                self.remove(above);
+                true
+            }
+            (Some(below), None) => {
+                self.ranges[below].1 = end;
+                true
+            }
+            (None, Some(above)) => {
+                self.ranges[above].0 = first;
+                true
+            }
+            (None, None) => self.ranges.push((first, end)).is_ok(),
+        }
+    }
+
+    /// Remove the range at `index`. The order of the ranges does not matter.
+    fn remove(&mut self, index: usize) {
+        if let Some(last) = self.ranges.pop() {
+            if index < self.ranges.len() {
+                self.ranges[index] = last;
+            }
+        }
+    }
+
+    /// Check whether frame `number` is free.
+    pub fn contains(&self, number: usize) -> bool {
+        self.ranges
+            .iter()
+            .any(|&(first, end)| number >= first && number < end)
+    }
+
+    /// Take `count` contiguous frames from the lowest range that has them, and return the first.
+    pub fn allocate(&mut self, count: usize) -> Option<usize> {
+        let index = (0..self.ranges.len())
+            .filter(|&i| self.ranges[i].1 - self.ranges[i].0 >= count)
+            .min_by_key(|&i| self.ranges[i].0)?;
+
+        let first = self.ranges[index].0;
+        self.ranges[index].0 += count;
+        if self.ranges[index].0 == self.ranges[index].1 {
+            self.remove(index);
+        }
+        Some(first)
+    }
+
+    /// Return the number of free frames.
+    pub fn free_frames(&self) -> usize {
+        self.ranges.iter().map(|&(first, end)| end - first).sum()
+    }
+
+    /// Return the length of the longest range. Ranges that touch are always merged.
+    pub fn largest_run(&self) -> usize {
+        self.ranges
+            .iter()
+            .map(|&(first, end)| end - first)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Find room for the two frame bitmaps in a free run of `areas` between `BITMAP_MIN_FRAME` and
+/// `BITMAP_END_FRAME`. They cover `frames` frames if there
+/// is room for that, and otherwise as many as fit, but at least the memory below 1 GiB. Returns
+/// the first frame of the bitmaps and the number of frames they cover, or `None` if not even that
+/// fits.
+pub fn place_bitmaps<F>(areas: &[MemoryArea], frames: usize, reserved: F) -> Option<(usize, usize)>
+where
+    F: Fn(usize) -> bool,
+{
+    let mut covered = frames;
+    loop {
+        let len = bitmap_len_for(covered);
+        match find_contiguous_run(areas, BITMAP_MIN_FRAME, 2 * len, &reserved) {
+            Some(start) if start + 2 * len <= BITMAP_END_FRAME => return Some((start, covered)),
+            _ if covered > BITMAP_END_FRAME => covered = (covered / 2).max(BITMAP_END_FRAME),
+            _ => return None,
+        }
+    }
+}
+
+/// Return the number of frames a bitmap covering `frames` frames takes.
+fn bitmap_len_for(frames: usize) -> usize {
+    (FrameBitmap::bytes_for(frames) + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+/// Take the frames from `other.0` to `other.1` off the inclusive range `range`. If `other` lies
+/// inside `range`, everything from `other.0` up is taken off, so that no frame of `other` is left.
+/// Returns `None` if no frame is left.
+fn clip(range: (usize, usize), other: (usize, usize)) -> Option<(usize, usize)> {
+    let (mut first, mut last) = range;
+    if other.1 < first || other.0 > last {
+        return Some(range);
+    } else if other.0 <= first {
+        first = other.1 + 1;
+    } else {
+        last = other.0 - 1;
+    }
+
+    if first <= last {
+        Some((first, last))
+    } else {
+        None
+    }
+}
 
 /// A frame allocator that uses the memory areas from the multiboot information structure as
-/// source. The {kernel, multiboot}_{start, end} fields are used to avoid returning memory that is
-/// already in use.
+/// source. A bitmap with a bit per frame up to the end of the highest area marks the frames that
+/// must not be handed out: those outside the areas, those used by the kernel, the multiboot
+/// structure and the bitmaps themselves, and those already allocated. If that bitmap would not
+/// fit below 1 GiB, it only covers as much memory as fits, and the free frames above it are kept
+/// in a `FrameRanges` list instead. The allocator keeps no other state, so it never allocates
+/// from the heap.
 ///
-/// `kernel_end` and `multiboot_end` are _inclusive_ bounds.
+/// A second bitmap of the same size is set aside next to the first, for `memory` to track which
+/// frames have been handed out to callers rather than cached in a magazine, see `take_handed_out`.
 pub struct AreaFrameAllocator {
-    /// The next available physical frame.
+    /// The lowest frame that may be free. No frame below it is.
     next_free_frame: Frame,
-    /// The current memory area, detected by multiboot using the e820.
-    current_area: Option<&'static MemoryArea>,
-    /// An iterator over all memory areas.
-    areas: MemoryAreaIter,
-    /// The starting frame of the kernel in physical memory.
-    /// frame.start_address().get() == 1MiB.
-    kernel_start: Frame,
-    /// The end frame of the kernel in physical memory.
-    kernel_end: Frame,
-    /// The starting frame of the multiboot structure in physical memory,
-    multiboot_start: Frame,
-    /// The end frame of the multiboot data structure in physical memory.
-    multiboot_end: Frame,
+    /// All usable memory areas.
+    areas: StaticVec<MemoryArea, [MemoryArea; MAX_AREAS]>,
+    /// Frames that are reserved or allocated, with a bit set for each.
+    bitmap: FrameBitmap,
+    /// Free frames above the end of the bitmap.
+    ranges: FrameRanges,
+    /// The bitmap of frames handed out to callers, until it is taken by `take_handed_out`.
+    handed_out: Option<FrameBitmap>,
+    /// The first and last frames both bitmaps occupy.
+    bitmap_frames: (Frame, Frame),
+    /// The first and last frames reserved for the kernel.
+    kernel: (Frame, Frame),
+    /// The first and last frames of the multiboot structure in physical memory. This is `None`
+    /// once the multiboot structure has been reclaimed.
+    multiboot: Option<(Frame, Frame)>,
 }
 
 impl AreaFrameAllocator {
+    /// Create an allocator over `memory_areas`, with the frames from `kernel_start` to
+    /// `kernel_end` and from `multiboot_start` to `multiboot_end`, both inclusive, reserved. The
+    /// bitmap covers at least the memory below `covered_end`, so that areas below it can be added
+    /// later with `add_area`, and is placed in the lowest free run of frames above 1 MiB, which
+    /// must be identity mapped. If there is not room for that, it covers less, see
+    /// `place_bitmaps`, and the rest of memory is kept as a list of ranges.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no room below 1 GiB even for bitmaps covering the memory below 1 GiB.
     pub fn new(
         kernel_start: usize,
         kernel_end: usize,
         multiboot_start: usize,
         multiboot_end: usize,
         memory_areas: MemoryAreaIter,
+        covered_end: usize,
     ) -> AreaFrameAllocator {
+        let mut areas: StaticVec<MemoryArea, [MemoryArea; MAX_AREAS]> = StaticVec::new();
+        for area in memory_areas {
+            if areas
+                .push(MemoryArea::new(area.start_address(), area.size()))
+                .is_err()
+            {
+                println!(
+                    "[ pmm ] Too many memory areas, ignoring area at {:#x}",
+                    area.start_address()
+                );
+            }
+        }
+
+        let frames = areas
+            .iter()
+            .map(|area| (area.start_address() + area.size() - 1) / PAGE_SIZE + 1)
+            .max()
+            .unwrap_or(0)
+            .max((covered_end + PAGE_SIZE - 1) / PAGE_SIZE);
+
+        let kernel = (kernel_start / PAGE_SIZE, kernel_end / PAGE_SIZE);
+        let multiboot = (multiboot_start / PAGE_SIZE, multiboot_end / PAGE_SIZE);
+        let in_use = |number: usize| {
+            (number >= kernel.0 && number <= kernel.1)
+                || (number >= multiboot.0 && number <= multiboot.1)
+        };
+
+        // The allocator's bitmap, followed by the one for `take_handed_out`.
+        let (bitmap_start, covered) = match place_bitmaps(&areas, frames, &in_use) {
+            Some(placed) => placed,
+            None => panic!("No room below 1 GiB for the frame bitmaps."),
+        };
+        let bitmap_len = bitmap_len_for(covered);
+
+        println!(
+            "[ pmm ] Frame bitmaps for {} frames at {:#x}, {} KiB each",
+            covered,
+            bitmap_start * PAGE_SIZE,
+            bitmap_len * PAGE_SIZE / 1024
+        );
+
+        // The kernel and the multiboot structure lie below 1 GiB, so always in the bitmap.
+        let mut ranges = FrameRanges::new();
+        if covered < frames {
+            println!(
+                "[ pmm ] Keeping the {} frames above {:#x} as a list of ranges.",
+                frames - covered,
+                covered * PAGE_SIZE
+            );
+            for area in areas.iter() {
+                let first = (area.start_address() / PAGE_SIZE).max(covered);
+                let end = (area.start_address() + area.size() - 1) / PAGE_SIZE + 1;
+                if first < end && !ranges.insert(first, end) {
+                    println!(
+                        "[ pmm ] Too many free ranges, ignoring frames from {:#x}",
+                        first * PAGE_SIZE
+                    );
+                }
+            }
+        }
+
+        let bitmap = unsafe { FrameBitmap::new(bitmap_start * PAGE_SIZE, covered) };
+        let handed_out =
+            unsafe { FrameBitmap::new_clear((bitmap_start + bitmap_len) * PAGE_SIZE, covered) };
+        for area in areas.iter() {
+            let first = area.start_address() / PAGE_SIZE;
+            let last = (area.start_address() + area.size() - 1) / PAGE_SIZE;
+            bitmap.clear_range(first, last + 1);
+        }
+        bitmap.set_range(kernel.0, kernel.1 + 1);
+        bitmap.set_range(multiboot.0, multiboot.1 + 1);
+        bitmap.set_range(bitmap_start, bitmap_start + 2 * bitmap_len);
+
         let mut allocator = AreaFrameAllocator {
             next_free_frame: Frame::containing_address(PhysicalAddress::new(0)),
-            current_area: None,
-            areas: memory_areas,
-            kernel_start: Frame::containing_address(PhysicalAddress::new(kernel_start)),
-            kernel_end: Frame::containing_address(PhysicalAddress::new(kernel_end)),
-            multiboot_start: Frame::containing_address(PhysicalAddress::new(multiboot_start)),
-            multiboot_end: Frame::containing_address(PhysicalAddress::new(multiboot_end)),
+            areas: areas,
+            bitmap: bitmap,
+            ranges: ranges,
+            handed_out: Some(handed_out),
+            bitmap_frames: (
+                Frame {
+                    number: bitmap_start,
+                },
+                Frame {
+                    number: bitmap_start + 2 * bitmap_len - 1,
+                },
+            ),
+            kernel: (Frame { number: kernel.0 }, Frame { number: kernel.1 }),
+            multiboot: Some((
+                Frame {
+                    number: multiboot.0,
+                },
+                Frame {
+                    number: multiboot.1,
+                },
+            )),
         };
-        allocator.choose_next_area();
         allocator.allocate_frame(1);
         allocator
     }
 
-    /// Choose the next available memory area.
-    fn choose_next_area(&mut self) {
-        self.current_area = self.areas
-            .clone()
-            .filter(|area| {
-                let address = area.start_address() + area.size() - 1;
-                Frame::containing_address(PhysicalAddress::new(address as usize))
-                    >= self.next_free_frame
+    /// Check whether `frame` is reserved or allocated: outside the usable areas, used by the
+    /// kernel, the multiboot structure or the bitmaps, or handed out and not yet freed.
+    pub fn is_reserved(&self, frame: &Frame) -> bool {
+        self.bitmap.is_set(frame.number)
+    }
+
+    /// Check whether `number` lies in one of the usable memory areas.
+    fn in_areas(&self, number: usize) -> bool {
+        self.areas.iter().any(|area| {
+            number >= area.start_address() / PAGE_SIZE
+                && number <= (area.start_address() + area.size() - 1) / PAGE_SIZE
+        })
+    }
+
+    /// Return the bitmap of reserved and allocated frames.
+    pub fn bitmap(&self) -> &FrameBitmap {
+        &self.bitmap
+    }
+
+    /// Take the bitmap set aside for tracking which frames are handed out. Its bits all start
+    /// clear, and the allocator never touches it. Returns `None` once it has been taken.
+    pub fn take_handed_out(&mut self) -> Option<FrameBitmap> {
+        self.handed_out.take()
+    }
+
+    /// Return the frames both bitmaps occupy, which must stay identity mapped.
+    pub fn bitmap_frames(&self) -> FrameIter {
+        Frame::range_inclusive(self.bitmap_frames.0.clone(), self.bitmap_frames.1.clone())
+    }
+
+    /// Return the first and last frames of the multiboot information structure that it does not
+    /// share with the kernel or the bitmaps, which are the ones `release_multiboot` releases.
+    /// Returns `None` if there are none, or once they have been released.
+    pub fn multiboot_frames(&self) -> Option<(Frame, Frame)> {
+        let kernel = (self.kernel.0.number, self.kernel.1.number);
+        let bitmaps = (self.bitmap_frames.0.number, self.bitmap_frames.1.number);
+
+        let range = self.multiboot
+            .as_ref()
+            .map(|&(ref start, ref end)| (start.number, end.number))?;
+        let (first, last) = clip(clip(range, kernel)?, bitmaps)?;
+        Some((Frame { number: first }, Frame { number: last }))
+    }
+
+    /// Stop reserving the frames of the multiboot information structure, so that they can be
+    /// handed out, and return the range they occupied. Frames it shares with the kernel or the
+    /// bitmaps stay reserved, see `multiboot_frames`. They must be unmapped before this is called.
+    pub fn release_multiboot(&mut self) -> Option<(Frame, Frame)> {
+        let range = self.multiboot_frames();
+        self.multiboot = None;
+
+        if let Some((ref start, ref end)) = range {
+            for number in start.number..end.number + 1 {
+                if self.in_areas(number) {
+                    self.bitmap.clear(number);
+                }
+            }
+            if *start < self.next_free_frame {
+                self.next_free_frame = start.clone();
+            }
+        }
+        range
+    }
+
+    /// Add `area` to the usable memory areas, and make the frames that lie entirely within it
+    /// free. Returns `false`, adding nothing, if there is no room for another area or the bitmap
+    /// does not cover all of it.
+    pub fn add_area(&mut self, area: MemoryArea) -> bool {
+        let first = (area.start_address() + PAGE_SIZE - 1) / PAGE_SIZE;
+        let end = (area.start_address() + area.size()) / PAGE_SIZE;
+
+        if end > self.bitmap.frames() || self.areas.push(area).is_err() {
+            return false;
+        }
+
+        self.bitmap.clear_range(first, end);
+        if first < self.next_free_frame.number {
+            self.next_free_frame = Frame { number: first };
+        }
+        true
+    }
+
+    /// Return every usable memory area.
+    pub fn areas(&self) -> &[MemoryArea] {
+        &self.areas
+    }
+
+    /// Return the number of frames in the usable memory areas, free or not.
+    pub fn total_frames(&self) -> usize {
+        self.areas
+            .iter()
+            .map(|area| {
+                let first = area.start_address() / PAGE_SIZE;
+                let last = (area.start_address() + area.size() - 1) / PAGE_SIZE;
+                last - first + 1
             })
-            .min_by_key(|area| area.start_address());
+            .sum()
+    }
 
-        if let Some(area) = self.current_area {
-            let start_frame = Frame::containing_address(PhysicalAddress::new(area.start_address()));
-            println!(
-                "First area starts at address: {:#x}",
-                start_frame.start_address().get()
-            );
-            if self.next_free_frame < start_frame {
-                self.next_free_frame = start_frame;
+    /// Return the address of the lowest frame that may be free. No frame below it is.
+    pub fn next_free_address(&self) -> PhysicalAddress {
+        self.next_free_frame.start_address()
+    }
+
+    /// Allocate `count` physically contiguous frames and return the first. This searches the
+    /// bitmap from the lowest frame that may be free for the lowest long enough run, and then the
+    /// ranges above the bitmap. Free frames passed over stay free.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<Frame> {
+        match self.bitmap.find_clear_run(self.next_free_frame.number, count) {
+            Some(start) => {
+                self.bitmap.set_range(start, start + count);
+                Some(Frame { number: start })
             }
+            None => self.ranges.allocate(count).map(|start| Frame { number: start }),
         }
     }
 }
 
 impl FrameAllocator for AreaFrameAllocator {
-    /// Allocate a single frame. Return `None` if we are out of memory.
+    /// Allocate `count` contiguous frames. Return `None` if we are out of memory.
     fn allocate_frame(&mut self, count: usize) -> Option<Frame> {
         if count == 0 {
             return None;
-        } else if let Some(area) = self.current_area {
-            // "clone" the frame to return it if it's free. Frame doesn't
-            // implement Clone, but we can construct an identical frame.
-            let start_frame = Frame {
-                number: self.next_free_frame.number,
-            };
+        } else if count > 1 {
+            return self.allocate_contiguous(count);
+        }
 
-            let end_frame = Frame {
-                number: self.next_free_frame.number + (count - 1),
-            };
+        // Words of reserved and allocated frames, such as the holes between areas, are skipped
+        // whole.
+        match self.bitmap.find_clear(self.next_free_frame.number) {
+            Some(number) => {
+                self.bitmap.set(number);
+                self.next_free_frame = Frame { number: number + 1 };
+                Some(Frame { number: number })
+            }
+            None => self.ranges.allocate(1).map(|number| Frame { number: number }),
+        }
+    }
 
-            // the last frame of the current area
-            let current_area_last_frame = {
-                let address = area.start_address() + area.size() - 1;
-                Frame::containing_address(PhysicalAddress::new(address))
-            };
+    /// Return a frame to the allocator. Frames are handed out lowest first, so it is handed out
+    /// again before any frame above it. Debug builds check that the frame is not freed twice.
+    fn deallocate_frame(&mut self, frame: Frame) {
+        if frame.number >= self.bitmap.frames() {
+            debug_assert!(!self.ranges.contains(frame.number), "{:?} freed twice", frame);
+            if !self.ranges.insert(frame.number, frame.number + 1) {
+                println!("[ pmm ] Too many free ranges, leaking {:?}", frame);
+            }
+            return;
+        }
 
-            if end_frame > current_area_last_frame {
-                // all frames of current area are used, switch to next area
-                self.choose_next_area();
-            } else if (start_frame >= self.kernel_start && start_frame <= self.kernel_end)
-                || (end_frame >= self.kernel_start && start_frame <= self.kernel_end)
-            {
-                // frame range is used by the kernel.
-                self.next_free_frame = Frame {
-                    number: self.kernel_end.number + 1,
-                };
-            } else if (start_frame >= self.multiboot_start && start_frame <= self.multiboot_end)
-                || (end_frame >= self.multiboot_start && end_frame <= self.multiboot_end)
-            {
-                // `frame` is used by the multiboot information structure
-                self.next_free_frame = Frame {
-                    number: self.multiboot_end.number + 1,
-                };
-            } else {
-                // frame is unused, increment `next_free_frame` and return it
-                self.next_free_frame.number += 1;
-                return Some(start_frame);
+        let was_allocated = self.bitmap.clear(frame.number);
+        debug_assert!(was_allocated, "{:?} freed twice", frame);
+
+        if frame < self.next_free_frame {
+            self.next_free_frame = frame;
+        }
+    }
+
+    /// Get a count of available free frames.
+    fn free_frames(&mut self) -> usize {
+        self.bitmap.count_clear() + self.ranges.free_frames()
+    }
+
+    /// Get the length of the longest run of contiguous free frames.
+    fn largest_free_run(&mut self) -> usize {
+        // A run spanning the end of the bitmap is counted as two.
+        self.bitmap.largest_clear_run().max(self.ranges.largest_run())
+    }
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use arch::memory::Frame;
+    use arch::memory::area_frame_allocator::AreaFrameAllocator;
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "contiguous_run",
+            run: contiguous_run,
+        },
+        KTest {
+            name: "frames_off_heap",
+            run: frames_off_heap,
+        },
+        KTest {
+            name: "contiguous_reuse",
+            run: contiguous_reuse,
+        },
+        KTest {
+            name: "add_area",
+            run: add_area,
+        },
+        KTest {
+            name: "multiboot_release",
+            run: multiboot_release,
+        },
+        KTest {
+            name: "frame_ranges",
+            run: frame_ranges,
+        },
+        KTest {
+            name: "bitmap_fallback",
+            run: bitmap_fallback,
+        },
+    ];
+
+    fn contiguous_run() -> Result<(), &'static str> {
+        use arch::memory::area_frame_allocator::{find_contiguous_run, MemoryArea};
+
+        // Out of order. Frames 257, 259 and 513 are reserved, which leaves the second area with no
+        // two free frames in a row and the third with a run of six from 514.
+        let areas = [
+            MemoryArea::new(0x20_0000, 0x8000),
+            MemoryArea::new(0x0, 0x3000),
+            MemoryArea::new(0x10_0000, 0x5000),
+        ];
+        let reserved = |number: usize| number == 257 || number == 259 || number == 513;
+        let find = |from, count| find_contiguous_run(&areas, from, count, &reserved);
+
+        if find(0, 3) != Some(0) {
+            Err("the run at the start of the lowest area was not found")
+        } else if find(3, 2) != Some(514) || find(3, 6) != Some(514) {
+            Err("the run was not found past the fragmented area")
+        } else if find(3, 7).is_some() || find(0, 0x100).is_some() {
+            Err("a run longer than any free one was found")
+        } else if find(257, 1) != Some(258) {
+            Err("a single frame between reserved ones was not found")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The heap grows by allocating frames, so the allocator must not allocate from the heap while
+    /// it is locked.
+    fn frames_off_heap() -> Result<(), &'static str> {
+        use arch::memory::heap_allocator::alloc_stats;
+        use arch::memory::{FrameAllocator, ALLOCATOR};
+
+        let mut guard = ALLOCATOR.lock();
+        let frame_allocator = guard.as_mut().ok_or("the frame allocator is not initialised")?;
+
+        let free = frame_allocator.free_frames();
+        let heap = alloc_stats().used;
+
+        // Allocate and free in an order that leaves gaps, then fill them again.
+        let mut frames = [0; 32];
+        for number in frames.iter_mut() {
+            *number = frame_allocator.allocate_frame(1).ok_or("out of frames")?.number;
+        }
+        for (i, &number) in frames.iter().enumerate() {
+            if i % 2 == 0 {
+                frame_allocator.deallocate_frame(Frame { number: number });
             }
-            // `frame` was not valid, try it again with the updated `next_free_frame`
-            self.allocate_frame(count)
+        }
+        let refilled = frames.iter().enumerate().filter(|&(i, _)| i % 2 == 0).all(|(_, &number)| {
+            frame_allocator.allocate_frame(1).map(|frame| frame.number) == Some(number)
+        });
+        for &number in frames.iter() {
+            frame_allocator.deallocate_frame(Frame { number: number });
+        }
+
+        let heap_after = alloc_stats().used;
+        let free_after = frame_allocator.free_frames();
+
+        if heap_after != heap {
+            Err("allocating and freeing frames used the heap")
+        } else if !refilled {
+            Err("freed frames were not handed out again lowest first")
+        } else if free_after != free {
+            Err("frames were lost or duplicated")
         } else {
-            None // no free frames left
+            Ok(())
         }
     }
 
-    fn deallocate_frame(&mut self, _frame: Frame) {
-        unimplemented!()
+    fn contiguous_reuse() -> Result<(), &'static str> {
+        use arch::memory::{FrameAllocator, ALLOCATOR};
+
+        const COUNT: usize = 8;
+
+        let mut guard = ALLOCATOR.lock();
+        let frame_allocator = guard.as_mut().ok_or("the frame allocator is not initialised")?;
+
+        let free = frame_allocator.free_frames();
+
+        // A single frame first, so that the run is searched for from above it.
+        let single = frame_allocator.allocate_frame(1).ok_or("out of frames")?.number;
+        let run = frame_allocator.allocate_frame(COUNT).ok_or("no free run")?.number;
+        for number in run..run + COUNT {
+            frame_allocator.deallocate_frame(Frame { number: number });
+        }
+        let again = frame_allocator.allocate_frame(COUNT).ok_or("the freed run was lost")?.number;
+
+        // Frames between the single frame and the run were passed over, and must still be free.
+        let skipped = frame_allocator.allocate_frame(1).ok_or("out of frames")?.number;
+
+        frame_allocator.deallocate_frame(Frame { number: single });
+        frame_allocator.deallocate_frame(Frame { number: skipped });
+        for number in again..again + COUNT {
+            frame_allocator.deallocate_frame(Frame { number: number });
+        }
+        let free_after = frame_allocator.free_frames();
+
+        if again != run {
+            Err("the freed run was not found again")
+        } else if skipped >= run && skipped < run + COUNT {
+            Err("a frame of the allocated run was handed out again")
+        } else if free_after != free {
+            Err("frames were lost or duplicated")
+        } else {
+            Ok(())
+        }
     }
 
-    /// Get a count of available free frames.
-    fn free_frames(&mut self) -> usize {
-        let mut count = 0;
-
-        for area in self.areas.clone() {
-            let start_frame = Frame::containing_address(PhysicalAddress::new(area.start_address()));
-            let end_frame = Frame::containing_address(PhysicalAddress::new(
-                area.start_address() + area.size() - 1,
-            ));
-
-            for frame in Frame::range_inclusive(start_frame, end_frame) {
-                if frame >= self.kernel_start && frame <= self.kernel_end {
-                    // Frame is used by the kernel.
-                } else if frame >= self.next_free_frame {
-                    count += 1;
-                } else {
-                    // Inside of used range.
+    /// Frames not in any area of an allocator from `with_allocator`, but covered by its bitmap.
+    const SPARE: usize = 4;
+
+    /// Run `f` on an allocator of its own, over a single area of frames allocated for it and
+    /// identity mapped, so that its bitmaps can go there like the real ones. `f` is given the
+    /// allocator, the first frame of the area and the number of frames in it. The first two
+    /// frames stand in for the kernel, and the next two, with the kernel's last frame, for the
+    /// multiboot structure. The bitmap also covers `SPARE` frames above the area.
+    fn with_allocator<F>(f: F) -> Result<(), &'static str>
+    where
+        F: FnOnce(&mut AreaFrameAllocator, usize, usize) -> Result<(), &'static str>,
+    {
+        use arch::memory::area_frame_allocator::{bitmap_len_for, BITMAP_END_FRAME,
+                                                 BITMAP_MIN_FRAME};
+        use arch::memory::paging::{EntryFlags, Page, VirtualAddress};
+        use arch::memory::{self, with_controller, PAGE_SIZE};
+        use ktest::multiboot_info;
+
+        const TAG_MEMORY_MAP: u32 = 6;
+        const ENTRY_SIZE: usize = 24;
+
+        // Room for bitmaps covering up to 1 GiB, the kernel, the multiboot structure and a few
+        // free frames.
+        let count = 2 * bitmap_len_for(BITMAP_END_FRAME) + 8;
+        let first = memory::allocate_frames(count).ok_or("no free run")?.number;
+        let page = |number: usize| {
+            Page::containing_address(VirtualAddress::new(number * PAGE_SIZE))
+        };
+        let frames = first..first + count;
+
+        // The bitmaps must lie between 1 MiB and 1 GiB.
+        let placeable = first >= BITMAP_MIN_FRAME && first + count <= BITMAP_END_FRAME;
+        let mapped = placeable && with_controller(|memory_controller| {
+            let active_table = &mut memory_controller.active_table;
+            if frames.clone().any(|number| active_table.translate_page(page(number)).is_some()) {
+                return false;
+            }
+            for number in frames.clone() {
+                let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+                let result = active_table.identity_map(Frame { number: number }, flags);
+                result.flush(active_table);
+            }
+            true
+        });
+
+        let result = if mapped {
+            // The entry size and version come first, then the one entry, of type 1 (available).
+            let (base, length) = (first * PAGE_SIZE, count * PAGE_SIZE);
+            let mut data = [0u8; 8 + ENTRY_SIZE];
+            data[0] = ENTRY_SIZE as u8;
+            for i in 0..8 {
+                data[8 + i] = (base >> (i * 8)) as u8;
+                data[16 + i] = (length >> (i * 8)) as u8;
+            }
+            data[24] = 1;
+
+            let mut buffer = [0u64; 8];
+            let tags: [(u32, &[u8]); 1] = [(TAG_MEMORY_MAP, &data)];
+            let boot_info = unsafe { ::multiboot2::load(multiboot_info(&mut buffer, &tags)) };
+
+            match boot_info.memory_map_tag() {
+                Some(memory_map_tag) => {
+                    let mut allocator = AreaFrameAllocator::new(
+                        first * PAGE_SIZE,
+                        (first + 1) * PAGE_SIZE,
+                        (first + 1) * PAGE_SIZE,
+                        (first + 3) * PAGE_SIZE,
+                        memory_map_tag.memory_areas(),
+                        (first + count + SPARE) * PAGE_SIZE,
+                    );
+                    f(&mut allocator, first, count)
                 }
+                None => Err("the memory map tag was not found"),
             }
+        } else if !placeable {
+            Err("the frames for the allocator are not between 1 MiB and 1 GiB")
+        } else {
+            Err("the frames for the allocator could not be identity mapped")
+        };
+
+        if mapped {
+            with_controller(|memory_controller| {
+                let active_table = &mut memory_controller.active_table;
+                for number in frames.clone() {
+                    let result = active_table.unmap(page(number));
+                    result.flush(active_table);
+                }
+            });
+        }
+        for number in frames {
+            memory::deallocate_frame(Frame { number: number });
         }
+        result
+    }
+
+    /// Reclaimed memory, such as the ACPI tables, is handed to the allocator as a new area.
+    fn add_area() -> Result<(), &'static str> {
+        use arch::memory::area_frame_allocator::MemoryArea;
+        use arch::memory::{FrameAllocator, PAGE_SIZE};
+
+        with_allocator(|allocator, first, count| {
+            let total = allocator.total_frames();
+            let free = allocator.free_frames();
 
-        count
+            let spare = MemoryArea::new((first + count) * PAGE_SIZE, SPARE * PAGE_SIZE);
+            let reserved = allocator.is_reserved(&Frame { number: first + count });
+            let added = allocator.add_area(spare);
+            let added_total = allocator.total_frames();
+            let added_free = allocator.free_frames();
+
+            let beyond = MemoryArea::new(allocator.bitmap().frames() * PAGE_SIZE, PAGE_SIZE);
+            let too_big = allocator.add_area(beyond);
+
+            // Every free frame, with the added ones last.
+            let mut handed_out = 0;
+            let mut last = None;
+            while let Some(frame) = allocator.allocate_frame(1) {
+                handed_out += 1;
+                last = Some(frame.number);
+            }
+
+            if !reserved {
+                Err("frames outside every area were free")
+            } else if !added || added_total != total + SPARE || added_free != free + SPARE {
+                Err("the area was not added, or its frames were not made free")
+            } else if too_big || allocator.total_frames() != total + SPARE {
+                Err("an area the bitmap does not cover was added")
+            } else if handed_out != free + SPARE || last != Some(first + count + SPARE - 1) {
+                Err("the frames of the added area were not handed out")
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    /// The multiboot frames are reserved until `release_multiboot`, and then handed out like any
+    /// other free frame, except the one shared with the kernel.
+    fn multiboot_release() -> Result<(), &'static str> {
+        use arch::memory::FrameAllocator;
+
+        with_allocator(|allocator, first, _| {
+            let numbers = |range: Option<(Frame, Frame)>| range.map(|(a, b)| (a.number, b.number));
+            let reserved = |allocator: &AreaFrameAllocator, number| {
+                allocator.is_reserved(&Frame { number: number })
+            };
+
+            let before = numbers(allocator.multiboot_frames());
+            let held = reserved(allocator, first + 2) && reserved(allocator, first + 3);
+            let released = numbers(allocator.release_multiboot());
+            let kept = reserved(allocator, first + 1);
+            let freed = !reserved(allocator, first + 2) && !reserved(allocator, first + 3);
+            let reused = allocator.allocate_frame(1).map(|frame| frame.number);
+            let again = allocator.release_multiboot().is_some();
+
+            if before != Some((first + 2, first + 3)) || released != before {
+                Err("the frames shared with the kernel were not left out of the range")
+            } else if !held {
+                Err("the multiboot frames were not reserved")
+            } else if !kept {
+                Err("the kernel frame the multiboot structure starts in was released")
+            } else if !freed {
+                Err("the multiboot frames are still reserved")
+            } else if reused != Some(first + 2) {
+                Err("the released frames were not handed out again")
+            } else if again || allocator.multiboot_frames().is_some() {
+                Err("the multiboot range was released twice")
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    fn frame_ranges() -> Result<(), &'static str> {
+        use arch::memory::area_frame_allocator::{FrameRanges, MAX_RANGES};
+
+        let mut ranges = FrameRanges::new();
+        ranges.insert(100, 104);
+        ranges.insert(200, 201);
+        // Joins the two ranges on either side into 100..201.
+        ranges.insert(104, 200);
+        let merged = ranges.largest_run() == 101 && ranges.free_frames() == 101;
+
+        let single = ranges.allocate(1);
+        let run = ranges.allocate(50);
+        let too_long = ranges.allocate(51);
+        let taken = !ranges.contains(100) && !ranges.contains(150) && ranges.contains(151);
+        ranges.insert(100, 101);
+
+        let mut full = FrameRanges::new();
+        let fitted = (0..MAX_RANGES).all(|i| full.insert(i * 2, i * 2 + 1));
+        let overflowed = full.insert(MAX_RANGES * 2, MAX_RANGES * 2 + 1);
+
+        if !merged {
+            Err("touching ranges were not merged")
+        } else if single != Some(100) || run != Some(101) || too_long.is_some() {
+            Err("frames were not taken from the start of a long enough range")
+        } else if !taken {
+            Err("allocated frames are still counted as free")
+        } else if ranges.free_frames() != 51 || ranges.largest_run() != 50 {
+            Err("a freed frame was not put back")
+        } else if !fitted || overflowed || full.free_frames() != MAX_RANGES {
+            Err("the range list did not hold exactly MAX_RANGES ranges")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// With 2 TiB of memory above 4 GiB and only 1 MiB free below 1 GiB, full bitmaps do not fit,
+    /// so they must cover less, and the rest goes on the range list.
+    fn bitmap_fallback() -> Result<(), &'static str> {
+        use arch::memory::area_frame_allocator::{bitmap_len_for, place_bitmaps, MemoryArea,
+                                                 BITMAP_END_FRAME, BITMAP_MIN_FRAME};
+        use arch::memory::PAGE_SIZE;
+
+        let areas = [
+            MemoryArea::new(0x10_0000, 0x10_0000),
+            MemoryArea::new(0x1_0000_0000, 0x200_0000_0000),
+        ];
+        let frames = (0x1_0000_0000 + 0x200_0000_0000) / PAGE_SIZE;
+        let free = |_| false;
+
+        let placed = place_bitmaps(&areas, frames, &free);
+        let fits = |&(start, covered): &(usize, usize)| {
+            start == BITMAP_MIN_FRAME && 2 * bitmap_len_for(covered) <= 0x100
+                && 2 * bitmap_len_for(covered * 2) > 0x100
+        };
+
+        // A bitmap covering less than 1 GiB would leave low memory to the range list.
+        let tiny = [MemoryArea::new(0x10_0000, 0x1000)];
+        let nowhere = place_bitmaps(&tiny, frames, &free);
+
+        let small = [MemoryArea::new(0x10_0000, 0x100_0000)];
+        let whole = place_bitmaps(&small, 0x1100, &free);
+
+        if placed.map_or(true, |placed| placed.1 >= frames || placed.1 < BITMAP_END_FRAME) {
+            Err("the bitmaps were not shrunk to cover less memory")
+        } else if !placed.as_ref().map_or(false, fits) {
+            Err("the bitmaps were not made as large as fits")
+        } else if nowhere.is_some() {
+            Err("bitmaps not covering the memory below 1 GiB were placed")
+        } else if whole != Some((BITMAP_MIN_FRAME, 0x1100)) {
+            Err("bitmaps that fit were shrunk")
+        } else {
+            Ok(())
+        }
     }
 }
@@ -0,0 +1,329 @@
+//! A bitmap with one bit per physical frame, kept in memory set aside for it at boot, so that it
+//! can be sized from the memory map and cover all of physical memory. The words are atomic, so
+//! the bitmap can be read and updated through a shared reference without a lock.
+
+use core::slice;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const BITS_PER_WORD: usize = 64;
+
+pub struct FrameBitmap {
+    words: &'static [AtomicU64],
+    /// The number of frames covered.
+    frames: usize,
+}
+
+impl FrameBitmap {
+    /// Return the number of bytes a bitmap covering `frames` frames takes.
+    pub fn bytes_for(frames: usize) -> usize {
+        (frames + BITS_PER_WORD - 1) / BITS_PER_WORD * 8
+    }
+
+    /// Create a bitmap covering `frames` frames in the memory at `address`, with every bit set.
+    ///
+    /// # Safety
+    ///
+    /// `address` must be 8 byte aligned and point to `bytes_for(frames)` bytes of writable memory
+    /// that nothing else uses for as long as the bitmap exists.
+    pub unsafe fn new(address: usize, frames: usize) -> FrameBitmap {
+        let words = slice::from_raw_parts(address as *const AtomicU64, Self::bytes_for(frames) / 8);
+        for word in words {
+            word.store(!0, Ordering::Relaxed);
+        }
+
+        FrameBitmap {
+            words: words,
+            frames: frames,
+        }
+    }
+
+    /// Create a bitmap covering `frames` frames in the memory at `address`, with every bit clear.
+    ///
+    /// # Safety
+    ///
+    /// The same as for `new`.
+    pub unsafe fn new_clear(address: usize, frames: usize) -> FrameBitmap {
+        let bitmap = Self::new(address, frames);
+        for word in bitmap.words.iter().take(frames / BITS_PER_WORD) {
+            word.store(0, Ordering::Relaxed);
+        }
+        bitmap.clear_range(frames / BITS_PER_WORD * BITS_PER_WORD, frames);
+
+        bitmap
+    }
+
+    /// Return the number of frames the bitmap covers.
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Check whether the bit of frame `number` is set. Frames the bitmap does not cover read as
+    /// set.
+    pub fn is_set(&self, number: usize) -> bool {
+        number >= self.frames
+            || self.words[number / BITS_PER_WORD].load(Ordering::Relaxed)
+                & 1 << (number % BITS_PER_WORD) != 0
+    }
+
+    /// Set the bit of frame `number`, and return whether it was set already.
+    pub fn set(&self, number: usize) -> bool {
+        if number >= self.frames {
+            return true;
+        }
+
+        let bit = 1 << (number % BITS_PER_WORD);
+        self.words[number / BITS_PER_WORD].fetch_or(bit, Ordering::Relaxed) & bit != 0
+    }
+
+    /// Clear the bit of frame `number`, and return whether it was set. Frames the bitmap does not
+    /// cover stay set.
+    pub fn clear(&self, number: usize) -> bool {
+        if number >= self.frames {
+            return true;
+        }
+
+        let bit = 1 << (number % BITS_PER_WORD);
+        self.words[number / BITS_PER_WORD].fetch_and(!bit, Ordering::Relaxed) & bit != 0
+    }
+
+    /// Return the lowest frame at or above `from` whose bit is clear, if there is one. Words with
+    /// every bit set are skipped whole.
+    pub fn find_clear(&self, from: usize) -> Option<usize> {
+        let mut number = from;
+        while number < self.frames {
+            let word = self.words[number / BITS_PER_WORD].load(Ordering::Relaxed);
+            let clear = !word >> (number % BITS_PER_WORD);
+            if clear == 0 {
+                number = (number / BITS_PER_WORD + 1) * BITS_PER_WORD;
+            } else {
+                number += clear.trailing_zeros() as usize;
+                return if number < self.frames {
+                    Some(number)
+                } else {
+                    None
+                };
+            }
+        }
+
+        None
+    }
+
+    /// Return the lowest frame at or above `from` and below `end` whose bit is set, if there is
+    /// one. Frames the bitmap does not cover count as set.
+    fn find_set(&self, from: usize, end: usize) -> Option<usize> {
+        let mut number = from;
+        while number < end {
+            if number >= self.frames {
+                return Some(number);
+            }
+
+            let set = self.words[number / BITS_PER_WORD].load(Ordering::Relaxed)
+                >> (number % BITS_PER_WORD);
+            if set == 0 {
+                number = (number / BITS_PER_WORD + 1) * BITS_PER_WORD;
+            } else {
+                number += set.trailing_zeros() as usize;
+                return if number < end {
+                    Some(number)
+                } else {
+                    None
+                };
+            }
+        }
+
+        None
+    }
+
+    /// Return the first frame of the lowest run of `count` frames at or above `from` whose bits
+    /// are all clear, if there is one. Like `find_clear`, this skips whole words at a time.
+    pub fn find_clear_run(&self, from: usize, count: usize) -> Option<usize> {
+        if count == 0 {
+            return None;
+        }
+
+        let mut start = self.find_clear(from)?;
+        while let Some(set) = self.find_set(start, start + count) {
+            start = self.find_clear(set + 1)?;
+        }
+
+        Some(start)
+    }
+
+    /// Return the number of frames whose bit is clear.
+    pub fn count_clear(&self) -> usize {
+        // Bits past the last frame are never cleared, so whole words can be counted.
+        self.words
+            .iter()
+            .map(|word| word.load(Ordering::Relaxed).count_zeros() as usize)
+            .sum()
+    }
+
+    /// Return the length of the longest run of frames whose bits are clear.
+    pub fn largest_clear_run(&self) -> usize {
+        let mut largest = 0;
+        let mut run = 0;
+
+        for number in 0..self.frames {
+            if self.is_set(number) {
+                run = 0;
+            } else {
+                run += 1;
+                largest = largest.max(run);
+            }
+        }
+
+        largest
+    }
+
+    /// Set the bits of frames `first` up to, but not including, `end`.
+    pub fn set_range(&self, first: usize, end: usize) {
+        for number in first..end.min(self.frames) {
+            self.set(number);
+        }
+    }
+
+    /// Clear the bits of frames `first` up to, but not including, `end`.
+    pub fn clear_range(&self, first: usize, end: usize) {
+        for number in first..end.min(self.frames) {
+            self.clear(number);
+        }
+    }
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "frame_bitmap",
+            run: frame_bitmap,
+        },
+        KTest {
+            name: "largest_clear_run",
+            run: largest_clear_run,
+        },
+    ];
+
+    fn frame_bitmap() -> Result<(), &'static str> {
+        use arch::memory::{allocate_frames, deallocate_frame, kernel_phys_range, phys_to_virt,
+                           Frame, ALLOCATOR, PAGE_SIZE};
+        use super::FrameBitmap;
+
+        // Covers a partial last word, so the bits past the end are in memory but not covered.
+        const FRAMES: usize = 200;
+
+        let frame = allocate_frames(1).ok_or("could not allocate a frame")?;
+        let bitmap = unsafe { FrameBitmap::new(phys_to_virt(frame.start_address()).get(), FRAMES) };
+
+        let all_set = (0..FRAMES + 64).all(|number| bitmap.is_set(number));
+
+        let clear = unsafe { FrameBitmap::new_clear(bitmap.words.as_ptr() as usize, FRAMES) };
+        let all_clear =
+            (0..FRAMES).all(|number| !clear.is_set(number)) && clear.count_clear() == FRAMES;
+        let bitmap = unsafe { FrameBitmap::new(phys_to_virt(frame.start_address()).get(), FRAMES) };
+
+        bitmap.clear_range(10, 140);
+        let was_set = bitmap.set(70);
+        let set_again = bitmap.set(70);
+        let cleared = bitmap.clear(71);
+        let past_end = bitmap.clear(FRAMES);
+
+        let expected = |number: usize| number < 10 || number >= 140 || number == 70;
+        let lookups = (0..FRAMES + 64).all(|number| bitmap.is_set(number) == expected(number));
+        let found = (bitmap.find_clear(0), bitmap.find_clear(70), bitmap.find_clear(140));
+        // Frames 10 to 69 and 71 to 139 are clear.
+        let runs = [
+            bitmap.find_clear_run(0, 60),
+            bitmap.find_clear_run(0, 61),
+            bitmap.find_clear_run(0, 69),
+            bitmap.find_clear_run(65, 5),
+            bitmap.find_clear_run(100, 40),
+            bitmap.find_clear_run(0, 70),
+        ];
+
+        deallocate_frame(frame);
+
+        // The allocator's own bitmap reserves the kernel and itself, and covers every area.
+        let (kernel_start, kernel_end) = kernel_phys_range();
+        let (kernel_reserved, bitmap_reserved, covered) = match *ALLOCATOR.lock() {
+            Some(ref frame_allocator) => {
+                let reserved =
+                    |number: usize| frame_allocator.is_reserved(&Frame { number: number });
+                let last = frame_allocator
+                    .areas()
+                    .iter()
+                    .map(|area| (area.start_address() + area.size() - 1) / PAGE_SIZE)
+                    .max()
+                    .unwrap_or(0);
+                (
+                    (kernel_start.get() / PAGE_SIZE..kernel_end.get() / PAGE_SIZE).all(&reserved),
+                    frame_allocator.bitmap_frames().all(|frame| reserved(frame.number)),
+                    frame_allocator.bitmap().frames() > last,
+                )
+            }
+            None => return Err("the frame allocator is not initialised"),
+        };
+
+        if FrameBitmap::bytes_for(FRAMES) != 32 || FrameBitmap::bytes_for(64) != 8 {
+            Err("the bitmap size is not rounded up to whole words")
+        } else if !all_set {
+            Err("a new bitmap does not have every bit set")
+        } else if !all_clear {
+            Err("a new clear bitmap does not have every bit it covers clear")
+        } else if was_set || !set_again || cleared {
+            Err("set and clear did not report the previous bit")
+        } else if !past_end || !lookups {
+            Err("a lookup did not match the bits set and cleared")
+        } else if found != (Some(10), Some(71), None) {
+            Err("find_clear did not find the lowest clear bit")
+        } else if runs != [Some(10), Some(71), Some(71), Some(65), Some(100), None] {
+            Err("find_clear_run did not find the lowest long enough run")
+        } else if !kernel_reserved || !bitmap_reserved {
+            Err("the kernel's or the bitmap's frames are not reserved in the allocator's bitmap")
+        } else if !covered {
+            Err("the allocator's bitmap does not cover the highest usable frame")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn largest_clear_run() -> Result<(), &'static str> {
+        use arch::memory::{self, allocate_frames, deallocate_frame, phys_to_virt};
+        use super::FrameBitmap;
+
+        const FRAMES: usize = 256;
+
+        let frame = allocate_frames(1).ok_or("could not allocate a frame")?;
+        let bitmap = unsafe { FrameBitmap::new(phys_to_virt(frame.start_address()).get(), FRAMES) };
+        let full = bitmap.largest_clear_run();
+
+        // A synthetic memory map of three areas, the middle one straddling a word boundary, with
+        // a frame allocated out of it. The largest gap is the 49 frames from 101 to 149.
+        bitmap.clear_range(10, 40);
+        bitmap.clear_range(60, 150);
+        bitmap.clear_range(200, 240);
+        bitmap.set(100);
+        let largest = bitmap.largest_clear_run();
+
+        // The gap running to the end of the bitmap counts too.
+        bitmap.clear_range(200, FRAMES);
+        let at_end = bitmap.largest_clear_run();
+
+        deallocate_frame(frame);
+
+        let available = memory::available_contiguous();
+
+        if full != 0 {
+            Err("a bitmap with every bit set has a free run")
+        } else if largest != 49 {
+            Err("the largest gap in the map was not found")
+        } else if at_end != 56 {
+            Err("a gap at the end of the bitmap was not counted")
+        } else if available == 0 || available > memory::stats().free {
+            Err("available_contiguous is not within the free frames")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -1,12 +1,34 @@
 use alloc::allocator::{Alloc, AllocErr, Layout};
 use linked_list_allocator::LockedHeap;
-use arch::interrupts::disable_interrupts_and_then;
+use arch::interrupts::without_interrupts;
+use arch::memory::{self, PAGE_SIZE};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub const HEAP_START: usize = 0o_000_001_000_000_0000;
 pub const HEAP_SIZE: usize = 500 * 1024;
+/// The size the heap may grow to. The virtual range up to `HEAP_START + HEAP_MAX_SIZE` is kept free
+/// for it.
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024;
+/// The smallest amount the heap grows by at once.
+const HEAP_GROWTH: usize = 64 * 1024;
+
+/// A snapshot of heap usage, in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct AllocStats {
+    /// The number of bytes mapped for the heap.
+    pub heap_size: usize,
+    /// The number of bytes handed out and not yet freed, not counting padding.
+    pub used: usize,
+    /// The size the heap may grow to.
+    pub max_size: usize,
+}
 
 pub struct HeapAllocator {
     inner: LockedHeap,
+    /// The number of bytes currently mapped for the heap.
+    size: AtomicUsize,
+    /// The number of bytes currently allocated.
+    used: AtomicUsize,
 }
 
 impl HeapAllocator {
@@ -14,6 +36,8 @@ impl HeapAllocator {
     pub const fn new() -> Self {
         HeapAllocator {
             inner: LockedHeap::empty(),
+            size: AtomicUsize::new(0),
+            used: AtomicUsize::new(0),
         }
     }
 
@@ -25,28 +49,209 @@ impl HeapAllocator {
     /// empty heap.  Also, it is assumed that interrupts are disabled.
     pub unsafe fn init(&self, heap_bottom: usize, heap_size: usize) {
         self.inner.lock().init(heap_bottom, heap_size);
+        self.size.store(heap_size, Ordering::SeqCst);
     }
 
-    pub unsafe fn extend(&mut self, by: usize) {
+    /// Extend the heap by `by` bytes. The memory directly above the heap must already be mapped.
+    pub unsafe fn extend(&self, by: usize) {
         self.inner.lock().extend(by);
+        self.size.fetch_add(by, Ordering::SeqCst);
+    }
+
+    /// Return the number of bytes currently mapped for the heap.
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    /// Return a snapshot of heap usage.
+    pub fn stats(&self) -> AllocStats {
+        AllocStats {
+            heap_size: self.size(),
+            used: self.used.load(Ordering::SeqCst),
+            max_size: HEAP_MAX_SIZE,
+        }
+    }
+
+    /// Report what could not be allocated, how full the heap is and where the allocation came
+    /// from. Called when an allocation fails for good.
+    fn report_oom(&self, err: &AllocErr) {
+        match *err {
+            AllocErr::Exhausted { ref request } => println!(
+                "\n[ mem ] Out of memory allocating {} bytes aligned to {}.",
+                request.size(),
+                request.align()
+            ),
+            AllocErr::Unsupported { details } => {
+                println!("\n[ mem ] Unsupported allocation: {}", details)
+            }
+        }
+
+        let stats = self.stats();
+        println!(
+            "[ mem ] Heap: {} of {} KiB used, {} KiB mapped.",
+            stats.used / 1024,
+            stats.max_size / 1024,
+            stats.heap_size / 1024
+        );
+
+        ::arch::backtrace::print(8);
+    }
+
+    /// Map more memory above the heap so that `layout` has a chance to fit. Returns `false` if
+    /// the heap is already at `HEAP_MAX_SIZE`, or the memory could not be mapped.
+    fn grow(&self, layout: &Layout) -> bool {
+        // Leave room for the allocation to be aligned within the new memory.
+        let wanted = (layout.size() + layout.align()).max(HEAP_GROWTH);
+        let by = (wanted + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+        let size = self.size();
+        if size + by > HEAP_MAX_SIZE {
+            return false;
+        }
+
+        if !memory::map_heap_pages(HEAP_START + size, by) {
+            return false;
+        }
+
+        unsafe { self.extend(by) };
+        true
     }
 }
 
 /// Wrappers for inner Alloc implementation
 unsafe impl<'a> Alloc for &'a HeapAllocator {
+    /// Allocate from the heap, growing it if it is full. Returns an error rather than panicking if
+    /// there is still no room, so that callers can recover.
     unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
-        disable_interrupts_and_then(|| -> Result<*mut u8, AllocErr> {
-            self.inner.lock().alloc(layout)
+        without_interrupts(|| -> Result<*mut u8, AllocErr> {
+            let size = layout.size();
+            let result = self.inner.lock().alloc(layout.clone());
+
+            let result = match result {
+                Err(_) if self.grow(&layout) => self.inner.lock().alloc(layout),
+                result => result,
+            };
+
+            if result.is_ok() {
+                self.used.fetch_add(size, Ordering::SeqCst);
+            }
+            result
         })
     }
 
     unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
-        disable_interrupts_and_then(|| {
+        without_interrupts(|| {
+            self.used.fetch_sub(layout.size(), Ordering::SeqCst);
             self.inner.lock().dealloc(ptr, layout);
         });
     }
 
-    fn oom(&mut self, _: AllocErr) -> ! {
+    /// Report what could not be allocated and how full the heap is, then panic.
+    fn oom(&mut self, err: AllocErr) -> ! {
+        self.report_oom(&err);
+
         panic!("Out of memory");
     }
 }
+
+/// Return a snapshot of kernel heap usage.
+pub fn alloc_stats() -> AllocStats {
+    ::HEAP_ALLOCATOR.stats()
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use alloc::Vec;
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "heap",
+            run: heap,
+        },
+        KTest {
+            name: "alloc_interrupts",
+            run: alloc_interrupts,
+        },
+        KTest {
+            name: "alloc_too_large",
+            run: alloc_too_large,
+        },
+    ];
+
+    /// Tests that end the session, only run when named.
+    pub const FATAL_TESTS: &[KTest] = &[
+        KTest {
+            name: "oom_exit",
+            run: oom_exit,
+        },
+    ];
+
+    fn heap() -> Result<(), &'static str> {
+        let values: Vec<usize> = (0..1000).collect();
+
+        if values.iter().sum::<usize>() == 999 * 1000 / 2 {
+            Ok(())
+        } else {
+            Err("heap allocated vector has the wrong contents")
+        }
+    }
+
+    /// Ask for more than the machine has. The heap cannot grow that far, so the raw allocation
+    /// must fail, and leave the heap as it was, instead of panicking.
+    fn alloc_too_large() -> Result<(), &'static str> {
+        use alloc::allocator::{Alloc, Layout};
+        use super::alloc_stats;
+
+        let layout = Layout::from_size_align(1 << 40, 8).ok_or("the layout is invalid")?;
+        let before = alloc_stats();
+
+        let mut allocator = &::HEAP_ALLOCATOR;
+        let result = unsafe { allocator.alloc(layout) };
+        let after = alloc_stats();
+
+        if result.is_ok() {
+            Err("an allocation larger than all memory succeeded")
+        } else if after.used != before.used || after.heap_size != before.heap_size {
+            Err("the failed allocation changed the heap")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Ask the global allocator for more than the heap can ever hold. The OOM handler should
+    /// print "Out of memory allocating 1099511627776 bytes aligned to 1." with the heap usage and
+    /// a backtrace, then panic, which exits QEMU with the failure code. Run with
+    /// `ktest=oom_exit`.
+    fn oom_exit() -> Result<(), &'static str> {
+        use arch::interrupts::{set_fatal_action, FatalAction};
+
+        set_fatal_action(FatalAction::QemuExit);
+        let huge: Vec<u8> = Vec::with_capacity(1 << 40);
+        drop(huge);
+
+        Err("an allocation larger than the heap succeeded")
+    }
+
+    /// Allocating with interrupts disabled, e.g. with a lock held that interrupt handlers take,
+    /// must leave them disabled.
+    fn alloc_interrupts() -> Result<(), &'static str> {
+        use alloc::boxed::Box;
+        use arch::interrupts::{interrupts_enabled, without_interrupts};
+
+        let (after_alloc, after_free) = without_interrupts(|| {
+            let value = Box::new([0u64; 64]);
+            let after_alloc = interrupts_enabled();
+            drop(value);
+            (after_alloc, interrupts_enabled())
+        });
+
+        if after_alloc || after_free {
+            Err("the heap allocator enabled interrupts")
+        } else if !interrupts_enabled() {
+            Err("interrupts were not enabled again")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Per-CPU caches ("magazines") of free frames. Single frame allocations and deallocations are
+//! served from the current CPU's magazine without taking a lock, and only touch the global
+//! `ALLOCATOR` lock to refill or drain it in batches.
+
+use arch::interrupts::without_interrupts;
+use arch::memory::{Frame, FrameAllocator};
+use arch::percpu::{self, MAX_CPUS};
+use core::cell::{Cell, UnsafeCell};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The number of frames a magazine holds.
+pub const MAGAZINE_SIZE: usize = 16;
+/// The number of frames moved between a magazine and the global allocator at once.
+const BATCH: usize = MAGAZINE_SIZE / 2;
+
+pub struct Magazine {
+    frames: [usize; MAGAZINE_SIZE],
+    len: usize,
+}
+
+impl Magazine {
+    const fn new() -> Magazine {
+        Magazine {
+            frames: [0; MAGAZINE_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Take a frame out of the magazine.
+    pub fn pop(&mut self) -> Option<Frame> {
+        if self.len == 0 {
+            return None;
+        }
+
+        self.len -= 1;
+        Some(Frame {
+            number: self.frames[self.len],
+        })
+    }
+
+    /// Put `frame` into the magazine, handing it back if the magazine is full.
+    pub fn push(&mut self, frame: Frame) -> Result<(), Frame> {
+        if self.len == MAGAZINE_SIZE {
+            return Err(frame);
+        }
+
+        self.frames[self.len] = frame.number;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Check if the magazine has no room left.
+    pub fn is_full(&self) -> bool {
+        self.len == MAGAZINE_SIZE
+    }
+
+    /// Move a batch of frames from `allocator` into the magazine.
+    pub fn refill<A: FrameAllocator>(&mut self, allocator: &mut A) {
+        while self.len < BATCH {
+            match allocator.allocate_frame(1) {
+                Some(frame) => {
+                    let _ = self.push(frame);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Move a batch of frames from the magazine back to `allocator`.
+    pub fn drain<A: FrameAllocator>(&mut self, allocator: &mut A) {
+        for _ in 0..BATCH {
+            match self.pop() {
+                Some(frame) => allocator.deallocate_frame(frame),
+                None => break,
+            }
+        }
+    }
+
+    /// The number of frames in the magazine.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+/// A CPU's magazine. Only the CPU it belongs to touches it, with interrupts disabled, so it
+/// needs no lock.
+struct Slot {
+    magazine: UnsafeCell<Magazine>,
+    /// Set while `with_current` runs on the magazine, so that a nested call backs off instead of
+    /// taking a second reference to it.
+    in_use: Cell<bool>,
+    /// The length of the magazine when `with_current` last returned, for `cached` to read from
+    /// any CPU.
+    cached: AtomicUsize,
+}
+
+unsafe impl Sync for Slot {}
+
+impl Slot {
+    const fn new() -> Slot {
+        Slot {
+            magazine: UnsafeCell::new(Magazine::new()),
+            in_use: Cell::new(false),
+            cached: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// The magazines, indexed by `percpu::index`.
+static MAGAZINES: [Slot; MAX_CPUS] = [
+    Slot::new(),
+    Slot::new(),
+    Slot::new(),
+    Slot::new(),
+    Slot::new(),
+    Slot::new(),
+    Slot::new(),
+    Slot::new(),
+];
+
+/// Run `f` on the current CPU's magazine, with interrupts disabled. Returns `None`, without
+/// running `f`, before the CPU has been given an index, or if `f` is already running on this
+/// CPU's magazine further up the stack, in which case the global allocator should be used.
+pub fn with_current<F, T>(f: F) -> Option<T>
+where
+    F: FnOnce(&mut Magazine) -> T,
+{
+    without_interrupts(|| {
+        let slot = MAGAZINES.get(percpu::index()?)?;
+        if slot.in_use.replace(true) {
+            return None;
+        }
+
+        let magazine = unsafe { &mut *slot.magazine.get() };
+        let result = f(magazine);
+        slot.cached.store(magazine.len(), Ordering::Relaxed);
+
+        slot.in_use.set(false);
+        Some(result)
+    })
+}
+
+/// Return the number of frames cached in all the magazines, as of the last time each was used.
+pub fn cached() -> usize {
+    MAGAZINES
+        .iter()
+        .map(|slot| slot.cached.load(Ordering::Relaxed))
+        .sum()
+}
@@ -1,42 +1,144 @@
-pub use self::area_frame_allocator::AreaFrameAllocator;
+pub use self::area_frame_allocator::{AreaFrameAllocator, MemoryArea};
 pub use self::paging::ActivePageTable;
-pub use self::stack_allocator::Stack;
+pub use self::stack_allocator::{Stack, STACK_CANARY};
+pub use self::owner::FrameOwner;
+use self::frame_bitmap::FrameBitmap;
 use self::paging::{PhysicalAddress, VirtualAddress};
 use self::paging::entry::EntryFlags;
-use acpi;
 use multiboot2::BootInformation;
-use spin::Mutex;
+use spin::{Mutex, MutexGuard, Once};
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::{fmt, ptr};
 
 pub mod area_frame_allocator;
+pub mod frame_bitmap;
 pub mod heap_allocator;
+pub mod magazine;
+pub mod owner;
 pub mod paging;
+pub mod refcount;
 pub mod stack_allocator;
 
 /// The size of a physical page on x86.
 pub const PAGE_SIZE: usize = 4096;
 
+/// The virtual address at which all usable physical memory is mapped by `init_direct_map`.
+pub const DIRECT_MAP_BASE: usize = 0xffff_8800_0000_0000;
+
 pub static ALLOCATOR: Mutex<Option<AreaFrameAllocator>> = Mutex::new(None);
 
-pub fn init(boot_info: &BootInformation) -> MemoryController {
+/// The kernel's memory controller, set up by `init`. Use `with_controller` to access it.
+static MEMORY_CONTROLLER: Once<Mutex<MemoryController>> = Once::new();
+
+/// The physical start and end of the kernel image, set by `init`.
+static KERNEL_RANGE: Once<(usize, usize)> = Once::new();
+
+/// Set once `init_direct_map` has mapped usable memory at `DIRECT_MAP_BASE`.
+static DIRECT_MAPPED: AtomicBool = AtomicBool::new(false);
+
+/// Whether `deallocate_frame` zeroes frames before they can be handed out again.
+static SCRUB_ON_FREE: AtomicBool = AtomicBool::new(false);
+/// The number of frames zeroed by `deallocate_frame`.
+static SCRUBBED_FRAMES: AtomicUsize = AtomicUsize::new(0);
+/// The number of TSC cycles spent zeroing them.
+static SCRUB_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+/// Frames handed out by `try_allocate_frames` and not freed since, set by `init`. Unlike the frame
+/// allocator's own bitmap this leaves out frames cached in the magazines, so `deallocate_frame`
+/// can tell a frame freed twice wherever it went the first time.
+static HANDED_OUT: Once<FrameBitmap> = Once::new();
+/// The number of frames passed to `deallocate_frame` that were not handed out.
+static DOUBLE_FREES: AtomicUsize = AtomicUsize::new(0);
+
+/// Run `f` with exclusive access to the memory controller.
+///
+/// # Panics
+///
+/// Panics if `init` has not been called yet.
+pub fn with_controller<F, T>(f: F) -> T
+where
+    F: FnOnce(&mut MemoryController) -> T,
+{
+    f(&mut lock_controller(&MEMORY_CONTROLLER))
+}
+
+/// Lock the memory controller in `controller`, which is `MEMORY_CONTROLLER` outside of tests.
+///
+/// # Panics
+///
+/// Panics if it has not been set by `init` yet.
+fn lock_controller(
+    controller: &'static Once<Mutex<MemoryController>>,
+) -> MutexGuard<'static, MemoryController> {
+    controller
+        .try()
+        .expect("Memory controller accessed before memory::init.")
+        .lock()
+}
+
+/// Exclusive access to the active page table. The memory controller stays locked for as long as
+/// this guard is alive, so only one subsystem can edit the page tables at a time.
+pub struct ActiveTableGuard {
+    guard: MutexGuard<'static, MemoryController>,
+}
+
+impl Deref for ActiveTableGuard {
+    type Target = ActivePageTable;
+
+    fn deref(&self) -> &ActivePageTable {
+        &self.guard.active_table
+    }
+}
+
+impl DerefMut for ActiveTableGuard {
+    fn deref_mut(&mut self) -> &mut ActivePageTable {
+        &mut self.guard.active_table
+    }
+}
+
+/// Lock the memory controller and return a guard giving access to the active page table.
+///
+/// # Panics
+///
+/// Panics if `init` has not been called yet.
+pub fn active_table() -> ActiveTableGuard {
+    ActiveTableGuard {
+        guard: lock_controller(&MEMORY_CONTROLLER),
+    }
+}
+
+/// Set up the frame allocator, paging, the heap and the stack allocator, and store the resulting
+/// `MemoryController` for `with_controller`.
+pub fn init(boot_info: &BootInformation) {
     assert_has_not_been_called!("memory::init must be called only once");
 
     let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
-    let elf_sections_tag = boot_info
-        .elf_sections_tag()
-        .expect("Elf sections tag required");
-
-    let kernel_start = elf_sections_tag
-        .sections()
-        .filter(|s| s.is_allocated())
-        .map(|s| s.start_address())
-        .min()
-        .unwrap();
-    let kernel_end = elf_sections_tag
-        .sections()
-        .filter(|s| s.is_allocated())
-        .map(|s| s.start_address() + s.size())
-        .max()
-        .unwrap();
+
+    let symbols = kernel_extent_from_symbols();
+    let sections = match boot_info.elf_sections_tag() {
+        Some(elf_sections_tag) => allocated_range(
+            elf_sections_tag
+                .sections()
+                .map(|s| (s.start_address() as usize, s.size() as usize, s.is_allocated())),
+        ).expect("Kernel has no allocated sections"),
+        None => {
+            println!("[ pmm ] No ELF sections tag, using the linker script's kernel extent.");
+            symbols
+        }
+    };
+    KERNEL_RANGE.call_once(|| sections);
+
+    // Reserve both if they disagree, so that no part of the kernel is handed out.
+    if sections != symbols {
+        println!(
+            "[ pmm ] Warning: the ELF sections put the kernel at {:#x}-{:#x}, but the linker \
+             script at {:#x}-{:#x}.",
+            sections.0, sections.1, symbols.0, symbols.1
+        );
+    }
+    let kernel_start = sections.0.min(symbols.0);
+    let kernel_end = sections.1.max(symbols.1);
 
     println!(
         "[ pmm ] Kernel start: {:#x}, kernel end: {:#x}",
@@ -48,21 +150,50 @@ pub fn init(boot_info: &BootInformation) -> MemoryController {
         boot_info.end_address()
     );
 
+    if let Some(entries) = ::arch::multiboot::memory_map(boot_info) {
+        let summary = ::arch::multiboot::MemorySummary::from_entries(entries);
+        println!(
+            "[ mem ] {} MiB total, {} MiB usable across {} regions, {} KiB reserved, {} KiB ACPI, \
+             {} KiB bad",
+            summary.total >> 20,
+            summary.usable >> 20,
+            summary.usable_regions,
+            summary.reserved >> 10,
+            summary.acpi >> 10,
+            summary.bad >> 10
+        );
+    }
+
+    // The ACPI tables are handed to the frame allocator once they have been read, so its bitmap
+    // has to cover them as well.
+    use arch::multiboot::MemoryAreaType;
+    let reclaimable_end = ::arch::multiboot::memory_map(boot_info).map_or(0, |entries| {
+        entries
+            .filter(|entry| entry.typ == MemoryAreaType::AcpiReclaimable)
+            .map(|entry| (entry.base + entry.length) as usize)
+            .max()
+            .unwrap_or(0)
+    });
+
     // Construct a physical frame allocator based on parameters passed to the main kernel.
-    let frame_allocator = AreaFrameAllocator::new(
-        kernel_start as usize,
-        kernel_end as usize,
+    let mut frame_allocator = AreaFrameAllocator::new(
+        kernel_start,
+        kernel_end,
         boot_info.start_address(),
         boot_info.end_address(),
         memory_map_tag.memory_areas(),
+        reclaimable_end,
     );
+    if let Some(handed_out) = frame_allocator.take_handed_out() {
+        HANDED_OUT.call_once(|| handed_out);
+    }
 
     *ALLOCATOR.lock() = Some(frame_allocator);
 
     let mut active_table = paging::init(&boot_info);
 
     use self::paging::Page;
-    use self::heap_allocator::{HEAP_SIZE, HEAP_START};
+    use self::heap_allocator::{HEAP_MAX_SIZE, HEAP_SIZE, HEAP_START};
 
     // The beginning and end of the heap.
     let heap_start_page = Page::containing_address(VirtualAddress::new(HEAP_START));
@@ -70,24 +201,281 @@ pub fn init(boot_info: &BootInformation) -> MemoryController {
 
     println!("[ vmm ] Mapping heap pages ...");
 
+    let result = active_table.map_range(
+        Page::range_inclusive(heap_start_page, heap_end_page),
+        EntryFlags::PRESENT | EntryFlags::WRITABLE,
+    );
+    result.flush(&mut active_table);
     for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        let result = active_table.map(page, EntryFlags::PRESENT | EntryFlags::WRITABLE);
-        // Flush this vaddr translation from the TLB.
-        result.flush(&mut active_table);
+        if let Some(frame) = active_table.translate_page(page) {
+            owner::set(&frame, Some(FrameOwner::Heap));
+        }
     }
 
     unsafe { ::HEAP_ALLOCATOR.init(HEAP_START, HEAP_SIZE) };
 
     let stack_allocator = {
-        let stack_start_page = heap_end_page + 1;
+        // Leave room above the heap for it to grow into.
+        let stack_start_page =
+            Page::containing_address(VirtualAddress::new(HEAP_START + HEAP_MAX_SIZE));
         let stack_end_page = stack_start_page + 100;
         let stack_alloc_range = Page::range_inclusive(stack_start_page, stack_end_page);
         stack_allocator::StackAllocator::new(stack_alloc_range)
     };
-    unsafe { acpi::init(&mut active_table) };
-    MemoryController {
-        active_table: active_table,
-        stack_allocator: stack_allocator,
+    MEMORY_CONTROLLER.call_once(|| {
+        Mutex::new(MemoryController {
+            active_table: active_table,
+            stack_allocator: stack_allocator,
+        })
+    });
+
+    init_direct_map();
+}
+
+/// Return the lowest start and highest end of the allocated sections in `sections`, given as
+/// `(start, size, allocated)`, or `None` if none are allocated.
+pub fn allocated_range<I>(sections: I) -> Option<(usize, usize)>
+where
+    I: Iterator<Item = (usize, usize, bool)>,
+{
+    sections
+        .filter(|&(_, _, allocated)| allocated)
+        .fold(None, |range, (start, size, _)| match range {
+            Some((low, high)) => Some((start.min(low), (start + size).max(high))),
+            None => Some((start, start + size)),
+        })
+}
+
+extern "C" {
+    /// The start of the kernel image, from the linker script.
+    static __kernel_start: u8;
+    /// The end of the kernel image, from the linker script.
+    static __kernel_end: u8;
+}
+
+/// Return the physical start and end of the kernel image from the `__kernel_start` and
+/// `__kernel_end` symbols in the linker script. Unlike the ELF sections, these do not depend on
+/// the bootloader passing them on.
+pub fn kernel_extent_from_symbols() -> (usize, usize) {
+    unsafe {
+        (
+            &__kernel_start as *const u8 as usize,
+            &__kernel_end as *const u8 as usize,
+        )
+    }
+}
+
+/// Return the physical addresses the kernel image starts and ends at, from its ELF sections, or
+/// from the linker script if there are none. The kernel is linked at the address it is loaded at,
+/// so these are its section addresses.
+///
+/// # Panics
+///
+/// Panics if `init` has not been called yet.
+pub fn kernel_phys_range() -> (PhysicalAddress, PhysicalAddress) {
+    let &(start, end) = KERNEL_RANGE
+        .try()
+        .expect("Kernel range asked for before memory::init.");
+
+    (PhysicalAddress::new(start), PhysicalAddress::new(end))
+}
+
+/// Allocate a frame for the heap without waiting for the frame allocator, which may be locked by
+/// the code the heap ran out under. Returns `None` if it is locked or has no frames left.
+fn allocate_heap_frame() -> Option<Frame> {
+    let frame = match magazine::with_current(|magazine| magazine.pop()) {
+        Some(Some(frame)) => frame,
+        _ => ALLOCATOR.try_lock()?.as_mut()?.allocate_frame(1)?,
+    };
+
+    mark_handed_out(&frame, 1);
+    owner::set(&frame, Some(FrameOwner::Heap));
+    Some(frame)
+}
+
+/// Map `size` bytes of fresh frames at `start`, to grow the heap into. Returns `false`, leaving
+/// nothing mapped, if there are not enough frames, or the page tables or the frame allocator are
+/// already locked.
+pub fn map_heap_pages(start: usize, size: usize) -> bool {
+    use self::paging::Page;
+
+    let controller = match MEMORY_CONTROLLER.try() {
+        Some(controller) => controller,
+        None => return false,
+    };
+
+    // The heap can run out while the page tables are being edited, so do not wait for the lock.
+    let mut controller = match controller.try_lock() {
+        Some(controller) => controller,
+        None => return false,
+    };
+    let active_table = &mut controller.active_table;
+
+    let start_page = Page::containing_address(VirtualAddress::new(start));
+    let end_page = Page::containing_address(VirtualAddress::new(start + size - 1));
+
+    for (mapped, page) in Page::range_inclusive(start_page, end_page).enumerate() {
+        match allocate_heap_frame() {
+            Some(frame) => {
+                let result =
+                    active_table.map_to(page, frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
+                result.flush(active_table);
+            }
+            None => {
+                // Give back the pages mapped so far.
+                for i in 0..mapped {
+                    let result = active_table.unmap_and_reclaim(start_page + i);
+                    result.flush(active_table);
+                }
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+/// Map every usable area of physical memory at `DIRECT_MAP_BASE + phys`, so that any frame can be
+/// accessed through `phys_to_virt` without a temporary mapping.
+pub fn init_direct_map() {
+    use self::area_frame_allocator::MAX_AREAS;
+    use heapless::Vec as StaticVec;
+
+    // Copy the areas out, so the frame allocator is not locked while the mapper allocates tables.
+    let mut areas: StaticVec<MemoryArea, [MemoryArea; MAX_AREAS]> = StaticVec::new();
+    match *ALLOCATOR.lock() {
+        Some(ref frame_allocator) => for area in frame_allocator.areas() {
+            let _ = areas.push(*area);
+        },
+        None => panic!("Frame allocator called before init."),
+    }
+
+    let mut total = 0;
+
+    with_controller(|memory_controller| {
+        let active_table = &mut memory_controller.active_table;
+
+        for area in areas.iter() {
+            total += direct_map_area(active_table, area);
+        }
+    });
+
+    DIRECT_MAPPED.store(true, Ordering::SeqCst);
+
+    println!(
+        "[ vmm ] Direct mapped {} KiB of physical memory at {:#x}",
+        total / 1024,
+        DIRECT_MAP_BASE
+    );
+}
+
+/// Map the pages that lie entirely within `area` at `DIRECT_MAP_BASE + phys`, and return the
+/// number of bytes mapped.
+fn direct_map_area(active_table: &mut ActivePageTable, area: &MemoryArea) -> usize {
+    let start = (area.start_address() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let end = (area.start_address() + area.size()) & !(PAGE_SIZE - 1);
+    if end <= start {
+        return 0;
+    }
+
+    let result = active_table.map_offset(
+        PhysicalAddress::new(start),
+        phys_to_virt(PhysicalAddress::new(start)),
+        end - start,
+        EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+    );
+    result.flush(active_table);
+    end - start
+}
+
+/// Hand `size` bytes of physical memory at `start` to the frame allocator as a new usable area,
+/// and direct map it like the others. This is for memory that held something the kernel no
+/// longer needs, such as the ACPI tables. Returns the number of frames added, which is 0 if the
+/// allocator could not take the area.
+pub fn add_area(active_table: &mut ActivePageTable, start: usize, size: usize) -> usize {
+    let area = MemoryArea::new(start, size);
+
+    if DIRECT_MAPPED.load(Ordering::SeqCst) {
+        direct_map_area(active_table, &area);
+    }
+
+    let added = match *ALLOCATOR.lock() {
+        Some(ref mut frame_allocator) => frame_allocator.add_area(area),
+        None => panic!("Frame allocator called before init."),
+    };
+
+    if added {
+        let first = (start + PAGE_SIZE - 1) / PAGE_SIZE;
+        let end = (start + size) / PAGE_SIZE;
+        end.saturating_sub(first)
+    } else {
+        println!("[ pmm ] Could not add the area at {:#x} to the frame allocator.", start);
+        0
+    }
+}
+
+/// Return the address at which `address` is reachable through the direct map. Only valid for
+/// usable memory, once `init_direct_map` has run.
+pub fn phys_to_virt(address: PhysicalAddress) -> VirtualAddress {
+    VirtualAddress::new(DIRECT_MAP_BASE + address.get())
+}
+
+/// If `address` lies in the guard page of a kernel stack, return the bottom of that stack. This
+/// never waits for the memory controller, so that the page fault handler can call it, and returns
+/// `None` if the controller is locked. The boot stack is recognised even then.
+pub fn guarded_stack(address: VirtualAddress) -> Option<usize> {
+    if paging::Page::containing_address(address) == paging::boot_stack_guard() {
+        return Some(paging::boot_stack().0);
+    }
+
+    MEMORY_CONTROLLER.try()?.try_lock()?.guarded_stack(address)
+}
+
+/// A snapshot of physical memory, heap and stack usage, from `MemoryController::stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    /// The number of frames in the usable memory areas.
+    pub total_frames: usize,
+    /// The number of frames that can still be handed out, the ones cached in the magazines
+    /// included.
+    pub free_frames: usize,
+    /// The number of frames in the usable memory areas that are not free, the kernel image's
+    /// included.
+    pub used_frames: usize,
+    /// The number of bytes of heap handed out and not yet freed.
+    pub heap_used: usize,
+    /// The number of bytes of heap that are mapped but not handed out. The heap can also grow.
+    pub heap_free: usize,
+    /// The number of stacks from `alloc_stack` that have not been freed.
+    pub stacks: usize,
+}
+
+/// A number of bytes, printed in the largest unit that keeps it at 1 or more.
+struct Bytes(usize);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            bytes if bytes >= 1 << 30 => write!(f, "{} GiB", bytes >> 30),
+            bytes if bytes >= 1 << 20 => write!(f, "{} MiB", bytes >> 20),
+            bytes if bytes >= 1 << 10 => write!(f, "{} KiB", bytes >> 10),
+            bytes => write!(f, "{} B", bytes),
+        }
+    }
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "free: {} / {}, used: {}, heap: {} used, {} free, stacks: {}",
+            Bytes(self.free_frames * PAGE_SIZE),
+            Bytes(self.total_frames * PAGE_SIZE),
+            Bytes(self.used_frames * PAGE_SIZE),
+            Bytes(self.heap_used),
+            Bytes(self.heap_free),
+            self.stacks
+        )
     }
 }
 
@@ -105,6 +493,50 @@ impl MemoryController {
         stack_allocator.alloc_stack(active_table, size_in_pages)
     }
 
+    /// Free a stack from `alloc_stack`, so that its frames and pages can be used again.
+    pub fn dealloc_stack(&mut self, stack: Stack) {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut stack_allocator,
+        } = self;
+        stack_allocator.dealloc_stack(active_table, stack)
+    }
+
+    /// Return the number of stacks that were freed with their canary clobbered.
+    pub fn stack_overflows(&self) -> usize {
+        self.stack_allocator.overflows()
+    }
+
+    /// If `address` lies in the guard page of a stack from `alloc_stack`, return the bottom of
+    /// that stack.
+    pub fn guarded_stack(&self, address: VirtualAddress) -> Option<usize> {
+        self.stack_allocator.guarded_stack(address)
+    }
+
+    /// Return a snapshot of physical memory, heap and stack usage.
+    pub fn stats(&self) -> MemoryStats {
+        use self::heap_allocator::alloc_stats;
+
+        let cached = magazine::cached();
+        let (total_frames, free_frames) = match *ALLOCATOR.lock() {
+            Some(ref mut frame_allocator) => (
+                frame_allocator.total_frames(),
+                frame_allocator.free_frames() + cached,
+            ),
+            None => panic!("Frame allocator called before init."),
+        };
+        let heap = alloc_stats();
+
+        MemoryStats {
+            total_frames: total_frames,
+            free_frames: free_frames,
+            used_frames: total_frames.saturating_sub(free_frames),
+            heap_used: heap.used,
+            heap_free: heap.heap_size.saturating_sub(heap.used),
+            stacks: self.stack_allocator.allocated(),
+        }
+    }
+
     /* pub fn allocate_frame(&mut self, count: usize) -> Option<Frame> {
         let &mut MemoryController {
             ref mut active_table,
@@ -116,7 +548,7 @@ impl MemoryController {
     } */
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Frame {
     number: usize,
 }
@@ -140,17 +572,39 @@ impl Frame {
         }
     }
 
+    /// Return an iterator over the frames from `start` to `end`, both included.
     pub fn range_inclusive(start: Frame, end: Frame) -> FrameIter {
         FrameIter {
-            start: start,
-            end: end,
+            start: start.number,
+            end: end.number,
         }
     }
 }
 
+impl fmt::Debug for Frame {
+    /// Print the start address of the frame along with its number.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Frame({:#x} #{})",
+            self.start_address().get(),
+            self.number
+        )
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// An iterator over frames between `start` and `end`. It holds frame numbers rather than frames,
+/// so that it can be copied without duplicating the frames themselves.
+#[derive(Copy, Clone)]
 pub struct FrameIter {
-    start: Frame,
-    end: Frame,
+    start: usize,
+    end: usize,
 }
 
 impl Iterator for FrameIter {
@@ -158,8 +612,8 @@ impl Iterator for FrameIter {
 
     fn next(&mut self) -> Option<Frame> {
         if self.start <= self.end {
-            let frame = self.start.clone();
-            self.start.number += 1;
+            let frame = Frame { number: self.start };
+            self.start += 1;
             Some(frame)
         } else {
             None
@@ -171,13 +625,800 @@ pub trait FrameAllocator {
     fn allocate_frame(&mut self, count: usize) -> Option<Frame>;
     fn deallocate_frame(&mut self, frame: Frame);
     fn free_frames(&mut self) -> usize;
+    fn largest_free_run(&mut self) -> usize;
+}
+
+/// Why `try_allocate_frames` could not hand out frames.
+#[derive(Clone, Copy)]
+pub struct FrameAllocError {
+    /// The number of contiguous frames asked for.
+    pub requested: usize,
+    /// The number of frames the allocator still had free. This can be more than `requested` when
+    /// the free frames are not contiguous.
+    pub free: usize,
+    /// The address of the lowest frame the allocator might have handed out. No frame below it
+    /// was free.
+    pub next_free: PhysicalAddress,
+}
+
+impl FrameAllocError {
+    fn new(requested: usize, frame_allocator: &mut AreaFrameAllocator) -> FrameAllocError {
+        FrameAllocError {
+            requested: requested,
+            free: frame_allocator.free_frames(),
+            next_free: frame_allocator.next_free_address(),
+        }
+    }
 }
 
-/// Allocate a frame.
+impl fmt::Debug for FrameAllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "could not allocate {} contiguous frames, {} free, next free frame at {:#x}",
+            self.requested,
+            self.free,
+            self.next_free.get()
+        )
+    }
+}
+
+/// Allocate `count` contiguous frames. Single frames come from the current CPU's magazine where
+/// possible, so that the global allocator lock is only taken to refill it.
+pub fn try_allocate_frames(count: usize) -> Result<Frame, FrameAllocError> {
+    let first = take_frames(count)?;
+    mark_handed_out(&first, count);
+    Ok(first)
+}
+
+/// Record that `count` frames from `first` on have been handed out, see `HANDED_OUT`.
+fn mark_handed_out(first: &Frame, count: usize) {
+    if let Some(handed_out) = HANDED_OUT.try() {
+        handed_out.set_range(first.number, first.number + count);
+    }
+}
+
+/// Take `count` contiguous frames from the magazine or the frame allocator.
+fn take_frames(count: usize) -> Result<Frame, FrameAllocError> {
+    if count == 1 {
+        let taken = magazine::with_current(|magazine| {
+            if let Some(frame) = magazine.pop() {
+                return Ok(frame);
+            }
+
+            match *ALLOCATOR.lock() {
+                Some(ref mut frame_allocator) => {
+                    magazine.refill(frame_allocator);
+                    magazine
+                        .pop()
+                        .ok_or_else(|| FrameAllocError::new(count, frame_allocator))
+                }
+                None => panic!("Frame allocator called before init."),
+            }
+        });
+        if let Some(result) = taken {
+            return result;
+        }
+    }
+
+    match *ALLOCATOR.lock() {
+        Some(ref mut frame_allocator) => frame_allocator
+            .allocate_frame(count)
+            .ok_or_else(|| FrameAllocError::new(count, frame_allocator)),
+        None => panic!("Frame allocator called before init."),
+    }
+}
+
+/// Allocate `count` contiguous frames, or return `None` if there are not enough free. Use
+/// `try_allocate_frames` to find out why.
 pub fn allocate_frames(count: usize) -> Option<Frame> {
+    try_allocate_frames(count).ok()
+}
+
+/// Allocate `count` contiguous frames and tag them as owned by `owner`, see `frame_owner`.
+pub fn allocate_frames_for(count: usize, owner: FrameOwner) -> Result<Frame, FrameAllocError> {
+    let first = try_allocate_frames(count)?;
+
+    for number in first.number..first.number + count {
+        owner::set(&Frame { number: number }, Some(owner));
+    }
+
+    Ok(first)
+}
+
+/// Return the owner `frame` was allocated for, if it was allocated with `allocate_frames_for`
+/// and the kernel was built with the `frame_owners` feature.
+pub fn frame_owner(frame: &Frame) -> Option<FrameOwner> {
+    owner::get(frame)
+}
+
+/// A snapshot of physical memory usage, for diagnostics.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// The number of frames that can still be handed out, the ones cached in the magazines
+    /// included.
+    pub free: usize,
+    /// The number of allocated frames tagged with each owner, in the order of `owner::OWNERS`.
+    /// These are all 0 unless the kernel was built with the `frame_owners` feature.
+    pub by_owner: [usize; owner::OWNER_COUNT],
+}
+
+/// Return how many frames are free, and how many are held by each owner.
+pub fn stats() -> FrameStats {
+    let cached = magazine::cached();
+    let free = match *ALLOCATOR.lock() {
+        Some(ref mut frame_allocator) => frame_allocator.free_frames() + cached,
+        None => panic!("Frame allocator called before init."),
+    };
+
+    FrameStats {
+        free: free,
+        by_owner: owner::counts(),
+    }
+}
+
+/// Return the length, in frames, of the longest run of contiguous free frames. This is only a
+/// snapshot for diagnostics; it does not reserve anything.
+pub fn available_contiguous() -> usize {
     if let Some(ref mut frame_allocator) = *ALLOCATOR.lock() {
-        return frame_allocator.allocate_frame(count);
+        frame_allocator.largest_free_run()
     } else {
         panic!("Frame allocator called before init.");
     }
 }
+
+/// Turn zeroing of freed frames on or off. While it is on, no data can leak from a freed frame to
+/// whoever allocates it next.
+pub fn set_scrub_on_free(scrub: bool) {
+    SCRUB_ON_FREE.store(scrub, Ordering::SeqCst);
+}
+
+/// Return the number of frames zeroed on free, and the TSC cycles spent doing so.
+pub fn scrub_stats() -> (usize, u64) {
+    (
+        SCRUBBED_FRAMES.load(Ordering::SeqCst),
+        SCRUB_CYCLES.load(Ordering::SeqCst),
+    )
+}
+
+fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { asm!("rdtsc" : "={eax}"(low), "={edx}"(high) : : : "volatile") };
+    (high as u64) << 32 | low as u64
+}
+
+/// Zero `frame` through the direct map. Frames outside of the usable areas are not direct mapped
+/// and are left as they are.
+fn scrub(frame: &Frame) {
+    if !DIRECT_MAPPED.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let start = frame.start_address().get();
+    let mapped = match *ALLOCATOR.lock() {
+        Some(ref frame_allocator) => frame_allocator.areas().iter().any(|area| {
+            area.start_address() <= start
+                && start + PAGE_SIZE <= area.start_address() + area.size()
+        }),
+        None => false,
+    };
+    if !mapped {
+        return;
+    }
+
+    let begin = rdtsc();
+    unsafe {
+        ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, PAGE_SIZE);
+    }
+    SCRUB_CYCLES.fetch_add(rdtsc() - begin, Ordering::SeqCst);
+    SCRUBBED_FRAMES.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Return a frame to the frame allocator, through the current CPU's magazine where possible. The
+/// frame is zeroed first if scrubbing is on, see `set_scrub_on_free`. A frame that was not handed
+/// out, most likely because it has been freed already, is reported and ignored.
+pub fn deallocate_frame(frame: Frame) {
+    if let Some(handed_out) = HANDED_OUT.try() {
+        if !handed_out.clear(frame.number) {
+            DOUBLE_FREES.fetch_add(1, Ordering::SeqCst);
+            println!("[ pmm ] {:?} freed but not allocated, ignoring it.", frame);
+            return;
+        }
+    }
+
+    owner::set(&frame, None);
+
+    if SCRUB_ON_FREE.load(Ordering::SeqCst) {
+        scrub(&frame);
+    }
+
+    let number = frame.number;
+    let cached = magazine::with_current(|magazine| {
+        if magazine.is_full() {
+            match *ALLOCATOR.lock() {
+                Some(ref mut frame_allocator) => magazine.drain(frame_allocator),
+                None => panic!("Frame allocator called before init."),
+            }
+        }
+
+        magazine.push(Frame { number: number }).is_ok()
+    });
+    if cached == Some(true) {
+        return;
+    }
+
+    if let Some(ref mut frame_allocator) = *ALLOCATOR.lock() {
+        frame_allocator.deallocate_frame(frame);
+    } else {
+        panic!("Frame allocator called before init.");
+    }
+}
+
+/// Return the number of frames passed to `deallocate_frame` that were not allocated, and so were
+/// ignored.
+pub fn double_frees() -> usize {
+    DOUBLE_FREES.load(Ordering::SeqCst)
+}
+
+/// Unmap the multiboot information structure and let the frame allocator hand its frames out.
+/// Everything needed from the multiboot structure (kernel arguments, memory areas) must already
+/// have been copied out, and the `BootInformation` must not be used after this is called. Frames
+/// the structure shares with the kernel stay mapped and reserved.
+pub fn reclaim_boot_memory() {
+    use self::paging::Page;
+
+    let range = match *ALLOCATOR.lock() {
+        Some(ref frame_allocator) => frame_allocator.multiboot_frames(),
+        None => panic!("Frame allocator called before init."),
+    };
+
+    // Unmap before releasing, or the frames could be handed out while still reachable through
+    // the identity mapping. Unmapping may free tables, so the allocator must not be locked here.
+    if let Some((start, end)) = range {
+        with_controller(|memory_controller| {
+            let active_table = &mut memory_controller.active_table;
+
+            for frame in Frame::range_inclusive(start, end) {
+                let page =
+                    Page::containing_address(VirtualAddress::new(frame.start_address().get()));
+
+                // The multiboot structure was identity mapped by `paging::init`.
+                if active_table.translate_page(page).is_some() {
+                    let result = active_table.unmap(page);
+                    result.flush(active_table);
+                }
+            }
+        });
+    }
+
+    let released = match *ALLOCATOR.lock() {
+        Some(ref mut frame_allocator) => frame_allocator.release_multiboot(),
+        None => panic!("Frame allocator called before init."),
+    };
+
+    let count = released.map_or(0, |(start, end)| end.number - start.number + 1);
+    println!("[ pmm ] Reclaimed {} frames of boot memory.", count);
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use ktest::KTest;
+    use spin::Mutex;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "frames",
+            run: frames,
+        },
+        KTest {
+            name: "double_free",
+            run: double_free,
+        },
+        KTest {
+            name: "scrub",
+            run: scrub,
+        },
+        KTest {
+            name: "frame_owner",
+            run: frame_owner,
+        },
+        KTest {
+            name: "kernel_range",
+            run: kernel_range,
+        },
+        KTest {
+            name: "frame_iter",
+            run: frame_iter,
+        },
+        KTest {
+            name: "frame_alloc_error",
+            run: frame_alloc_error,
+        },
+        KTest {
+            name: "kernel_extent",
+            run: kernel_extent,
+        },
+        KTest {
+            name: "memory_stats",
+            run: memory_stats,
+        },
+        KTest {
+            name: "stats_magazines",
+            run: stats_magazines,
+        },
+        KTest {
+            name: "active_table_serialised",
+            run: active_table_serialised,
+        },
+        KTest {
+            name: "direct_map",
+            run: direct_map,
+        },
+        KTest {
+            name: "magazine_concurrent",
+            run: magazine_concurrent,
+        },
+        KTest {
+            name: "magazine_nested",
+            run: magazine_nested,
+        },
+    ];
+
+    /// Tests that end the session, only run when named.
+    pub const FATAL_TESTS: &[KTest] = &[
+        KTest {
+            name: "controller_before_init",
+            run: controller_before_init,
+        },
+    ];
+
+    fn frames() -> Result<(), &'static str> {
+        use arch::memory::{allocate_frames, deallocate_frame};
+
+        let first = allocate_frames(1).ok_or("could not allocate a frame")?;
+        let second = allocate_frames(1).ok_or("could not allocate a second frame")?;
+
+        let distinct = first != second;
+
+        deallocate_frame(first);
+        deallocate_frame(second);
+
+        if distinct {
+            Ok(())
+        } else {
+            Err("the same frame was handed out twice")
+        }
+    }
+
+    fn double_free() -> Result<(), &'static str> {
+        use arch::memory::{allocate_frames, deallocate_frame, double_frees, Frame};
+
+        let frame = allocate_frames(1).ok_or("could not allocate a frame")?;
+        let number = frame.number;
+        let before = double_frees();
+
+        deallocate_frame(frame);
+        deallocate_frame(Frame { number: number });
+        let reported = double_frees() - before;
+
+        // Had the second free gone through, the frame would be handed out twice in a row.
+        let first = allocate_frames(1).ok_or("could not allocate a frame")?;
+        let second = allocate_frames(1).ok_or("could not allocate a second frame")?;
+        let distinct = first != second;
+        deallocate_frame(first);
+        deallocate_frame(second);
+
+        if reported != 1 {
+            Err("the second free of the frame was not reported")
+        } else if !distinct {
+            Err("the frame freed twice was handed out twice")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn scrub() -> Result<(), &'static str> {
+        use arch::memory::{allocate_frames, deallocate_frame, phys_to_virt, set_scrub_on_free,
+                           PAGE_SIZE};
+        use core::slice;
+
+        let frame = allocate_frames(1).ok_or("could not allocate a frame")?;
+        let bytes = unsafe {
+            slice::from_raw_parts_mut(phys_to_virt(frame.start_address()).as_mut_ptr(), PAGE_SIZE)
+        };
+        for byte in bytes.iter_mut() {
+            *byte = 0xaa;
+        }
+
+        set_scrub_on_free(true);
+        deallocate_frame(frame);
+        set_scrub_on_free(false);
+
+        // Freed frames are handed out again last in, first out.
+        let frame = allocate_frames(1).ok_or("could not reallocate the frame")?;
+        let same = phys_to_virt(frame.start_address()).get() == bytes.as_ptr() as usize;
+        let zeroed = bytes.iter().all(|&byte| byte == 0);
+        deallocate_frame(frame);
+
+        if !same {
+            Err("a different frame was handed out")
+        } else if !zeroed {
+            Err("the freed frame was not zeroed")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn frame_owner() -> Result<(), &'static str> {
+        use arch::memory::{self, allocate_frames_for, deallocate_frame, FrameOwner};
+        use arch::memory::owner::OWNERS;
+
+        let frame = allocate_frames_for(1, FrameOwner::PageTable)
+            .map_err(|_| "could not allocate a frame")?;
+        let owner = memory::frame_owner(&frame);
+        let index = OWNERS.iter().position(|&owner| owner == FrameOwner::PageTable).unwrap();
+        let page_tables = memory::stats().by_owner[index];
+        deallocate_frame(frame);
+
+        // Without the feature nothing is tagged, so there is nothing more to check.
+        if !cfg!(feature = "frame_owners") {
+            return if owner.is_none() {
+                Ok(())
+            } else {
+                Err("a frame was tagged without the frame_owners feature")
+            };
+        }
+
+        if owner != Some(FrameOwner::PageTable) {
+            Err("the frame was not tagged as a page table")
+        } else if page_tables == 0 {
+            Err("stats did not count the page table frame")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn kernel_range() -> Result<(), &'static str> {
+        use arch::memory::{allocated_range, kernel_phys_range};
+
+        // (start, size, allocated), out of order and with sections that are not loaded on both
+        // sides.
+        const SECTIONS: [(usize, usize, bool); 5] = [
+            (0x10_5000, 0x2000, true),
+            (0x0, 0x800, false),
+            (0x10_0000, 0x4000, true),
+            (0x10_8000, 0x1000, true),
+            (0x20_0000, 0x300, false),
+        ];
+
+        let (start, end) = kernel_phys_range();
+        let code = kernel_range as usize;
+
+        if allocated_range(SECTIONS.iter().cloned()) != Some((0x10_0000, 0x10_9000)) {
+            Err("the range does not span exactly the allocated sections")
+        } else if allocated_range(SECTIONS.iter().cloned().filter(|s| !s.2)).is_some() {
+            Err("sections that are not allocated gave a range")
+        } else if code < start.get() || code >= end.get() {
+            Err("the kernel's range does not contain its own code")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn frame_iter() -> Result<(), &'static str> {
+        use arch::memory::Frame;
+        use ktest::frame_at as frame;
+
+        let range = Frame::range_inclusive(frame(0x10_0000), frame(0x10_3fff));
+        let copy = range;
+
+        let first = range.clone().next().map(|frame| frame.start_address().get());
+        let last = range.last().map(|frame| frame.start_address().get());
+
+        if copy.count() != 4 {
+            Err("the range does not hold both endpoints and the frames between them")
+        } else if first != Some(0x10_0000) || last != Some(0x10_3000) {
+            Err("the range starts or ends at the wrong frame")
+        } else if Frame::range_inclusive(frame(0x2000), frame(0x1000)).next().is_some() {
+            Err("a range ending before it starts is not empty")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Running out of frames for real would strand the rest of the current memory area, so this
+    /// only checks that a successful allocation is unaffected and that the error describes itself.
+    fn frame_alloc_error() -> Result<(), &'static str> {
+        use arch::memory::{deallocate_frame, try_allocate_frames, FrameAllocError};
+        use arch::memory::paging::PhysicalAddress;
+
+        let frame = try_allocate_frames(1).map_err(|_| "could not allocate a frame")?;
+        deallocate_frame(frame);
+
+        let err = FrameAllocError {
+            requested: 4,
+            free: 3,
+            next_free: PhysicalAddress::new(0x20_0000),
+        };
+        let message = format!("{:?}", err);
+        let expected = "could not allocate 4 contiguous frames, 3 free, next free frame at 0x200000";
+
+        if message != expected {
+            Err("the error does not give the request, the free frames and the next free frame")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn kernel_extent() -> Result<(), &'static str> {
+        use arch::memory::{kernel_extent_from_symbols, kernel_phys_range, PAGE_SIZE};
+
+        let (start, end) = kernel_extent_from_symbols();
+        let (sections_start, sections_end) = kernel_phys_range();
+        let code = kernel_extent as usize;
+
+        if start != 0x10_0000 || end % PAGE_SIZE != 0 {
+            Err("the linker script's kernel extent is not page aligned from 1 MiB")
+        } else if code < start || code >= end {
+            Err("the linker script's kernel extent does not contain the kernel's code")
+        } else if (start, end) != (sections_start.get(), sections_end.get()) {
+            Err("the linker script's kernel extent differs from the ELF sections'")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn memory_stats() -> Result<(), &'static str> {
+        use arch::memory::{self, MemoryStats};
+
+        let (before, during, after) = memory::with_controller(|controller| {
+            let before = controller.stats();
+            let stack = controller.alloc_stack(1);
+            let during = controller.stats();
+            if let Some(stack) = stack {
+                controller.dealloc_stack(stack);
+            }
+            (before, during, controller.stats())
+        });
+
+        let example = MemoryStats {
+            total_frames: 0x10000,
+            free_frames: 0x7b00,
+            used_frames: 0x8500,
+            heap_used: 1536,
+            heap_free: 3 << 20,
+            stacks: 4,
+        };
+        let expected =
+            "free: 123 MiB / 256 MiB, used: 133 MiB, heap: 1 KiB used, 3 MiB free, stacks: 4";
+
+        let accounted = before.free_frames + before.used_frames;
+
+        if before.total_frames == 0 || accounted < before.total_frames {
+            Err("the free and used frames do not add up to the total")
+        } else if during.stacks != before.stacks + 1 || after.stacks != before.stacks {
+            Err("allocating and freeing a stack did not show in the stack count")
+        } else if format!("{}", example) != expected {
+            Err("the statistics are not printed in human-readable units")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Allocating a frame can move a batch of them into the current CPU's magazine, and freeing it
+    /// puts it there. Frames in a magazine are still free.
+    fn stats_magazines() -> Result<(), &'static str> {
+        use arch::memory::{self, allocate_frames, deallocate_frame};
+
+        let before = memory::stats().free;
+        let frame = allocate_frames(1).ok_or("could not allocate a frame")?;
+        let during = memory::stats().free;
+        deallocate_frame(frame);
+        let after = memory::stats().free;
+
+        let controller = memory::with_controller(|controller| controller.stats());
+
+        if during + 1 != before {
+            Err("allocating a frame did not take exactly one off the free count")
+        } else if after != before {
+            Err("the freed frame was not counted as free")
+        } else if controller.free_frames != after {
+            Err("the memory controller's statistics disagree on the free frames")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Using the memory controller before `init` must panic with a message saying so, rather than
+    /// touch uninitialised memory. The panic takes the fatal action, so QEMU exits with the failure
+    /// code and the message on the serial line. Run with `ktest=controller_before_init`.
+    fn controller_before_init() -> Result<(), &'static str> {
+        use arch::interrupts::{set_fatal_action, FatalAction};
+        use arch::memory::{lock_controller, MemoryController};
+        use spin::{Mutex, Once};
+
+        static NEVER_INITIALISED: Once<Mutex<MemoryController>> = Once::new();
+
+        set_fatal_action(FatalAction::QemuExit);
+        lock_controller(&NEVER_INITIALISED);
+
+        Err("the uninitialised controller was handed out")
+    }
+
+    /// Set by `table_thread` once it holds the active table.
+    static TABLE_ACQUIRED: AtomicBool = AtomicBool::new(false);
+
+    fn table_thread() {
+        let _active_table = ::arch::memory::active_table();
+        TABLE_ACQUIRED.store(true, Ordering::SeqCst);
+    }
+
+    /// A thread asking for the active table while the test holds it must wait until the test lets
+    /// go. The thread spins rather than giving way, so the test runs again through the timer.
+    fn active_table_serialised() -> Result<(), &'static str> {
+        use arch::ARCH;
+        use arch::memory::active_table;
+        use device::pit;
+        use ktest::{alive, yield_until};
+        use task;
+
+        TABLE_ACQUIRED.store(false, Ordering::SeqCst);
+
+        // Spawning takes the memory controller for the thread's kernel stack, so it goes first.
+        let pid = task::spawn("ktest_table", table_thread).map_err(|_| "could not spawn a thread")?;
+
+        let acquired_while_held = {
+            let _active_table = active_table();
+
+            let deadline = pit::uptime_ms() + 100;
+            while pit::uptime_ms() < deadline {
+                ARCH.halt();
+            }
+
+            TABLE_ACQUIRED.load(Ordering::SeqCst)
+        };
+
+        let acquired = yield_until(|| TABLE_ACQUIRED.load(Ordering::SeqCst));
+        yield_until(|| !alive(pid));
+
+        if acquired_while_held {
+            Err("the thread got the active table while the test held it")
+        } else if !acquired {
+            Err("the thread never got the active table once it was free")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// A frame written through a scratch mapping must read back the same through the direct map.
+    fn direct_map() -> Result<(), &'static str> {
+        use arch::memory::{self, allocate_frames, phys_to_virt};
+        use arch::memory::paging::{Page, VirtualAddress};
+        use arch::memory::paging::entry::EntryFlags;
+        use core::ptr;
+        use ktest::SCRATCH_ADDRESS;
+
+        let frame = allocate_frames(1).ok_or("could not allocate a frame")?;
+        let page = Page::containing_address(VirtualAddress::new(SCRATCH_ADDRESS));
+        let direct = phys_to_virt(frame.start_address()).get() + 0x18;
+        let pattern = 0x5eed_d1ec_7ab1_e000u64;
+
+        let mut active_table = memory::active_table();
+
+        let result = active_table.map_to(page, frame, EntryFlags::WRITABLE);
+        result.flush(&mut active_table);
+        unsafe { ptr::write_volatile((SCRATCH_ADDRESS + 0x18) as *mut u64, pattern) };
+
+        let mapped = active_table.translate(VirtualAddress::new(direct)).is_some();
+        let read = if mapped {
+            unsafe { ptr::read_volatile(direct as *const u64) }
+        } else {
+            0
+        };
+
+        let result = active_table.unmap_and_reclaim(page);
+        result.flush(&mut active_table);
+
+        if !mapped {
+            Err("the frame is not in the direct map")
+        } else if read != pattern {
+            Err("the direct map did not read back what was written to the frame")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The number of frames `magazine_concurrent` and its thread each allocate.
+    const CONCURRENT_FRAMES: usize = 32;
+
+    /// The frames allocated by `magazine_thread`, as start addresses, once it is done.
+    static THREAD_FRAMES: Mutex<[usize; CONCURRENT_FRAMES]> = Mutex::new([0; CONCURRENT_FRAMES]);
+
+    /// Allocate single frames into `frames`, waiting for a timer tick after each so that whoever
+    /// else is allocating gets to run in between, possibly while the magazine is held. A failed
+    /// allocation is recorded as 0.
+    fn allocate_per_tick(frames: &mut [usize]) {
+        use arch::ARCH;
+        use arch::memory::allocate_frames;
+        use device::pit;
+
+        for slot in frames.iter_mut() {
+            *slot = allocate_frames(1).map_or(0, |frame| frame.start_address().get());
+
+            let tick = pit::ticks();
+            while pit::ticks() == tick {
+                ARCH.halt();
+            }
+        }
+    }
+
+    fn magazine_thread() {
+        let mut frames = [0; CONCURRENT_FRAMES];
+        allocate_per_tick(&mut frames);
+        *THREAD_FRAMES.lock() = frames;
+    }
+
+    /// There is only one CPU, so this stands in for two allocating at once with two tasks the
+    /// timer switches between. No frame may be handed out twice, and all of them must be counted
+    /// as free again once returned.
+    fn magazine_concurrent() -> Result<(), &'static str> {
+        use arch::memory::{self, deallocate_frame};
+        use ktest::{alive, frame_at, yield_until};
+        use task;
+
+        let before = memory::stats().free;
+        *THREAD_FRAMES.lock() = [0; CONCURRENT_FRAMES];
+
+        let pid = task::spawn("ktest_magazine", magazine_thread)
+            .map_err(|_| "could not spawn a thread")?;
+        let mut frames = [0; 2 * CONCURRENT_FRAMES];
+        allocate_per_tick(&mut frames[..CONCURRENT_FRAMES]);
+        let finished = yield_until(|| !alive(pid));
+        frames[CONCURRENT_FRAMES..].copy_from_slice(&*THREAD_FRAMES.lock());
+
+        let allocated = frames.iter().all(|&address| address != 0);
+        let distinct = frames
+            .iter()
+            .enumerate()
+            .all(|(i, address)| !frames[i + 1..].contains(address));
+
+        for &address in frames.iter().filter(|&&address| address != 0) {
+            deallocate_frame(frame_at(address));
+        }
+
+        if !finished || !allocated {
+            Err("a task could not allocate its frames")
+        } else if !distinct {
+            Err("the same frame was handed out twice")
+        } else if memory::stats().free != before {
+            Err("the free count changed once every frame was returned")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Allocating while the magazine is in use, as when the heap grows inside `with_current`, must
+    /// fall back to the global allocator rather than take a second reference to the magazine.
+    fn magazine_nested() -> Result<(), &'static str> {
+        use arch::memory::magazine::with_current;
+        use arch::memory::{allocate_frames, deallocate_frame};
+
+        let (inner, frame) = with_current(|_| (with_current(|_| ()).is_some(), allocate_frames(1)))
+            .ok_or("the bootstrap CPU has no magazine")?;
+        let allocated = frame.is_some();
+        if let Some(frame) = frame {
+            deallocate_frame(frame);
+        }
+
+        if inner {
+            Err("the magazine was handed out while in use")
+        } else if !allocated {
+            Err("allocating while the magazine was in use failed")
+        } else if with_current(|_| ()).is_none() {
+            Err("the magazine was left marked as in use")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Owner tags for physical frames, to find out which subsystem is holding on to frames when
+//! chasing a leak. The tags are only kept when the kernel is built with the `frame_owners`
+//! feature, since the table takes half a byte per frame. Without it tagging does nothing, and no
+//! frame has a known owner.
+
+use arch::memory::Frame;
+
+/// The subsystem a frame was allocated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameOwner {
+    Kernel,
+    Heap,
+    PageTable,
+    Dma,
+    Stack,
+    User,
+}
+
+/// The number of different owners.
+pub const OWNER_COUNT: usize = 6;
+
+/// Every owner, in the order `counts` reports them in.
+pub const OWNERS: [FrameOwner; OWNER_COUNT] = [
+    FrameOwner::Kernel,
+    FrameOwner::Heap,
+    FrameOwner::PageTable,
+    FrameOwner::Dma,
+    FrameOwner::Stack,
+    FrameOwner::User,
+];
+
+impl FrameOwner {
+    /// The tag stored for this owner. 0 means the frame has no owner.
+    #[cfg_attr(not(feature = "frame_owners"), allow(dead_code))]
+    fn tag(self) -> u8 {
+        OWNERS.iter().position(|&owner| owner == self).unwrap() as u8 + 1
+    }
+
+    #[cfg_attr(not(feature = "frame_owners"), allow(dead_code))]
+    fn from_tag(tag: u8) -> Option<FrameOwner> {
+        match tag {
+            0 => None,
+            tag => OWNERS.get(tag as usize - 1).cloned(),
+        }
+    }
+}
+
+#[cfg(feature = "frame_owners")]
+mod table {
+    use super::{FrameOwner, OWNER_COUNT};
+    use arch::memory::Frame;
+    use spin::Mutex;
+
+    /// The number of frames that can be tagged, enough for 4 GiB of physical memory.
+    const MAX_FRAMES: usize = 1024 * 1024;
+
+    /// Two 4-bit tags per byte.
+    static TAGS: Mutex<[u8; MAX_FRAMES / 2]> = Mutex::new([0; MAX_FRAMES / 2]);
+
+    pub fn set(frame: &Frame, owner: Option<FrameOwner>) {
+        if frame.number >= MAX_FRAMES {
+            return;
+        }
+
+        let tag = owner.map_or(0, FrameOwner::tag);
+        let shift = (frame.number % 2) * 4;
+        let mut tags = TAGS.lock();
+        let byte = &mut tags[frame.number / 2];
+        *byte = (*byte & !(0xf << shift)) | tag << shift;
+    }
+
+    pub fn get(frame: &Frame) -> Option<FrameOwner> {
+        if frame.number >= MAX_FRAMES {
+            return None;
+        }
+
+        let shift = (frame.number % 2) * 4;
+        FrameOwner::from_tag((TAGS.lock()[frame.number / 2] >> shift) & 0xf)
+    }
+
+    pub fn counts() -> [usize; OWNER_COUNT] {
+        let mut counts = [0; OWNER_COUNT];
+
+        for &byte in TAGS.lock().iter() {
+            for &tag in [byte & 0xf, byte >> 4].iter() {
+                if tag != 0 {
+                    counts[tag as usize - 1] += 1;
+                }
+            }
+        }
+
+        counts
+    }
+}
+
+#[cfg(not(feature = "frame_owners"))]
+mod table {
+    use super::{FrameOwner, OWNER_COUNT};
+    use arch::memory::Frame;
+
+    pub fn set(_frame: &Frame, _owner: Option<FrameOwner>) {}
+
+    pub fn get(_frame: &Frame) -> Option<FrameOwner> {
+        None
+    }
+
+    pub fn counts() -> [usize; OWNER_COUNT] {
+        [0; OWNER_COUNT]
+    }
+}
+
+/// Record that `frame` belongs to `owner`, or that it is free if `owner` is `None`.
+pub fn set(frame: &Frame, owner: Option<FrameOwner>) {
+    table::set(frame, owner)
+}
+
+/// Return the owner `frame` was tagged with, if any.
+pub fn get(frame: &Frame) -> Option<FrameOwner> {
+    table::get(frame)
+}
+
+/// Return the number of frames tagged with each owner, in the order of `OWNERS`.
+pub fn counts() -> [usize; OWNER_COUNT] {
+    table::counts()
+}
@@ -1,5 +1,6 @@
+use arch::cpuid::has_nx;
 use arch::memory::Frame;
-use multiboot2::ElfSection;
+use multiboot2::{ElfSection, ElfSectionFlags};
 use arch::memory::paging::PhysicalAddress;
 
 /// A page table entry.
@@ -32,11 +33,19 @@ impl Entry {
         }
     }
 
-    /// Set some flags on an entry.
+    /// Set some flags on an entry. `NO_EXECUTE` is dropped if the CPU does not support it.
     pub fn set(&mut self, frame: Frame, flags: EntryFlags) {
         assert!(frame.start_address().get() & !0x000fffff_fffff000 == 0);
+        let flags = flags.supported(has_nx());
         self.0 = (frame.start_address().get() as u64) | flags.bits();
     }
+
+    /// Set the flags in `add` and clear the flags in `remove`, leaving every other bit of the
+    /// entry (including the frame address) untouched. `NO_EXECUTE` is never added if the CPU does
+    /// not support it.
+    pub fn update_flags(&mut self, add: EntryFlags, remove: EntryFlags) {
+        self.0 = (self.0 | add.supported(has_nx()).bits()) & !remove.bits();
+    }
 }
 
 bitflags! {
@@ -61,29 +70,84 @@ bitflags! {
         /// This page's address will not be updated in the TLB,
         /// if CR3 is reset.
         const GLOBAL =          1 << 8;
+        /// Ignored by the CPU, available for use by the kernel.
+        const AVAILABLE_1 =     1 << 9;
+        /// Ignored by the CPU, available for use by the kernel.
+        const AVAILABLE_2 =     1 << 10;
+        /// Ignored by the CPU, available for use by the kernel.
+        const AVAILABLE_3 =     1 << 11;
         /// Non-executable page.
         const NO_EXECUTE =      1 << 63;
     }
 }
 
 impl EntryFlags {
+    /// Return these flags without `NO_EXECUTE` unless `nx` is set. On a CPU without NX the bit is
+    /// reserved, and an entry with it set faults on every access, so flags copied from another
+    /// mapping must go through this before they are written.
+    pub fn supported(self, nx: bool) -> EntryFlags {
+        if nx {
+            self
+        } else {
+            self - EntryFlags::NO_EXECUTE
+        }
+    }
+
     /// Parse the flags on an ELF section to our `EntryFlags` struct.
     pub fn from_elf_section_flags(section: &ElfSection) -> EntryFlags {
-        use multiboot2::ElfSectionFlags;
+        EntryFlags::from_elf_flags(section.flags())
+    }
 
+    /// Translate ELF section flags to `EntryFlags`. Only writable sections are mapped `WRITABLE`,
+    /// and every section that is not executable is mapped `NO_EXECUTE`, so `.text` comes out
+    /// executable but read-only and the data sections writable but not executable.
+    pub fn from_elf_flags(elf_flags: ElfSectionFlags) -> EntryFlags {
         let mut flags = EntryFlags::empty();
 
-        if section.flags().contains(ElfSectionFlags::ALLOCATED) {
+        if elf_flags.contains(ElfSectionFlags::ALLOCATED) {
             // section is loaded to memory
             flags = flags | EntryFlags::PRESENT;
         }
-        if section.flags().contains(ElfSectionFlags::WRITABLE) {
+        if elf_flags.contains(ElfSectionFlags::WRITABLE) {
             flags = flags | EntryFlags::WRITABLE;
         }
-        if !section.flags().contains(ElfSectionFlags::EXECUTABLE) {
+        if !elf_flags.contains(ElfSectionFlags::EXECUTABLE) {
             flags = flags | EntryFlags::NO_EXECUTE;
         }
 
         flags
     }
 }
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "elf_flags",
+            run: elf_flags,
+        },
+    ];
+
+    fn elf_flags() -> Result<(), &'static str> {
+        use arch::memory::paging::entry::EntryFlags;
+        use multiboot2::ElfSectionFlags;
+
+        // SHF_WRITE, SHF_ALLOC and SHF_EXECINSTR.
+        let section = |bits| EntryFlags::from_elf_flags(ElfSectionFlags::from_bits_truncate(bits));
+        let data = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+
+        if section(0x6) != EntryFlags::PRESENT {
+            Err(".text is not mapped executable and read-only")
+        } else if section(0x2) != EntryFlags::PRESENT | EntryFlags::NO_EXECUTE {
+            Err(".rodata is not mapped read-only and non-executable")
+        } else if section(0x3) != data {
+            Err(".data is not mapped writable and non-executable")
+        } else if section(0x0).contains(EntryFlags::PRESENT) {
+            Err("a section that is not allocated is mapped present")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -1,10 +1,24 @@
-use super::{ActivePageTable, Page, PhysicalAddress, VirtualAddress, ENTRY_COUNT};
-use super::entry::EntryFlags;
+use super::{ActivePageTable, Page, PageIter, PageSize, PhysicalAddress, VirtualAddress};
+use super::ENTRY_COUNT;
+use super::is_valid_physical;
+use arch::cpuid;
+use super::entry::{Entry, EntryFlags};
 use super::table::{self, Level4, Table};
-use arch::memory::{allocate_frames, Frame, PAGE_SIZE};
+use arch::memory::{deallocate_frame, try_allocate_frames, Frame, PAGE_SIZE};
 use core::ptr::Unique;
 use core::mem;
 
+/// The result of a detailed translation: where a virtual address points, and how it is mapped.
+#[derive(Debug)]
+pub struct Translation {
+    /// The physical address the virtual address translates to.
+    pub phys: PhysicalAddress,
+    /// The size of the page containing the virtual address.
+    pub size: PageSize,
+    /// The flags of the entry that maps the page.
+    pub flags: EntryFlags,
+}
+
 /// A helper struct which does most of the paging gruntwork.
 pub struct Mapper {
     p4: Unique<Table<Level4>>,
@@ -32,10 +46,89 @@ impl Mapper {
             .map(|frame| PhysicalAddress::new(frame.number * PAGE_SIZE + offset))
     }
 
+    /// Return the P4 table that maps `page`. With 5-level paging it is looked up in the P5 table,
+    /// otherwise it is the root table.
+    #[cfg(not(feature = "la57"))]
+    fn p4_for(&self, _page: Page) -> Option<&Table<Level4>> {
+        Some(self.p4())
+    }
+
+    #[cfg(feature = "la57")]
+    fn p4_for(&self, page: Page) -> Option<&Table<Level4>> {
+        if cpuid::five_level_paging() {
+            let p5 = unsafe { &*table::P5 };
+            p5.next_table(page.p5_index())
+        } else {
+            Some(self.p4())
+        }
+    }
+
+    /// Return a mutable reference to the P4 table that maps `page`.
+    #[cfg(not(feature = "la57"))]
+    fn p4_for_mut(&mut self, _page: Page) -> Option<&mut Table<Level4>> {
+        Some(self.p4_mut())
+    }
+
+    #[cfg(feature = "la57")]
+    fn p4_for_mut(&mut self, page: Page) -> Option<&mut Table<Level4>> {
+        if cpuid::five_level_paging() {
+            let p5 = unsafe { &mut *table::P5 };
+            p5.next_table_mut(page.p5_index())
+        } else {
+            Some(self.p4_mut())
+        }
+    }
+
+    /// Return the P4 table that maps `page`, creating it if it does not exist yet.
+    #[cfg(not(feature = "la57"))]
+    fn p4_for_create(&mut self, _page: Page) -> &mut Table<Level4> {
+        self.p4_mut()
+    }
+
+    #[cfg(feature = "la57")]
+    fn p4_for_create(&mut self, page: Page) -> &mut Table<Level4> {
+        if cpuid::five_level_paging() {
+            let p5 = unsafe { &mut *table::P5 };
+            p5.next_table_create(page.p5_index())
+        } else {
+            self.p4_mut()
+        }
+    }
+
+    /// Translate `virtual_address`, also reporting the size of the page it lies in and the flags
+    /// of the entry mapping it.
+    pub fn translate_detailed(&self, virtual_address: VirtualAddress) -> Option<Translation> {
+        let address = virtual_address.get();
+        let page = Page::containing_address(virtual_address);
+
+        let translation = |entry: &Entry, size: PageSize| {
+            entry.pointed_frame().map(|frame| Translation {
+                phys: PhysicalAddress::new(frame.start_address().get() + address % size.bytes()),
+                size: size,
+                flags: entry.flags(),
+            })
+        };
+
+        let p3 = self.p4_for(page)?.next_table(page.p4_index())?;
+        let p3_entry = &p3[page.p3_index()];
+        if p3_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+            return translation(p3_entry, PageSize::Size1GiB);
+        }
+
+        let p2 = p3.next_table(page.p3_index())?;
+        let p2_entry = &p2[page.p2_index()];
+        if p2_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+            return translation(p2_entry, PageSize::Size2MiB);
+        }
+
+        let p1 = p2.next_table(page.p2_index())?;
+        translation(&p1[page.p1_index()], PageSize::Size4KiB)
+    }
+
     /// Walk the page tables to find the physical frame that a passed `page` is mapped to.
     pub fn translate_page(&self, page: Page) -> Option<Frame> {
         // Get reference to the P3 table.
-        let p3 = self.p4().next_table(page.p4_index());
+        let p3 = self.p4_for(page).and_then(|p4| p4.next_table(page.p4_index()));
 
         // Check if this page is a huge page.
         let huge_page = || {
@@ -76,11 +169,26 @@ impl Mapper {
     }
 
     /// Map a page to a frame by getting reference to the page tables and setting the index in the
-    /// P1 table to the given frame.
+    /// P1 table to the given frame. A `USER_ACCESSIBLE` mapping also marks the tables on its path
+    /// `USER_ACCESSIBLE`, since ring 3 can only reach the page if every level allows it.
     pub fn map_to(&mut self, page: Page, frame: Frame, flags: EntryFlags) -> MapperFlush {
-        let p3 = self.p4_mut().next_table_create(page.p4_index());
-        let p2 = p3.next_table_create(page.p3_index());
-        let p1 = p2.next_table_create(page.p2_index());
+        assert!(
+            is_valid_physical(frame.start_address().get(), cpuid::phys_addr_bits()),
+            "{:?} is beyond the physical address width",
+            frame
+        );
+
+        let user = flags & EntryFlags::USER_ACCESSIBLE;
+        let p4 = self.p4_for_create(page);
+        p4.next_table_create(page.p4_index());
+        p4[page.p4_index()].update_flags(user, EntryFlags::empty());
+        let p3 = p4.next_table_mut(page.p4_index()).unwrap();
+        p3.next_table_create(page.p3_index());
+        p3[page.p3_index()].update_flags(user, EntryFlags::empty());
+        let p2 = p3.next_table_mut(page.p3_index()).unwrap();
+        p2.next_table_create(page.p2_index());
+        p2[page.p2_index()].update_flags(user, EntryFlags::empty());
+        let p1 = p2.next_table_mut(page.p2_index()).unwrap();
 
         assert!(p1[page.p1_index()].is_unused());
         p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
@@ -88,38 +196,365 @@ impl Mapper {
         MapperFlush::new(page)
     }
 
+    /// Map the `size`-sized page at `virt` to the physical memory at `phys`. Both addresses must be
+    /// aligned to `size`. Huge pages are mapped directly in the P2 or P3 table, so there must not
+    /// already be a table in that slot.
+    pub fn map_sized(
+        &mut self,
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        size: PageSize,
+        flags: EntryFlags,
+    ) -> MapperFlush {
+        assert!(
+            size.is_aligned(virt.get()),
+            "virtual address {:#x} is not aligned to {:?}",
+            virt.get(),
+            size
+        );
+        assert!(
+            size.is_aligned(phys.get()),
+            "physical address {:#x} is not aligned to {:?}",
+            phys.get(),
+            size
+        );
+
+        assert!(
+            is_valid_physical(phys.get() + size.bytes() - 1, cpuid::phys_addr_bits()),
+            "physical address {:#x} is beyond the physical address width",
+            phys.get()
+        );
+
+        let page = Page::containing_address(virt);
+        let frame = Frame::containing_address(phys);
+
+        match size {
+            PageSize::Size4KiB => self.map_to(page, frame, flags),
+            PageSize::Size2MiB => {
+                let p3 = self.p4_for_create(page).next_table_create(page.p4_index());
+                let p2 = p3.next_table_create(page.p3_index());
+
+                assert!(p2[page.p2_index()].is_unused());
+                p2[page.p2_index()].set(
+                    frame,
+                    flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE,
+                );
+
+                MapperFlush::new(page)
+            }
+            PageSize::Size1GiB => {
+                let p3 = self.p4_for_create(page).next_table_create(page.p4_index());
+
+                assert!(p3[page.p3_index()].is_unused());
+                p3[page.p3_index()].set(
+                    frame,
+                    flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE,
+                );
+
+                MapperFlush::new(page)
+            }
+        }
+    }
+
+    /// Map the `len` bytes of physical memory at `phys_start` to the same number of bytes at
+    /// `virt_start`, so that `phys_start + i` is reachable at `virt_start + i`. 2MiB pages are used
+    /// wherever both addresses are suitably aligned, and 4KiB pages elsewhere. `len` is rounded up
+    /// to a whole number of pages.
+    pub fn map_offset(
+        &mut self,
+        phys_start: PhysicalAddress,
+        virt_start: VirtualAddress,
+        len: usize,
+        flags: EntryFlags,
+    ) -> MapperFlushAll {
+        assert!(
+            phys_start.get() % PAGE_SIZE == 0 && virt_start.get() % PAGE_SIZE == 0,
+            "map_offset addresses must be page aligned"
+        );
+
+        let huge = PageSize::Size2MiB.bytes();
+        let len = (len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let mut flush_all = MapperFlushAll::new();
+        let mut offset = 0;
+
+        while offset < len {
+            let phys = PhysicalAddress::new(phys_start.get() + offset);
+            let virt = VirtualAddress::new(virt_start.get() + offset);
+
+            let aligned = phys.get() % huge == 0 && virt.get() % huge == 0;
+            let size = if aligned && len - offset >= huge {
+                PageSize::Size2MiB
+            } else {
+                PageSize::Size4KiB
+            };
+
+            flush_all.consume(self.map_sized(virt, phys, size, flags));
+            offset += size.bytes();
+        }
+
+        flush_all
+    }
+
     /// Map a page by allocating a free frame and mapping a page to that frame.
     pub fn map(&mut self, page: Page, flags: EntryFlags) -> MapperFlush {
-        let frame = allocate_frames(1).expect("out of memory");
+        let frame = try_allocate_frames(1).expect("out of memory");
         self.map_to(page, frame, flags)
     }
 
+    /// Map every page in `pages` to a newly allocated frame, with a single flush for the whole
+    /// range.
+    ///
+    /// # Panics
+    ///
+    /// Panics, naming the page, if the frames run out partway through, rather than leaving the
+    /// range half mapped without telling the caller.
+    pub fn map_range(&mut self, pages: PageIter, flags: EntryFlags) -> MapperFlushAll {
+        let mut flush_all = MapperFlushAll::new();
+
+        for page in pages {
+            let frame = match try_allocate_frames(1) {
+                Ok(frame) => frame,
+                Err(err) => panic!("out of memory mapping {:?}: {:?}", page, err),
+            };
+            flush_all.consume(self.map_to(page, frame, flags));
+        }
+
+        flush_all
+    }
+
     /// Map a page by translating a given `Frame` to a `Page`.
     pub fn identity_map(&mut self, frame: Frame, flags: EntryFlags) -> MapperFlush {
         let page = Page::containing_address(VirtualAddress::new(frame.start_address().get()));
         self.map_to(page, frame, flags)
     }
 
-    /// Unmap a page from a physical frame.
+    /// Set the flags in `add` and clear the flags in `remove` on the entry mapping `page`. Every
+    /// other bit, including the OS-available and accessed/dirty bits, is preserved. Prefer this
+    /// over remapping whenever only the flags of a mapping need to change.
+    pub fn update_flags(&mut self, page: Page, add: EntryFlags, remove: EntryFlags) -> MapperFlush {
+        let p1 = self.p4_for_mut(page)
+            .and_then(|p4| p4.next_table_mut(page.p4_index()))
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .and_then(|p2| p2.next_table_mut(page.p2_index()))
+            .expect("mapping code does not support huge pages");
+
+        assert!(
+            !p1[page.p1_index()].is_unused(),
+            "update_flags on unmapped page {:?}",
+            page
+        );
+        p1[page.p1_index()].update_flags(add, remove);
+
+        MapperFlush::new(page)
+    }
+
+    /// Replace the flags on the entry mapping `page` with `flags`, keeping the frame it points to.
+    /// Unlike `update_flags`, nothing of the old flags survives except `PRESENT`, which is always
+    /// set. This is the way to, say, make a range read-only or non-executable after it has been
+    /// filled in, without allocating a new frame.
+    pub fn remap(&mut self, page: Page, flags: EntryFlags) -> MapperFlush {
+        let p1 = self.p4_for_mut(page)
+            .and_then(|p4| p4.next_table_mut(page.p4_index()))
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .and_then(|p2| p2.next_table_mut(page.p2_index()))
+            .expect("mapping code does not support huge pages");
+
+        let frame = match p1[page.p1_index()].pointed_frame() {
+            Some(frame) => frame,
+            None => panic!("remap on unmapped page {:?}", page),
+        };
+        p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+
+        MapperFlush::new(page)
+    }
+
+    /// Unmap a page from a physical frame. The frame is left alone, so this is the one to use for
+    /// memory the caller does not own, such as identity mapped firmware tables. Page tables left
+    /// empty are freed.
     pub fn unmap(&mut self, page: Page) -> MapperFlush {
+        let (result, _frame) = self.unmap_and_free(page);
+        result
+    }
+
+    /// Unmap a page and return the frame that was backing it, so that the caller can hand it back
+    /// to the frame allocator. Any P1, P2 or P3 table left empty by the unmapping is freed, except
+    /// for the tables under the recursive P4 entry, which are the page tables themselves.
+    ///
+    /// If `page` is the start of a 2MiB or 1GiB mapping, the whole huge page is unmapped and the
+    /// first frame it covered is returned.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `page` is not mapped, or lies inside a huge page without being its first page.
+    pub fn unmap_and_free(&mut self, page: Page) -> (MapperFlush, Frame) {
         use x86_64;
         use x86_64::instructions::tlb;
 
         // Check if the page is already unmapped (page not mapped to frame, translation failed).
         assert!(self.translate(page.start_address()).is_some());
 
-        let p1 = self.p4_mut()
-            .next_table_mut(page.p4_index())
-            .and_then(|p3| p3.next_table_mut(page.p3_index()))
-            .and_then(|p2| p2.next_table_mut(page.p2_index()))
-            .expect("mapping code does not support huge pages");
-        let _frame = p1[page.p1_index()].pointed_frame().unwrap();
-        p1[page.p1_index()].set_unused();
+        let (frame, size) = self.clear_entry(page);
+        // invlpg drops the translation for the whole page containing the address, whatever its
+        // size, so this covers the entire range of a huge page.
         tlb::flush(x86_64::VirtualAddress(page.start_address().get()));
-        // TODO free p(1,2,3) table if empty
-        // allocator.deallocate_frame(frame);
-        MapperFlush::new(page)
+
+        if page.p4_index() != ENTRY_COUNT - 1 {
+            self.free_empty_tables(page, size);
+        }
+
+        (MapperFlush::new(page), frame)
     }
+
+    /// Clear the entry mapping `page`, which may be a huge page entry in the P2 or P3 table, and
+    /// return the frame it pointed to and the size of the page it mapped.
+    fn clear_entry(&mut self, page: Page) -> (Frame, PageSize) {
+        let p3 = self.p4_for_mut(page)
+            .and_then(|p4| p4.next_table_mut(page.p4_index()))
+            .expect("P3 table of a mapped page is missing");
+
+        if p3[page.p3_index()].flags().contains(EntryFlags::HUGE_PAGE) {
+            assert!(
+                page.p2_index() == 0 && page.p1_index() == 0,
+                "{:?} is inside a 1GiB page, unmap the whole of it",
+                page
+            );
+            let frame = p3[page.p3_index()].pointed_frame().unwrap();
+            p3[page.p3_index()].set_unused();
+            return (frame, PageSize::Size1GiB);
+        }
+
+        let p2 = p3.next_table_mut(page.p3_index())
+            .expect("P2 table of a mapped page is missing");
+
+        if p2[page.p2_index()].flags().contains(EntryFlags::HUGE_PAGE) {
+            assert!(
+                page.p1_index() == 0,
+                "{:?} is inside a 2MiB page, unmap the whole of it",
+                page
+            );
+            let frame = p2[page.p2_index()].pointed_frame().unwrap();
+            p2[page.p2_index()].set_unused();
+            return (frame, PageSize::Size2MiB);
+        }
+
+        let p1 = p2.next_table_mut(page.p2_index())
+            .expect("P1 table of a mapped page is missing");
+        let frame = p1[page.p1_index()].pointed_frame().unwrap();
+        p1[page.p1_index()].set_unused();
+
+        (frame, PageSize::Size4KiB)
+    }
+
+    /// Unmap a page and return its frame to the frame allocator. Every frame a huge page covered
+    /// is returned.
+    pub fn unmap_and_reclaim(&mut self, page: Page) -> MapperFlush {
+        let size = self.translate_detailed(page.start_address())
+            .map_or(PageSize::Size4KiB, |translation| translation.size);
+        let (result, first) = self.unmap_and_free(page);
+
+        let last = Frame {
+            number: first.number + size.bytes() / PAGE_SIZE - 1,
+        };
+        for frame in Frame::range_inclusive(first, last) {
+            deallocate_frame(frame);
+        }
+
+        result
+    }
+
+    /// Free the page tables on the path to `page`, from the bottom up, for as long as they are
+    /// empty. `size` is the size of the page that was unmapped, so the walk starts at the P1
+    /// table for a normal page, at the P2 table for a 2MiB page, and at the P3 table for a 1GiB
+    /// one. Each freed table is flushed from the TLB at its recursive address, so that a stale
+    /// translation cannot write to the frame once it has been reused.
+    fn free_empty_tables(&mut self, page: Page, size: PageSize) {
+        let p1_empty = size == PageSize::Size4KiB
+            && self.p4_for(page)
+                .and_then(|p4| p4.next_table(page.p4_index()))
+                .and_then(|p3| p3.next_table(page.p3_index()))
+                .and_then(|p2| p2.next_table(page.p2_index()))
+                .map_or(false, |p1| p1.is_empty());
+
+        let p2_empty = match size {
+            PageSize::Size4KiB if p1_empty => {
+                let p1_address = self.p4_for(page)
+                    .and_then(|p4| p4.next_table(page.p4_index()))
+                    .and_then(|p3| p3.next_table(page.p3_index()))
+                    .and_then(|p2| p2.next_table(page.p2_index()))
+                    .map(|p1| p1 as *const _ as usize)
+                    .unwrap();
+
+                let p2_empty = {
+                    let p2 = self.p4_for_mut(page)
+                        .and_then(|p4| p4.next_table_mut(page.p4_index()))
+                        .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                        .expect("P2 table of a mapped page disappeared");
+                    let frame = p2[page.p2_index()].pointed_frame().unwrap();
+                    p2[page.p2_index()].set_unused();
+                    deallocate_frame(frame);
+                    p2.is_empty()
+                };
+                flush_table(p1_address);
+                p2_empty
+            }
+            PageSize::Size4KiB => return,
+            PageSize::Size2MiB => self.p4_for(page)
+                .and_then(|p4| p4.next_table(page.p4_index()))
+                .and_then(|p3| p3.next_table(page.p3_index()))
+                .map_or(false, |p2| p2.is_empty()),
+            PageSize::Size1GiB => false,
+        };
+
+        let p3_empty = match size {
+            PageSize::Size1GiB => self.p4_for(page)
+                .and_then(|p4| p4.next_table(page.p4_index()))
+                .map_or(false, |p3| p3.is_empty()),
+            _ if p2_empty => {
+                let p2_address = self.p4_for(page)
+                    .and_then(|p4| p4.next_table(page.p4_index()))
+                    .and_then(|p3| p3.next_table(page.p3_index()))
+                    .map(|p2| p2 as *const _ as usize)
+                    .unwrap();
+
+                let p3_empty = {
+                    let p3 = self.p4_for_mut(page)
+                        .and_then(|p4| p4.next_table_mut(page.p4_index()))
+                        .expect("P3 table of a mapped page disappeared");
+                    let frame = p3[page.p3_index()].pointed_frame().unwrap();
+                    p3[page.p3_index()].set_unused();
+                    deallocate_frame(frame);
+                    p3.is_empty()
+                };
+                flush_table(p2_address);
+                p3_empty
+            }
+            _ => return,
+        };
+
+        if !p3_empty {
+            return;
+        }
+
+        let p3_address = self.p4_for(page)
+            .and_then(|p4| p4.next_table(page.p4_index()))
+            .map(|p3| p3 as *const _ as usize)
+            .unwrap();
+        {
+            let p4 = self.p4_for_mut(page).expect("P4 table of a mapped page disappeared");
+            let frame = p4[page.p4_index()].pointed_frame().unwrap();
+            p4[page.p4_index()].set_unused();
+            deallocate_frame(frame);
+        }
+        flush_table(p3_address);
+    }
+}
+
+/// Flush the recursive mapping of a page table that has just been freed.
+fn flush_table(address: usize) {
+    use x86_64;
+    use x86_64::instructions::tlb;
+
+    tlb::flush(x86_64::VirtualAddress(address));
 }
 
 /// A promise to flush a virtual address.
@@ -147,9 +582,30 @@ impl MapperFlush {
     }
 }
 
-/// A way to flush the entire active page table.
+/// The most pages `MapperFlushAll` flushes one by one. Past this, reloading CR3 is cheaper than
+/// that many `invlpg`s.
+pub const MAX_TRACKED_PAGES: usize = 16;
+
+/// How a `MapperFlushAll` is going to flush.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlushKind {
+    /// Nothing was consumed, so there is nothing to flush.
+    Nothing,
+    /// Flush this many pages one by one.
+    Pages(usize),
+    /// Flush the whole TLB.
+    All,
+}
+
+/// A way to flush a batch of changes to the active page table. The pages consumed are tracked, so
+/// that a small batch can be flushed page by page, and only a batch of more than
+/// `MAX_TRACKED_PAGES` flushes the entire TLB.
 #[must_use = "The active page table must be flushed, or the changes ignored"]
-pub struct MapperFlushAll(bool);
+pub struct MapperFlushAll {
+    pages: [Option<Page>; MAX_TRACKED_PAGES],
+    /// The number of flushes consumed, which may be more than the number of pages tracked.
+    count: usize,
+}
 
 impl Drop for MapperFlushAll {
     fn drop(&mut self) {
@@ -159,17 +615,38 @@ impl Drop for MapperFlushAll {
 
 impl MapperFlushAll {
     pub fn new() -> Self {
-        MapperFlushAll(false)
+        MapperFlushAll {
+            pages: [None; MAX_TRACKED_PAGES],
+            count: 0,
+        }
     }
 
     pub fn consume(&mut self, flush: MapperFlush) {
-        self.0 = true;
+        if self.count < MAX_TRACKED_PAGES {
+            self.pages[self.count] = Some(flush.0);
+        }
+        self.count += 1;
         mem::forget(flush);
     }
 
+    /// Return how `flush` will flush the consumed pages.
+    pub fn kind(&self) -> FlushKind {
+        match self.count {
+            0 => FlushKind::Nothing,
+            count if count <= MAX_TRACKED_PAGES => FlushKind::Pages(count),
+            _ => FlushKind::All,
+        }
+    }
+
     pub fn flush(self, table: &mut ActivePageTable) {
-        if self.0 {
-            unsafe { table.flush_all() };
+        match self.kind() {
+            FlushKind::Nothing => {}
+            FlushKind::Pages(count) => {
+                for page in self.pages[..count].iter().filter_map(|page| *page) {
+                    table.flush(page);
+                }
+            }
+            FlushKind::All => unsafe { table.flush_all() },
         }
 
         mem::forget(self);
@@ -179,3 +656,470 @@ impl MapperFlushAll {
         mem::forget(self);
     }
 }
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::{KTest, SCRATCH_ADDRESS};
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "remap",
+            run: remap,
+        },
+        KTest {
+            name: "flush_batch",
+            run: flush_batch,
+        },
+        KTest {
+            name: "unmap_huge",
+            run: unmap_huge,
+        },
+        KTest {
+            name: "no_nx",
+            run: no_nx,
+        },
+        KTest {
+            name: "map_sized",
+            run: map_sized,
+        },
+        KTest {
+            name: "update_flags",
+            run: update_flags,
+        },
+        KTest {
+            name: "map_offset",
+            run: map_offset,
+        },
+        KTest {
+            name: "free_tables",
+            run: free_tables,
+        },
+        KTest {
+            name: "huge_next_table",
+            run: huge_next_table,
+        },
+        KTest {
+            name: "translate_huge",
+            run: translate_huge,
+        },
+    ];
+
+    fn remap() -> Result<(), &'static str> {
+        use arch::cpuid::has_nx;
+        use arch::memory;
+        use arch::memory::paging::{Page, VirtualAddress};
+        use arch::memory::paging::entry::EntryFlags;
+
+        let address = SCRATCH_ADDRESS;
+        let page = Page::containing_address(VirtualAddress::new(address));
+        let expected = (EntryFlags::PRESENT | EntryFlags::NO_EXECUTE).supported(has_nx());
+
+        let mut active_table = memory::active_table();
+
+        let result = active_table.map(page, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
+        result.flush(&mut active_table);
+        let before = active_table.translate_detailed(VirtualAddress::new(address));
+
+        let result = active_table.remap(page, EntryFlags::NO_EXECUTE);
+        result.flush(&mut active_table);
+        let after = active_table.translate_detailed(VirtualAddress::new(address));
+
+        let result = active_table.unmap_and_reclaim(page);
+        result.flush(&mut active_table);
+
+        match (before, after) {
+            (Some(before), Some(after)) => if before.phys.get() != after.phys.get() {
+                Err("remap changed the frame")
+            } else if after.flags.contains(EntryFlags::WRITABLE) {
+                Err("remap kept the old flags")
+            } else if !after.flags.contains(expected) {
+                Err("remap did not set the new flags")
+            } else {
+                Ok(())
+            },
+            _ => Err("the page was not mapped"),
+        }
+    }
+
+    fn flush_batch() -> Result<(), &'static str> {
+        use arch::memory::paging::{Page, VirtualAddress};
+        use super::{FlushKind, MapperFlush, MapperFlushAll, MAX_TRACKED_PAGES};
+
+        let page = Page::containing_address(VirtualAddress::new(SCRATCH_ADDRESS));
+
+        let mut small = MapperFlushAll::new();
+        let empty = small.kind();
+        for i in 0..3 {
+            small.consume(MapperFlush::new(page + i));
+        }
+        let small_kind = small.kind();
+
+        let mut large = MapperFlushAll::new();
+        for i in 0..MAX_TRACKED_PAGES + 1 {
+            large.consume(MapperFlush::new(page + i));
+        }
+        let large_kind = large.kind();
+
+        // Nothing was changed, so there is nothing to flush.
+        unsafe {
+            small.forget();
+            large.forget();
+        }
+
+        if empty != FlushKind::Nothing {
+            Err("an empty batch would flush")
+        } else if small_kind != FlushKind::Pages(3) {
+            Err("a small batch would not flush page by page")
+        } else if large_kind != FlushKind::All {
+            Err("a large batch would not flush the whole TLB")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn unmap_huge() -> Result<(), &'static str> {
+        use arch::memory;
+        use arch::memory::paging::{Page, PageSize, PhysicalAddress, VirtualAddress};
+        use arch::memory::paging::entry::EntryFlags;
+
+        let address = SCRATCH_ADDRESS;
+
+        let mut active_table = memory::active_table();
+
+        // Map the first 2MiB of physical memory, which `unmap` will leave alone.
+        let result = active_table.map_sized(
+            VirtualAddress::new(address),
+            PhysicalAddress::new(0),
+            PageSize::Size2MiB,
+            EntryFlags::NO_EXECUTE,
+        );
+        result.flush(&mut active_table);
+
+        let mapped = active_table
+            .translate_detailed(VirtualAddress::new(address + 0x1000))
+            .map_or(false, |translation| translation.size == PageSize::Size2MiB);
+
+        let result = active_table.unmap(Page::containing_address(VirtualAddress::new(address)));
+        result.flush(&mut active_table);
+
+        let last = address + PageSize::Size2MiB.bytes() - 1;
+        if !mapped {
+            Err("the huge page was not mapped")
+        } else if active_table.translate(VirtualAddress::new(last)).is_some() {
+            Err("part of the huge page is still mapped")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn no_nx() -> Result<(), &'static str> {
+        use arch::cpuid::has_nx;
+        use arch::memory;
+        use arch::memory::paging::{Page, VirtualAddress};
+        use arch::memory::paging::entry::EntryFlags;
+
+        let address = SCRATCH_ADDRESS;
+        let page = Page::containing_address(VirtualAddress::new(address));
+
+        let mut active_table = memory::active_table();
+
+        let result = active_table.map(page, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
+        result.flush(&mut active_table);
+        let mapped = active_table.translate_detailed(VirtualAddress::new(address));
+
+        let result = active_table.unmap_and_reclaim(page);
+        result.flush(&mut active_table);
+
+        let flags = mapped.ok_or("the page was not mapped")?.flags;
+        // What cloning the mapping into another address space would write on a CPU without NX.
+        let cloned = flags.supported(false);
+
+        if flags.contains(EntryFlags::NO_EXECUTE) != has_nx() {
+            Err("NO_EXECUTE was not written exactly when the CPU supports it")
+        } else if cloned.contains(EntryFlags::NO_EXECUTE) {
+            Err("cloning for a CPU without NX kept NO_EXECUTE")
+        } else if cloned != flags - EntryFlags::NO_EXECUTE {
+            Err("cloning for a CPU without NX dropped other flags")
+        } else if flags.supported(true) != flags {
+            Err("cloning for a CPU with NX changed the flags")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn map_sized() -> Result<(), &'static str> {
+        use arch::memory::{self, PAGE_SIZE};
+        use arch::memory::paging::{Page, PageSize, PhysicalAddress, VirtualAddress};
+        use arch::memory::paging::entry::EntryFlags;
+
+        let sizes = [PageSize::Size4KiB, PageSize::Size2MiB, PageSize::Size1GiB];
+        let aligned = sizes.iter().all(|size| {
+            size.is_aligned(0) && size.is_aligned(size.bytes() * 3) && !size.is_aligned(0x800)
+                && !size.is_aligned(size.bytes() + PAGE_SIZE / 2)
+        });
+        let huge_only = !PageSize::Size2MiB.is_aligned(PAGE_SIZE)
+            && !PageSize::Size1GiB.is_aligned(PageSize::Size2MiB.bytes());
+
+        let mut active_table = memory::active_table();
+
+        // Map low physical memory, which `unmap` leaves alone. Not every CPU has 1GiB pages.
+        let mut mapped = [None; 2];
+        for (size, mapped) in sizes[..2].iter().zip(mapped.iter_mut()) {
+            let address = SCRATCH_ADDRESS + size.bytes();
+            let result = active_table.map_sized(
+                VirtualAddress::new(address),
+                PhysicalAddress::new(size.bytes()),
+                *size,
+                EntryFlags::NO_EXECUTE,
+            );
+            result.flush(&mut active_table);
+
+            *mapped = active_table
+                .translate_detailed(VirtualAddress::new(address + 0x123))
+                .map(|translation| (translation.size, translation.phys.get()));
+
+            let result = active_table.unmap(Page::containing_address(VirtualAddress::new(address)));
+            result.flush(&mut active_table);
+        }
+
+        if !aligned || !huge_only {
+            Err("an address was checked against the wrong alignment")
+        } else if mapped[0] != Some((PageSize::Size4KiB, PAGE_SIZE + 0x123)) {
+            Err("the 4KiB page was not mapped")
+        } else if mapped[1] != Some((PageSize::Size2MiB, PageSize::Size2MiB.bytes() + 0x123)) {
+            Err("the 2MiB page was not mapped")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn update_flags() -> Result<(), &'static str> {
+        use arch::memory;
+        use arch::memory::paging::{Page, VirtualAddress};
+        use arch::memory::paging::entry::EntryFlags;
+
+        let address = VirtualAddress::new(SCRATCH_ADDRESS);
+        let page = Page::containing_address(address);
+        // Standing in for copy-on-write, which would keep its mark in an OS-available bit.
+        let cow = EntryFlags::AVAILABLE_1;
+
+        let mut active_table = memory::active_table();
+
+        let result = active_table.map(page, cow | EntryFlags::NO_EXECUTE);
+        result.flush(&mut active_table);
+        let before = active_table.translate_detailed(address);
+
+        let result = active_table.update_flags(page, EntryFlags::WRITABLE, EntryFlags::empty());
+        result.flush(&mut active_table);
+        let writable = active_table.translate_detailed(address);
+
+        let result = active_table.update_flags(page, EntryFlags::empty(), EntryFlags::WRITABLE);
+        result.flush(&mut active_table);
+        let read_only = active_table.translate_detailed(address);
+
+        let result = active_table.unmap_and_reclaim(page);
+        result.flush(&mut active_table);
+
+        match (before, writable, read_only) {
+            (Some(before), Some(writable), Some(read_only)) => if !before.flags.contains(cow) {
+                Err("the OS-available bit was not mapped")
+            } else if writable.phys.get() != before.phys.get() {
+                Err("update_flags changed the frame")
+            } else if !writable.flags.contains(cow | EntryFlags::WRITABLE) {
+                Err("adding WRITABLE cleared the OS-available bit")
+            } else if read_only.flags & (cow | EntryFlags::WRITABLE) != cow {
+                Err("removing WRITABLE did not keep the OS-available bit")
+            } else {
+                Ok(())
+            },
+            _ => Err("update_flags unmapped the page"),
+        }
+    }
+
+    fn map_offset() -> Result<(), &'static str> {
+        use arch::memory;
+        use arch::memory::paging::{Page, PageSize, PhysicalAddress, VirtualAddress};
+        use arch::memory::paging::entry::EntryFlags;
+
+        // 4MiB of low physical memory, which `unmap` leaves alone. Both ends are one page off a
+        // 2MiB boundary, so the first and last pages must be small ones and the rest huge.
+        let phys = 0x3f_f000;
+        let virt = SCRATCH_ADDRESS + 0x3f_f000;
+        let len = 2 * PageSize::Size2MiB.bytes();
+
+        let mut active_table = memory::active_table();
+
+        let result = active_table.map_offset(
+            PhysicalAddress::new(phys),
+            VirtualAddress::new(virt),
+            len,
+            EntryFlags::NO_EXECUTE,
+        );
+        result.flush(&mut active_table);
+
+        let offsets = [0, 0x1234, 0x20_5678, len - 1];
+        let mut translations = [None; 4];
+        for (&offset, translation) in offsets.iter().zip(translations.iter_mut()) {
+            *translation = active_table
+                .translate_detailed(VirtualAddress::new(virt + offset))
+                .map(|translation| (translation.size, translation.phys.get()));
+        }
+
+        let mut offset = 0;
+        while offset < len {
+            let address = VirtualAddress::new(virt + offset);
+            let size = active_table
+                .translate_detailed(address)
+                .map_or(PageSize::Size4KiB, |translation| translation.size);
+            let result = active_table.unmap(Page::containing_address(address));
+            result.flush(&mut active_table);
+            offset += size.bytes();
+        }
+
+        let expected = [
+            Some((PageSize::Size4KiB, phys)),
+            Some((PageSize::Size2MiB, phys + 0x1234)),
+            Some((PageSize::Size4KiB, phys + 0x20_5678)),
+            Some((PageSize::Size4KiB, phys + len - 1)),
+        ];
+        if translations != expected {
+            Err("the region was not mapped at the offset with huge pages where aligned")
+        } else if active_table.translate(VirtualAddress::new(virt + 0x1000)).is_some() {
+            Err("the test mapping was not removed")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The scratch P4 slot is empty, so mapping a page there allocates a P3, P2 and P1 table, and
+    /// unmapping it must free all three along with the page.
+    fn free_tables() -> Result<(), &'static str> {
+        use arch::memory;
+        use arch::memory::paging::{Page, VirtualAddress};
+        use arch::memory::paging::entry::EntryFlags;
+
+        let page = Page::containing_address(VirtualAddress::new(SCRATCH_ADDRESS + 0x1234_5000));
+
+        let mut active_table = memory::active_table();
+
+        let slot_empty = active_table
+            .p4_for(page)
+            .map_or(true, |p4| p4[page.p4_index()].is_unused());
+        let before = memory::stats().free;
+
+        let result = active_table.map(page, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
+        result.flush(&mut active_table);
+        let tables = active_table
+            .p4_for(page)
+            .and_then(|p4| p4.next_table(page.p4_index()))
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .and_then(|p2| p2.next_table(page.p2_index()))
+            .is_some();
+        let during = memory::stats().free;
+
+        let result = active_table.unmap_and_reclaim(page);
+        result.flush(&mut active_table);
+
+        if !slot_empty {
+            Err("an earlier test left tables in the scratch P4 slot")
+        } else if !tables || during + 4 != before {
+            Err("mapping the page did not allocate the page and three tables")
+        } else if !active_table
+            .p4_for(page)
+            .map_or(true, |p4| p4[page.p4_index()].is_unused())
+        {
+            Err("the P3 table was not freed")
+        } else if memory::stats().free != before {
+            Err("the P1, P2 and P3 tables were not all freed")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn huge_next_table() -> Result<(), &'static str> {
+        use arch::memory;
+        use arch::memory::paging::{Page, PageSize, PhysicalAddress, VirtualAddress};
+        use arch::memory::paging::entry::EntryFlags;
+
+        let page = Page::containing_address(VirtualAddress::new(SCRATCH_ADDRESS));
+
+        let mut active_table = memory::active_table();
+
+        // Map the first 2MiB of physical memory, which `unmap` will leave alone.
+        let result = active_table.map_sized(
+            VirtualAddress::new(SCRATCH_ADDRESS),
+            PhysicalAddress::new(0),
+            PageSize::Size2MiB,
+            EntryFlags::NO_EXECUTE,
+        );
+        result.flush(&mut active_table);
+
+        let (huge, followed) = match active_table
+            .p4_for(page)
+            .and_then(|p4| p4.next_table(page.p4_index()))
+            .and_then(|p3| p3.next_table(page.p3_index()))
+        {
+            Some(p2) => (
+                p2[page.p2_index()].flags().contains(EntryFlags::HUGE_PAGE),
+                p2.next_table(page.p2_index()).is_some(),
+            ),
+            None => (false, false),
+        };
+        let followed_mut = active_table
+            .p4_for_mut(page)
+            .and_then(|p4| p4.next_table_mut(page.p4_index()))
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .map_or(false, |p2| p2.next_table_mut(page.p2_index()).is_some());
+
+        let result = active_table.unmap(page);
+        result.flush(&mut active_table);
+
+        if !huge {
+            Err("the huge page was not mapped")
+        } else if followed || followed_mut {
+            Err("a huge page entry was followed as a page table")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn translate_huge() -> Result<(), &'static str> {
+        use arch::cpuid::has_nx;
+        use arch::memory;
+        use arch::memory::paging::{Page, PageSize, PhysicalAddress, VirtualAddress};
+        use arch::memory::paging::entry::EntryFlags;
+
+        let mut active_table = memory::active_table();
+
+        // Map the second 2MiB of physical memory read-only, which `unmap` will leave alone.
+        let result = active_table.map_sized(
+            VirtualAddress::new(SCRATCH_ADDRESS),
+            PhysicalAddress::new(0x20_0000),
+            PageSize::Size2MiB,
+            EntryFlags::NO_EXECUTE,
+        );
+        result.flush(&mut active_table);
+        let translation =
+            active_table.translate_detailed(VirtualAddress::new(SCRATCH_ADDRESS + 0x1_2345));
+
+        let page = Page::containing_address(VirtualAddress::new(SCRATCH_ADDRESS));
+        let result = active_table.unmap(page);
+        result.flush(&mut active_table);
+
+        let translation = translation.ok_or("the huge page was not mapped")?;
+        let flags = translation.flags;
+        if translation.size != PageSize::Size2MiB {
+            Err("the mapping was not reported as a 2MiB page")
+        } else if translation.phys.get() != 0x21_2345 {
+            Err("the offset into the huge page was lost")
+        } else if !flags.contains(EntryFlags::PRESENT | EntryFlags::HUGE_PAGE) {
+            Err("the entry was not reported as a present huge page")
+        } else if flags.contains(EntryFlags::WRITABLE) {
+            Err("a read-only huge page was reported as writable")
+        } else if flags.contains(EntryFlags::NO_EXECUTE) != has_nx() {
+            Err("NO_EXECUTE was not reported")
+        } else {
+            Ok(())
+        }
+    }
+}
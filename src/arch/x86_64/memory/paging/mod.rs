@@ -1,14 +1,17 @@
 pub use self::entry::EntryFlags;
 pub use self::mapper::Mapper;
 use arch::memory::{Frame, PAGE_SIZE};
-use arch::memory::allocate_frames;
+use arch::memory::{deallocate_frame, try_allocate_frames, ALLOCATOR};
+use self::entry::Entry;
 use self::temporary_page::TemporaryPage;
+use self::table::{Level4, Table};
+use core::fmt;
 use core::ops::{Add, Deref, DerefMut};
 use multiboot2::BootInformation;
 
 pub mod entry;
 mod table;
-mod temporary_page;
+pub mod temporary_page;
 pub mod mapper;
 
 /// Maximum number of entries a page table can hold.
@@ -41,10 +44,77 @@ impl VirtualAddress {
     pub fn get(&self) -> usize {
         self.0
     }
+
+    /// Return this address as a pointer to a `T`.
+    pub fn as_ptr<T>(&self) -> *const T {
+        self.0 as *const T
+    }
+
+    /// Return this address as a mutable pointer to a `T`.
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.0 as *mut T
+    }
+
+    /// Return a reference to the `T` at this address.
+    ///
+    /// # Safety
+    ///
+    /// The address must be mapped, suitably aligned, and hold a valid `T` for as long as the
+    /// reference is used.
+    pub unsafe fn as_ref<'a, T>(&self) -> &'a T {
+        &*self.as_ptr()
+    }
+
+    /// Return a mutable reference to the `T` at this address.
+    ///
+    /// # Safety
+    ///
+    /// As for `as_ref`, and nothing else may access the `T` while the reference is used.
+    pub unsafe fn as_mut<'a, T>(&self) -> &'a mut T {
+        &mut *self.as_mut_ptr()
+    }
+}
+
+/// The page sizes supported by the mapper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    /// A regular page, mapped by a P1 entry.
+    Size4KiB,
+    /// A huge page, mapped by a P2 entry.
+    Size2MiB,
+    /// A huge page, mapped by a P3 entry. The CPU must support 1GiB pages.
+    Size1GiB,
+}
+
+impl PageSize {
+    /// Return the size of this page in bytes.
+    pub fn bytes(&self) -> usize {
+        match *self {
+            PageSize::Size4KiB => PAGE_SIZE,
+            PageSize::Size2MiB => PAGE_SIZE * ENTRY_COUNT,
+            PageSize::Size1GiB => PAGE_SIZE * ENTRY_COUNT * ENTRY_COUNT,
+        }
+    }
+    /// Check if `address` is aligned to this page size.
+    pub fn is_aligned(&self, address: usize) -> bool {
+        address % self.bytes() == 0
+    }
+}
+
+/// Check if `address` is canonical for `bits`-bit virtual addresses, i.e. every bit above the
+/// top implemented bit is a copy of it.
+pub fn is_canonical(address: usize, bits: u8) -> bool {
+    let top_half = !0usize << (bits - 1);
+    address & top_half == 0 || address & top_half == top_half
+}
+
+/// Check if `address` fits in `bits`-bit physical addresses.
+pub fn is_valid_physical(address: usize, bits: u8) -> bool {
+    bits >= 64 || address >> bits == 0
 }
 
 /// A 4KiB page.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Page {
     number: usize,
 }
@@ -53,7 +123,7 @@ impl Page {
     /// Return the number of the page which contains the given `VirtualAddress`.
     pub fn containing_address(address: VirtualAddress) -> Page {
         assert!(
-            address.get() < 0x0000_8000_0000_0000 || address.get() >= 0xffff_8000_0000_0000,
+            is_canonical(address.get(), ::arch::cpuid::virtual_address_bits()),
             "invalid address: 0x{:x}",
             address.get()
         );
@@ -67,6 +137,10 @@ impl Page {
         VirtualAddress::new(self.number * PAGE_SIZE)
     }
 
+    #[cfg(feature = "la57")]
+    fn p5_index(&self) -> usize {
+        (self.number >> 36) & 0o777
+    }
     fn p4_index(&self) -> usize {
         (self.number >> 27) & 0o777
     }
@@ -89,6 +163,27 @@ impl Page {
     }
 }
 
+impl fmt::Debug for Page {
+    /// Print the start address of the page along with its P4, P3, P2 and P1 indices.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Page({:#x} [{}, {}, {}, {}])",
+            self.start_address().get(),
+            self.p4_index(),
+            self.p3_index(),
+            self.p2_index(),
+            self.p1_index()
+        )
+    }
+}
+
+impl fmt::Display for Page {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
 impl Add<usize> for Page {
     type Output = Page;
 
@@ -208,6 +303,36 @@ impl ActivePageTable {
         old_table
     }
 
+    /// Translate the `len` bytes at `start`, checking that every page they span is mapped and that
+    /// the frames behind them are physically contiguous, as a device doing DMA needs. Returns the
+    /// physical address of `start`, or the start of the first page that is unmapped or not
+    /// contiguous with the ones before it.
+    pub fn translate_range(
+        &self,
+        start: VirtualAddress,
+        len: usize,
+    ) -> Result<PhysicalAddress, VirtualAddress> {
+        let phys_start = match self.translate(VirtualAddress::new(start.get())) {
+            Some(phys_start) => phys_start,
+            None => return Err(Page::containing_address(start).start_address()),
+        };
+
+        let first_page = Page::containing_address(VirtualAddress::new(start.get()));
+        let last_page = Page::containing_address(VirtualAddress::new(
+            start.get().saturating_add(len.max(1) - 1),
+        ));
+        let first_frame = phys_start.get() - start.get() % PAGE_SIZE;
+
+        for (i, page) in Page::range_inclusive(first_page, last_page).enumerate().skip(1) {
+            match self.translate_page(page) {
+                Some(ref frame) if frame.start_address().get() == first_frame + i * PAGE_SIZE => {}
+                _ => return Err(page.start_address()),
+            }
+        }
+
+        Ok(phys_start)
+    }
+
     pub fn flush(&mut self, page: Page) {
         unsafe { asm!("invlpg ($0)" :: "r"(page.start_address().get())) };
     }
@@ -219,6 +344,40 @@ impl ActivePageTable {
     }
 }
 
+/// Check that entry 511 of `p4` points back at `p4_frame` itself, which is what the recursive
+/// mapping relies on.
+fn recursive_entry_is_valid(p4: &Table<Level4>, p4_frame: &Frame) -> bool {
+    let entry = &p4[511];
+    let flags = entry.flags();
+
+    flags.contains(EntryFlags::PRESENT | EntryFlags::WRITABLE)
+        && !flags.contains(EntryFlags::HUGE_PAGE)
+        && entry.pointed_frame().map_or(false, |frame| frame == *p4_frame)
+}
+
+/// Read the active P4 table through the recursive mapping and check that entry 511 points at the
+/// frame in `cr3`. Logs the result and returns whether the check passed.
+pub fn check_recursive_mapping(active_table: &ActivePageTable) -> bool {
+    let p4_frame = Frame::containing_address(PhysicalAddress::new(active_table.address()));
+    let valid = recursive_entry_is_valid(active_table.p4(), &p4_frame);
+
+    if valid {
+        println!(
+            "[ vmm ] Recursive mapping OK, P4[511] points at {:#x}.",
+            p4_frame.start_address().get()
+        );
+    } else {
+        println!(
+            "[ vmm ] Recursive mapping BROKEN: P4[511] is {:?} {:?}, expected frame at {:#x}.",
+            active_table.p4()[511].pointed_frame(),
+            active_table.p4()[511].flags(),
+            p4_frame.start_address().get()
+        );
+    }
+
+    valid
+}
+
 /// A page table which has a frame wherein the P4 table lives.
 pub struct InactivePageTable {
     p4_frame: Frame,
@@ -239,58 +398,261 @@ impl InactivePageTable {
 
         InactivePageTable { p4_frame: frame }
     }
+
+    /// Make this the active address space, run `f`, and switch back to the one that was active
+    /// before. Writing CR3 flushes the TLB each way, so nothing from either address space leaks
+    /// into the other. Interrupts are disabled while `f` runs, so that nothing is scheduled under
+    /// the wrong address space.
+    ///
+    /// `f` is not given a mapper, and cannot borrow `active_table` while it is switched, so it has
+    /// no way to edit the tables it is running under. The table must map the kernel, including
+    /// the current stack, like the active one does.
+    pub fn switch_and_run<F, T>(&mut self, active_table: &mut ActivePageTable, f: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        use arch::interrupts::without_interrupts;
+
+        without_interrupts(|| {
+            let previous = active_table.switch(InactivePageTable {
+                p4_frame: self.p4_frame.clone(),
+            });
+
+            let result = f();
+
+            active_table.switch(previous);
+            result
+        })
+    }
+
+    /// Tear down the address space: unmap every user mapping, free the frames behind them and the
+    /// page tables that held them, and finally free the P4 table itself. A mapping belongs to user
+    /// space if it is `USER_ACCESSIBLE` at every level, since that is what ring 3 needs to reach
+    /// it. The kernel's tables are shared by every address space and are left alone. Frames with
+    /// extra references in `FRAME_REFCOUNTS` lose one instead of being freed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this is the active table.
+    pub fn teardown(
+        mut self,
+        active_table: &mut ActivePageTable,
+        temporary_page: &mut TemporaryPage,
+    ) -> TeardownStats {
+        assert!(
+            self.p4_frame.start_address().get() != active_table.address(),
+            "cannot tear down the active address space"
+        );
+
+        let mut stats = TeardownStats::default();
+        active_table.with(&mut self, temporary_page, |mapper| {
+            free_user_tables(mapper.p4_mut(), &mut stats);
+        });
+
+        deallocate_frame(self.p4_frame);
+        stats.tables += 1;
+
+        stats
+    }
+}
+
+/// What `InactivePageTable::teardown` released.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TeardownStats {
+    /// User frames handed back to the frame allocator.
+    pub frames: usize,
+    /// User frames that were shared, and only lost a reference.
+    pub shared: usize,
+    /// Page tables freed, including the P4 table.
+    pub tables: usize,
+}
+
+/// Check if `entry` is present and reachable from user space, as a page or a page table.
+fn is_user(entry: &Entry) -> bool {
+    entry.flags().contains(EntryFlags::PRESENT | EntryFlags::USER_ACCESSIBLE)
+}
+
+/// Check if `entry`, in a P2 or P3 table, maps a huge page rather than pointing to a table.
+fn is_huge(entry: &Entry) -> bool {
+    entry.flags().contains(EntryFlags::HUGE_PAGE)
+}
+
+/// Drop a mapping of the `count` frames from `first`, freeing each unless it is shared.
+fn release_frames(first: Frame, count: usize, stats: &mut TeardownStats) {
+    use arch::memory::refcount::FRAME_REFCOUNTS;
+
+    for number in first.number..first.number + count {
+        let frame = Frame { number: number };
+        let mut refcounts = FRAME_REFCOUNTS.lock();
+
+        if refcounts.get(&frame) > 0 {
+            refcounts.decrement(&frame);
+            stats.shared += 1;
+        } else {
+            deallocate_frame(frame);
+            stats.frames += 1;
+        }
+    }
+}
+
+/// Clear `entry`, which points to an empty page table, and free the table.
+fn free_table(entry: &mut Entry, stats: &mut TeardownStats) {
+    let frame = entry.pointed_frame().unwrap();
+    entry.set_unused();
+    deallocate_frame(frame);
+    stats.tables += 1;
+}
+
+/// Unmap every user page under `p4` and free the tables left empty, keeping the recursive entry.
+fn free_user_tables(p4: &mut Table<Level4>, stats: &mut TeardownStats) {
+    const PAGES_PER_P2_ENTRY: usize = ENTRY_COUNT;
+    const PAGES_PER_P3_ENTRY: usize = ENTRY_COUNT * ENTRY_COUNT;
+
+    for i4 in 0..ENTRY_COUNT - 1 {
+        if !is_user(&p4[i4]) {
+            continue;
+        }
+
+        let p3_empty = {
+            let p3 = p4.next_table_mut(i4).unwrap();
+            for i3 in 0..ENTRY_COUNT {
+                if !is_user(&p3[i3]) {
+                    continue;
+                } else if is_huge(&p3[i3]) {
+                    release_frames(p3[i3].pointed_frame().unwrap(), PAGES_PER_P3_ENTRY, stats);
+                    p3[i3].set_unused();
+                    continue;
+                }
+
+                let p2_empty = {
+                    let p2 = p3.next_table_mut(i3).unwrap();
+                    for i2 in 0..ENTRY_COUNT {
+                        if !is_user(&p2[i2]) {
+                            continue;
+                        } else if is_huge(&p2[i2]) {
+                            let frame = p2[i2].pointed_frame().unwrap();
+                            release_frames(frame, PAGES_PER_P2_ENTRY, stats);
+                            p2[i2].set_unused();
+                            continue;
+                        }
+
+                        let p1_empty = {
+                            let p1 = p2.next_table_mut(i2).unwrap();
+                            for i1 in 0..ENTRY_COUNT {
+                                if is_user(&p1[i1]) {
+                                    release_frames(p1[i1].pointed_frame().unwrap(), 1, stats);
+                                    p1[i1].set_unused();
+                                }
+                            }
+                            p1.is_empty()
+                        };
+                        if p1_empty {
+                            free_table(&mut p2[i2], stats);
+                        }
+                    }
+                    p2.is_empty()
+                };
+                if p2_empty {
+                    free_table(&mut p3[i3], stats);
+                }
+            }
+            p3.is_empty()
+        };
+        if p3_empty {
+            free_table(&mut p4[i4], stats);
+        }
+    }
+}
+
+extern "C" {
+    /// The top of the stack `boot.asm` starts the kernel on.
+    static stack_top: u8;
+    /// The size of that stack. This is an absolute symbol, so its address is its value.
+    static boot_stack_size: u8;
+}
+
+/// Return the bottom and top of the stack the BSP booted on, which `init` keeps running on.
+pub fn boot_stack() -> (usize, usize) {
+    let top = unsafe { &stack_top as *const u8 as usize };
+    let size = unsafe { &boot_stack_size as *const u8 as usize };
+    (top - size, top)
+}
+
+/// Return the guard page below the boot stack, which `init` unmaps.
+pub fn boot_stack_guard() -> Page {
+    let (bottom, _) = boot_stack();
+    Page::containing_address(VirtualAddress::new(bottom - PAGE_SIZE))
 }
 
 /// Identity map important sections and switch the page table, remapping the kernel one page above
 /// and turning the previous kernel stack into a guard page - this prevents silent stack overflows, as
 /// given that the guard page is unmapped, any stack overflow into this page will instantly cause a
-/// page fault. Returns the currently active kernel page table.
+/// page fault. The page right below the boot stack is unmapped as well, so that an overflow of the
+/// stack the kernel boots on faults before it writes anywhere else. Returns the currently active
+/// kernel page table.
 pub fn init(boot_info: &BootInformation) -> ActivePageTable {
     let mut temporary_page = TemporaryPage::new(Page { number: 0xcafebabe });
     let mut active_table = unsafe { ActivePageTable::new() };
     let mut new_table = {
         // Allocate a frame for the PML4.
-        let frame = allocate_frames(1).expect("out of memory");
+        let frame = try_allocate_frames(1).expect("out of memory");
         InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
     };
+    let bitmap_frames = match *ALLOCATOR.lock() {
+        Some(ref frame_allocator) => frame_allocator.bitmap_frames(),
+        None => panic!("Frame allocator called before init."),
+    };
 
     // Do important mapping work.
     active_table.with(&mut new_table, &mut temporary_page, |mapper| {
         println!("[ vmm ] Initialising paging.");
 
-        let elf_sections_tag = boot_info
-            .elf_sections_tag()
-            .expect("Memory map tag required");
-
         // identity map the entire kernel.
-        for section in elf_sections_tag.sections() {
-            if !section.is_allocated() {
-                // section is not loaded to memory
-                continue;
-            }
+        if let Some(elf_sections_tag) = boot_info.elf_sections_tag() {
+            for section in elf_sections_tag.sections() {
+                if !section.is_allocated() {
+                    // section is not loaded to memory
+                    continue;
+                }
 
-            assert!(
-                section.start_address() as usize % PAGE_SIZE == 0,
-                "sections need to be page aligned"
-            );
+                assert!(
+                    section.start_address() as usize % PAGE_SIZE == 0,
+                    "sections need to be page aligned"
+                );
+                println!(
+                    "[ vmm ] Identity mapping kernel section at addr: {:#x}, size: {} KiB",
+                    section.start_address(),
+                    section.size() / 1024,
+                );
+
+                // Translate ELF section flags to paging flags, and map the kernel sections
+                // into the virtual address space using these flags.
+                let flags = EntryFlags::from_elf_section_flags(&section);
+
+                let start_address = section.start_address() as usize;
+                let start_frame = Frame::containing_address(PhysicalAddress::new(start_address));
+                let end_frame = Frame::containing_address(PhysicalAddress::new(
+                    (section.end_address() - 1) as usize,
+                ));
+                for frame in Frame::range_inclusive(start_frame, end_frame) {
+                    let result = mapper.identity_map(frame, flags);
+                    // Ignore this result since this table is not currently active.
+                    unsafe { result.ignore() };
+                }
+            }
+        } else {
+            // Without the sections there is nothing to take the flags from, so the whole image is
+            // mapped writable and executable.
+            let (start, end) = ::arch::memory::kernel_extent_from_symbols();
             println!(
-                "[ vmm ] Identity mapping kernel section at addr: {:#x}, size: {} KiB",
-                section.start_address(),
-                section.size() / 1024,
+                "[ vmm ] No ELF sections tag, identity mapping the kernel at {:#x}-{:#x} as one.",
+                start, end
             );
 
-            // Translate ELF section flags to paging flags, and map the kernel sections
-            // into the virtual address space using these flags.
-            let flags = EntryFlags::from_elf_section_flags(&section);
-
-            let start_frame =
-                Frame::containing_address(PhysicalAddress::new(section.start_address() as usize));
-            let end_frame = Frame::containing_address(PhysicalAddress::new(
-                (section.end_address() - 1) as usize,
-            ));
+            let start_frame = Frame::containing_address(PhysicalAddress::new(start));
+            let end_frame = Frame::containing_address(PhysicalAddress::new(end - 1));
             for frame in Frame::range_inclusive(start_frame, end_frame) {
-                let result = mapper.identity_map(frame, flags);
-                // Ignore this result since this table is not currently active.
+                let result = mapper.identity_map(frame, EntryFlags::WRITABLE);
                 unsafe { result.ignore() };
             }
         }
@@ -311,6 +673,13 @@ pub fn init(boot_info: &BootInformation) -> ActivePageTable {
             let result = mapper.identity_map(frame, EntryFlags::PRESENT);
             unsafe { result.ignore() };
         }
+
+        // The frame allocator reaches its bitmaps at their physical addresses.
+        println!("[ vmm ] Identity mapping the frame bitmaps.");
+        for frame in bitmap_frames {
+            let result = mapper.identity_map(frame, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
+            unsafe { result.ignore() };
+        }
     });
 
     let old_table = active_table.switch(new_table);
@@ -319,6 +688,10 @@ pub fn init(boot_info: &BootInformation) -> ActivePageTable {
         active_table.address()
     );
 
+    if ::arch::args::get().has("checkpaging") {
+        check_recursive_mapping(&active_table);
+    }
+
     // Create a guard page.
     let old_p4_page = Page::containing_address(VirtualAddress::new(
         old_table.p4_frame.start_address().get(),
@@ -333,5 +706,351 @@ pub fn init(boot_info: &BootInformation) -> ActivePageTable {
         old_p4_page.start_address().get()
     );
 
+    // The boot stack has no guard page of its own, and the page below it holds the boot P2 table.
+    // Nothing uses that once the new table is active, so it can be unmapped.
+    let (bottom, top) = boot_stack();
+    let rsp: usize;
+    unsafe { asm!("mov $0, rsp" : "=r"(rsp) : : : "intel", "volatile") };
+    assert!(rsp > bottom && rsp <= top, "not running on the boot stack");
+
+    let boot_guard = boot_stack_guard();
+    let result = active_table.unmap(boot_guard);
+    result.flush(&mut active_table);
+
+    println!(
+        "[ vmm ] Boot stack at {:#x}-{:#x}, guard page at {:#x}.",
+        bottom,
+        top,
+        boot_guard.start_address().get()
+    );
+
     active_table
 }
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use alloc::Vec;
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "virtual_address",
+            run: virtual_address,
+        },
+        KTest {
+            name: "translate_range",
+            run: translate_range,
+        },
+        KTest {
+            name: "teardown",
+            run: teardown,
+        },
+        KTest {
+            name: "switch_and_run",
+            run: switch_and_run,
+        },
+        KTest {
+            name: "boot_stack_guard",
+            run: boot_stack_guard,
+        },
+        KTest {
+            name: "page_display",
+            run: page_display,
+        },
+        KTest {
+            name: "address_widths",
+            run: address_widths,
+        },
+        KTest {
+            name: "page_indices",
+            run: page_indices,
+        },
+    ];
+
+    fn virtual_address() -> Result<(), &'static str> {
+        use arch::memory::paging::VirtualAddress;
+
+        let mut value: u64 = 0x1122_3344_5566_7788;
+        let address = VirtualAddress::new(&mut value as *mut u64 as usize);
+
+        if address.as_ptr::<u64>() != &value as *const u64 {
+            return Err("as_ptr returned the wrong pointer");
+        }
+
+        unsafe {
+            if *address.as_ref::<u64>() != 0x1122_3344_5566_7788 {
+                return Err("as_ref read the wrong value");
+            }
+            *address.as_mut::<u64>() = 42;
+        }
+
+        if value == 42 {
+            Ok(())
+        } else {
+            Err("as_mut did not write through to the value")
+        }
+    }
+
+    fn translate_range() -> Result<(), &'static str> {
+        use arch::memory::{self, allocate_frames, deallocate_frame, PAGE_SIZE};
+        use arch::memory::paging::{Page, VirtualAddress};
+        use arch::memory::paging::entry::EntryFlags;
+        use ktest::{frame_at, SCRATCH_ADDRESS};
+
+        let address = SCRATCH_ADDRESS;
+        let page = Page::containing_address(VirtualAddress::new(address));
+        let frame = |number: usize, start: usize| frame_at(start + number * PAGE_SIZE);
+
+        let first = allocate_frames(3).ok_or("could not allocate three contiguous frames")?;
+        let start = first.start_address().get();
+
+        let mut active_table = memory::active_table();
+
+        // Skip the middle frame, so that the two pages are not physically contiguous.
+        let result = active_table.map_to(page, first, EntryFlags::NO_EXECUTE);
+        result.flush(&mut active_table);
+        let result = active_table.map_to(page + 1, frame(2, start), EntryFlags::NO_EXECUTE);
+        result.flush(&mut active_table);
+
+        let within = active_table.translate_range(VirtualAddress::new(address + 8), 16);
+        let across = active_table.translate_range(VirtualAddress::new(address + 8), PAGE_SIZE);
+        let unmapped =
+            active_table.translate_range(VirtualAddress::new(address + PAGE_SIZE), 2 * PAGE_SIZE);
+
+        for i in 0..2 {
+            let result = active_table.unmap(page + i);
+            result.flush(&mut active_table);
+        }
+        for i in 0..3 {
+            deallocate_frame(frame(i, start));
+        }
+
+        if within.map(|phys| phys.get()).ok() != Some(start + 8) {
+            Err("a range within one page did not translate")
+        } else if across.map_err(|virt| virt.get()).err() != Some(address + PAGE_SIZE) {
+            Err("did not report the discontiguous page")
+        } else if unmapped.map_err(|virt| virt.get()).err() != Some(address + 2 * PAGE_SIZE) {
+            Err("did not report the unmapped page")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn teardown() -> Result<(), &'static str> {
+        use arch::memory::{self, allocate_frames, Frame};
+        use arch::memory::paging::{Page, VirtualAddress};
+        use arch::memory::paging::entry::EntryFlags;
+        use arch::memory::refcount::FRAME_REFCOUNTS;
+        use ktest::{self, USER_ADDRESS};
+
+        // Three user pages sharing one P1 table.
+        const USER_PAGES: usize = 3;
+        let user_flags = EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE;
+
+        let mut active_table = memory::active_table();
+        let mut temporary_page = ktest::temporary_page();
+        let mut table = ktest::new_table(&mut active_table, &mut temporary_page)?;
+
+        // Share the kernel's identity mapped tables, as a process's address space would.
+        let kernel_p3 = active_table.p4()[0]
+            .pointed_frame()
+            .ok_or("the kernel has no P4 entry 0")?;
+        let kernel_flags = active_table.p4()[0].flags();
+        let shared = allocate_frames(1).ok_or("could not allocate a shared frame")?;
+        FRAME_REFCOUNTS.lock().increment(&shared);
+
+        active_table.with(&mut table, &mut temporary_page, |mapper| {
+            let kernel_p3 = Frame::containing_address(kernel_p3.start_address());
+            mapper.p4_mut()[0].set(kernel_p3, kernel_flags);
+
+            let first = Page::containing_address(VirtualAddress::new(USER_ADDRESS));
+            for i in 0..USER_PAGES {
+                unsafe { mapper.map(first + i, user_flags).ignore() };
+            }
+            let shared = Frame::containing_address(shared.start_address());
+            unsafe { mapper.map_to(first + USER_PAGES, shared, user_flags).ignore() };
+        });
+
+        let stats = table.teardown(&mut active_table, &mut temporary_page);
+
+        let still_shared = FRAME_REFCOUNTS.lock().get(&shared);
+        memory::deallocate_frame(shared);
+
+        if stats.frames != USER_PAGES {
+            Err("the user frames were not all freed")
+        } else if stats.shared != 1 || still_shared != 0 {
+            Err("the shared frame did not just lose a reference")
+        } else if stats.tables != 4 {
+            Err("the P3, P2 and P1 user tables and the P4 table were not all freed")
+        } else if active_table.p4()[0].pointed_frame() != Some(kernel_p3) {
+            Err("the kernel's tables did not survive")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn switch_and_run() -> Result<(), &'static str> {
+        use arch::memory::{self, Frame};
+        use arch::memory::paging::{Page, VirtualAddress};
+        use arch::memory::paging::entry::EntryFlags;
+        use core::ptr;
+        use ktest::{self, USER_ADDRESS};
+
+        // Only mapped in the new table.
+        const ADDRESS: usize = USER_ADDRESS;
+        const MAGIC: u64 = 0x5717_c4ed_a11d_ba5e;
+
+        let mut active_table = memory::active_table();
+        let mut temporary_page = ktest::temporary_page();
+        let mut table = ktest::new_table(&mut active_table, &mut temporary_page)?;
+
+        // Share every kernel mapping, so the code and stack are still there after the switch.
+        let kernel: Vec<_> = (0..511)
+            .filter_map(|i| {
+                let entry = &active_table.p4()[i];
+                entry.pointed_frame().map(|frame| (i, frame, entry.flags()))
+            })
+            .collect();
+        active_table.with(&mut table, &mut temporary_page, |mapper| {
+            for &(i, ref frame, flags) in kernel.iter() {
+                mapper.p4_mut()[i].set(Frame::containing_address(frame.start_address()), flags);
+            }
+
+            let page = Page::containing_address(VirtualAddress::new(ADDRESS));
+            let flags =
+                EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE | EntryFlags::NO_EXECUTE;
+            unsafe { mapper.map(page, flags).ignore() };
+        });
+
+        let cr3 = active_table.address();
+        let read = table.switch_and_run(&mut active_table, || unsafe {
+            ptr::write_volatile(ADDRESS as *mut u64, MAGIC);
+            ptr::read_volatile(ADDRESS as *const u64)
+        });
+        let restored = active_table.address() == cr3;
+        let leaked = active_table.translate(VirtualAddress::new(ADDRESS)).is_some();
+
+        table.teardown(&mut active_table, &mut temporary_page);
+
+        if read != MAGIC {
+            Err("the page mapped in the switched to table did not hold what was written")
+        } else if !restored {
+            Err("the previous table was not switched back to")
+        } else if leaked {
+            Err("the switched to table's mapping is visible from the active one")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn boot_stack_guard() -> Result<(), &'static str> {
+        use arch::memory::{self, PAGE_SIZE};
+        use arch::memory::paging::{self, VirtualAddress};
+
+        let (bottom, top) = paging::boot_stack();
+        let guard = paging::boot_stack_guard().start_address();
+        let rsp: usize;
+        unsafe { asm!("mov $0, rsp" : "=r"(rsp) : : : "intel", "volatile") };
+
+        let active_table = memory::active_table();
+
+        if top - bottom != 8 * PAGE_SIZE || bottom % PAGE_SIZE != 0 {
+            Err("the boot stack is not the 32 KiB reserved in boot.asm")
+        } else if rsp <= bottom || rsp > top {
+            Err("the tests are not running on the boot stack")
+        } else if guard.get() != bottom - PAGE_SIZE {
+            Err("the guard page is not right below the boot stack")
+        } else if active_table.translate(guard).is_some() {
+            Err("the guard page below the boot stack is mapped")
+        } else if active_table.translate(VirtualAddress::new(bottom)).is_none() {
+            Err("the bottom page of the boot stack is not mapped")
+        } else if memory::guarded_stack(VirtualAddress::new(guard.get() + 8)) != Some(bottom) {
+            Err("a fault on the guard page is not recognised as a boot stack overflow")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn page_display() -> Result<(), &'static str> {
+        use arch::memory::paging::{Page, VirtualAddress};
+        use ktest::frame_at;
+
+        // Indices 1, 2, 3 and 4 into the P4, P3, P2 and P1 tables.
+        let page = Page::containing_address(VirtualAddress::new(
+            1 << 39 | 2 << 30 | 3 << 21 | 4 << 12 | 0x123,
+        ));
+        let frame = frame_at(0x123_4567);
+
+        if format!("{}", page) != "Page(0x8080604000 [1, 2, 3, 4])" {
+            Err("the page is not shown with its address and table indices")
+        } else if format!("{}", frame) != "Frame(0x1234000 #4660)" {
+            Err("the frame is not shown with its address and number")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn address_widths() -> Result<(), &'static str> {
+        use arch::cpuid;
+        use arch::memory::paging::{is_canonical, is_valid_physical};
+
+        // The top frame a CPU reporting 52 physical address bits can address, and one past it.
+        let top_52 = (1 << 52) - 0x1000;
+        if !is_valid_physical(top_52, 52) || is_valid_physical(1 << 52, 52) {
+            return Err("a 52-bit physical address width was not applied");
+        }
+        if is_valid_physical(top_52, 46) || !is_valid_physical(!0, 64) {
+            return Err("a narrower or full physical address width was not applied");
+        }
+
+        let canonical_48 = is_canonical(0x0000_7fff_ffff_f000, 48)
+            && is_canonical(0xffff_8000_0000_0000, 48)
+            && !is_canonical(0x0000_8000_0000_0000, 48);
+        let canonical_57 = is_canonical(0x0000_8000_0000_0000, 57)
+            && is_canonical(0xff00_0000_0000_0000, 57)
+            && !is_canonical(0xfe00_0000_0000_0000, 57);
+        if !canonical_48 || !canonical_57 {
+            return Err("the canonical check did not follow the linear address width");
+        }
+
+        let phys = cpuid::phys_addr_bits();
+        let linear = cpuid::linear_addr_bits();
+        if phys < 32 || phys > 52 || linear < 48 || linear > 57 {
+            Err("the CPU reported implausible address widths")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The P5 index of `page`, or `None` without 5-level paging support.
+    #[cfg(feature = "la57")]
+    fn p5_index(page: &super::Page) -> Option<usize> {
+        Some(page.p5_index())
+    }
+
+    #[cfg(not(feature = "la57"))]
+    fn p5_index(_page: &super::Page) -> Option<usize> {
+        None
+    }
+
+    /// Split a high 57-bit address into its table indices. The page is built directly, since the
+    /// address is not canonical under 4-level paging.
+    fn page_indices() -> Result<(), &'static str> {
+        use arch::memory::PAGE_SIZE;
+        use super::Page;
+
+        // Indices 0x1a3, 0xf5, 0x12c, 0x77 and 0x1e0, with bit 56 copied into the bits above it.
+        let page = Page {
+            number: 0xffa3_7acb_0efe_0000 / PAGE_SIZE,
+        };
+
+        let indices = [page.p4_index(), page.p3_index(), page.p2_index(), page.p1_index()];
+        if indices != [0xf5, 0x12c, 0x77, 0x1e0] {
+            Err("the P4 to P1 indices were not taken from the right bits")
+        } else if cfg!(feature = "la57") && p5_index(&page) != Some(0x1a3) {
+            Err("the P5 index was not taken from bits 48 to 56")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -1,13 +1,17 @@
 use arch::memory::paging::entry::EntryFlags;
 use arch::memory::paging::entry::*;
 use arch::memory::paging::ENTRY_COUNT;
-use arch::memory::allocate_frames;
+use arch::memory::{allocate_frames_for, FrameOwner};
 use core::ops::{Index, IndexMut};
 use core::marker::PhantomData;
 
 /// This physical address will point to the highest-level P4 table.
 pub const P4: *mut Table<Level4> = 0xffffffff_fffff000 as *mut _;
 
+/// With 5-level paging, the same recursive address points to the P5 table instead.
+#[cfg(feature = "la57")]
+pub const P5: *mut Table<Level5> = 0xffffffff_fffff000 as *mut _;
+
 pub struct Table<L: TableLevel> {
     entries: [Entry; ENTRY_COUNT],
     level: PhantomData<L>,
@@ -23,6 +27,16 @@ where
             entry.set_unused();
         }
     }
+
+    /// Return the number of entries in use.
+    pub fn used_count(&self) -> usize {
+        self.entries.iter().filter(|entry| !entry.is_unused()).count()
+    }
+
+    /// Check if every entry of the page table is unused.
+    pub fn is_empty(&self) -> bool {
+        self.used_count() == 0
+    }
 }
 
 impl<L> Table<L>
@@ -30,7 +44,8 @@ where
     L: HierarchicalLevel,
 {
     /// Get the address of the next-lowest page table, using the passed index which should be the
-    /// index of the next page table in the current-level page table.
+    /// index of the next page table in the current-level page table. Returns `None` for entries
+    /// that are not present, and for huge page entries, which point to data rather than a table.
     fn next_table_address(&self, index: usize) -> Option<usize> {
         let entry_flags = self[index].flags();
         if entry_flags.contains(EntryFlags::PRESENT) && !entry_flags.contains(EntryFlags::HUGE_PAGE)
@@ -42,13 +57,15 @@ where
         }
     }
 
-    /// Return a reference to the next table.
+    /// Return a reference to the next table, or `None` if the entry is not present or maps a huge
+    /// page.
     pub fn next_table(&self, index: usize) -> Option<&Table<L::NextLevel>> {
         self.next_table_address(index)
             .map(|address| unsafe { &*(address as *const _) })
     }
 
-    /// Return a mutable reference to the next table.
+    /// Return a mutable reference to the next table, or `None` if the entry is not present or maps
+    /// a huge page.
     pub fn next_table_mut(&mut self, index: usize) -> Option<&mut Table<L::NextLevel>> {
         self.next_table_address(index)
             .map(|address| unsafe { &mut *(address as *mut _) })
@@ -60,7 +77,8 @@ where
                 !self.entries[index].flags().contains(EntryFlags::HUGE_PAGE),
                 "mapping code does not support huge pages"
             );
-            let frame = allocate_frames(1).expect("no frames available");
+            let frame =
+                allocate_frames_for(1, FrameOwner::PageTable).expect("no frames available");
             self.entries[index].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
             self.next_table_mut(index).unwrap().zero();
         }
@@ -90,6 +108,9 @@ where
 
 pub trait TableLevel {}
 
+#[cfg(feature = "la57")]
+pub enum Level5 {}
+
 pub enum Level4 {}
 #[allow(dead_code)]
 pub enum Level3 {}
@@ -97,6 +118,8 @@ pub enum Level3 {}
 pub enum Level2 {}
 pub enum Level1 {}
 
+#[cfg(feature = "la57")]
+impl TableLevel for Level5 {}
 impl TableLevel for Level4 {}
 impl TableLevel for Level3 {}
 impl TableLevel for Level2 {}
@@ -106,6 +129,11 @@ pub trait HierarchicalLevel: TableLevel {
     type NextLevel: TableLevel;
 }
 
+#[cfg(feature = "la57")]
+impl HierarchicalLevel for Level5 {
+    type NextLevel = Level4;
+}
+
 impl HierarchicalLevel for Level4 {
     type NextLevel = Level3;
 }
@@ -32,7 +32,7 @@ impl TemporaryPage {
         frame: Frame,
         active_table: &mut ActivePageTable,
     ) -> &mut Table<Level1> {
-        unsafe { &mut *(self.map(frame, active_table).get() as *mut Table<Level1>) }
+        unsafe { self.map(frame, active_table).as_mut::<Table<Level1>>() }
     }
 
     /// Unmaps the temporary page in the active table.
@@ -0,0 +1,97 @@
+//! Reference counts for physical frames that are mapped more than once, such as frames shared
+//! between address spaces. Frames that are not in the table have no extra references.
+
+use alloc::btree_map::BTreeMap;
+use arch::memory::Frame;
+use spin::Mutex;
+
+pub struct FrameRefcounts {
+    counts: BTreeMap<Frame, usize>,
+}
+
+impl FrameRefcounts {
+    pub fn new() -> FrameRefcounts {
+        FrameRefcounts {
+            counts: BTreeMap::new(),
+        }
+    }
+
+    /// Return the reference count of `frame`, which is 0 if it is not tracked.
+    pub fn get(&self, frame: &Frame) -> usize {
+        self.counts.get(frame).cloned().unwrap_or(0)
+    }
+
+    /// Add a reference to `frame`, returning the new count.
+    pub fn increment(&mut self, frame: &Frame) -> usize {
+        let count = self.counts.entry(frame.clone()).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    /// Drop a reference to `frame`, returning the new count. The frame is removed from the table
+    /// once its count reaches 0.
+    pub fn decrement(&mut self, frame: &Frame) -> usize {
+        let count = match self.counts.get_mut(frame) {
+            Some(count) => {
+                *count -= 1;
+                *count
+            }
+            None => return 0,
+        };
+
+        if count == 0 {
+            self.counts.remove(frame);
+        }
+
+        count
+    }
+
+    /// The number of frames with a non-zero reference count.
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+}
+
+lazy_static! {
+    /// The global frame reference count table.
+    pub static ref FRAME_REFCOUNTS: Mutex<FrameRefcounts> = Mutex::new(FrameRefcounts::new());
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "frame_refcounts",
+            run: frame_refcounts,
+        },
+    ];
+
+    fn frame_refcounts() -> Result<(), &'static str> {
+        use arch::memory::refcount::FrameRefcounts;
+        use ktest::frame_at;
+
+        // A table of its own, so the test does not disturb the frames tracked in the global one.
+        let mut refcounts = FrameRefcounts::new();
+        refcounts.increment(&frame_at(0x5000));
+        refcounts.increment(&frame_at(0x1000));
+        refcounts.increment(&frame_at(0x5000));
+
+        // Lookups go by frame number, not by which `Frame` value was inserted.
+        if refcounts.get(&frame_at(0x5fff)) != 2 || refcounts.get(&frame_at(0x1000)) != 1 {
+            return Err("a frame's count was not found");
+        }
+        if refcounts.get(&frame_at(0x3000)) != 0 || refcounts.len() != 2 {
+            return Err("a frame that was never added has a count");
+        }
+
+        let first = refcounts.decrement(&frame_at(0x1000));
+        let second = refcounts.decrement(&frame_at(0x5000));
+        if first != 0 || second != 1 || refcounts.len() != 1 {
+            Err("dropping references did not update the counts")
+        } else {
+            Ok(())
+        }
+    }
+}
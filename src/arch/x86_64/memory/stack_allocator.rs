@@ -1,21 +1,92 @@
-use arch::memory::paging::{ActivePageTable, Page, PageIter};
-use arch::memory::PAGE_SIZE;
+use arch::memory::paging::{ActivePageTable, Page, PageIter, VirtualAddress};
+use arch::memory::{allocate_frames_for, deallocate_frame, FrameOwner, PAGE_SIZE};
 use arch::memory::paging::EntryFlags;
+use core::ptr;
+
+/// The number of stacks whose guard pages the allocator remembers. Stacks allocated past this
+/// still get a guard page, but a fault on it is not recognised as an overflow.
+pub const MAX_GUARDED_STACKS: usize = 32;
+
+/// The number of freed page ranges the allocator remembers. The pages of a stack freed while this
+/// many ranges are already held are not handed out again.
+pub const MAX_FREE_RANGES: usize = 16;
+
+/// A run of pages given back by `dealloc_stack`, guard page included.
+#[derive(Debug, Clone, Copy)]
+struct FreeRange {
+    start: Page,
+    pages: usize,
+}
+
+/// The unmapped page below a stack, and the stack it guards.
+#[derive(Debug, Clone, Copy)]
+struct GuardPage {
+    page: Page,
+    stack_bottom: usize,
+}
 
 /// A stack allocator.
 #[derive(Copy, Clone)]
 pub struct StackAllocator {
     range: PageIter,
+    /// The guard pages of the stacks handed out so far. This is a fixed array rather than a `Vec`
+    /// so that it can be searched from the page fault handler without touching the heap.
+    guard_pages: [Option<GuardPage>; MAX_GUARDED_STACKS],
+    /// Pages from freed stacks, which `alloc_stack` uses before taking fresh pages from `range`.
+    free_ranges: [Option<FreeRange>; MAX_FREE_RANGES],
+    /// The number of stacks handed out and not yet freed.
+    allocated: usize,
+    /// The number of stacks freed with their canary clobbered.
+    overflows: usize,
 }
 
 impl StackAllocator {
     pub fn new(page_range: PageIter) -> StackAllocator {
-        StackAllocator { range: page_range }
+        StackAllocator {
+            range: page_range,
+            guard_pages: [None; MAX_GUARDED_STACKS],
+            free_ranges: [None; MAX_FREE_RANGES],
+            allocated: 0,
+            overflows: 0,
+        }
+    }
+
+    /// Return the number of stacks handed out and not yet freed.
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    /// Return the number of stacks that were freed with their canary clobbered.
+    pub fn overflows(&self) -> usize {
+        self.overflows
+    }
+
+    /// Check if `address` lies in the guard page of a stack this allocator handed out.
+    pub fn is_guard_page(&self, address: VirtualAddress) -> bool {
+        self.guarded_stack(address).is_some()
+    }
+
+    /// If `address` lies in the guard page of a stack this allocator handed out, return the
+    /// bottom of that stack. A fault there means the stack overflowed.
+    pub fn guarded_stack(&self, address: VirtualAddress) -> Option<usize> {
+        let page = Page::containing_address(address);
+
+        self.guard_pages
+            .iter()
+            .filter_map(|guard| *guard)
+            .find(|guard| guard.page == page)
+            .map(|guard| guard.stack_bottom)
     }
 }
 
 impl StackAllocator {
-    /// Allocate a range of pages to use as a stack.
+    /// Allocate a range of pages to use as a stack. The stack is mapped writable and not
+    /// executable.
+    ///
+    /// The page below the stack is its guard page, which is never given an entry in the page
+    /// tables at all, not even one without flags set. Any access to it, read or write, then
+    /// faults, so a stack that runs over its bottom stops at the guard page instead of quietly
+    /// writing into whatever lies below.
     pub fn alloc_stack(
         &mut self,
         active_table: &mut ActivePageTable,
@@ -25,40 +96,118 @@ impl StackAllocator {
             return None; /* a zero sized stack makes no sense */
         }
 
-        // clone the range, since we only want to change it on success
-        let mut range = self.range.clone();
+        let (guard, start) = match self.take_free_range(size_in_pages + 1) {
+            Some(guard) => (guard, guard + 1),
+            None => {
+                // clone the range, since we only want to change it on success
+                let mut range = self.range.clone();
 
-        // try to allocate the stack pages and a guard page
-        let guard_page = range.next();
-        let stack_start = range.next();
-        let stack_end = if size_in_pages == 1 {
-            stack_start
-        } else {
-            // choose the (size_in_pages-2)th element, since index
-            // starts at 0 and we already allocated the start page
-            range.nth(size_in_pages - 2)
+                // try to allocate the stack pages and a guard page
+                let guard_page = range.next();
+                let stack_start = range.next();
+                let stack_end = if size_in_pages == 1 {
+                    stack_start
+                } else {
+                    // choose the (size_in_pages-2)th element, since index
+                    // starts at 0 and we already allocated the start page
+                    range.nth(size_in_pages - 2)
+                };
+
+                match (guard_page, stack_start, stack_end) {
+                    (Some(guard), Some(start), Some(_)) => {
+                        // success! write back updated range
+                        self.range = range;
+                        (guard, start)
+                    }
+                    _ => return None, /* not enough pages */
+                }
+            }
         };
+        let end = start + (size_in_pages - 1);
 
-        match (guard_page, stack_start, stack_end) {
-            (Some(_), Some(start), Some(end)) => {
-                // success! write back updated range
-                self.range = range;
+        if let Some(slot) = self.guard_pages.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(GuardPage {
+                page: guard,
+                stack_bottom: start.start_address().get(),
+            });
+        }
 
-                // map stack pages to physical frames
-                for page in Page::range_inclusive(start, end) {
-                    let result = active_table.map(page, EntryFlags::PRESENT);
-                    result.flush(active_table);
-                }
+        // map stack pages to physical frames
+        for page in Page::range_inclusive(start, end) {
+            let frame = allocate_frames_for(1, FrameOwner::Stack).expect("out of memory");
+            let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+            let result = active_table.map_to(page, frame, flags);
+            result.flush(active_table);
+        }
 
-                // create a new stack
-                let top_of_stack = end.start_address().get() + PAGE_SIZE;
-                Some(Stack::new(top_of_stack, start.start_address().get()))
-            }
-            _ => None, /* not enough pages */
+        self.allocated += 1;
+
+        // create a new stack
+        let top_of_stack = end.start_address().get() + PAGE_SIZE;
+        let stack = Stack::new(top_of_stack, start.start_address().get());
+        stack.install_canary();
+        Some(stack)
+    }
+
+    /// Unmap `stack`, give its frames back to the frame allocator, and keep its pages, along with
+    /// its guard page, for a later `alloc_stack`. `stack` must have come from this allocator.
+    ///
+    /// A stack whose canary has been clobbered overflowed at some point without reaching its guard
+    /// page. It is reported and counted, and freed all the same.
+    pub fn dealloc_stack(&mut self, active_table: &mut ActivePageTable, stack: Stack) {
+        if !stack.check_canary() {
+            self.overflows += 1;
+            println!("[ ERR ] Stack at {:#x} overflowed: canary clobbered", stack.bottom());
         }
+
+        let start = Page::containing_address(VirtualAddress::new(stack.bottom()));
+        let pages = (stack.top() - stack.bottom()) / PAGE_SIZE;
+
+        for page in Page::range_inclusive(start, start + (pages - 1)) {
+            let (result, frame) = active_table.unmap_and_free(page);
+            result.flush(active_table);
+            deallocate_frame(frame);
+        }
+        self.allocated -= 1;
+
+        let guard_page = self.guard_pages
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |guard| guard.stack_bottom == stack.bottom()));
+        if let Some(slot) = guard_page {
+            *slot = None;
+        }
+
+        if let Some(slot) = self.free_ranges.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(FreeRange {
+                start: Page::containing_address(VirtualAddress::new(stack.bottom() - PAGE_SIZE)),
+                pages: pages + 1,
+            });
+        }
+    }
+
+    /// Take `pages` pages from the smallest freed range that has enough, and return the first.
+    fn take_free_range(&mut self, pages: usize) -> Option<Page> {
+        let slot = self.free_ranges
+            .iter_mut()
+            .filter(|slot| slot.map_or(false, |range| range.pages >= pages))
+            .min_by_key(|slot| slot.map_or(0, |range| range.pages))?;
+
+        let range = slot.take()?;
+        if range.pages > pages {
+            *slot = Some(FreeRange {
+                start: range.start + pages,
+                pages: range.pages - pages,
+            });
+        }
+
+        Some(range.start)
     }
 }
 
+/// Magic value written to the lowest word of a stack. If it has changed, something overflowed the
+/// stack.
+pub const STACK_CANARY: usize = 0xdead_beef_cafe_babe;
+
 /// A stack that grows downwards.
 #[derive(Debug)]
 pub struct Stack {
@@ -79,8 +228,164 @@ impl Stack {
         self.top
     }
 
-    #[allow(dead_code)]
     pub fn bottom(&self) -> usize {
         self.bottom
     }
+
+    /// Write the canary value to the bottom of the stack. This is a cheaper alternative to a guard
+    /// page, but overflows are only detected when `check_canary` is called.
+    pub fn install_canary(&self) {
+        unsafe { ptr::write_volatile(self.bottom as *mut usize, STACK_CANARY) };
+    }
+
+    /// Check that the canary at the bottom of the stack is intact.
+    pub fn check_canary(&self) -> bool {
+        unsafe { ptr::read_volatile(self.bottom as *const usize) == STACK_CANARY }
+    }
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "stack_guard",
+            run: stack_guard,
+        },
+        KTest {
+            name: "stack_dealloc",
+            run: stack_dealloc,
+        },
+        KTest {
+            name: "stack_sentinel",
+            run: stack_sentinel,
+        },
+        KTest {
+            name: "stack_canary",
+            run: stack_canary,
+        },
+    ];
+
+    fn stack_guard() -> Result<(), &'static str> {
+        use arch::memory::{self, PAGE_SIZE};
+        use arch::memory::paging::VirtualAddress;
+
+        let stack = memory::with_controller(|controller| controller.alloc_stack(1))
+            .ok_or("could not allocate a stack")?;
+        let bottom = stack.bottom();
+
+        let result = if memory::guarded_stack(VirtualAddress::new(bottom - 8)) != Some(bottom) {
+            Err("the page below the stack is not its guard page")
+        } else if memory::guarded_stack(VirtualAddress::new(bottom - PAGE_SIZE - 8)).is_some() {
+            Err("the page below the guard page is a guard page too")
+        } else if memory::guarded_stack(VirtualAddress::new(bottom)).is_some() {
+            Err("the stack itself is a guard page")
+        } else {
+            Ok(())
+        };
+
+        memory::with_controller(|controller| controller.dealloc_stack(stack));
+
+        if result.is_ok() && memory::guarded_stack(VirtualAddress::new(bottom - 8)).is_some() {
+            return Err("the guard page of a freed stack is still recorded");
+        }
+        result
+    }
+
+    fn stack_dealloc() -> Result<(), &'static str> {
+        use arch::memory::{self, PAGE_SIZE};
+        use arch::memory::paging::VirtualAddress;
+
+        let stack = memory::with_controller(|controller| controller.alloc_stack(3))
+            .ok_or("could not allocate a stack")?;
+        let bottom = stack.bottom();
+        let free_before = memory::stats().free;
+
+        memory::with_controller(|controller| controller.dealloc_stack(stack));
+
+        // Page tables left empty are freed too, so there may be more.
+        if memory::stats().free < free_before + 3 {
+            return Err("the frames of the stack were not freed");
+        }
+        if memory::active_table().translate(VirtualAddress::new(bottom)).is_some() {
+            return Err("the stack is still mapped");
+        }
+
+        // A smaller stack fits in the freed pages, and the rest stays free for the next one.
+        let first = memory::with_controller(|controller| controller.alloc_stack(1))
+            .ok_or("could not allocate a stack in the freed pages")?;
+        let second = memory::with_controller(|controller| controller.alloc_stack(1))
+            .ok_or("could not allocate a second stack in the freed pages")?;
+        let reused = first.bottom() == bottom && second.bottom() == bottom + 2 * PAGE_SIZE;
+
+        memory::with_controller(|controller| {
+            controller.dealloc_stack(first);
+            controller.dealloc_stack(second);
+        });
+
+        if reused {
+            Ok(())
+        } else {
+            Err("the freed pages were not reused")
+        }
+    }
+
+    fn stack_sentinel() -> Result<(), &'static str> {
+        use arch::memory;
+        use core::ptr;
+
+        const SENTINEL: usize = 0x5e47_1e1e_5e47_1e1e;
+
+        let stack = memory::with_controller(|controller| controller.alloc_stack(1))
+            .ok_or("could not allocate a stack")?;
+
+        // The first push onto the stack lands here. This faults if the stack is read-only.
+        let slot = (stack.top() - 8) as *mut usize;
+        let value = unsafe {
+            ptr::write_volatile(slot, SENTINEL);
+            ptr::read_volatile(slot)
+        };
+
+        memory::with_controller(|controller| controller.dealloc_stack(stack));
+
+        if value == SENTINEL {
+            Ok(())
+        } else {
+            Err("the sentinel did not read back from the stack")
+        }
+    }
+
+    fn stack_canary() -> Result<(), &'static str> {
+        use arch::memory;
+        use core::ptr;
+
+        let overflows = || memory::with_controller(|controller| controller.stack_overflows());
+        let before = overflows();
+
+        let intact = memory::with_controller(|controller| controller.alloc_stack(1))
+            .ok_or("could not allocate a stack")?;
+        let installed = intact.check_canary();
+        memory::with_controller(|controller| controller.dealloc_stack(intact));
+        let after_intact = overflows();
+
+        let clobbered = memory::with_controller(|controller| controller.alloc_stack(1))
+            .ok_or("could not allocate a stack")?;
+        unsafe { ptr::write_volatile(clobbered.bottom() as *mut usize, 0) };
+        let detected = !clobbered.check_canary();
+        memory::with_controller(|controller| controller.dealloc_stack(clobbered));
+        let after_clobbered = overflows();
+
+        if !installed {
+            Err("a new stack has no canary")
+        } else if after_intact != before {
+            Err("a stack with an intact canary was counted as overflowed")
+        } else if !detected {
+            Err("a clobbered canary was not detected")
+        } else if after_clobbered != before + 1 {
+            Err("freeing a stack with a clobbered canary was not counted")
+        } else {
+            Ok(())
+        }
+    }
 }
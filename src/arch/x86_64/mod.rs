@@ -3,5 +3,102 @@
 pub mod interrupts;
 pub mod memory;
 pub mod init;
+pub mod args;
+pub mod multiboot;
+pub mod cpuid;
+pub mod percpu;
+pub mod backtrace;
 
 pub use self::init::init;
+
+use arch::{Arch, MapFlags};
+use core::ptr;
+
+/// `Arch` for AMD64.
+pub struct X86_64;
+
+/// The architecture the kernel is running on.
+pub static ARCH: X86_64 = X86_64;
+
+impl Arch for X86_64 {
+    fn page_size(&self) -> usize {
+        memory::PAGE_SIZE
+    }
+
+    fn map(&self, virt: usize, phys: usize, flags: MapFlags) -> Result<(), &'static str> {
+        use self::memory::Frame;
+        use self::memory::paging::{Page, PhysicalAddress, VirtualAddress};
+        use self::memory::paging::entry::EntryFlags;
+
+        let mut entry_flags = EntryFlags::PRESENT;
+        if flags.contains(MapFlags::WRITABLE) {
+            entry_flags |= EntryFlags::WRITABLE;
+        }
+        if !flags.contains(MapFlags::EXECUTABLE) {
+            entry_flags |= EntryFlags::NO_EXECUTE;
+        }
+        if flags.contains(MapFlags::NO_CACHE) {
+            entry_flags |= EntryFlags::NO_CACHE;
+        }
+
+        let mut active_table = memory::active_table();
+        let page = Page::containing_address(VirtualAddress::new(virt));
+        if active_table.translate_page(page).is_some() {
+            return Err("page is already mapped");
+        }
+
+        let frame = Frame::containing_address(PhysicalAddress::new(phys));
+        let result = active_table.map_to(page, frame, entry_flags);
+        result.flush(&mut active_table);
+
+        Ok(())
+    }
+
+    fn unmap(&self, virt: usize) -> Option<usize> {
+        use self::memory::paging::{Page, VirtualAddress};
+
+        let mut active_table = memory::active_table();
+        let phys = active_table.translate(VirtualAddress::new(virt))?.get();
+
+        let result = active_table.unmap(Page::containing_address(VirtualAddress::new(virt)));
+        result.flush(&mut active_table);
+
+        Some(phys)
+    }
+
+    fn translate(&self, virt: usize) -> Option<usize> {
+        use self::memory::paging::VirtualAddress;
+
+        memory::active_table()
+            .translate(VirtualAddress::new(virt))
+            .map(|phys| phys.get())
+    }
+
+    fn disable_interrupts(&self) -> bool {
+        interrupts::disable_interrupts()
+    }
+
+    fn restore_interrupts(&self, enabled: bool) {
+        interrupts::restore_interrupts(enabled)
+    }
+
+    fn halt(&self) {
+        unsafe { asm!("sti; hlt" :::: "volatile") };
+    }
+
+    unsafe fn read_port(&self, port: u16) -> u8 {
+        ::device::Port::<u8>::new(port).read()
+    }
+
+    unsafe fn write_port(&self, port: u16, value: u8) {
+        ::device::Port::<u8>::new(port).write(value)
+    }
+
+    unsafe fn read_mmio(&self, address: usize) -> u32 {
+        ptr::read_volatile(address as *const u32)
+    }
+
+    unsafe fn write_mmio(&self, address: usize, value: u32) {
+        ptr::write_volatile(address as *mut u32, value)
+    }
+}
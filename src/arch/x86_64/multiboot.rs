@@ -0,0 +1,270 @@
+//! Raw access to the multiboot2 information structure. The `multiboot2` crate only exposes a
+//! handful of tags, so this walks the tag list directly for everything else (command line, full
+//! memory map, RSDP copies).
+
+use multiboot2::BootInformation;
+use core::{slice, str};
+
+/// Tag type of the boot command line.
+pub const TAG_COMMAND_LINE: u32 = 1;
+/// Tag type of the memory map.
+pub const TAG_MEMORY_MAP: u32 = 6;
+/// Tag type of the ACPI 1.0 RSDP copy.
+pub const TAG_RSDP_V1: u32 = 14;
+/// Tag type of the ACPI 2.0+ RSDP copy.
+pub const TAG_RSDP_V2: u32 = 15;
+
+/// A single tag in the multiboot information structure.
+#[derive(Debug, Clone, Copy)]
+pub struct Tag {
+    /// The type of this tag.
+    pub typ: u32,
+    /// The size of this tag in bytes, including the 8 byte header.
+    pub size: u32,
+    /// The address of the tag header.
+    pub address: usize,
+}
+
+impl Tag {
+    /// Return the address of the data following the tag header.
+    pub fn data_address(&self) -> usize {
+        self.address + 8
+    }
+
+    /// Return the length of the data following the tag header.
+    pub fn data_len(&self) -> usize {
+        (self.size as usize).saturating_sub(8)
+    }
+
+    /// Return the data following the tag header as a byte slice.
+    pub unsafe fn data(&self) -> &'static [u8] {
+        slice::from_raw_parts(self.data_address() as *const u8, self.data_len())
+    }
+}
+
+/// An iterator over all the tags in the multiboot information structure.
+pub struct TagIter {
+    current: usize,
+    end: usize,
+}
+
+impl Iterator for TagIter {
+    type Item = Tag;
+
+    fn next(&mut self) -> Option<Tag> {
+        if self.current + 8 > self.end {
+            return None;
+        }
+
+        let typ = unsafe { *(self.current as *const u32) };
+        let size = unsafe { *((self.current + 4) as *const u32) };
+
+        // Type 0 marks the end of the tag list.
+        if typ == 0 || size < 8 {
+            return None;
+        }
+
+        let tag = Tag {
+            typ: typ,
+            size: size,
+            address: self.current,
+        };
+
+        // Tags are padded to 8 byte alignment.
+        self.current = (self.current + size as usize + 7) & !7;
+
+        Some(tag)
+    }
+}
+
+/// Return an iterator over every tag in `boot_info`.
+pub fn tags(boot_info: &BootInformation) -> TagIter {
+    TagIter {
+        // Skip `total_size` and `reserved`.
+        current: boot_info.start_address() + 8,
+        end: boot_info.end_address(),
+    }
+}
+
+/// Find the first tag of the given type.
+pub fn find_tag(boot_info: &BootInformation, typ: u32) -> Option<Tag> {
+    tags(boot_info).find(|tag| tag.typ == typ)
+}
+
+/// Return the command line passed to the kernel by the bootloader, if any.
+pub fn command_line(boot_info: &BootInformation) -> Option<&'static str> {
+    find_tag(boot_info, TAG_COMMAND_LINE).and_then(|tag| {
+        let data = unsafe { tag.data() };
+        // The string is null-terminated.
+        let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+        str::from_utf8(&data[..len]).ok()
+    })
+}
+
+/// The type of a region in the multiboot memory map.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryAreaType {
+    Usable,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    BadMemory,
+}
+
+impl MemoryAreaType {
+    /// Classify a raw memory map type. Unknown types are treated as reserved.
+    pub fn from_raw(typ: u32) -> MemoryAreaType {
+        match typ {
+            1 => MemoryAreaType::Usable,
+            3 => MemoryAreaType::AcpiReclaimable,
+            4 => MemoryAreaType::AcpiNvs,
+            5 => MemoryAreaType::BadMemory,
+            _ => MemoryAreaType::Reserved,
+        }
+    }
+}
+
+/// A single entry of the memory map, of any type.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryMapEntry {
+    pub base: u64,
+    pub length: u64,
+    pub typ: MemoryAreaType,
+}
+
+/// An iterator over every entry of the memory map, including the ones that are not usable.
+pub struct MemoryMapIter {
+    current: usize,
+    end: usize,
+    entry_size: usize,
+}
+
+impl Iterator for MemoryMapIter {
+    type Item = MemoryMapEntry;
+
+    fn next(&mut self) -> Option<MemoryMapEntry> {
+        if self.entry_size < 24 || self.current + self.entry_size > self.end {
+            return None;
+        }
+
+        let entry = unsafe {
+            MemoryMapEntry {
+                base: *(self.current as *const u64),
+                length: *((self.current + 8) as *const u64),
+                typ: MemoryAreaType::from_raw(*((self.current + 16) as *const u32)),
+            }
+        };
+        self.current += self.entry_size;
+
+        Some(entry)
+    }
+}
+
+/// Return an iterator over the full memory map, or `None` if there is no memory map tag.
+pub fn memory_map(boot_info: &BootInformation) -> Option<MemoryMapIter> {
+    find_tag(boot_info, TAG_MEMORY_MAP).map(|tag| {
+        // The tag data starts with the size and version of each entry.
+        let entry_size = unsafe { *(tag.data_address() as *const u32) } as usize;
+        MemoryMapIter {
+            current: tag.data_address() + 8,
+            end: tag.data_address() + tag.data_len(),
+            entry_size: entry_size,
+        }
+    })
+}
+
+/// Totals over a memory map, in bytes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemorySummary {
+    pub total: u64,
+    pub usable: u64,
+    pub reserved: u64,
+    pub acpi: u64,
+    pub bad: u64,
+    /// The number of usable regions.
+    pub usable_regions: usize,
+}
+
+impl MemorySummary {
+    /// Sum up `entries` by type.
+    pub fn from_entries<I: Iterator<Item = MemoryMapEntry>>(entries: I) -> MemorySummary {
+        let mut summary = MemorySummary::default();
+
+        for entry in entries {
+            summary.total += entry.length;
+            match entry.typ {
+                MemoryAreaType::Usable => {
+                    summary.usable += entry.length;
+                    summary.usable_regions += 1;
+                }
+                MemoryAreaType::Reserved => summary.reserved += entry.length,
+                MemoryAreaType::AcpiReclaimable | MemoryAreaType::AcpiNvs => {
+                    summary.acpi += entry.length
+                }
+                MemoryAreaType::BadMemory => summary.bad += entry.length,
+            }
+        }
+
+        summary
+    }
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "memory_summary",
+            run: memory_summary,
+        },
+    ];
+
+    fn memory_summary() -> Result<(), &'static str> {
+        use ktest::multiboot_info;
+        use super::{memory_map, MemorySummary, TAG_MEMORY_MAP};
+
+        const ENTRY_SIZE: usize = 24;
+
+        // Base, length and raw type of each region. Type 7 is unknown, so it counts as reserved.
+        let regions: [(u64, u64, u32); 7] = [
+            (0, 0x9_fc00, 1),
+            (0x9_fc00, 0x400, 2),
+            (0x10_0000, 0x7ee_0000, 1),
+            (0x7fe_0000, 0x1_0000, 3),
+            (0x7ff_0000, 0x1_0000, 4),
+            (0x800_0000, 0x1000, 5),
+            (0xfffc_0000, 0x4_0000, 7),
+        ];
+
+        // The entry size and version come first, then the entries.
+        let mut data = [0u8; 8 + 7 * ENTRY_SIZE];
+        data[0] = ENTRY_SIZE as u8;
+        for (index, &(base, length, typ)) in regions.iter().enumerate() {
+            let entry = &mut data[8 + index * ENTRY_SIZE..8 + (index + 1) * ENTRY_SIZE];
+            for i in 0..8 {
+                entry[i] = (base >> (i * 8)) as u8;
+                entry[8 + i] = (length >> (i * 8)) as u8;
+            }
+            entry[16] = typ as u8;
+        }
+
+        let mut buffer = [0u64; 32];
+        let tags: [(u32, &[u8]); 1] = [(TAG_MEMORY_MAP, &data)];
+        let boot_info = unsafe { ::multiboot2::load(multiboot_info(&mut buffer, &tags)) };
+        let entries = memory_map(&boot_info).ok_or("the memory map tag was not found")?;
+        let summary = MemorySummary::from_entries(entries);
+
+        if summary.total != 0x7fe_1000 {
+            Err("the total does not cover every region")
+        } else if summary.usable != 0x7f7_fc00 || summary.usable_regions != 2 {
+            Err("the usable regions were summed wrongly")
+        } else if summary.reserved != 0x4_0400 {
+            Err("reserved and unknown regions were not both counted as reserved")
+        } else if summary.acpi != 0x2_0000 || summary.bad != 0x1000 {
+            Err("ACPI or bad memory was summed wrongly")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Data each CPU keeps for itself. Every CPU is given a dense index as it is brought up, and its
+//! GS base is pointed at a `PerCpu` holding it, so that per-CPU arrays can be indexed without
+//! asking the CPU for its APIC ID, which is sparse and, under a hypervisor, costs a VM exit.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+
+/// The number of CPUs that can be given an index.
+pub const MAX_CPUS: usize = 8;
+
+/// What the GS base of a CPU points at.
+#[repr(C)]
+pub struct PerCpu {
+    /// The dense index of the CPU, read through `gs:0`.
+    index: usize,
+}
+
+static PER_CPU: [PerCpu; MAX_CPUS] = [
+    PerCpu { index: 0 },
+    PerCpu { index: 1 },
+    PerCpu { index: 2 },
+    PerCpu { index: 3 },
+    PerCpu { index: 4 },
+    PerCpu { index: 5 },
+    PerCpu { index: 6 },
+    PerCpu { index: 7 },
+];
+
+/// The number of CPUs that have called `init`.
+static BROUGHT_UP: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Set once the bootstrap CPU has its GS base, from which point `index` reads it.
+static READY: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Give the calling CPU the next free index and point its GS base at its `PerCpu`. This must be
+/// the first thing each CPU does as it is brought up, the bootstrap CPU first, since `index` reads
+/// through the GS base once the bootstrap CPU has called it. Returns the index, or `None`, leaving
+/// the GS base alone, if `MAX_CPUS` have been given one already.
+pub fn init() -> Option<usize> {
+    use x86_64::registers::msr::{wrmsr, IA32_GS_BASE};
+
+    let index = BROUGHT_UP.fetch_add(1, Ordering::SeqCst);
+    let per_cpu = PER_CPU.get(index)?;
+
+    unsafe { wrmsr(IA32_GS_BASE, per_cpu as *const PerCpu as u64) };
+    READY.store(true, Ordering::SeqCst);
+
+    Some(index)
+}
+
+/// Return the index of the current CPU, or `None` before the bootstrap CPU has called `init`.
+pub fn index() -> Option<usize> {
+    if !READY.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    let index: usize;
+    unsafe { asm!("mov $0, qword ptr gs:[0]" : "=r"(index) : : : "intel") };
+    Some(index)
+}
+
+/// Return the number of CPUs that have been given an index.
+pub fn count() -> usize {
+    BROUGHT_UP.load(Ordering::SeqCst).min(MAX_CPUS)
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "percpu_index",
+            run: percpu_index,
+        },
+    ];
+
+    /// Only the bootstrap CPU is brought up, so it has index 0, and its GS base points at the
+    /// first `PerCpu`.
+    fn percpu_index() -> Result<(), &'static str> {
+        use super::{count, index, PerCpu, PER_CPU};
+        use x86_64::registers::msr::{rdmsr, IA32_GS_BASE};
+
+        let gs_base = unsafe { rdmsr(IA32_GS_BASE) };
+
+        if index() != Some(0) {
+            Err("the bootstrap CPU does not have index 0")
+        } else if count() != 1 {
+            Err("more than one CPU was given an index")
+        } else if gs_base != &PER_CPU[0] as *const PerCpu as u64 {
+            Err("the GS base does not point at the bootstrap CPU's data")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -2,25 +2,24 @@
 use x86_64::registers::msr::{rdmsr, wrmsr, IA32_APIC_BASE};
 use core::ptr;
 use core::sync::atomic::{AtomicU32, Ordering};
-use arch::memory::paging::{Page, VirtualAddress, PhysicalAddress, ActivePageTable};
-use arch::memory::paging::entry::EntryFlags;
-use arch::memory::Frame;
+use arch::{Arch, MapFlags, ARCH};
 use heapless::Vec as StaticVec;
 use spin::Mutex;
 use acpi::madt;
 
-/// This will manage all the apic hardware on the system.
+/// This will manage all the apic hardware on the system. The MADT entries are copied in, so that
+/// nothing refers to the ACPI tables once they have been reclaimed.
 pub struct ApicManager {
     /// The base address of the local APIC register space.
     pub lapic_base: u32,
-    pub local_apics: StaticVec<&'static madt::LapicEntry, [&'static madt::LapicEntry; 20]>,
+    pub local_apics: StaticVec<madt::LapicEntry, [madt::LapicEntry; 20]>,
     /// All the I/O APICs on a system. FIXME: Figure out how to set the size of the backing
     /// array dynamically.
-    pub io_apics: StaticVec<&'static madt::IoApic, [&'static madt::IoApic; 10]>,
+    pub io_apics: StaticVec<madt::IoApic, [madt::IoApic; 10]>,
     /// All the non-maskable interrupts, specified by the MADT.
-    pub nmis: StaticVec<&'static madt::ApicNMI, [&'static madt::ApicNMI; 10]>,
+    pub nmis: StaticVec<madt::ApicNMI, [madt::ApicNMI; 10]>,
     /// Interrupt source overrides.
-    pub isos: StaticVec<&'static madt::InterruptSourceOverride, [&'static madt::InterruptSourceOverride; 10]>,
+    pub isos: StaticVec<madt::InterruptSourceOverride, [madt::InterruptSourceOverride; 10]>,
 }
 
 impl ApicManager {
@@ -166,7 +165,7 @@ impl ApicManager {
     }
 }
 
-pub fn init(active_table: &mut ActivePageTable) {
+pub fn init() {
     if let Some(ref mut apic_manager) = *APIC_MANAGER.lock() {
         println!("[ dev ] Initialising APIC, lapic base at {:#x}", apic_manager.lapic_base);
         println!("[ dev ] Mapping local APIC address space...");
@@ -175,25 +174,12 @@ pub fn init(active_table: &mut ActivePageTable) {
             println!("Max redirect for this i/o apic is {}", apic_manager.get_max_redirect(i));
         }
 
-        {
-            let page = Page::containing_address(VirtualAddress::new(apic_manager.lapic_base as usize));
-            let frame = Frame::containing_address(PhysicalAddress::new(apic_manager.lapic_base as usize));
-            let result = active_table.map_to(page, frame,
-                                             EntryFlags::PRESENT |
-                                             EntryFlags::WRITABLE |
-                                             EntryFlags::NO_EXECUTE);
-            result.flush(active_table);
-        }
-
-        {
-            for io_apic in apic_manager.io_apics.iter() {
-                let page = Page::containing_address(VirtualAddress::new(io_apic.address as usize));
-                let frame = Frame::containing_address(PhysicalAddress::new(io_apic.address as usize));
-                let result = active_table.map_to(page, frame,
-                                                 EntryFlags::PRESENT |
-                                                 EntryFlags::WRITABLE |
-                                                 EntryFlags::NO_EXECUTE);
-                result.flush(active_table);
+        let addresses = Some(apic_manager.lapic_base as usize)
+            .into_iter()
+            .chain(apic_manager.io_apics.iter().map(|io_apic| io_apic.address as usize));
+        for address in addresses {
+            if let Err(e) = ARCH.map(address, address, MapFlags::WRITABLE) {
+                println!("[ dev ] WARNING: Could not map APIC at {:#x}: {}", address, e);
             }
         }
 
@@ -206,6 +192,29 @@ pub fn init(active_table: &mut ActivePageTable) {
     }
 }
 
+/// Route a legacy ISA IRQ to the BSP through the I/O APIC, unless an interrupt source override
+/// already redirects it.
+pub fn route_irq(irq: u8) {
+    if let Some(ref apic_manager) = *APIC_MANAGER.lock() {
+        if apic_manager.isos.iter().any(|iso| iso.irq_source == irq) {
+            return;
+        }
+
+        apic_manager.set_redirect(irq, irq as u32, 0, apic_manager.local_apics[0].id);
+    }
+}
+
+/// Route `gsi` to the BSP at the vector of legacy IRQ `irq`, for devices that are not wired to an
+/// ISA IRQ. Returns `false` if there is no APIC.
+pub fn route_gsi(irq: u8, gsi: u32) -> bool {
+    if let Some(ref apic_manager) = *APIC_MANAGER.lock() {
+        apic_manager.set_redirect(irq, gsi, 0, apic_manager.local_apics[0].id);
+        true
+    } else {
+        false
+    }
+}
+
 pub fn eoi() {
     if let Some(ref mut apic_manager) = *APIC_MANAGER.lock() {
         apic_manager.eoi();
@@ -0,0 +1,404 @@
+//! PIO driver for the master drive on the primary ATA channel. Commands are issued by polling the
+//! status register, but transfers then sleep on a wait queue until the drive raises IRQ 14, rather
+//! than spinning on BSY/DRQ for the whole transfer.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use device::{pit, Port};
+use spin::Mutex;
+use task::WaitQueue;
+
+/// The IRQ of the primary ATA channel.
+pub const PRIMARY_IRQ: u8 = 14;
+/// The size of a sector in bytes.
+pub const SECTOR_SIZE: usize = 512;
+/// How long to wait for the drive to respond to a command before giving up, in milliseconds.
+pub const TIMEOUT_MS: usize = 1000;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_CACHE_FLUSH: u8 = 0xe7;
+const COMMAND_IDENTIFY: u8 = 0xec;
+
+/// Reasons an ATA transfer can fail.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AtaError {
+    /// There is no drive on the channel.
+    NoDrive,
+    /// The buffer is empty, not a whole number of sectors, or more than 256 sectors long.
+    InvalidBuffer,
+    /// The transfer does not fit in 28-bit LBA.
+    OutOfRange,
+    /// The drive did not respond within `TIMEOUT_MS`.
+    Timeout,
+    /// The drive reported an error. Holds the error register.
+    DeviceError(u8),
+}
+
+/// The registers of an ATA channel.
+struct AtaChannel {
+    data: Port<u16>,
+    error: Port<u8>,
+    sector_count: Port<u8>,
+    lba_low: Port<u8>,
+    lba_mid: Port<u8>,
+    lba_high: Port<u8>,
+    drive: Port<u8>,
+    /// Status when read, command when written.
+    command: Port<u8>,
+    /// Alternate status when read. Reading it does not acknowledge an interrupt.
+    control: Port<u8>,
+    /// The number of sectors on the master drive, or 0 if there is none.
+    sectors: u32,
+}
+
+static PRIMARY: Mutex<AtaChannel> = Mutex::new(unsafe {
+    AtaChannel {
+        data: Port::new(0x1f0),
+        error: Port::new(0x1f1),
+        sector_count: Port::new(0x1f2),
+        lba_low: Port::new(0x1f3),
+        lba_mid: Port::new(0x1f4),
+        lba_high: Port::new(0x1f5),
+        drive: Port::new(0x1f6),
+        command: Port::new(0x1f7),
+        control: Port::new(0x3f6),
+        sectors: 0,
+    }
+});
+
+/// Set while a transfer is under way. Transfers sleep with `PRIMARY` unlocked, so this keeps
+/// another from issuing a command in between.
+static BUSY: AtomicBool = ATOMIC_BOOL_INIT;
+/// Set by the interrupt handler, cleared before waiting for the next interrupt.
+static IRQ_PENDING: AtomicBool = ATOMIC_BOOL_INIT;
+/// The status the drive reported when it last raised its interrupt.
+static IRQ_STATUS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+lazy_static! {
+    /// Woken by the interrupt handler, and by the PIT once a transfer has timed out.
+    static ref IRQ_QUEUE: WaitQueue = WaitQueue::new();
+    /// Woken when a transfer is done and `BUSY` clears.
+    static ref IDLE_QUEUE: WaitQueue = WaitQueue::new();
+}
+
+impl AtaChannel {
+    /// Wait 400ns for the status register to settle, by reading the alternate status four times.
+    fn settle(&mut self) {
+        for _ in 0..4 {
+            self.control.read();
+        }
+    }
+
+    /// Spin until BSY clears. Only used before a command is issued, when no interrupt is coming.
+    fn wait_not_busy(&mut self) -> Result<u8, AtaError> {
+        let deadline = pit::ticks() + pit::ms_to_ticks(TIMEOUT_MS);
+
+        loop {
+            let status = self.control.read();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            if pit::ticks() >= deadline {
+                return Err(AtaError::Timeout);
+            }
+        }
+    }
+
+    /// Spin until the drive is ready to take data. A write only raises an interrupt after each
+    /// sector has been sent, so the first sector has to be polled for.
+    fn wait_drq(&mut self) -> Result<(), AtaError> {
+        let deadline = pit::ticks() + pit::ms_to_ticks(TIMEOUT_MS);
+
+        loop {
+            let status = self.control.read();
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(AtaError::DeviceError(self.error.read()));
+            }
+            if status & STATUS_BSY == 0 && status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+            if pit::ticks() >= deadline {
+                return Err(AtaError::Timeout);
+            }
+        }
+    }
+
+    /// Select the master drive and write the LBA and sector count, then issue `command`.
+    fn issue(&mut self, command: u8, lba: u32, count: usize) -> Result<(), AtaError> {
+        self.wait_not_busy()?;
+
+        self.drive.write(0xe0 | ((lba >> 24) & 0x0f) as u8);
+        self.settle();
+
+        // A count of 0 means 256 sectors.
+        self.sector_count.write(count as u8);
+        self.lba_low.write(lba as u8);
+        self.lba_mid.write((lba >> 8) as u8);
+        self.lba_high.write((lba >> 16) as u8);
+
+        IRQ_PENDING.store(false, Ordering::SeqCst);
+        self.command.write(command);
+
+        Ok(())
+    }
+
+    /// Identify the master drive, recording how many sectors it has. This polls, since it runs
+    /// before the IRQ is routed.
+    fn identify(&mut self) -> Result<u32, AtaError> {
+        self.drive.write(0xa0);
+        self.settle();
+
+        self.sector_count.write(0);
+        self.lba_low.write(0);
+        self.lba_mid.write(0);
+        self.lba_high.write(0);
+        self.command.write(COMMAND_IDENTIFY);
+
+        // A floating bus reads all ones, and a status of 0 means there is nothing on the channel.
+        match self.command.read_present() {
+            None | Some(0) => return Err(AtaError::NoDrive),
+            Some(_) => {}
+        }
+
+        self.wait_not_busy()?;
+
+        // ATAPI and SATA drives set these to a signature instead of aborting.
+        if self.lba_mid.read() != 0 || self.lba_high.read() != 0 {
+            return Err(AtaError::NoDrive);
+        }
+
+        self.wait_drq()?;
+
+        let mut identify = [0u16; 256];
+        for word in identify.iter_mut() {
+            *word = self.data.read();
+        }
+
+        // Words 60 and 61 hold the number of 28-bit addressable sectors.
+        Ok(identify[60] as u32 | (identify[61] as u32) << 16)
+    }
+}
+
+/// Sleep until the drive raises its interrupt, and return the status it reported. `PRIMARY` must
+/// not be held, since the interrupt may take a while.
+fn wait_irq() -> Result<u8, AtaError> {
+    IRQ_QUEUE.wait_timeout(|| IRQ_PENDING.load(Ordering::SeqCst), TIMEOUT_MS);
+
+    if !IRQ_PENDING.swap(false, Ordering::SeqCst) {
+        return Err(AtaError::Timeout);
+    }
+
+    let status = IRQ_STATUS.load(Ordering::SeqCst) as u8;
+    if status & (STATUS_ERR | STATUS_DF) != 0 {
+        return Err(AtaError::DeviceError(PRIMARY.lock().error.read()));
+    }
+
+    Ok(status)
+}
+
+/// Run `f` as the only transfer on the channel, sleeping until any other one is done first.
+fn exclusive<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    IDLE_QUEUE.wait_until(|| !BUSY.swap(true, Ordering::SeqCst));
+    let result = f();
+    BUSY.store(false, Ordering::SeqCst);
+    IDLE_QUEUE.wake_all();
+    result
+}
+
+/// Check that `len` bytes starting at sector `lba` can be transferred with one command.
+fn check_transfer(channel: &AtaChannel, lba: u32, len: usize) -> Result<usize, AtaError> {
+    if channel.sectors == 0 {
+        return Err(AtaError::NoDrive);
+    }
+
+    let count = len / SECTOR_SIZE;
+    if len % SECTOR_SIZE != 0 || count == 0 || count > 256 {
+        return Err(AtaError::InvalidBuffer);
+    }
+
+    if lba as u64 + count as u64 > channel.sectors as u64 {
+        return Err(AtaError::OutOfRange);
+    }
+
+    Ok(count)
+}
+
+/// Read `buffer.len() / SECTOR_SIZE` sectors starting at `lba`. The calling process sleeps while
+/// the drive fetches each sector.
+pub fn read_sectors(lba: u32, buffer: &mut [u8]) -> Result<(), AtaError> {
+    exclusive(|| {
+        {
+            let mut channel = PRIMARY.lock();
+            let count = check_transfer(&channel, lba, buffer.len())?;
+            channel.issue(COMMAND_READ_SECTORS, lba, count)?;
+        }
+
+        for sector in buffer.chunks_mut(SECTOR_SIZE) {
+            wait_irq()?;
+
+            let mut channel = PRIMARY.lock();
+            for word in sector.chunks_mut(2) {
+                let value = channel.data.read();
+                word[0] = value as u8;
+                word[1] = (value >> 8) as u8;
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Write `buffer.len() / SECTOR_SIZE` sectors starting at `lba`, then flush the drive's cache. The
+/// calling process sleeps while the drive stores each sector.
+pub fn write_sectors(lba: u32, buffer: &[u8]) -> Result<(), AtaError> {
+    exclusive(|| {
+        {
+            let mut channel = PRIMARY.lock();
+            let count = check_transfer(&channel, lba, buffer.len())?;
+            channel.issue(COMMAND_WRITE_SECTORS, lba, count)?;
+            channel.wait_drq()?;
+        }
+
+        for sector in buffer.chunks(SECTOR_SIZE) {
+            {
+                let mut channel = PRIMARY.lock();
+                for word in sector.chunks(2) {
+                    channel.data.write(word[0] as u16 | (word[1] as u16) << 8);
+                }
+            }
+
+            wait_irq()?;
+        }
+
+        PRIMARY.lock().issue(COMMAND_CACHE_FLUSH, 0, 0)?;
+        wait_irq()?;
+
+        Ok(())
+    })
+}
+
+/// Return the number of sectors on the drive, or 0 if there is none.
+pub fn sectors() -> u32 {
+    PRIMARY.lock().sectors
+}
+
+/// Handle an interrupt from the primary channel. Reading the status register acknowledges it.
+pub fn handle_interrupt() {
+    let mut command = unsafe { Port::<u8>::new(0x1f7) };
+
+    IRQ_STATUS.store(command.read() as usize, Ordering::SeqCst);
+    IRQ_PENDING.store(true, Ordering::SeqCst);
+
+    IRQ_QUEUE.wake_all();
+}
+
+/// Look for a drive on the primary channel and route its IRQ.
+pub fn init() {
+    let mut channel = PRIMARY.lock();
+
+    match channel.identify() {
+        Ok(sectors) => {
+            channel.sectors = sectors;
+            println!(
+                "[ dev ] ATA: primary master has {} sectors ({} MiB).",
+                sectors,
+                sectors as usize * SECTOR_SIZE / (1024 * 1024)
+            );
+        }
+        Err(err) => {
+            println!("[ dev ] ATA: no usable primary master: {:?}", err);
+            return;
+        }
+    }
+
+    ::device::apic::route_irq(PRIMARY_IRQ);
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "ata_read",
+            run: ata_read,
+        },
+        KTest {
+            name: "ata_timeout",
+            run: ata_timeout,
+        },
+    ];
+
+    /// Read the first two sectors both together and one at a time, and the last sector, without
+    /// writing anything. This needs a disk on the primary master, e.g. QEMU's `-hda disk.img`;
+    /// without one, only the error is checked.
+    fn ata_read() -> Result<(), &'static str> {
+        use super::{read_sectors, sectors, AtaError, SECTOR_SIZE};
+
+        let mut first = [0u8; SECTOR_SIZE];
+        if sectors() == 0 {
+            return match read_sectors(0, &mut first) {
+                Err(AtaError::NoDrive) => Ok(()),
+                _ => Err("reading without a drive did not fail with NoDrive"),
+            };
+        }
+        if sectors() < 2 {
+            return Err("the disk has fewer than two sectors");
+        }
+
+        let mut second = [0u8; SECTOR_SIZE];
+        let mut both = [0u8; 2 * SECTOR_SIZE];
+        read_sectors(0, &mut first)
+            .and_then(|_| read_sectors(1, &mut second))
+            .and_then(|_| read_sectors(0, &mut both))
+            .map_err(|_| "could not read the first two sectors")?;
+
+        let mut last = [0u8; SECTOR_SIZE];
+        let read_last = read_sectors(sectors() - 1, &mut last);
+        let past_end = read_sectors(sectors(), &mut last);
+        let partial = read_sectors(0, &mut last[..SECTOR_SIZE - 2]);
+
+        if both[..SECTOR_SIZE] != first[..] || both[SECTOR_SIZE..] != second[..] {
+            Err("reading two sectors at once does not match reading them one at a time")
+        } else if read_last.is_err() {
+            Err("could not read the last sector")
+        } else if past_end != Err(AtaError::OutOfRange) {
+            Err("reading past the end of the disk did not fail with OutOfRange")
+        } else if partial != Err(AtaError::InvalidBuffer) {
+            Err("reading into part of a sector did not fail with InvalidBuffer")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Wait for an interrupt without issuing a command, so the drive never responds.
+    fn ata_timeout() -> Result<(), &'static str> {
+        use core::sync::atomic::Ordering;
+        use device::pit;
+        use super::{exclusive, wait_irq, AtaError, IRQ_PENDING, TIMEOUT_MS};
+
+        let (result, waited) = exclusive(|| {
+            IRQ_PENDING.store(false, Ordering::SeqCst);
+
+            let start = pit::ticks();
+            let result = wait_irq();
+            (result, pit::ticks() - start)
+        });
+
+        if result != Err(AtaError::Timeout) {
+            Err("waiting for an interrupt that never comes did not time out")
+        } else if waited < pit::ms_to_ticks(TIMEOUT_MS) {
+            Err("the wait gave up before the timeout")
+        } else {
+            Ok(())
+        }
+    }
+}
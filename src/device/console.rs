@@ -0,0 +1,169 @@
+//! The kernel console. Input is merged from the keyboard and the serial line so that the kernel
+//! can be driven from either a monitor or a serial terminal, and output goes to COM1 and is
+//! mirrored to the screen, so that logs survive in a `-serial stdio` capture after the screen is
+//! gone.
+
+use arch::{Arch, ARCH};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use device::{keyboard, serial};
+
+/// Whether output is mirrored to the screen. It always goes to COM1.
+static MIRROR: AtomicBool = AtomicBool::new(true);
+
+/// Mirror output to the screen, or stop doing so.
+pub fn set_mirror(enabled: bool) {
+    MIRROR.store(enabled, Ordering::SeqCst);
+}
+
+/// Return whether output is mirrored to the screen.
+pub fn mirror() -> bool {
+    MIRROR.load(Ordering::SeqCst)
+}
+
+/// Write `args` to COM1, and to the screen if mirroring is on. This is what `print!` calls.
+///
+/// The COM1 and screen locks are never held together, so there is no order to get wrong. Either
+/// may already be held by the code this interrupted, or by a print that panicked, so neither is
+/// waited for: COM1 is then written without its lock, and the screen is skipped.
+pub fn print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use device::vga::buffer::SCREEN;
+
+    match serial::COM1.try_lock() {
+        Some(mut port) => {
+            let _ = port.write_fmt(args);
+        }
+        None => serial::write_unlocked(args),
+    }
+
+    if mirror() {
+        if let Some(mut screen) = SCREEN.try_lock() {
+            let _ = screen.write_fmt(args);
+        }
+    }
+}
+
+/// Whether the last byte from the serial line was a carriage return, so that the line feed of a
+/// CR/LF pair can be dropped.
+static LAST_WAS_CR: AtomicBool = AtomicBool::new(false);
+
+/// Translate a byte from the serial line to what the keyboard would have produced. Terminals send
+/// CR (or CR/LF) for enter and DEL for backspace. Returns `None` for bytes that should be dropped.
+fn normalize_serial(byte: u8) -> Option<u8> {
+    let last_was_cr = LAST_WAS_CR.swap(byte == b'\r', Ordering::SeqCst);
+
+    match byte {
+        b'\r' => Some(b'\n'),
+        b'\n' if last_was_cr => None,
+        0x7f => Some(0x8),
+        byte => Some(byte),
+    }
+}
+
+/// Return the next character from the keyboard or the serial line, without blocking.
+pub fn try_read_char() -> Option<char> {
+    if let Some(byte) = keyboard::pop_byte() {
+        return Some(byte as char);
+    }
+
+    while let Some(byte) = serial::read_byte() {
+        if let Some(byte) = normalize_serial(byte) {
+            return Some(byte as char);
+        }
+    }
+
+    None
+}
+
+/// Wait for the next character from the keyboard or the serial line. Interrupts must be enabled.
+pub fn read_char() -> char {
+    loop {
+        if let Some(character) = try_read_char() {
+            return character;
+        }
+
+        ARCH.halt();
+    }
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "console_mirror",
+            run: console_mirror,
+        },
+        KTest {
+            name: "console_serial_input",
+            run: console_serial_input,
+        },
+    ];
+
+    fn console_mirror() -> Result<(), &'static str> {
+        use arch::without_interrupts;
+        use device::console;
+        use device::serial::COM1;
+        use device::vga::buffer::{SCREEN, BUFFER_HEIGHT};
+
+        let on_screen = |marker: &[u8]| {
+            let screen = SCREEN.lock();
+            screen.chars()[BUFFER_HEIGHT - 2].starts_with(marker)
+        };
+
+        let mirror = console::mirror();
+
+        console::set_mirror(true);
+        println!("console_mirror: on");
+        let mirrored = on_screen(b"console_mirror: on");
+
+        console::set_mirror(false);
+        println!("console_mirror: off");
+        let hidden = !on_screen(b"console_mirror: off");
+
+        console::set_mirror(mirror);
+
+        // Printing with either lock held, as a panic while printing would, must not spin forever.
+        without_interrupts(|| {
+            let _screen = SCREEN.lock();
+            println!("[ ktest ] console_mirror: printed with the screen locked");
+        });
+        without_interrupts(|| {
+            let _port = COM1.lock();
+            println!("[ ktest ] console_mirror: printed with COM1 locked");
+        });
+
+        if !mirrored {
+            Err("output was not mirrored to the screen")
+        } else if !hidden {
+            Err("output reached the screen with mirroring off")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn console_serial_input() -> Result<(), &'static str> {
+        use device::console;
+        use ktest::serial_receive;
+
+        while console::try_read_char().is_some() {}
+
+        // Enter from a terminal, as CR/LF, and a DEL for backspace.
+        serial_receive(b"\r\n\x7fq");
+
+        // Only block once input is known to be there, so that a failure cannot hang the tests.
+        if console::try_read_char() != Some('\n') {
+            return Err("a CR from the serial line was not read as a newline");
+        }
+        if console::read_char() != '\x08' {
+            return Err("the LF of a CR/LF pair or a DEL was not translated");
+        }
+        if console::read_char() != 'q' {
+            Err("a byte from the serial line was not returned by read_char")
+        } else {
+            Ok(())
+        }
+    }
+}
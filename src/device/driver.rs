@@ -0,0 +1,208 @@
+//! A minimal driver registry. Drivers declare which other drivers they depend on, and
+//! `init_all` initialises them in dependency order.
+
+use alloc::Vec;
+
+/// A piece of hardware that needs initialising at boot.
+pub trait Driver {
+    /// The unique name of this driver, used by other drivers to depend on it.
+    fn name(&self) -> &'static str;
+
+    /// The names of the drivers that must be initialised before this one.
+    fn depends_on(&self) -> &[&'static str] {
+        &[]
+    }
+
+    /// Initialise the hardware.
+    unsafe fn init(&self);
+}
+
+/// A driver made from a name, a list of dependencies and an init function.
+pub struct FnDriver {
+    pub name: &'static str,
+    pub depends_on: &'static [&'static str],
+    pub init: unsafe fn(),
+}
+
+impl Driver for FnDriver {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn depends_on(&self) -> &[&'static str] {
+        self.depends_on
+    }
+
+    unsafe fn init(&self) {
+        (self.init)()
+    }
+}
+
+/// Reasons the drivers could not be ordered.
+#[derive(Debug)]
+pub enum DriverError {
+    /// A driver depends on a driver that is not registered.
+    MissingDependency(&'static str, &'static str),
+    /// The named driver is part of a dependency cycle.
+    Cycle(&'static str),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mark {
+    Unvisited,
+    Visiting,
+    Done,
+}
+
+/// Depth-first visit of `drivers[index]`, pushing it to `order` after all of its dependencies.
+fn visit(
+    drivers: &[&Driver],
+    index: usize,
+    marks: &mut Vec<Mark>,
+    order: &mut Vec<usize>,
+) -> Result<(), DriverError> {
+    match marks[index] {
+        Mark::Done => return Ok(()),
+        Mark::Visiting => return Err(DriverError::Cycle(drivers[index].name())),
+        Mark::Unvisited => {}
+    }
+
+    marks[index] = Mark::Visiting;
+
+    for dependency in drivers[index].depends_on() {
+        let dep_index = drivers
+            .iter()
+            .position(|d| d.name() == *dependency)
+            .ok_or(DriverError::MissingDependency(
+                drivers[index].name(),
+                *dependency,
+            ))?;
+        visit(drivers, dep_index, marks, order)?;
+    }
+
+    marks[index] = Mark::Done;
+    order.push(index);
+
+    Ok(())
+}
+
+/// Return the indices of `drivers` sorted so that every driver comes after its dependencies.
+pub fn sort(drivers: &[&Driver]) -> Result<Vec<usize>, DriverError> {
+    let mut marks = vec![Mark::Unvisited; drivers.len()];
+    let mut order = Vec::with_capacity(drivers.len());
+
+    for index in 0..drivers.len() {
+        visit(drivers, index, &mut marks, &mut order)?;
+    }
+
+    Ok(order)
+}
+
+/// Initialise every driver in dependency order. Nothing is initialised if the drivers cannot be
+/// ordered.
+pub unsafe fn init_all(drivers: &[&Driver]) -> Result<(), DriverError> {
+    let order = sort(drivers)?;
+
+    for index in order {
+        drivers[index].init();
+    }
+
+    Ok(())
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use ktest::KTest;
+    use super::{init_all, DriverError, FnDriver};
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "driver_order",
+            run: driver_order,
+        },
+    ];
+
+    /// The drivers initialised so far, one decimal digit each, in order.
+    static INITIALISED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    fn record(driver: usize) {
+        let order = INITIALISED.load(Ordering::SeqCst);
+        INITIALISED.store(order * 10 + driver, Ordering::SeqCst);
+    }
+
+    unsafe fn init_first() {
+        record(1);
+    }
+
+    unsafe fn init_second() {
+        record(2);
+    }
+
+    unsafe fn init_third() {
+        record(3);
+    }
+
+    static FIRST: FnDriver = FnDriver {
+        name: "first",
+        depends_on: &[],
+        init: init_first,
+    };
+
+    static SECOND: FnDriver = FnDriver {
+        name: "second",
+        depends_on: &["first"],
+        init: init_second,
+    };
+
+    static THIRD: FnDriver = FnDriver {
+        name: "third",
+        depends_on: &["second"],
+        init: init_third,
+    };
+
+    static CYCLE_A: FnDriver = FnDriver {
+        name: "cycle_a",
+        depends_on: &["cycle_b"],
+        init: init_first,
+    };
+
+    static CYCLE_B: FnDriver = FnDriver {
+        name: "cycle_b",
+        depends_on: &["cycle_a"],
+        init: init_second,
+    };
+
+    fn driver_order() -> Result<(), &'static str> {
+        INITIALISED.store(0, Ordering::SeqCst);
+        let chain = unsafe { init_all(&[&THIRD, &FIRST, &SECOND]) };
+        let chain_order = INITIALISED.load(Ordering::SeqCst);
+
+        INITIALISED.store(0, Ordering::SeqCst);
+        let cycle = unsafe { init_all(&[&FIRST, &CYCLE_A, &CYCLE_B]) };
+        let cycle_order = INITIALISED.load(Ordering::SeqCst);
+
+        let missing = unsafe { init_all(&[&THIRD, &SECOND]) };
+
+        let cycle_reported = match cycle {
+            Err(DriverError::Cycle(name)) => name == "cycle_a" || name == "cycle_b",
+            _ => false,
+        };
+        let missing_reported = match missing {
+            Err(DriverError::MissingDependency("second", "first")) => true,
+            _ => false,
+        };
+
+        if chain.is_err() || chain_order != 123 {
+            Err("the chain was not initialised in dependency order")
+        } else if !cycle_reported {
+            Err("the cycle was not reported")
+        } else if cycle_order != 0 {
+            Err("drivers were initialised despite the cycle")
+        } else if !missing_reported {
+            Err("the missing dependency was not reported")
+        } else {
+            Ok(())
+        }
+    }
+}
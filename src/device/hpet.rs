@@ -0,0 +1,255 @@
+//! The High Precision Event Timer. Its comparators can raise periodic interrupts through the I/O
+//! APIC, which makes it a tick source that does not depend on the PIT or the local APIC timer. It
+//! is only used as the scheduler tick when the `tick=hpet` kernel argument is given.
+
+use core::ptr;
+
+// Offsets of the general registers.
+const CAPABILITIES: usize = 0x000;
+const CONFIG: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0f0;
+
+/// Set in the general config to run the main counter.
+const CONFIG_ENABLE: u64 = 1 << 0;
+/// Set in the general config to route comparators 0 and 1 to IRQs 0 and 8, like the PIT and RTC.
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+/// Set in a comparator's config for a level triggered interrupt.
+const TIMER_LEVEL: u64 = 1 << 1;
+const TIMER_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+/// Set in a comparator's config if it can run in periodic mode.
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+/// Set in a comparator's config if it is 64 bits wide.
+const TIMER_64BIT_CAPABLE: u64 = 1 << 5;
+/// Set in a comparator's config so that the next write to the comparator sets its value, and the
+/// one after that its period. The hardware clears it again.
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+
+/// The longest main counter period the specification allows, in femtoseconds.
+const MAX_PERIOD: u64 = 100_000_000;
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// The size of a timer block's registers.
+const REGISTERS_SIZE: usize = 0x400;
+
+/// The comparator used for the tick.
+pub const TICK_TIMER: usize = 0;
+/// The IRQ whose vector the tick is delivered at, so that it reaches the timer handler.
+const TICK_IRQ: u8 = 0;
+
+/// The offset of comparator `timer`'s config register.
+fn timer_config(timer: usize) -> usize {
+    0x100 + 0x20 * timer
+}
+
+/// The offset of comparator `timer`'s comparator register.
+fn timer_comparator(timer: usize) -> usize {
+    0x108 + 0x20 * timer
+}
+
+/// Access to the HPET registers, so that the setup sequence can be run against fake hardware.
+pub trait Registers {
+    fn read(&mut self, offset: usize) -> u64;
+    fn write(&mut self, offset: usize, value: u64);
+}
+
+/// The registers of a mapped timer block.
+pub struct Mmio {
+    base: usize,
+}
+
+impl Registers for Mmio {
+    fn read(&mut self, offset: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.base + offset) as *const u64) }
+    }
+
+    fn write(&mut self, offset: usize, value: u64) {
+        unsafe { ptr::write_volatile((self.base + offset) as *mut u64, value) }
+    }
+}
+
+/// Program comparator `timer` to interrupt `hz` times a second and return the GSI it was routed
+/// to. That is the lowest I/O APIC input above the ISA IRQs that the comparator supports, so that
+/// it is not shared with a legacy device, or its lowest input if it has none. The main counter is
+/// halted and reset while the comparator is set up, and restarted once it is.
+pub fn start_periodic<R: Registers>(
+    regs: &mut R,
+    timer: usize,
+    hz: u32,
+) -> Result<u32, &'static str> {
+    let capabilities = regs.read(CAPABILITIES);
+
+    let period = capabilities >> 32;
+    if period == 0 || period > MAX_PERIOD {
+        return Err("main counter period is invalid");
+    }
+
+    let timers = ((capabilities >> 8) & 0x1f) as usize + 1;
+    if timer >= timers {
+        return Err("no such comparator");
+    }
+
+    let config = regs.read(timer_config(timer));
+    if config & TIMER_PERIODIC_CAPABLE == 0 {
+        return Err("comparator cannot run periodically");
+    }
+
+    // The top half of the config is a mask of the I/O APIC inputs the comparator can drive.
+    let routes = (config >> 32) as u32;
+    if routes == 0 {
+        return Err("comparator cannot be routed to the I/O APIC");
+    }
+    let gsi = if routes >> 16 != 0 {
+        (routes >> 16).trailing_zeros() + 16
+    } else {
+        routes.trailing_zeros()
+    };
+
+    let delta = FEMTOSECONDS_PER_SECOND / (period * hz.max(1) as u64);
+    if delta == 0 {
+        return Err("tick frequency is too high");
+    } else if config & TIMER_64BIT_CAPABLE == 0 && delta > u32::max_value() as u64 {
+        return Err("tick period does not fit in a 32-bit comparator");
+    }
+
+    // Halt and reset the main counter, so that the first deadline is `delta` from now.
+    let general = regs.read(CONFIG) & !(CONFIG_ENABLE | CONFIG_LEGACY_ROUTE);
+    regs.write(CONFIG, general);
+    regs.write(MAIN_COUNTER, 0);
+
+    // Edge triggered, through the I/O APIC rather than as a message.
+    let config = config & !(TIMER_LEVEL | TIMER_32BIT_MODE | TIMER_ROUTE_MASK | TIMER_FSB_ENABLE);
+    let config = config | TIMER_ENABLE | TIMER_PERIODIC | TIMER_VALUE_SET
+        | (gsi as u64) << TIMER_ROUTE_SHIFT;
+    regs.write(timer_config(timer), config);
+
+    // With VALUE_SET, the first write sets the comparator and the second its period.
+    regs.write(timer_comparator(timer), delta);
+    regs.write(timer_comparator(timer), delta);
+
+    regs.write(CONFIG, general | CONFIG_ENABLE);
+
+    Ok(gsi)
+}
+
+/// Map the timer block described by the ACPI HPET table and start the tick on it.
+fn start_tick() -> Result<(), &'static str> {
+    use acpi::fadt::AddressSpace;
+    use arch::ARCH;
+    use device::{apic, pit};
+    use device::mmio::map_mmio;
+
+    let table = ::acpi::hpet().ok_or("no HPET table")?;
+    if table.base_address.space != AddressSpace::SystemMemory {
+        return Err("registers are not memory mapped");
+    }
+    let base = map_mmio(&ARCH, table.base_address.address as usize, REGISTERS_SIZE)?;
+
+    // Tick at the PIT's frequency, so that tick counts keep meaning the same thing.
+    let hz = pit::frequency() as u32;
+    let gsi = start_periodic(&mut Mmio { base: base }, TICK_TIMER, hz)?;
+
+    if !apic::route_gsi(TICK_IRQ, gsi) {
+        return Err("no I/O APIC to route the comparator to");
+    }
+    pit::stop();
+
+    println!("[ dev ] HPET: comparator {} ticking at {} Hz on GSI {}.", TICK_TIMER, hz, gsi);
+
+    Ok(())
+}
+
+/// Use the HPET as the scheduler tick if the `tick=hpet` kernel argument is given. The PIT keeps
+/// ticking if the HPET cannot be used.
+pub fn init() {
+    match ::arch::args::get().get("tick") {
+        Some("hpet") => {}
+        Some("pit") | None => return,
+        Some(other) => {
+            println!("[ dev ] Unknown tick source {}, using the PIT.", other);
+            return;
+        }
+    }
+
+    if let Err(err) = start_tick() {
+        println!("[ dev ] HPET: {}, using the PIT.", err);
+    }
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use alloc::Vec;
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "hpet_periodic",
+            run: hpet_periodic,
+        },
+    ];
+
+    fn hpet_periodic() -> Result<(), &'static str> {
+        use device::hpet::{start_periodic, Registers};
+
+        /// A timer block with a 10 MHz counter and three comparators, which records every write.
+        struct FakeHpet {
+            registers: [u64; 0x40],
+            writes: Vec<(usize, u64)>,
+        }
+
+        impl Registers for FakeHpet {
+            fn read(&mut self, offset: usize) -> u64 {
+                self.registers[offset / 8]
+            }
+
+            fn write(&mut self, offset: usize, value: u64) {
+                self.registers[offset / 8] = value;
+                self.writes.push((offset, value));
+            }
+        }
+
+        let mut hpet = FakeHpet {
+            registers: [0; 0x40],
+            writes: Vec::new(),
+        };
+        // 100ns period, three comparators, running in legacy replacement mode.
+        hpet.registers[0x000 / 8] = 100_000_000 << 32 | 2 << 8;
+        hpet.registers[0x010 / 8] = 0b11;
+        // Comparator 0 is 64 bits wide and can drive GSIs 2, 8 and 20, but not periodically.
+        hpet.registers[0x100 / 8] = 0x0010_0104 << 32 | 1 << 5;
+
+        if start_periodic(&mut hpet, 0, 100).is_ok() || !hpet.writes.is_empty() {
+            return Err("programmed a comparator without the periodic capability");
+        }
+        if start_periodic(&mut hpet, 3, 100).is_ok() {
+            return Err("programmed a comparator that does not exist");
+        }
+
+        hpet.registers[0x100 / 8] |= 1 << 4;
+        let gsi = start_periodic(&mut hpet, 0, 100)?;
+
+        // Halt and reset the counter, configure the comparator, set its value and then its period,
+        // and finally restart the counter without legacy replacement.
+        let expected: [(usize, u64); 6] = [
+            (0x010, 0),
+            (0x0f0, 0),
+            (0x100, 0x0010_0104 << 32 | 20 << 9 | 1 << 6 | 1 << 5 | 1 << 4 | 1 << 3 | 1 << 2),
+            (0x108, 100_000),
+            (0x108, 100_000),
+            (0x010, 1),
+        ];
+
+        if gsi != 20 {
+            Err("did not route to the lowest GSI above the ISA IRQs")
+        } else if hpet.writes != expected {
+            Err("wrote the wrong periodic setup sequence")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -42,12 +42,17 @@ use self::x86_io::{inb, inl, inw, outb, outl, outw};
 
 /// Nice little type that allows us to specify the size of the value read without using inb
 /// directly.
-pub trait InOut {
+pub trait InOut: Copy + PartialEq {
+    /// The value read from a port with no device behind it.
+    const ALL_ONES: Self;
+
     unsafe fn port_in(port: u16) -> Self;
     unsafe fn port_out(port: u16, value: Self);
 }
 
 impl InOut for u8 {
+    const ALL_ONES: u8 = 0xFF;
+
     unsafe fn port_in(port: u16) -> u8 {
         inb(port)
     }
@@ -57,6 +62,8 @@ impl InOut for u8 {
 }
 
 impl InOut for u16 {
+    const ALL_ONES: u16 = 0xFFFF;
+
     unsafe fn port_in(port: u16) -> u16 {
         inw(port)
     }
@@ -66,6 +73,8 @@ impl InOut for u16 {
 }
 
 impl InOut for u32 {
+    const ALL_ONES: u32 = 0xFFFF_FFFF;
+
     unsafe fn port_in(port: u16) -> u32 {
         inl(port)
     }
@@ -99,6 +108,24 @@ impl<T: InOut> Port<T> {
         unsafe { T::port_in(self.port) }
     }
 
+    /// Read a value from the port, returning `None` if all bits are set. Reads from a port with
+    /// no device behind it float high, so this is how probe routines detect absent hardware.
+    pub fn read_present(&mut self) -> Option<T> {
+        self.read_present_with(false)
+    }
+
+    /// Like `read_present`, but for devices that can legitimately return all ones: if
+    /// `all_ones_valid` is set, the value is always returned.
+    pub fn read_present_with(&mut self, all_ones_valid: bool) -> Option<T> {
+        let value = self.read();
+
+        if value == T::ALL_ONES && !all_ones_valid {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
     /// Write a value to the port.
     pub fn write(&mut self, value: T) {
         unsafe {
@@ -132,3 +159,56 @@ impl<T: InOut> UnsafePort<T> {
         T::port_out(self.port, value);
     }
 }
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "read_present",
+            run: read_present,
+        },
+    ];
+
+    /// What the next read from a `Fake` port returns.
+    static FAKE_VALUE: AtomicUsize = AtomicUsize::new(0);
+
+    /// A port value that comes from `FAKE_VALUE` instead of the hardware.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Fake(u8);
+
+    impl super::InOut for Fake {
+        const ALL_ONES: Fake = Fake(0xff);
+
+        unsafe fn port_in(_port: u16) -> Fake {
+            Fake(FAKE_VALUE.load(Ordering::SeqCst) as u8)
+        }
+
+        unsafe fn port_out(_port: u16, value: Fake) {
+            FAKE_VALUE.store(value.0 as usize, Ordering::SeqCst);
+        }
+    }
+
+    fn read_present() -> Result<(), &'static str> {
+        use device::io::Port;
+
+        let mut port = unsafe { Port::<Fake>::new(0x1f7) };
+
+        port.write(Fake(0xff));
+        if port.read_present().is_some() {
+            return Err("an all-ones read was taken for a device");
+        }
+        if port.read_present_with(true) != Some(Fake(0xff)) {
+            return Err("an all-ones read was dropped for a device that allows it");
+        }
+
+        port.write(Fake(0x50));
+        if port.read_present() != Some(Fake(0x50)) {
+            Err("a real value was not returned")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use arch::{Arch, MapFlags};
 use core::intrinsics::{volatile_load, volatile_store};
 use core::mem::uninitialized;
 use core::ops::{BitAnd, BitOr, Not};
@@ -38,3 +39,126 @@ where
         self.write(tmp);
     }
 }
+
+/// Identity map the `len` bytes of device registers at physical address `base`, uncached and not
+/// executable, and return the address they can be reached at. Pages that are already identity
+/// mapped are left alone.
+pub fn map_mmio<A: Arch>(arch: &A, base: usize, len: usize) -> Result<usize, &'static str> {
+    let page_size = arch.page_size();
+    let end = base.checked_add(len.max(1)).ok_or("register block wraps around")?;
+
+    let mut page = base & !(page_size - 1);
+    while page < end {
+        match arch.translate(page) {
+            Some(phys) if phys == page => {}
+            Some(_) => return Err("registers are already mapped somewhere else"),
+            None => arch.map(page, page, MapFlags::WRITABLE | MapFlags::NO_CACHE)?,
+        }
+        page += page_size;
+    }
+
+    Ok(base)
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use alloc::Vec;
+    use arch::{Arch, MapFlags};
+    use core::cell::RefCell;
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "arch_mock",
+            run: arch_mock,
+        },
+    ];
+
+    /// An `Arch` that only keeps a list of mappings, to run generic memory code against.
+    struct MockArch {
+        mappings: RefCell<Vec<(usize, usize, MapFlags)>>,
+    }
+
+    impl Arch for MockArch {
+        fn page_size(&self) -> usize {
+            0x1000
+        }
+
+        fn map(&self, virt: usize, phys: usize, flags: MapFlags) -> Result<(), &'static str> {
+            if self.translate(virt).is_some() {
+                return Err("page is already mapped");
+            }
+            self.mappings.borrow_mut().push((virt & !0xfff, phys & !0xfff, flags));
+            Ok(())
+        }
+
+        fn unmap(&self, virt: usize) -> Option<usize> {
+            let mut mappings = self.mappings.borrow_mut();
+            let index = mappings.iter().position(|&(page, _, _)| page == virt & !0xfff)?;
+            Some(mappings.remove(index).1 | virt & 0xfff)
+        }
+
+        fn translate(&self, virt: usize) -> Option<usize> {
+            self.mappings
+                .borrow()
+                .iter()
+                .find(|&&(page, _, _)| page == virt & !0xfff)
+                .map(|&(_, frame, _)| frame | virt & 0xfff)
+        }
+
+        fn disable_interrupts(&self) -> bool {
+            false
+        }
+
+        fn restore_interrupts(&self, _enabled: bool) {}
+
+        fn halt(&self) {}
+
+        unsafe fn read_port(&self, _port: u16) -> u8 {
+            0xff
+        }
+
+        unsafe fn write_port(&self, _port: u16, _value: u8) {}
+
+        unsafe fn read_mmio(&self, _address: usize) -> u32 {
+            0xffff_ffff
+        }
+
+        unsafe fn write_mmio(&self, _address: usize, _value: u32) {}
+    }
+
+    fn arch_mock() -> Result<(), &'static str> {
+        use device::mmio::map_mmio;
+
+        let arch = MockArch {
+            mappings: RefCell::new(Vec::new()),
+        };
+        arch.map(0xfed0_1000, 0xfed0_1000, MapFlags::WRITABLE)?;
+
+        // Starts partway into a page and ends partway into the third.
+        if map_mmio(&arch, 0xfed0_0010, 0x2000) != Ok(0xfed0_0010) {
+            return Err("could not map a register block");
+        }
+
+        let mappings = arch.mappings.borrow().clone();
+        let device = MapFlags::WRITABLE | MapFlags::NO_CACHE;
+        let expected = [
+            (0xfed0_1000, 0xfed0_1000, MapFlags::WRITABLE),
+            (0xfed0_0000, 0xfed0_0000, device),
+            (0xfed0_2000, 0xfed0_2000, device),
+        ];
+        let matches = mappings.iter().zip(expected.iter()).all(|(mapping, want)| mapping == want);
+        if mappings.len() != expected.len() || !matches {
+            return Err("the register block was not identity mapped uncached, page by page");
+        }
+
+        arch.map(0x5000, 0x9000, MapFlags::WRITABLE)?;
+        if map_mmio(&arch, 0x5000, 0x10).is_ok() {
+            Err("registers mapped somewhere else were accepted")
+        } else if arch.unmap(0x5010) != Some(0x9010) || arch.translate(0x5000).is_some() {
+            Err("the mock did not unmap")
+        } else {
+            Ok(())
+        }
+    }
+}
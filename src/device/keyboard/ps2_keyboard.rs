@@ -1,8 +1,9 @@
-use device::ps2_8042;
+use device::ps2;
 use device::keyboard;
 use alloc::Vec;
 use alloc::string::{String, ToString};
 use spin::Mutex;
+use klib::RingBuffer;
 
 /// A pair of keys on the left and the right of the keyboard.
 #[derive(Debug)]
@@ -110,12 +111,21 @@ impl ModifierState {
             ControlLeft(m) => self.control.left = m,
             ControlRight(m) => self.control.right = m,
             NumLock => self.num_lock = !self.num_lock,
-            ScrollLock => self.num_lock = !self.scroll_lock,
+            ScrollLock => self.scroll_lock = !self.scroll_lock,
             ShiftLeft(m) => self.shift.left = m,
             ShiftRight(m) => self.shift.right = m,
             FunctionKeys(m) => self.function_keys[m] = true,
         }
     }
+
+    /// Update modifier state for a key being let go of. Only the function keys say which key
+    /// they are rather than whether they are down, so the rest are handled by `update`.
+    fn release(&mut self, modifier: Modifiers) {
+        match modifier {
+            Modifiers::FunctionKeys(m) => self.function_keys[m] = false,
+            modifier => self.update(modifier),
+        }
+    }
 }
 
 /// Possible types of keyboard input we might receive.
@@ -133,20 +143,68 @@ pub enum KeyEvent {
 
 static STATE: Mutex<ModifierState> = Mutex::new(ModifierState::new());
 
+lazy_static! {
+    /// Characters typed on the keyboard, waiting to be read by `pop_byte`.
+    static ref INPUT: Mutex<RingBuffer<u8>> = Mutex::new(RingBuffer::new(0));
+}
+
+/// Return the oldest character typed on the keyboard, if any.
+pub fn pop_byte() -> Option<u8> {
+    INPUT.lock().pop()
+}
+
+/// Queue the ascii characters of `string` for `pop_byte`.
+fn queue_input(string: &str) {
+    let mut input = INPUT.lock();
+    for byte in string.bytes().filter(|&b| b < 0x80) {
+        input.push(byte);
+    }
+}
+
+/// Return the oldest character typed on the keyboard, if any, without waiting.
+pub fn read_char() -> Option<char> {
+    pop_byte().map(|byte| byte as char)
+}
+
 /// Parse the retrieved key and print the output or update modifier state dependant on the type of
-/// key received. This is called by our keyboard IRQ handler.
+/// key received. Releasing a key that is not a modifier does nothing.
 pub fn parse_key(scancode: u8) {
     let sequence: u64 = retrieve_bytes(scancode);
 
-    if let Some(key) = keyboard::get_key(sequence) {
-        match key {
-            Key::Ascii(k) => print_char(k as char),
-            Key::Meta(modifier) => STATE.lock().update(modifier),
-            Key::LowerAscii(byte) => print_str(STATE.lock().apply_to(byte as char)),
+    match keyboard::get_key_event(sequence) {
+        Some(KeyEvent::Pressed(Key::Ascii(k))) => {
+            INPUT.lock().push(k);
+            print_char(k as char)
+        }
+        Some(KeyEvent::Pressed(Key::Meta(modifier))) => STATE.lock().update(modifier),
+        Some(KeyEvent::Pressed(Key::LowerAscii(byte))) => {
+            let string = STATE.lock().apply_to(byte as char);
+            queue_input(&string);
+            print_str(string)
         }
+        Some(KeyEvent::Released(Key::Meta(modifier))) => STATE.lock().release(modifier),
+        Some(KeyEvent::Released(_)) | None => {}
     }
 }
 
+/// The IRQ the keyboard interrupts on, through the first PS/2 port.
+pub const KEYBOARD_IRQ: u8 = 1;
+
+/// Route the keyboard's IRQ. The PS/2 controller must already be initialised, which enables the
+/// first port and its interrupt.
+pub fn init() {
+    use device::apic;
+
+    apic::route_irq(KEYBOARD_IRQ);
+
+    println!("[ dev ] PS/2 keyboard initialised.");
+}
+
+/// Read the scancode waiting on the data port and act on it. This is called by the IRQ1 handler.
+pub fn handle_interrupt() {
+    parse_key(ps2::read_char());
+}
+
 /// Read bytes until end of sequence and combine into a number.
 fn retrieve_bytes(scancode: u8) -> u64 {
     let mut byte_sequence: Vec<u8> = vec![scancode];
@@ -155,17 +213,15 @@ fn retrieve_bytes(scancode: u8) -> u64 {
     // some keys are pressed. If they are the byte we receive, read until the end of the sequence.
     if scancode == 0xE0 || scancode == 0xE1 {
         // Read another byte from the keyboard.
-        let check: u8 = ps2_8042::read_char();
+        let check: u8 = ps2::read_char();
 
         if let Some(byte) = keyboard::is_special_key(check) {
             byte_sequence.push(byte);
         }
     }
 
-    byte_sequence
-        .iter()
-        .rev()
-        .fold(0, |acc, &b| (acc << 1) + b as u64)
+    // The prefix ends up in the high byte, to match the scancodes `keyboard` looks for.
+    byte_sequence.iter().fold(0, |acc, &b| (acc << 8) | b as u64)
 }
 
 /// Print an ascii character.
@@ -179,3 +235,45 @@ pub fn print_char(character: char) {
 pub fn print_str(string: String) {
     print!("{}", string);
 }
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "keyboard_scancodes",
+            run: keyboard_scancodes,
+        },
+    ];
+
+    fn keyboard_scancodes() -> Result<(), &'static str> {
+        use alloc::String;
+        use arch::without_interrupts;
+        use device::keyboard;
+
+        // a, shift down, a, 1, shift up, a, caps lock, a, caps lock, then a released a.
+        const SCANCODES: [u8; 10] = [0x1E, 0x2A, 0x1E, 0x02, 0xAA, 0x1E, 0x3A, 0x1E, 0x3A, 0x9E];
+        const EXPECTED: &str = "aA!aA";
+
+        let typed = without_interrupts(|| {
+            while keyboard::read_char().is_some() {}
+
+            for &scancode in SCANCODES.iter() {
+                keyboard::parse_key(scancode);
+            }
+
+            let mut typed = String::new();
+            while let Some(character) = keyboard::read_char() {
+                typed.push(character);
+            }
+            typed
+        });
+
+        if typed == EXPECTED {
+            Ok(())
+        } else {
+            Err("scancodes were not translated with the shift and caps lock state")
+        }
+    }
+}
@@ -1,24 +1,133 @@
 #[macro_use]
 pub mod io;
 pub mod keyboard;
-pub mod ps2_8042;
+pub mod ps2;
+pub mod mouse;
 pub mod vga;
 pub mod pic;
 pub mod pit;
+pub mod hpet;
 pub mod ahci;
+pub mod ata;
 pub mod pci;
 pub mod apic;
 pub mod serial;
+pub mod console;
+pub mod driver;
+pub mod qemu;
 
 pub use self::io::cpuio::{Port, UnsafePort};
 pub use self::io::mmio;
 
 use raw_cpuid::CpuId;
 
-/// Perform hardware init.
-pub unsafe fn init() {
+use self::driver::{Driver, FnDriver};
+
+static VGA_DRIVER: FnDriver = FnDriver {
+    name: "vga",
+    depends_on: &[],
+    init: vga_init,
+};
+
+static PIT_DRIVER: FnDriver = FnDriver {
+    name: "pit",
+    depends_on: &[],
+    init: pit_init,
+};
+
+static PS2_DRIVER: FnDriver = FnDriver {
+    name: "ps2",
+    depends_on: &[],
+    init: ps2_init,
+};
+
+static KEYBOARD_DRIVER: FnDriver = FnDriver {
+    name: "keyboard",
+    depends_on: &["ps2"],
+    init: keyboard_init,
+};
+
+static MOUSE_DRIVER: FnDriver = FnDriver {
+    name: "mouse",
+    depends_on: &["ps2"],
+    init: mouse_init,
+};
+
+static SERIAL_DRIVER: FnDriver = FnDriver {
+    name: "serial",
+    depends_on: &[],
+    init: serial_init,
+};
+
+static PCI_DRIVER: FnDriver = FnDriver {
+    name: "pci",
+    depends_on: &[],
+    init: pci_init,
+};
+
+static ATA_DRIVER: FnDriver = FnDriver {
+    name: "ata",
+    depends_on: &["pit"],
+    init: ata_init,
+};
+
+static HPET_DRIVER: FnDriver = FnDriver {
+    name: "hpet",
+    depends_on: &["pit"],
+    init: hpet_init,
+};
+
+unsafe fn vga_init() {
     vga::init();
-    pit::init();
-    ps2_8042::PS2.lock().init();
+}
+
+unsafe fn pit_init() {
+    pit::init(pit::configured_frequency());
+}
+
+unsafe fn hpet_init() {
+    hpet::init();
+}
+
+unsafe fn ps2_init() {
+    ps2::PS2.lock().init();
+}
+
+unsafe fn keyboard_init() {
+    keyboard::init();
+}
+
+unsafe fn mouse_init() {
+    mouse::init();
+}
+
+unsafe fn serial_init() {
+    serial::init_rx();
+}
+
+unsafe fn pci_init() {
     pci::init();
 }
+
+unsafe fn ata_init() {
+    ata::init();
+}
+
+/// Perform hardware init, initialising each driver after the drivers it depends on.
+pub unsafe fn init() {
+    let drivers: [&Driver; 9] = [
+        &VGA_DRIVER,
+        &PIT_DRIVER,
+        &HPET_DRIVER,
+        &PS2_DRIVER,
+        &KEYBOARD_DRIVER,
+        &MOUSE_DRIVER,
+        &SERIAL_DRIVER,
+        &PCI_DRIVER,
+        &ATA_DRIVER,
+    ];
+
+    if let Err(err) = driver::init_all(&drivers) {
+        panic!("Could not initialise drivers: {:?}", err);
+    }
+}
@@ -0,0 +1,231 @@
+//! PS/2 mouse driver. The mouse sits on the second port of the 8042 controller and sends 3 byte
+//! movement packets on IRQ12, which are decoded into `MouseEvent`s and queued for `poll`.
+
+use device::ps2::{Config, Ps2Port, PS2};
+use device::apic;
+use klib::RingBuffer;
+use spin::Mutex;
+
+/// The mouse acknowledges every command with this byte.
+const ACK: u8 = 0xFA;
+/// Restore the default settings.
+const CMD_SET_DEFAULTS: u8 = 0xF6;
+/// Set the number of samples per second, followed by the rate.
+const CMD_SET_SAMPLE_RATE: u8 = 0xF3;
+/// Start sending movement packets.
+const CMD_ENABLE_REPORTING: u8 = 0xF4;
+
+/// The sample rate we ask for, in samples per second.
+const SAMPLE_RATE: u8 = 100;
+
+/// The ISA IRQ of the second PS/2 port.
+pub const MOUSE_IRQ: u8 = 12;
+
+bitflags! {
+    /// The first byte of a movement packet.
+    struct PacketFlags: u8 {
+        const LEFT_BUTTON =     1 << 0;
+        const RIGHT_BUTTON =    1 << 1;
+        const MIDDLE_BUTTON =   1 << 2;
+        /// Always set, used to find the start of a packet.
+        const ALWAYS_ONE =      1 << 3;
+        const X_SIGN =          1 << 4;
+        const Y_SIGN =          1 << 5;
+        const X_OVERFLOW =      1 << 6;
+        const Y_OVERFLOW =      1 << 7;
+    }
+}
+
+/// A single decoded movement packet. `dx` grows to the right and `dy` grows upwards.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+impl MouseEvent {
+    /// Decode a 3 byte movement packet. Movement on an axis whose overflow bit is set is
+    /// unreliable, and is reported as zero.
+    pub fn from_packet(packet: [u8; 3]) -> MouseEvent {
+        let flags = PacketFlags::from_bits_truncate(packet[0]);
+
+        // The movement is a 9 bit two's complement number, with the sign bit in the first byte.
+        let delta = |value: u8, sign: bool, overflow: bool| -> i16 {
+            if overflow {
+                0
+            } else if sign {
+                value as i16 - 0x100
+            } else {
+                value as i16
+            }
+        };
+
+        MouseEvent {
+            dx: delta(
+                packet[1],
+                flags.contains(PacketFlags::X_SIGN),
+                flags.contains(PacketFlags::X_OVERFLOW),
+            ),
+            dy: delta(
+                packet[2],
+                flags.contains(PacketFlags::Y_SIGN),
+                flags.contains(PacketFlags::Y_OVERFLOW),
+            ),
+            left: flags.contains(PacketFlags::LEFT_BUTTON),
+            right: flags.contains(PacketFlags::RIGHT_BUTTON),
+            middle: flags.contains(PacketFlags::MIDDLE_BUTTON),
+        }
+    }
+}
+
+/// Packet assembly state.
+struct Mouse {
+    packet: [u8; 3],
+    index: usize,
+}
+
+impl Mouse {
+    const fn new() -> Mouse {
+        Mouse {
+            packet: [0; 3],
+            index: 0,
+        }
+    }
+
+    /// Add a byte to the current packet, returning the decoded event once it is complete.
+    fn push(&mut self, byte: u8) -> Option<MouseEvent> {
+        // Resynchronise if we lost a byte: the first byte always has bit 3 set.
+        if self.index == 0 && byte & PacketFlags::ALWAYS_ONE.bits() == 0 {
+            return None;
+        }
+
+        self.packet[self.index] = byte;
+        self.index += 1;
+
+        if self.index == 3 {
+            self.index = 0;
+            Some(MouseEvent::from_packet(self.packet))
+        } else {
+            None
+        }
+    }
+}
+
+static MOUSE: Mutex<Mouse> = Mutex::new(Mouse::new());
+
+lazy_static! {
+    static ref EVENTS: Mutex<RingBuffer<MouseEvent>> = Mutex::new(RingBuffer::new(MouseEvent {
+        dx: 0,
+        dy: 0,
+        left: false,
+        right: false,
+        middle: false,
+    }));
+}
+
+/// Send a command to the mouse and check that it was acknowledged.
+fn command(byte: u8) -> bool {
+    let mut ps2 = PS2.lock();
+    ps2.write_device(Ps2Port::Second, byte);
+    ps2.read_data() == ACK
+}
+
+/// Enable the auxiliary device, configure the mouse and route IRQ12.
+pub fn init() {
+    println!("[ dev ] Initialising PS/2 mouse.");
+
+    {
+        let mut ps2 = PS2.lock();
+
+        if !ps2.test_port(Ps2Port::Second) {
+            println!("[ dev ] No PS/2 mouse found.");
+            return;
+        }
+
+        ps2.enable_port(Ps2Port::Second);
+
+        let mut config = ps2.read_config();
+        config.insert(Config::SECOND_INTERRUPT);
+        config.remove(Config::SECOND_CLOCK_OFF);
+        ps2.write_config(config);
+    }
+
+    if !(command(CMD_SET_DEFAULTS) && command(CMD_SET_SAMPLE_RATE) && command(SAMPLE_RATE)
+        && command(CMD_ENABLE_REPORTING))
+    {
+        println!("[ dev ] PS/2 mouse did not acknowledge setup.");
+        return;
+    }
+
+    apic::route_irq(MOUSE_IRQ);
+
+    println!("[ dev ] PS/2 mouse initialised.");
+}
+
+/// Handle a byte received from the mouse. This is called by the IRQ12 handler.
+pub fn handle_byte(byte: u8) {
+    if let Some(event) = MOUSE.lock().push(byte) {
+        // Drop events if nobody is reading them.
+        EVENTS.lock().push(event);
+    }
+}
+
+/// Return the oldest mouse event that has not been read yet.
+pub fn poll() -> Option<MouseEvent> {
+    EVENTS.lock().pop()
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "mouse_packet",
+            run: mouse_packet,
+        },
+    ];
+
+    fn mouse_packet() -> Result<(), &'static str> {
+        use device::mouse::{Mouse, MouseEvent};
+
+        // Left button, always-one bit and X sign: 5 to the left and 5 up.
+        let left_up = MouseEvent {
+            dx: -5,
+            dy: 5,
+            left: true,
+            right: false,
+            middle: false,
+        };
+        if MouseEvent::from_packet([0x19, 0xfb, 0x05]) != left_up {
+            return Err("a packet moving left and up was decoded wrongly");
+        }
+
+        // Right and middle buttons, Y sign, and X overflow.
+        let overflowed = MouseEvent {
+            dx: 0,
+            dy: -256,
+            left: false,
+            right: true,
+            middle: true,
+        };
+        if MouseEvent::from_packet([0x6e, 0x80, 0x00]) != overflowed {
+            return Err("overflow or sign bits in a packet were decoded wrongly");
+        }
+
+        // A stray byte without the always-one bit is skipped rather than starting a packet.
+        let mut mouse = Mouse::new();
+        let events = [0x02, 0x19, 0xfb]
+            .iter()
+            .filter_map(|&byte| mouse.push(byte))
+            .count();
+        if events != 0 || mouse.push(0x05) != Some(left_up) {
+            Err("a packet after a stray byte was not reassembled")
+        } else {
+            Ok(())
+        }
+    }
+}
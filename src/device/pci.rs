@@ -13,6 +13,31 @@ const MAX_DEVICE: u8 = 31;
 #[allow(dead_code)]
 const MAX_FUNCTION: u8 = 7;
 
+/// The offset of the command register. It is the low half of a dword whose high half is the
+/// status register.
+const COMMAND: u32 = 0x04;
+
+/// Set in the command register to let the device respond to I/O space accesses.
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+/// Set in the command register to let the device respond to memory space accesses, such as MMIO.
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+/// Set in the command register to let the device master the bus, which DMA needs.
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+/// Access to a device's configuration space, so that register updates can be run against a fake
+/// device.
+pub trait ConfigSpace {
+    fn read_config(&mut self, offset: u32) -> u32;
+    fn write_config(&mut self, offset: u32, value: u32);
+}
+
+/// Set `bits` in the command register, keeping the ones already set. The status register's bits
+/// are cleared by writing 1 to them, so its half of the dword is written back as 0.
+pub fn set_command_bits<C: ConfigSpace>(config: &mut C, bits: u16) {
+    let command = config.read_config(COMMAND) as u16;
+    config.write_config(COMMAND, (command | bits) as u32);
+}
+
 static PCI: Mutex<Pci> = Mutex::new(Pci {
     cfg_address: unsafe { Port::new(0xCF8) },
     cfg_data: unsafe { Port::new(0xCFC) },
@@ -37,13 +62,25 @@ impl Pci {
         self.cfg_data.read()
     }
 
+    /// Read an aligned dword from the PCI configuration space, returning `None` if there is no
+    /// device at this address.
+    pub unsafe fn read_config_present(
+        &mut self,
+        bus: u8,
+        slot: u8,
+        func: u8,
+        offset: u8,
+    ) -> Option<u32> {
+        let address: u32 = 0x80000000 | (bus as u32) << 16 | (slot as u32) << 11
+            | (func as u32) << 8 | (offset & 0xFC) as u32;
+
+        self.cfg_address.write(address);
+        self.cfg_data.read_present()
+    }
+
     /// Read data from `CFG_DATA` to determine unique info about a device.
     pub unsafe fn probe(&mut self, bus: u8, slot: u8, function: u8) -> Option<Device> {
-        let config_0 = self.read_config(bus, slot, function, 0);
-
-        if config_0 == 0xFFFFFFFF {
-            return None;
-        }
+        let config_0 = self.read_config_present(bus, slot, function, 0)?;
 
         let config_4 = self.read_config(bus, slot, function, 0x8);
         let config_c = self.read_config(bus, slot, function, 0xC);
@@ -182,6 +219,28 @@ impl Device {
     pub fn bar(&self, index: usize) -> u32 {
         self.bars[index]
     }
+
+    /// Let the device master the bus, so that it can do DMA.
+    pub fn enable_bus_master(&self) {
+        let mut config = self;
+        set_command_bits(&mut config, COMMAND_BUS_MASTER);
+    }
+
+    /// Let the device respond to accesses to its memory BARs.
+    pub fn enable_memory_space(&self) {
+        let mut config = self;
+        set_command_bits(&mut config, COMMAND_MEMORY_SPACE);
+    }
+}
+
+impl<'a> ConfigSpace for &'a Device {
+    fn read_config(&mut self, offset: u32) -> u32 {
+        unsafe { self.read(offset) }
+    }
+
+    fn write_config(&mut self, offset: u32, value: u32) {
+        unsafe { self.write(offset, value) }
+    }
 }
 
 fn init_dev(bus: u8, dev: u8) {
@@ -245,3 +304,59 @@ pub fn init() {
         }
     }
 }
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use alloc::Vec;
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "pci_command",
+            run: pci_command,
+        },
+    ];
+
+    fn pci_command() -> Result<(), &'static str> {
+        use device::pci::{self, ConfigSpace, COMMAND_BUS_MASTER, COMMAND_IO_SPACE,
+                          COMMAND_MEMORY_SPACE};
+
+        struct FakeDevice {
+            config: [u32; 64],
+            writes: Vec<(u32, u32)>,
+        }
+
+        impl ConfigSpace for FakeDevice {
+            fn read_config(&mut self, offset: u32) -> u32 {
+                self.config[offset as usize / 4]
+            }
+
+            fn write_config(&mut self, offset: u32, value: u32) {
+                self.config[offset as usize / 4] = value;
+                self.writes.push((offset, value));
+            }
+        }
+
+        // I/O space and SERR# enabled, with the capabilities list and two error bits in the status.
+        let mut device = FakeDevice {
+            config: [0; 64],
+            writes: Vec::new(),
+        };
+        device.config[1] = 0x4290_0101;
+
+        pci::set_command_bits(&mut device, COMMAND_BUS_MASTER);
+        pci::set_command_bits(&mut device, COMMAND_MEMORY_SPACE);
+
+        let expected =
+            (0x0100 | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER) as u32;
+        if device.writes.len() != 2 || device.writes.iter().any(|&(offset, _)| offset != 0x04) {
+            Err("wrote somewhere other than the command register")
+        } else if device.config[1] & 0xffff != expected {
+            Err("the command register did not get exactly the requested bits added")
+        } else if device.writes.iter().any(|&(_, value)| value >> 16 != 0) {
+            Err("wrote 1s to the status register, which would clear its bits")
+        } else {
+            Ok(())
+        }
+    }
+}
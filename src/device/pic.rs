@@ -98,6 +98,19 @@ impl ChainedPics {
         println!("[ dev ] PIC1 has vector offset: {:#x}", self.pics[1].offset);
     }
 
+    /// Change the vector offsets of both PICs and reinitialise them. `handles_interrupt` and EOI
+    /// routing use the new offsets from then on.
+    pub unsafe fn remap(&mut self, offset1: u8, offset2: u8) {
+        self.pics[0].offset = offset1;
+        self.pics[1].offset = offset2;
+        self.init();
+    }
+
+    /// Return the vector offsets of the master and slave PICs.
+    pub fn offsets(&self) -> (u8, u8) {
+        (self.pics[0].offset, self.pics[1].offset)
+    }
+
     /// Cycle through the PICS until we find one that can handle this interrupt.
     pub fn handles_interrupt(&self, interrupt_id: u8) -> bool {
         self.pics.iter().any(|p| p.handles_interrupt(interrupt_id))
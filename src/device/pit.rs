@@ -1,32 +1,239 @@
+use arch::args::KernelArgs;
 use device::Port;
 use spin::Mutex;
-use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT};
+use task::WaitQueue;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
 /// Configuration data. Use channel 0 and mode 3, square wave generator. Use lohi operation.
 const PIT_SET: u8 = 0x36;
-static DIVISOR: u16 = 2685;
+/// Configuration data. Use channel 0 and mode 0, interrupt on terminal count, which does not start
+/// counting until a count is written.
+const PIT_STOP: u8 = 0x30;
+/// The frequency of the PIT's input clock, in Hz.
+pub const BASE_FREQUENCY: u32 = 1193182;
+/// The frequency used if no `hz` kernel argument is given.
+const DEFAULT_HZ: u32 = 444;
+/// The lowest frequency we accept, just above what the largest 16-bit divisor gives.
+pub const MIN_HZ: u32 = 19;
+/// The highest frequency we accept.
+pub const MAX_HZ: u32 = 1000;
+
+/// The frequency the PIT has been programmed with, in Hz.
+static FREQUENCY: AtomicUsize = ATOMIC_USIZE_INIT;
 
 /// Simple interface to the PIT.
 pub static PIT: Mutex<[Port<u8>; 2]> = Mutex::new(unsafe { [Port::new(0x43), Port::new(0x40)] });
 
-pub fn init() {
+/// Return the divisor that makes the PIT tick at `hz`, clamped to between `MIN_HZ` and `MAX_HZ`.
+pub fn divisor_for(hz: u32) -> u16 {
+    let hz = hz.max(MIN_HZ).min(MAX_HZ);
+    (BASE_FREQUENCY / hz) as u16
+}
+
+/// Return the frequency requested by the `hz` kernel argument, or the default one.
+pub fn configured_frequency() -> u32 {
+    frequency_from_args(::arch::args::get())
+}
+
+/// Return the frequency requested by the `hz` argument in `args`, or the default one.
+fn frequency_from_args(args: &KernelArgs) -> u32 {
+    match args.get("hz").map(|hz| hz.parse::<u32>()) {
+        Some(Ok(hz)) => hz,
+        Some(Err(_)) => {
+            println!("[ dev ] Ignoring invalid hz kernel argument.");
+            DEFAULT_HZ
+        }
+        None => DEFAULT_HZ,
+    }
+}
+
+/// Program channel 0 to raise IRQ0 `frequency` times a second, clamped to between `MIN_HZ` and
+/// `MAX_HZ`.
+pub fn init(frequency: u32) {
+    let divisor = divisor_for(frequency);
+
     println!("[ dev ] Setting pit mode.");
     PIT.lock()[0].write(PIT_SET);
     println!("[ dev ] Setting up frequency.");
-    PIT.lock()[1].write((DIVISOR & 0xFF) as u8);
-    PIT.lock()[1].write((DIVISOR >> 8) as u8);
-
-    let frequency: u32 = 1193182 / 2685;
+    PIT.lock()[1].write((divisor & 0xFF) as u8);
+    PIT.lock()[1].write((divisor >> 8) as u8);
 
-    let irq0_int_timeout = {
-        let val = 1 / frequency;
-        val * 1000
-    };
+    let frequency = BASE_FREQUENCY / divisor as u32;
+    FREQUENCY.store(frequency as usize, Ordering::SeqCst);
 
     println!(
-        "[ dev ] Initialising PIT, setup to interrupt every {} ms",
-        irq0_int_timeout
+        "[ dev ] Initialising PIT at {} Hz, setup to interrupt every {} us",
+        frequency,
+        1_000_000 / frequency
     );
 }
 
+/// Stop the PIT from raising interrupts, for when another timer provides the tick. `frequency`
+/// keeps returning the configured frequency, which the replacement is expected to tick at.
+pub fn stop() {
+    PIT.lock()[0].write(PIT_STOP);
+    println!("[ dev ] Stopped the PIT.");
+}
+
+/// Return the frequency the PIT ticks at, in Hz.
+pub fn frequency() -> usize {
+    match FREQUENCY.load(Ordering::SeqCst) {
+        0 => (BASE_FREQUENCY / divisor_for(DEFAULT_HZ) as u32) as usize,
+        frequency => frequency,
+    }
+}
+
+/// Convert a number of PIT ticks to milliseconds.
+pub fn ticks_to_ms(ticks: usize) -> usize {
+    ticks * 1000 / frequency()
+}
+
+/// Convert milliseconds to a number of PIT ticks, rounding up.
+pub fn ms_to_ticks(ms: usize) -> usize {
+    (ms * frequency() + 999) / 1000
+}
+
 pub static PIT_TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// PIT ticks since the PIT was initialised.
+static UPTIME_TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The number of timeouts that can be pending at once.
+const MAX_TIMEOUTS: usize = 8;
+
+/// A wait queue to wake once `deadline` has passed.
+#[derive(Clone, Copy)]
+struct Timeout {
+    deadline: usize,
+    queue: &'static WaitQueue,
+}
+
+/// Pending timeouts. Only locked with interrupts disabled, since the timer handler takes it.
+static TIMEOUTS: Mutex<[Option<Timeout>; MAX_TIMEOUTS]> = Mutex::new([None; MAX_TIMEOUTS]);
+
+/// Return the number of ticks since the PIT was initialised.
+pub fn ticks() -> usize {
+    UPTIME_TICKS.load(Ordering::SeqCst)
+}
+
+/// Return the number of milliseconds since the PIT was initialised.
+pub fn uptime_ms() -> usize {
+    ticks_to_ms(ticks())
+}
+
+/// Wake everything waiting on `queue` once `ticks()` reaches `deadline`. The queue is woken
+/// regardless of what its waiters are waiting for, so they must check the deadline themselves.
+/// Returns `false` if too many timeouts are already pending.
+pub fn wake_at(deadline: usize, queue: &'static WaitQueue) -> bool {
+    use arch::without_interrupts;
+
+    without_interrupts(|| {
+        let mut timeouts = TIMEOUTS.lock();
+        match timeouts.iter_mut().find(|timeout| timeout.is_none()) {
+            Some(slot) => {
+                *slot = Some(Timeout {
+                    deadline: deadline,
+                    queue: queue,
+                });
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Cancel a timeout set with `wake_at`, once whatever it guarded has happened, so that it does not
+/// keep a slot until it expires. Does nothing if it has already fired.
+pub fn cancel_wake(deadline: usize, queue: &'static WaitQueue) {
+    use arch::without_interrupts;
+
+    without_interrupts(|| {
+        let mut timeouts = TIMEOUTS.lock();
+        let slot = timeouts.iter_mut().find(|timeout| {
+            timeout.map_or(false, |timeout| {
+                timeout.deadline == deadline && ptr::eq(timeout.queue, queue)
+            })
+        });
+        if let Some(slot) = slot {
+            *slot = None;
+        }
+    })
+}
+
+/// Count a timer interrupt and wake any timeouts that have expired. Called by the timer handler.
+pub fn tick() {
+    let now = UPTIME_TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+
+    let mut expired: [Option<&'static WaitQueue>; MAX_TIMEOUTS] = [None; MAX_TIMEOUTS];
+    {
+        let mut timeouts = TIMEOUTS.lock();
+        for (slot, expired) in timeouts.iter_mut().zip(expired.iter_mut()) {
+            if slot.map_or(false, |timeout| timeout.deadline <= now) {
+                *expired = slot.take().map(|timeout| timeout.queue);
+            }
+        }
+    }
+
+    for queue in expired.iter().filter_map(|queue| *queue) {
+        queue.wake_all();
+    }
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "pit_frequency",
+            run: pit_frequency,
+        },
+        KTest {
+            name: "pit_hz_arg",
+            run: pit_hz_arg,
+        },
+    ];
+
+    fn pit_frequency() -> Result<(), &'static str> {
+        use arch::ARCH;
+        use device::pit::{self, BASE_FREQUENCY, MAX_HZ, MIN_HZ};
+
+        let deadline = pit::ticks() + 2;
+        while pit::ticks() < deadline {
+            ARCH.halt();
+        }
+
+        if pit::divisor_for(100) != 11931 || pit::divisor_for(1000) != 1193 {
+            Err("the divisor is not the base frequency over the requested one")
+        } else if pit::divisor_for(0) as u32 != BASE_FREQUENCY / MIN_HZ
+            || pit::divisor_for(100_000) as u32 != BASE_FREQUENCY / MAX_HZ
+        {
+            Err("frequencies out of range were not clamped")
+        } else if pit::uptime_ms() < pit::ticks_to_ms(deadline) {
+            Err("the uptime did not advance with the ticks")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn pit_hz_arg() -> Result<(), &'static str> {
+        use arch::args::KernelArgs;
+        use device::pit::{divisor_for, frequency_from_args, DEFAULT_HZ};
+
+        let divisor = |command_line: &str| {
+            divisor_for(frequency_from_args(&KernelArgs::parse(command_line)))
+        };
+        let default = divisor_for(DEFAULT_HZ);
+
+        if divisor("quiet hz=250 ktest") != 4772 {
+            Err("hz=250 did not give the divisor for 250 Hz")
+        } else if divisor("hz=fast") != default || divisor("") != default {
+            Err("the default frequency was not used without a valid hz argument")
+        } else if divisor("hz=5000") != 1193 {
+            Err("a frequency above the maximum was not clamped")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! The 8042 PS/2 controller. Both the keyboard (first port) and the mouse (second port) talk to
+//! the CPU through it, using the data port at 0x60 and the status/command port at 0x64.
+
+use spin::Mutex;
+use device::io::Port;
+
+/// Read the controller configuration byte.
+const CMD_READ_CONFIG: u8 = 0x20;
+/// Write the controller configuration byte.
+const CMD_WRITE_CONFIG: u8 = 0x60;
+/// Disable the second PS/2 port.
+const CMD_DISABLE_SECOND: u8 = 0xA7;
+/// Enable the second PS/2 port.
+const CMD_ENABLE_SECOND: u8 = 0xA8;
+/// Test the second PS/2 port.
+const CMD_TEST_SECOND: u8 = 0xA9;
+/// Test the controller.
+const CMD_SELF_TEST: u8 = 0xAA;
+/// Test the first PS/2 port.
+const CMD_TEST_FIRST: u8 = 0xAB;
+/// Disable the first PS/2 port.
+const CMD_DISABLE_FIRST: u8 = 0xAD;
+/// Enable the first PS/2 port.
+const CMD_ENABLE_FIRST: u8 = 0xAE;
+/// Send the next data byte to the second PS/2 port instead of the first.
+const CMD_WRITE_SECOND: u8 = 0xD4;
+/// Pulse the CPU reset line.
+const CMD_PULSE_RESET: u8 = 0xFE;
+
+/// Response to a successful controller self test.
+const SELF_TEST_PASSED: u8 = 0x55;
+/// Response to a successful port test.
+const PORT_TEST_PASSED: u8 = 0x00;
+
+bitflags! {
+    /// The status register, read from the command port.
+    pub struct Status: u8 {
+        /// There is data waiting to be read from the data port.
+        const OUTPUT_FULL =     1 << 0;
+        /// The controller has not yet consumed the last byte written.
+        const INPUT_FULL =      1 << 1;
+        /// The last byte written was a command rather than data.
+        const COMMAND =         1 << 3;
+        /// The waiting data came from the second port.
+        const SECOND_PORT =     1 << 5;
+        /// A timeout error occured.
+        const TIMEOUT_ERROR =   1 << 6;
+        /// A parity error occured.
+        const PARITY_ERROR =    1 << 7;
+    }
+}
+
+bitflags! {
+    /// The controller configuration byte.
+    pub struct Config: u8 {
+        /// Interrupts (IRQ1) for the first port.
+        const FIRST_INTERRUPT =     1 << 0;
+        /// Interrupts (IRQ12) for the second port.
+        const SECOND_INTERRUPT =    1 << 1;
+        /// The system passed POST.
+        const SYSTEM_FLAG =         1 << 2;
+        /// The clock of the first port is disabled.
+        const FIRST_CLOCK_OFF =     1 << 4;
+        /// The clock of the second port is disabled.
+        const SECOND_CLOCK_OFF =    1 << 5;
+        /// Scancodes from the first port are translated to set 1.
+        const FIRST_TRANSLATE =     1 << 6;
+    }
+}
+
+/// One of the two devices attached to the controller.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ps2Port {
+    /// The first port, usually the keyboard.
+    First,
+    /// The second (auxiliary) port, usually the mouse.
+    Second,
+}
+
+/// The controller's command and data ports, so that commands can be run against a fake controller.
+pub trait Ps2Io {
+    /// Read the status register.
+    fn read_status(&mut self) -> u8;
+    /// Write to the command register.
+    fn write_command(&mut self, command: u8);
+    fn read_data(&mut self) -> u8;
+    fn write_data(&mut self, data: u8);
+}
+
+/// The I/O ports of a real controller.
+pub struct Ports {
+    /// Status register on read, command register on write.
+    command: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Ps2Io for Ports {
+    fn read_status(&mut self) -> u8 {
+        self.command.read()
+    }
+
+    fn write_command(&mut self, command: u8) {
+        self.command.write(command)
+    }
+
+    fn read_data(&mut self) -> u8 {
+        self.data.read()
+    }
+
+    fn write_data(&mut self, data: u8) {
+        self.data.write(data)
+    }
+}
+
+pub struct Ps2Controller<P: Ps2Io = Ports> {
+    io: P,
+}
+
+impl Ps2Controller {
+    pub const unsafe fn new(command: u16, data: u16) -> Ps2Controller {
+        Ps2Controller {
+            io: Ports {
+                command: Port::new(command),
+                data: Port::new(data),
+            },
+        }
+    }
+}
+
+impl<P: Ps2Io> Ps2Controller<P> {
+    /// A controller driven through `io` rather than the real ports.
+    pub fn with_io(io: P) -> Ps2Controller<P> {
+        Ps2Controller { io: io }
+    }
+
+    /// Read the status register.
+    pub fn status(&mut self) -> Status {
+        Status::from_bits_truncate(self.io.read_status())
+    }
+
+    /// Poll the status register until there is data to read, and then read it.
+    pub fn read_data(&mut self) -> u8 {
+        while !self.status().contains(Status::OUTPUT_FULL) {}
+        self.io.read_data()
+    }
+
+    /// Poll the status register until the controller is ready for input, and then write `data`.
+    pub fn write_data(&mut self, data: u8) {
+        while self.status().contains(Status::INPUT_FULL) {}
+        self.io.write_data(data);
+    }
+
+    /// Send a command to the controller.
+    pub fn write_command(&mut self, command: u8) {
+        while self.status().contains(Status::INPUT_FULL) {}
+        self.io.write_command(command);
+    }
+
+    /// Reset the machine through the controller's CPU reset line. Returns if the controller does
+    /// not have one wired up.
+    pub fn pulse_reset(&mut self) {
+        self.write_command(CMD_PULSE_RESET);
+    }
+
+    /// Send a byte to the device on `port`.
+    pub fn write_device(&mut self, port: Ps2Port, data: u8) {
+        if port == Ps2Port::Second {
+            self.write_command(CMD_WRITE_SECOND);
+        }
+        self.write_data(data);
+    }
+
+    /// Read the controller configuration byte.
+    pub fn read_config(&mut self) -> Config {
+        self.write_command(CMD_READ_CONFIG);
+        Config::from_bits_truncate(self.read_data())
+    }
+
+    /// Write the controller configuration byte.
+    pub fn write_config(&mut self, config: Config) {
+        self.write_command(CMD_WRITE_CONFIG);
+        self.write_data(config.bits());
+    }
+
+    /// Run the controller self test. Returns `true` if it passed.
+    pub fn self_test(&mut self) -> bool {
+        self.write_command(CMD_SELF_TEST);
+        self.read_data() == SELF_TEST_PASSED
+    }
+
+    /// Test the interface to `port`. Returns `true` if it passed.
+    pub fn test_port(&mut self, port: Ps2Port) -> bool {
+        self.write_command(match port {
+            Ps2Port::First => CMD_TEST_FIRST,
+            Ps2Port::Second => CMD_TEST_SECOND,
+        });
+        self.read_data() == PORT_TEST_PASSED
+    }
+
+    /// Enable the device on `port`.
+    pub fn enable_port(&mut self, port: Ps2Port) {
+        self.write_command(match port {
+            Ps2Port::First => CMD_ENABLE_FIRST,
+            Ps2Port::Second => CMD_ENABLE_SECOND,
+        });
+    }
+
+    /// Disable the device on `port`.
+    pub fn disable_port(&mut self, port: Ps2Port) {
+        self.write_command(match port {
+            Ps2Port::First => CMD_DISABLE_FIRST,
+            Ps2Port::Second => CMD_DISABLE_SECOND,
+        });
+    }
+
+    pub fn init(&mut self) {
+        println!("[ dev ] Initialising PS/2 8042 controller.");
+        // Disable devices.
+        self.disable_port(Ps2Port::First);
+        self.disable_port(Ps2Port::Second);
+
+        // Flush output buffer.
+        self.io.read_data();
+
+        // Disable IRQs while we test the controller.
+        let mut config = self.read_config();
+        config.remove(Config::FIRST_INTERRUPT | Config::SECOND_INTERRUPT);
+        self.write_config(config);
+
+        // Controller self test.
+        assert!(self.self_test(), "PS/2 self test failed");
+
+        // Interface tests.
+        assert!(self.test_port(Ps2Port::First), "Interface tests failed");
+
+        // Enable devices.
+        self.enable_port(Ps2Port::First);
+
+        // Re-enable IRQs.
+        let mut config = self.read_config();
+        config.insert(Config::FIRST_INTERRUPT);
+        self.write_config(config);
+
+        // Clear output buffer.
+        self.io.read_data();
+
+        println!("[ dev ] PS/2 8042 initialised.");
+    }
+
+    /// Read a byte from the data port without waiting.
+    pub fn read_char(&mut self) -> u8 {
+        self.io.read_data()
+    }
+}
+
+pub static PS2: Mutex<Ps2Controller> = Mutex::new(unsafe { Ps2Controller::new(0x64, 0x60) });
+
+pub fn read_char() -> u8 {
+    PS2.lock().read_char()
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use alloc::Vec;
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "ps2_self_test",
+            run: ps2_self_test,
+        },
+    ];
+
+    fn ps2_self_test() -> Result<(), &'static str> {
+        use device::ps2::{Ps2Controller, Ps2Io, Status, CMD_SELF_TEST};
+
+        /// A controller that is busy for the first status read, and answers the self test with
+        /// `reply`.
+        struct FakeController {
+            reply: u8,
+            busy: bool,
+            output: Option<u8>,
+            commands: Vec<u8>,
+        }
+
+        impl Ps2Io for FakeController {
+            fn read_status(&mut self) -> u8 {
+                let mut status = Status::empty();
+                if self.busy {
+                    self.busy = false;
+                    status.insert(Status::INPUT_FULL);
+                }
+                if self.output.is_some() {
+                    status.insert(Status::OUTPUT_FULL);
+                }
+                status.bits()
+            }
+
+            fn write_command(&mut self, command: u8) {
+                if self.busy {
+                    // Dropped, as a real controller would.
+                    return;
+                }
+                self.commands.push(command);
+                if command == CMD_SELF_TEST {
+                    self.output = Some(self.reply);
+                }
+            }
+
+            fn read_data(&mut self) -> u8 {
+                self.output.take().unwrap_or(0xff)
+            }
+
+            fn write_data(&mut self, _data: u8) {}
+        }
+
+        let run = |reply| {
+            let mut ps2 = Ps2Controller::with_io(FakeController {
+                reply: reply,
+                busy: true,
+                output: None,
+                commands: Vec::new(),
+            });
+            let passed = ps2.self_test();
+            (passed, ps2.io.commands, ps2.io.output)
+        };
+
+        let (passed, commands, output) = run(0x55);
+        if commands != [CMD_SELF_TEST] {
+            Err("the self test was not a single 0xAA command sent once the controller was ready")
+        } else if output.is_some() {
+            Err("the self test reply was not read")
+        } else if !passed {
+            Err("a 0x55 reply did not pass the self test")
+        } else if run(0xfc).0 {
+            Err("a 0xFC reply passed the self test")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,28 @@
+//! QEMU's `isa-debug-exit` device, which lets the kernel end a QEMU session with an exit code.
+//! QEMU must be started with `-device isa-debug-exit,iobase=0xf4,iosize=0x04`. On real hardware
+//! the write goes nowhere.
+
+use device::Port;
+
+/// The I/O port of the `isa-debug-exit` device.
+const EXIT_PORT: u16 = 0xf4;
+
+/// Exit codes. QEMU exits with `(code << 1) | 1`, so these become 33 and 35.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u32)]
+pub enum ExitCode {
+    Success = 0x10,
+    Failure = 0x11,
+}
+
+/// Exit QEMU with `code`. If we are not running under QEMU, halt forever instead.
+pub fn exit(code: ExitCode) -> ! {
+    unsafe {
+        let mut port: Port<u32> = Port::new(EXIT_PORT);
+        port.write(code as u32);
+    }
+
+    loop {
+        unsafe { asm!("cli; hlt" :::: "volatile") };
+    }
+}
@@ -1,5 +1,6 @@
 use spin::Mutex;
-use device::vga::vga::{Color, ColorCode, VGA};
+use device::vga::vga::VGA;
+pub use device::vga::vga::{get_cursor, set_cursor, Color, ColorCode};
 use core::fmt;
 
 /// Main print interface.
@@ -12,27 +13,98 @@ pub fn print(args: fmt::Arguments) {
 pub const BUFFER_WIDTH: usize = 80;
 /// The height of the VGA text buffer.
 pub const BUFFER_HEIGHT: usize = 25;
+/// The distance between tab stops.
+pub const TAB_WIDTH: usize = 4;
+/// What is shown for a character the VGA font has no glyph for: a small square in code page 437.
+const UNPRINTABLE: u8 = 0xfe;
 
 #[derive(Copy, Clone)]
 /// A virtual text buffer.
 pub struct TextBuffer {
     /// Array of rows of characters.
     pub chars: [[u8; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    /// The colour of each character in `chars`.
+    pub colors: [[ColorCode; BUFFER_WIDTH]; BUFFER_HEIGHT],
     /// How far along a row we are.
     pub column_position: usize,
+    /// The column position stored by `save_cursor`.
+    pub saved_column: usize,
     /// Represents the colour of the TTY buffer.
     pub color_code: ColorCode,
     pub active: bool,
 }
 
-/// Clear the VGA buffer.
+/// Clear the VGA buffer, filling it with the current colour.
 pub fn clear_screen() {
-    for _row in 0..BUFFER_HEIGHT {
-        SCREEN.lock().new_line();
+    let mut screen = SCREEN.lock();
+    for row in 0..BUFFER_HEIGHT {
+        screen.clear_row(row);
+    }
+    screen.column_position = 0;
+
+    if screen.active {
+        screen.sync();
+    }
+}
+
+/// Set the colour that text written to the screen from now on is drawn in.
+pub fn set_color(foreground: Color, background: Color) {
+    SCREEN.lock().set_color(foreground, background);
+}
+
+/// Run `f` with text written to the screen drawn in the given colours, then go back to the colour
+/// from before. The screen is not kept locked while `f` runs, so `f` can print.
+pub fn with_color<F, T>(foreground: Color, background: Color, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let previous = {
+        let mut screen = SCREEN.lock();
+        let previous = screen.color_code;
+        screen.set_color(foreground, background);
+        previous
+    };
+
+    let result = f();
+
+    SCREEN.lock().color_code = previous;
+    result
+}
+
+/// Write `s` at (`row`, `col`) in `color`, without moving the cursor. Text that does not fit on
+/// the row is cut off.
+pub fn write_at(row: usize, col: usize, s: &str, color: ColorCode) -> Result<(), &'static str> {
+    SCREEN.lock().write_at(row, col, s, color)
+}
+
+/// Remember the current cursor position, to be restored with `restore_cursor`.
+pub fn save_cursor() {
+    let mut screen = SCREEN.lock();
+    screen.saved_column = screen.column_position;
+}
+
+/// Move the cursor back to the position stored by the last `save_cursor`.
+pub fn restore_cursor() {
+    let mut screen = SCREEN.lock();
+    screen.column_position = screen.saved_column;
+    if screen.active {
+        screen.sync();
     }
 }
 
 impl TextBuffer {
+    /// Create a blank, inactive text buffer.
+    pub fn new() -> TextBuffer {
+        TextBuffer {
+            column_position: 0,
+            color_code: ColorCode::new(Color::LightGray, Color::Black),
+            chars: [[b' '; BUFFER_WIDTH]; BUFFER_HEIGHT],
+            colors: [[ColorCode::new(Color::LightGray, Color::Black); BUFFER_WIDTH]; BUFFER_HEIGHT],
+            saved_column: 0,
+            active: false,
+        }
+    }
+
     /// Sync this virtual text buffer with the actual VGA buffer at 0xb8000.
     fn sync(&self) {
         VGA.lock().sync_buffer(&self);
@@ -45,11 +117,60 @@ impl TextBuffer {
         &self.chars
     }
 
+    /// Return the colour of every character.
+    pub fn colors(&self) -> &[[ColorCode; BUFFER_WIDTH]; BUFFER_HEIGHT] {
+        &self.colors
+    }
+
     /// Return the current colour code.
     pub fn color_code(&self) -> ColorCode {
         self.color_code
     }
 
+    /// Set the colour that text written from now on is drawn in. Cleared rows are filled with it
+    /// too.
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background);
+    }
+
+    /// Run `f` on this buffer with text drawn in the given colours, then go back to the colour from
+    /// before.
+    pub fn with_color<F, T>(&mut self, foreground: Color, background: Color, f: F) -> T
+    where
+        F: FnOnce(&mut TextBuffer) -> T,
+    {
+        let previous = self.color_code;
+        self.set_color(foreground, background);
+        let result = f(self);
+        self.color_code = previous;
+        result
+    }
+
+    /// Write `s` at (`row`, `col`) in `color`, leaving `column_position` alone. Text that does
+    /// not fit on the row is cut off.
+    pub fn write_at(
+        &mut self,
+        row: usize,
+        col: usize,
+        s: &str,
+        color: ColorCode,
+    ) -> Result<(), &'static str> {
+        if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
+            return Err("position outside of the text buffer");
+        }
+
+        for (offset, byte) in s.bytes().take(BUFFER_WIDTH - col).enumerate() {
+            self.chars[row][col + offset] = byte;
+            self.colors[row][col + offset] = color;
+        }
+
+        if self.active {
+            self.sync();
+        }
+
+        Ok(())
+    }
+
     /// Write a byte to the VGA buffer.
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
@@ -57,22 +178,22 @@ impl TextBuffer {
             b'\n' => self.new_line(),
             // Backspace.
             0x8 => self.delete_byte(),
-            // Tab escape.
-            b'\t' => for _ in 0..4 {
-                self.write_byte(b' ');
-            },
-            // Catch-all pattern that just updates the character array with the given byte.
-            byte => {
+            // Carriage return, back to the start of the row.
+            b'\r' => self.column_position = 0,
+            // Tab escape, to the next tab stop. A tab at the end of a row only wraps the row.
+            b'\t' => {
                 if self.column_position >= BUFFER_WIDTH {
-                    // At end of row.
                     self.new_line();
                 }
-
-                let row = BUFFER_HEIGHT - 1;
-                let col = self.column_position;
-                self.chars[row][col] = byte;
-                self.column_position += 1;
+                let stop = (self.column_position / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.column_position < stop.min(BUFFER_WIDTH) {
+                    self.put_byte(b' ');
+                }
             }
+            // Other control characters have no glyph of their own.
+            0x0...0x1f | 0x7f => self.put_byte(UNPRINTABLE),
+            // Catch-all pattern that just updates the character array with the given byte.
+            byte => self.put_byte(byte),
         }
 
         if self.active {
@@ -80,6 +201,20 @@ impl TextBuffer {
         }
     }
 
+    /// Put `byte` in the next cell of the bottom row, wrapping onto a new row if this one is full.
+    fn put_byte(&mut self, byte: u8) {
+        if self.column_position >= BUFFER_WIDTH {
+            // At end of row.
+            self.new_line();
+        }
+
+        let row = BUFFER_HEIGHT - 1;
+        let col = self.column_position;
+        self.chars[row][col] = byte;
+        self.colors[row][col] = self.color_code;
+        self.column_position += 1;
+    }
+
     /// Delete a single byte from the buffer.
     pub fn delete_byte(&mut self) {
         if self.column_position == 0 {
@@ -100,13 +235,7 @@ impl TextBuffer {
     /// Newline. This method will be called when a `\n` character is written
     /// to the virtual buffer.
     pub fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                self.chars[row - 1][col] = self.chars[row][col]
-            }
-        }
-
-        self.clear_row(BUFFER_HEIGHT - 1);
+        self.scroll_up();
         //Set position to start of row.
         self.column_position = 0;
 
@@ -115,19 +244,38 @@ impl TextBuffer {
         }
     }
 
+    /// Move every row up by one, dropping the top row, and blank the bottom row. Text is always
+    /// written to the bottom row, so this makes room for the next line.
+    pub fn scroll_up(&mut self) {
+        for row in 1..BUFFER_HEIGHT {
+            self.chars[row - 1] = self.chars[row];
+            self.colors[row - 1] = self.colors[row];
+        }
+
+        self.clear_row(BUFFER_HEIGHT - 1);
+    }
+
     /// Clear a single row by stepping across the entire width of the current row, and writing a
     /// blank character to each position.
     pub fn clear_row(&mut self, row: usize) {
         for col in 0..BUFFER_WIDTH {
             self.chars[row][col] = b' ';
+            self.colors[row][col] = self.color_code;
         }
     }
 }
 
 impl ::core::fmt::Write for TextBuffer {
+    /// Write all of `s`. Each character takes one cell, as the formatting machinery assumes when
+    /// it pads, so characters outside ASCII are shown as a single placeholder rather than as the
+    /// bytes of their UTF-8 encoding.
     fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
-        for byte in s.bytes() {
-            self.write_byte(byte)
+        for character in s.chars() {
+            if (character as u32) < 0x80 {
+                self.write_byte(character as u8)
+            } else {
+                self.write_byte(UNPRINTABLE)
+            }
         }
 
         Ok(())
@@ -139,6 +287,8 @@ pub static SCREEN: Mutex<TextBuffer> = Mutex::new(TextBuffer {
     column_position: 0,
     color_code: ColorCode::new(Color::LightGray, Color::Black),
     chars: [[b' '; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    colors: [[ColorCode::new(Color::LightGray, Color::Black); BUFFER_WIDTH]; BUFFER_HEIGHT],
+    saved_column: 0,
     active: true,
 });
 
@@ -165,12 +315,165 @@ pub fn switch(index: usize) {
 /// Initialise all the TTYS.
 pub fn tty_init() {
     // Create six identical TTYS.
-    let buffers: [TextBuffer; 6] = [TextBuffer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::LightGray, Color::Black),
-        chars: [[b' '; BUFFER_WIDTH]; BUFFER_HEIGHT],
-        active: false,
-    }; 6];
+    let buffers: [TextBuffer; 6] = [TextBuffer::new(); 6];
 
     *TTYS.lock() = Some(buffers);
 }
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "vga_format",
+            run: vga_format,
+        },
+        KTest {
+            name: "vga_color",
+            run: vga_color,
+        },
+        KTest {
+            name: "vga_scroll",
+            run: vga_scroll,
+        },
+        KTest {
+            name: "vga_write_at",
+            run: vga_write_at,
+        },
+    ];
+
+    fn vga_format() -> Result<(), &'static str> {
+        use core::fmt::Write;
+        use device::vga::buffer::{TextBuffer, BUFFER_HEIGHT, TAB_WIDTH};
+
+        // Inactive, so nothing reaches the screen.
+        let mut buffer = TextBuffer::new();
+        let row = BUFFER_HEIGHT - 1;
+
+        let _ = write!(buffer, "{:>10}|", "abc");
+        if &buffer.chars()[row][..11] != b"       abc|" {
+            return Err("right-aligned padding filled the wrong number of cells");
+        }
+
+        buffer.new_line();
+        let _ = write!(buffer, "ab\tc\t");
+        if buffer.chars()[row][TAB_WIDTH] != b'c' || buffer.column_position != 2 * TAB_WIDTH {
+            return Err("a tab did not advance to the next tab stop");
+        }
+
+        buffer.new_line();
+        let _ = write!(buffer, "xy\rz");
+        if &buffer.chars()[row][..2] != b"zy" || buffer.column_position != 1 {
+            return Err("a carriage return did not go back to the start of the row");
+        }
+
+        buffer.new_line();
+        let _ = write!(buffer, "{:>4}|", "\u{e9}");
+        if buffer.column_position != 5 || buffer.chars()[row][4] != b'|' {
+            Err("a character outside ASCII did not take exactly one cell")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn vga_color() -> Result<(), &'static str> {
+        use core::fmt::Write;
+        use device::vga::buffer::{Color, ColorCode, TextBuffer, BUFFER_HEIGHT};
+
+        let mut buffer = TextBuffer::new();
+        let row = BUFFER_HEIGHT - 1;
+        let default = buffer.color_code();
+        let green = ColorCode::new(Color::Green, Color::Black);
+
+        let _ = write!(buffer, "[ ");
+        buffer.with_color(Color::Green, Color::Black, |buffer| {
+            let _ = write!(buffer, "OK");
+        });
+        let _ = write!(buffer, " ]");
+
+        let colors = &buffer.colors()[row];
+        if colors[2] != green || colors[3] != green {
+            return Err("text written inside with_color is not in its colour");
+        }
+        if colors[1] != default || colors[4] != default || buffer.color_code() != default {
+            return Err("with_color did not put the previous colour back");
+        }
+
+        buffer.set_color(Color::White, Color::Blue);
+        buffer.new_line();
+        let white_on_blue = ColorCode::new(Color::White, Color::Blue);
+        if buffer.colors()[row].iter().any(|&color| color != white_on_blue) {
+            Err("a cleared row is not filled with the current colour")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn vga_scroll() -> Result<(), &'static str> {
+        use core::fmt::Write;
+        use device::vga::buffer::{self, TextBuffer, BUFFER_HEIGHT};
+
+        let mut buffer = TextBuffer::new();
+        let last = BUFFER_HEIGHT - 1;
+
+        // More lines than fit on the screen.
+        for line in 0..BUFFER_HEIGHT + 5 {
+            let _ = write!(buffer, "{:02}\n", line);
+        }
+        let _ = write!(buffer, "end");
+
+        if &buffer.chars()[last - 1][..2] != b"29" || &buffer.chars()[last][..3] != b"end" {
+            return Err("the buffer did not scroll with the text");
+        } else if &buffer.chars()[0][..2] != b"06" {
+            return Err("the top row is not the oldest line still on screen");
+        }
+
+        buffer.scroll_up();
+        let blank = buffer.chars()[last].iter().all(|&c| c == b' ');
+        if &buffer.chars()[last - 1][..3] != b"end" || !blank {
+            return Err("scroll_up did not move the rows up and blank the bottom one");
+        }
+
+        let previous = buffer::get_cursor();
+        buffer::set_cursor(3, 7);
+        let moved = buffer::get_cursor();
+        buffer::set_cursor(previous.0, previous.1);
+
+        if moved != (3, 7) {
+            Err("the cursor did not move where it was set")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn vga_write_at() -> Result<(), &'static str> {
+        use core::fmt::Write;
+        use device::vga::buffer::{Color, ColorCode, TextBuffer, BUFFER_HEIGHT, BUFFER_WIDTH};
+
+        let mut buffer = TextBuffer::new();
+        let red = ColorCode::new(Color::Red, Color::Black);
+
+        let _ = write!(buffer, "ab");
+        buffer.write_at(3, 10, "status", red)?;
+        buffer.write_at(4, BUFFER_WIDTH - 2, "cut", red)?;
+
+        if &buffer.chars()[3][9..17] != b" status " || buffer.colors()[3][10] != red {
+            return Err("write_at did not put the text at its row and column");
+        }
+        if &buffer.chars()[4][BUFFER_WIDTH - 2..] != b"cu" || buffer.chars()[5][0] != b' ' {
+            return Err("text past the end of the row was not cut off");
+        }
+        if buffer.column_position != 2 || &buffer.chars()[BUFFER_HEIGHT - 1][..3] != b"ab " {
+            return Err("write_at moved the cursor");
+        }
+
+        let outside = buffer.write_at(BUFFER_HEIGHT, 0, "x", red).is_ok()
+            || buffer.write_at(0, BUFFER_WIDTH, "x", red).is_ok();
+        if outside {
+            Err("a position outside of the buffer was accepted")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -2,6 +2,7 @@
 
 use device::vga::buffer::{TextBuffer, BUFFER_HEIGHT, BUFFER_WIDTH};
 use core::ptr::Unique;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use spin::Mutex;
 use volatile::Volatile;
 
@@ -27,7 +28,7 @@ pub enum Color {
     White = 15,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 /// A representation of a VGA bg/fg colour code, calculated from byte-sized
 /// bg/fg data.
 pub struct ColorCode(u8);
@@ -60,6 +61,20 @@ pub struct Vga {
     frame: Unique<ScreenBuffer>,
 }
 
+/// Whether there is a VGA text buffer to write to. Cleared by the `novga` kernel argument.
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Stop or start writing to the VGA text buffer and its cursor. While disabled, text buffers are
+/// still kept up to date in memory, but nothing at 0xb8000 or on the CRTC ports is touched.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Check whether output reaches the VGA text buffer.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
 /// Static VGA interface. We cast the base address `0xb8000` of VGA memory to a `ScreenBuffer`
 /// struct, which makes it useful to us.
 pub static VGA: Mutex<Vga> = Mutex::new(Vga {
@@ -74,6 +89,10 @@ impl Vga {
 
     /// Sync the virtual `buffer` with the `ScreenBuffer` pointer.
     pub fn sync_buffer(&mut self, buffer: &TextBuffer) {
+        if !enabled() {
+            return;
+        }
+
         let frame = self.frame();
 
         for row in 0..BUFFER_HEIGHT {
@@ -81,7 +100,7 @@ impl Vga {
                 // Update using the text buffer.
                 let character = ScreenChar {
                     ascii_character: buffer.chars()[row][col],
-                    color_code: buffer.color_code(),
+                    color_code: buffer.colors()[row][col],
                 };
 
                 frame.chars[row][col].write(character);
@@ -89,20 +108,111 @@ impl Vga {
         }
     }
 
-    #[allow(exceeding_bitshifts)]
     /// Update the text mode cursor to coordinates (row, col).
     pub fn update_cursor(&self, row: usize, col: usize) {
-        let pos = ((BUFFER_WIDTH as u16) * (row as u16)) + col as u16;
-        use device::Port;
+        set_cursor(row, col);
+    }
+}
+
+// CRTC registers holding the cursor position, as an offset in cells from the top left.
+const CRTC_INDEX: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+const CURSOR_HIGH: u8 = 0x0E;
+const CURSOR_LOW: u8 = 0x0F;
+
+/// The last position given to `set_cursor`, as a cell offset, for when the hardware is not there.
+static CURSOR: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Move the hardware text mode cursor to (`row`, `col`).
+pub fn set_cursor(row: usize, col: usize) {
+    use device::Port;
+
+    let pos = BUFFER_WIDTH * row.min(BUFFER_HEIGHT - 1) + col.min(BUFFER_WIDTH - 1);
+    CURSOR.store(pos, Ordering::SeqCst);
+
+    if !enabled() {
+        return;
+    }
+
+    unsafe {
+        let mut control_port: Port<u8> = Port::new(CRTC_INDEX);
+        let mut value_port: Port<u8> = Port::new(CRTC_DATA);
 
+        control_port.write(CURSOR_LOW);
+        value_port.write((pos & 0xFF) as u8);
+        control_port.write(CURSOR_HIGH);
+        value_port.write(((pos >> 8) & 0xFF) as u8);
+    }
+}
+
+/// Return the (row, column) of the hardware text mode cursor. With VGA disabled this is the last
+/// position given to `set_cursor`.
+pub fn get_cursor() -> (usize, usize) {
+    use device::Port;
+
+    let pos = if enabled() {
         unsafe {
-            let mut control_port: Port<u8> = Port::new(0x3D4);
-            let mut value_port: Port<u8> = Port::new(0x3D5);
+            let mut control_port: Port<u8> = Port::new(CRTC_INDEX);
+            let mut value_port: Port<u8> = Port::new(CRTC_DATA);
+
+            control_port.write(CURSOR_LOW);
+            let low = value_port.read() as usize;
+            control_port.write(CURSOR_HIGH);
+            let high = value_port.read() as usize;
+
+            high << 8 | low
+        }
+    } else {
+        CURSOR.load(Ordering::SeqCst)
+    };
+
+    (pos / BUFFER_WIDTH, pos % BUFFER_WIDTH)
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "novga",
+            run: novga,
+        },
+    ];
+
+    fn novga() -> Result<(), &'static str> {
+        use core::fmt::Write;
+        use core::ptr;
+        use device::serial;
+        use device::vga::buffer::{TextBuffer, BUFFER_HEIGHT, BUFFER_WIDTH};
+        use device::vga::vga;
+
+        // A checksum of every cell of the text buffer at 0xb8000.
+        let screen = || {
+            (0..BUFFER_HEIGHT * BUFFER_WIDTH).fold(0u64, |sum, cell| {
+                let value = unsafe { ptr::read_volatile((0xb8000 + cell * 2) as *const u16) };
+                sum.rotate_left(5) ^ value as u64
+            })
+        };
+
+        let was_enabled = vga::enabled();
+        vga::set_enabled(false);
+
+        let before = screen();
+        let mut buffer = TextBuffer::new();
+        buffer.active = true;
+        let _ = write!(buffer, "this must not reach the screen");
+        let after = screen();
+        let logged = write!(serial::COM1.lock(), "[ ktest ] novga: serial still works\n");
+
+        vga::set_enabled(was_enabled);
 
-            control_port.write(0x0F);
-            value_port.write((pos & 0xFF) as u8);
-            control_port.write(0x0E);
-            value_port.write(((pos >> 8) & 0xFF) as u8);
+        if before != after {
+            Err("the VGA text buffer was written to while disabled")
+        } else if logged.is_err() {
+            Err("could not log to serial")
+        } else {
+            Ok(())
         }
     }
 }
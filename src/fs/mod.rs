@@ -0,0 +1,98 @@
+//! A minimal virtual file system. File systems are mounted at a path, and a path is resolved by
+//! the file system mounted at its longest matching prefix. There are no file descriptors yet, so
+//! files are read by path.
+
+use alloc::{String, Vec};
+use spin::RwLock;
+
+pub mod procfs;
+
+/// Reasons a file system operation can fail.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsError {
+    /// Nothing is mounted at the path, or the file system has no such file.
+    NotFound,
+    /// Something is already mounted at the path.
+    AlreadyMounted,
+    /// The file does not hold UTF-8 text.
+    InvalidData,
+}
+
+/// A mountable file system.
+pub trait FileSystem: Sync {
+    /// Read from `path`, relative to where the file system is mounted, starting `offset` bytes
+    /// into the file. Returns the number of bytes read into `buffer`, which is 0 at the end of the
+    /// file.
+    fn read(&self, path: &str, offset: usize, buffer: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Return the names of the files in the file system.
+    fn list(&self) -> Vec<&'static str>;
+}
+
+/// A file system and the path it is mounted at.
+struct Mount {
+    path: &'static str,
+    fs: &'static FileSystem,
+}
+
+lazy_static! {
+    static ref MOUNTS: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+}
+
+/// Mount `fs` at `path`, which must start with a `/` and not end with one.
+pub fn mount(path: &'static str, fs: &'static FileSystem) -> Result<(), FsError> {
+    let mut mounts = MOUNTS.write();
+
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(FsError::AlreadyMounted);
+    }
+
+    mounts.push(Mount { path: path, fs: fs });
+    println!("[ fs ] Mounted a file system at {}", path);
+
+    Ok(())
+}
+
+/// Find the file system `path` is on, and return it with the rest of the path.
+fn resolve<'a>(path: &'a str) -> Result<(&'static FileSystem, &'a str), FsError> {
+    let mounts = MOUNTS.read();
+
+    mounts
+        .iter()
+        .filter(|mount| {
+            path.starts_with(mount.path)
+                && (path.len() == mount.path.len() || path[mount.path.len()..].starts_with('/'))
+        })
+        .max_by_key(|mount| mount.path.len())
+        .map(|mount| (mount.fs, path[mount.path.len()..].trim_left_matches('/')))
+        .ok_or(FsError::NotFound)
+}
+
+/// Read from the file at `path`, starting `offset` bytes into it. Returns the number of bytes read
+/// into `buffer`, which is 0 at the end of the file.
+pub fn read(path: &str, offset: usize, buffer: &mut [u8]) -> Result<usize, FsError> {
+    let (fs, relative) = resolve(path)?;
+    fs.read(relative, offset, buffer)
+}
+
+/// Read the whole of the text file at `path`.
+pub fn read_to_string(path: &str) -> Result<String, FsError> {
+    let mut bytes = Vec::new();
+    let mut buffer = [0u8; 256];
+
+    loop {
+        match read(path, bytes.len(), &mut buffer)? {
+            0 => break,
+            read => bytes.extend_from_slice(&buffer[..read]),
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| FsError::InvalidData)
+}
+
+/// Mount the built-in file systems.
+pub fn init() {
+    if let Err(err) = mount("/proc", &procfs::PROCFS) {
+        println!("[ fs ] Could not mount procfs: {:?}", err);
+    }
+}
@@ -0,0 +1,173 @@
+//! A synthetic file system of read-only files describing the running kernel, usually mounted at
+//! `/proc`. The content of a file is generated every time it is read.
+
+use alloc::{String, Vec};
+use core::fmt::Write;
+use fs::{FileSystem, FsError};
+
+/// The files in procfs, with the functions that generate them.
+const FILES: [(&str, fn(&mut String)); 4] = [
+    ("meminfo", meminfo),
+    ("interrupts", interrupts),
+    ("uptime", uptime),
+    ("cpuinfo", cpuinfo),
+];
+
+pub struct ProcFs;
+
+/// The instance that `fs::init` mounts.
+pub static PROCFS: ProcFs = ProcFs;
+
+impl FileSystem for ProcFs {
+    fn read(&self, path: &str, offset: usize, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let generate = FILES
+            .iter()
+            .find(|&&(name, _)| name == path)
+            .map(|&(_, generate)| generate)
+            .ok_or(FsError::NotFound)?;
+
+        let mut content = String::new();
+        generate(&mut content);
+
+        let bytes = content.as_bytes();
+        if offset >= bytes.len() {
+            return Ok(0);
+        }
+
+        let len = buffer.len().min(bytes.len() - offset);
+        buffer[..len].copy_from_slice(&bytes[offset..offset + len]);
+
+        Ok(len)
+    }
+
+    fn list(&self) -> Vec<&'static str> {
+        FILES.iter().map(|&(name, _)| name).collect()
+    }
+}
+
+/// Free and used physical memory, and the heap.
+fn meminfo(out: &mut String) {
+    use arch::memory::{self, owner, PAGE_SIZE};
+    use arch::memory::heap_allocator::alloc_stats;
+
+    let stats = memory::stats();
+    let heap = alloc_stats();
+
+    let _ = writeln!(out, "MemFree: {} kB", stats.free * PAGE_SIZE / 1024);
+    // These are all 0 unless the kernel was built with the `frame_owners` feature.
+    for (owner, &frames) in owner::OWNERS.iter().zip(stats.by_owner.iter()) {
+        let _ = writeln!(out, "{:?}: {} kB", owner, frames * PAGE_SIZE / 1024);
+    }
+    let _ = writeln!(out, "HeapSize: {} kB", heap.heap_size / 1024);
+    let _ = writeln!(out, "HeapUsed: {} kB", heap.used / 1024);
+    let _ = writeln!(out, "HeapMax: {} kB", heap.max_size / 1024);
+}
+
+/// The number of times each interrupt vector has been handled, for the vectors that have been.
+fn interrupts(out: &mut String) {
+    use arch::interrupts::guard;
+
+    for (vector, &count) in guard::counts().iter().enumerate() {
+        if count != 0 {
+            let _ = writeln!(out, "{:#04x}: {:>10}", vector, count);
+        }
+    }
+}
+
+/// Seconds since the PIT was initialised, to a hundredth of a second.
+fn uptime(out: &mut String) {
+    use device::pit;
+
+    let ms = pit::uptime_ms();
+    let _ = writeln!(out, "{}.{:02}", ms / 1000, ms % 1000 / 10);
+}
+
+/// The CPU vendor, model and address widths.
+fn cpuinfo(out: &mut String) {
+    use arch::cpuid::{self, cpuid};
+
+    let (_, ebx, ecx, edx) = cpuid(0);
+    let mut vendor = [0u8; 12];
+    for (i, &register) in [ebx, edx, ecx].iter().enumerate() {
+        for byte in 0..4 {
+            vendor[i * 4 + byte] = (register >> (byte * 8)) as u8;
+        }
+    }
+
+    let (eax, _, _, _) = cpuid(1);
+    let mut family = (eax >> 8) & 0xf;
+    let mut model = (eax >> 4) & 0xf;
+    if family == 0xf {
+        family += (eax >> 20) & 0xff;
+    }
+    if family == 0x6 || family >= 0xf {
+        model += ((eax >> 16) & 0xf) << 4;
+    }
+
+    let _ = writeln!(out, "vendor_id: {}", String::from_utf8_lossy(&vendor));
+    let _ = writeln!(out, "cpu family: {}", family);
+    let _ = writeln!(out, "model: {}", model);
+    let _ = writeln!(out, "stepping: {}", eax & 0xf);
+
+    let (max_extended_leaf, _, _, _) = cpuid(0x8000_0000);
+    if max_extended_leaf >= 0x8000_0004 {
+        let mut brand = [0u8; 48];
+        for (i, leaf) in (0x8000_0002..0x8000_0005).enumerate() {
+            let (eax, ebx, ecx, edx) = cpuid(leaf);
+            for (j, &register) in [eax, ebx, ecx, edx].iter().enumerate() {
+                for byte in 0..4 {
+                    brand[i * 16 + j * 4 + byte] = (register >> (byte * 8)) as u8;
+                }
+            }
+        }
+        let len = brand.iter().position(|&byte| byte == 0).unwrap_or(brand.len());
+        let _ = writeln!(out, "model name: {}", String::from_utf8_lossy(&brand[..len]).trim());
+    }
+
+    let _ = writeln!(
+        out,
+        "address sizes: {} bits physical, {} bits virtual",
+        cpuid::phys_addr_bits(),
+        cpuid::virtual_address_bits()
+    );
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "proc_uptime",
+            run: proc_uptime,
+        },
+    ];
+
+    fn proc_uptime() -> Result<(), &'static str> {
+        use device::pit;
+
+        let before = pit::uptime_ms();
+        let uptime =
+            ::fs::read_to_string("/proc/uptime").map_err(|_| "could not read /proc/uptime")?;
+        let after = pit::uptime_ms();
+
+        let mut parts = uptime.trim_right().splitn(2, '.');
+        let seconds = parts.next().and_then(|seconds| seconds.parse::<usize>().ok());
+        let hundredths = parts.next().and_then(|hundredths| hundredths.parse::<usize>().ok());
+
+        let ms = match (seconds, hundredths) {
+            (Some(seconds), Some(hundredths)) if hundredths < 100 => {
+                seconds * 1000 + hundredths * 10
+            }
+            _ => return Err("/proc/uptime is not a number of seconds"),
+        };
+
+        if ms + 10 < before || ms > after {
+            Err("/proc/uptime disagrees with the PIT")
+        } else if ::fs::read_to_string("/proc/nonexistent").is_ok() {
+            Err("read a file that does not exist")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! A bump-pointer arena for short-lived allocations that are all freed together, such as the
+//! objects built while parsing a table. Allocating is a pointer bump, and the whole buffer is
+//! handed back to the heap in one go when the arena is dropped.
+//!
+//! Destructors of values placed in the arena are never run, so only store plain data in it.
+
+use alloc::boxed::Box;
+use core::cell::{Cell, UnsafeCell};
+use core::{mem, ptr, slice};
+
+pub struct Arena {
+    /// The heap-backed buffer that allocations are carved out of.
+    storage: UnsafeCell<Box<[u8]>>,
+    /// Offset of the first unused byte in `storage`.
+    next: Cell<usize>,
+}
+
+impl Arena {
+    /// Create an arena backed by a `capacity` byte buffer on the heap.
+    pub fn new(capacity: usize) -> Arena {
+        Arena {
+            storage: UnsafeCell::new(vec![0u8; capacity].into_boxed_slice()),
+            next: Cell::new(0),
+        }
+    }
+
+    /// The size of the backing buffer in bytes.
+    pub fn capacity(&self) -> usize {
+        unsafe { (*self.storage.get()).len() }
+    }
+
+    /// The number of bytes handed out so far, including alignment padding.
+    pub fn used(&self) -> usize {
+        self.next.get()
+    }
+
+    /// Reserve `size` bytes aligned to `align`, returning `None` if the arena is full.
+    fn alloc_raw(&self, size: usize, align: usize) -> Option<*mut u8> {
+        let base = unsafe { (*self.storage.get()).as_mut_ptr() };
+        let current = base as usize + self.next.get();
+        let start = (current + align - 1) & !(align - 1);
+        let end = start.checked_add(size)?;
+
+        if end > base as usize + self.capacity() {
+            return None;
+        }
+
+        self.next.set(end - base as usize);
+        Some(start as *mut u8)
+    }
+
+    /// Move `value` into the arena. Returns `None`, dropping `value`, if the arena is full.
+    pub fn alloc<T>(&self, value: T) -> Option<&mut T> {
+        let address = self.alloc_raw(mem::size_of::<T>(), mem::align_of::<T>())? as *mut T;
+        unsafe {
+            ptr::write(address, value);
+            Some(&mut *address)
+        }
+    }
+
+    /// Copy `values` into the arena. Returns `None` if the arena is full.
+    pub fn alloc_slice<T: Copy>(&self, values: &[T]) -> Option<&mut [T]> {
+        let size = mem::size_of::<T>().checked_mul(values.len())?;
+        let address = self.alloc_raw(size, mem::align_of::<T>())? as *mut T;
+        unsafe {
+            ptr::copy_nonoverlapping(values.as_ptr(), address, values.len());
+            Some(slice::from_raw_parts_mut(address, values.len()))
+        }
+    }
+
+    /// Free every allocation at once, so the buffer can be reused. Taking `&mut self` ensures no
+    /// references into the arena are still alive.
+    pub fn reset(&mut self) {
+        self.next.set(0);
+    }
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "arena",
+            run: arena,
+        },
+        KTest {
+            name: "arena_reclaim",
+            run: arena_reclaim,
+        },
+    ];
+
+    fn arena() -> Result<(), &'static str> {
+        use klib::Arena;
+
+        let arena = Arena::new(64);
+
+        match arena.alloc(0x1234u64) {
+            Some(value) if *value == 0x1234 => {}
+            _ => return Err("could not allocate a value"),
+        }
+        if arena.used() < 8 {
+            return Err("allocation was not counted");
+        }
+        if arena.alloc([0u8; 128]).is_some() {
+            return Err("allocation larger than the arena succeeded");
+        }
+
+        Ok(())
+    }
+
+    fn arena_reclaim() -> Result<(), &'static str> {
+        use arch::memory::heap_allocator::alloc_stats;
+        use klib::Arena;
+
+        let before = alloc_stats().used;
+        let (allocated, full, during) = {
+            let arena = Arena::new(4096);
+            let mut allocated = 0;
+            for i in 0..512u64 {
+                match arena.alloc(i) {
+                    Some(value) if *value == i => allocated += 1,
+                    _ => break,
+                }
+            }
+            let full = arena.alloc(0u8).is_none() && arena.alloc_slice(&[0u8]).is_none();
+            (allocated, full, alloc_stats().used)
+        };
+        let after = alloc_stats().used;
+
+        if allocated != 512 {
+            Err("small allocations failed before the arena was full")
+        } else if !full {
+            Err("allocations past the end of the arena succeeded")
+        } else if during < before + 4096 {
+            Err("the arena buffer did not come from the heap")
+        } else if after != before {
+            Err("dropping the arena did not give its buffer back to the heap")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Checksums for data read from storage or received from the network. CRC32 uses the IEEE 802.3
+//! polynomial, as zlib, Ethernet and most on-disk formats do. The internet checksum is the one's
+//! complement sum of RFC 1071, used by IPv4, ICMP, UDP and TCP.
+
+use spin::Once;
+
+/// The bit-reversed IEEE 802.3 polynomial.
+const CRC32_POLYNOMIAL: u32 = 0xedb8_8320;
+
+/// The CRC of every byte value, built the first time `crc32` is called.
+static CRC32_TABLE: Once<[u32; 256]> = Once::new();
+
+fn crc32_table() -> &'static [u32; 256] {
+    CRC32_TABLE.call_once(|| {
+        let mut table = [0; 256];
+        for (byte, entry) in table.iter_mut().enumerate() {
+            let mut crc = byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ CRC32_POLYNOMIAL
+                } else {
+                    crc >> 1
+                };
+            }
+            *entry = crc;
+        }
+        table
+    })
+}
+
+/// Return the CRC32 of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Continue the CRC32 `crc` of the data before `data`, so that a checksum can be computed over
+/// several buffers. `crc32_update(crc32(a), b)` is the CRC32 of `a` followed by `b`.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let table = crc32_table();
+
+    !data.iter().fold(!crc, |crc, &byte| {
+        table[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Return the internet checksum of `data`: the one's complement of the one's complement sum of
+/// its big-endian 16-bit words, with an odd trailing byte padded with zero. A header that carries
+/// a correct checksum sums to 0.
+pub fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum = data.chunks(2).fold(0u32, |sum, word| {
+        let high = (word[0] as u32) << 8;
+        let low = word.get(1).cloned().unwrap_or(0) as u32;
+        sum + (high | low)
+    });
+
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "checksums",
+            run: checksums,
+        },
+    ];
+
+    fn checksums() -> Result<(), &'static str> {
+        use klib::{crc32, crc32_update, internet_checksum};
+
+        // An IPv4 header with its checksum field (bytes 10 and 11) zeroed. The checksum is 0xb861.
+        let mut header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+
+        if crc32(b"") != 0 || crc32(b"a") != 0xe8b7_be43 || crc32(b"123456789") != 0xcbf4_3926 {
+            return Err("CRC32 does not match the known values");
+        } else if crc32(b"The quick brown fox jumps over the lazy dog") != 0x414f_a339 {
+            return Err("CRC32 of a sentence does not match the known value");
+        } else if crc32_update(crc32(b"1234"), b"56789") != crc32(b"123456789") {
+            return Err("a CRC32 continued over two buffers differs from one over both");
+        }
+
+        let checksum = internet_checksum(&header);
+        header[10] = (checksum >> 8) as u8;
+        header[11] = checksum as u8;
+
+        if checksum != 0xb861 {
+            Err("the internet checksum of an IPv4 header is wrong")
+        } else if internet_checksum(&header) != 0 {
+            Err("a header with its checksum filled in does not sum to 0")
+        } else if internet_checksum(&[0x01]) != !0x0100 {
+            Err("an odd trailing byte was not padded with zero")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! A doubly linked list whose links live inside the elements themselves, so that adding an element
+//! never allocates.
+//!
+//! # Safety
+//!
+//! The list only stores raw pointers to its elements. Whoever pushes an element must make sure
+//! that, for as long as it is on the list:
+//!
+//! - the element is not moved or freed, e.g. by keeping it behind an `Arc` or `Box`;
+//! - the element is on no other list using the same `ListNode`;
+//! - nothing but the list touches the element's `ListNode`, and all access to the list is
+//!   serialised, e.g. by keeping the list behind a lock.
+
+use core::marker::PhantomData;
+use core::ptr;
+
+/// The links embedded in an element of an `IntrusiveList`.
+pub struct ListNode<T> {
+    next: *mut T,
+    prev: *mut T,
+    linked: bool,
+}
+
+impl<T> ListNode<T> {
+    pub const fn new() -> ListNode<T> {
+        ListNode {
+            next: ptr::null_mut(),
+            prev: ptr::null_mut(),
+            linked: false,
+        }
+    }
+
+    /// Check if the element is currently on a list.
+    pub fn is_linked(&self) -> bool {
+        self.linked
+    }
+}
+
+/// A copy of an element is never on a list, whatever the original is on.
+impl<T> Clone for ListNode<T> {
+    fn clone(&self) -> ListNode<T> {
+        ListNode::new()
+    }
+}
+
+impl<T> ::core::fmt::Debug for ListNode<T> {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        write!(f, "ListNode {{ linked: {} }}", self.linked)
+    }
+}
+
+// The pointers are only followed by the list owning the node, which the caller keeps serialised.
+unsafe impl<T> Send for ListNode<T> {}
+unsafe impl<T> Sync for ListNode<T> {}
+
+/// An element that can be put on an `IntrusiveList`.
+pub trait Linked: Sized {
+    /// Return the links of this element.
+    fn node(&mut self) -> &mut ListNode<Self>;
+}
+
+pub struct IntrusiveList<T: Linked> {
+    head: *mut T,
+    tail: *mut T,
+    len: usize,
+}
+
+// See the module documentation: elements are only reached through the list, which the caller
+// keeps behind a lock.
+unsafe impl<T: Linked> Send for IntrusiveList<T> {}
+unsafe impl<T: Linked> Sync for IntrusiveList<T> {}
+
+impl<T: Linked> IntrusiveList<T> {
+    pub const fn new() -> IntrusiveList<T> {
+        IntrusiveList {
+            head: ptr::null_mut(),
+            tail: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    /// The number of elements on the list.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Add `element` to the back of the list.
+    ///
+    /// # Safety
+    ///
+    /// `element` must stay valid and in place until it is removed, see the module documentation.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `element` is already on a list.
+    pub unsafe fn push_back(&mut self, element: *mut T) {
+        {
+            let node = (*element).node();
+            assert!(!node.linked, "element is already on a list");
+            node.linked = true;
+            node.next = ptr::null_mut();
+            node.prev = self.tail;
+        }
+
+        if self.tail.is_null() {
+            self.head = element;
+        } else {
+            (*self.tail).node().next = element;
+        }
+
+        self.tail = element;
+        self.len += 1;
+    }
+
+    /// Remove and return the element at the front of the list.
+    pub fn pop_front(&mut self) -> Option<*mut T> {
+        if self.head.is_null() {
+            return None;
+        }
+
+        let element = self.head;
+        // The head is on this list, so it is still valid.
+        unsafe { self.remove(element) };
+        Some(element)
+    }
+
+    /// Remove `element` from the list.
+    ///
+    /// # Safety
+    ///
+    /// `element` must be on this list, and not on some other list.
+    pub unsafe fn remove(&mut self, element: *mut T) {
+        let (next, prev) = {
+            let node = (*element).node();
+            assert!(node.linked, "element is not on a list");
+            let links = (node.next, node.prev);
+            *node = ListNode::new();
+            links
+        };
+
+        if prev.is_null() {
+            self.head = next;
+        } else {
+            (*prev).node().next = next;
+        }
+
+        if next.is_null() {
+            self.tail = prev;
+        } else {
+            (*next).node().prev = prev;
+        }
+
+        self.len -= 1;
+    }
+
+    /// Return an iterator over the elements, front to back.
+    pub fn iter(&self) -> Iter<T> {
+        Iter {
+            current: self.head,
+            list: PhantomData,
+        }
+    }
+}
+
+/// An iterator over the elements of an `IntrusiveList`.
+pub struct Iter<'a, T: 'a + Linked> {
+    current: *mut T,
+    list: PhantomData<&'a IntrusiveList<T>>,
+}
+
+impl<'a, T: Linked> Iterator for Iter<'a, T> {
+    type Item = *mut T;
+
+    fn next(&mut self) -> Option<*mut T> {
+        if self.current.is_null() {
+            return None;
+        }
+
+        let element = self.current;
+        // Elements on the list are valid, and the list cannot change while it is borrowed.
+        self.current = unsafe { (*element).node().next };
+        Some(element)
+    }
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "intrusive_remove",
+            run: intrusive_remove,
+        },
+    ];
+
+    struct Element {
+        value: u32,
+        node: super::ListNode<Element>,
+    }
+
+    impl super::Linked for Element {
+        fn node(&mut self) -> &mut super::ListNode<Element> {
+            &mut self.node
+        }
+    }
+
+    fn element(value: u32) -> Element {
+        Element {
+            value: value,
+            node: super::ListNode::new(),
+        }
+    }
+
+    fn intrusive_remove() -> Result<(), &'static str> {
+        use super::IntrusiveList;
+
+        let mut elements = [element(1), element(2), element(3)];
+        let base = elements.as_mut_ptr();
+        let (first, second, third) = unsafe { (base, base.offset(1), base.offset(2)) };
+
+        // The elements outlive the list and are not moved while on it.
+        let mut list = IntrusiveList::new();
+        unsafe {
+            list.push_back(first);
+            list.push_back(second);
+            list.push_back(third);
+            list.remove(second);
+        }
+
+        let len = list.len();
+        let mut values = [0; 3];
+        for (value, item) in values.iter_mut().zip(list.iter()) {
+            *value = unsafe { (*item).value };
+        }
+        let unlinked = unsafe { !(*second).node.is_linked() };
+
+        let front = list.pop_front();
+        let back = list.pop_front();
+
+        if !unlinked {
+            Err("the removed element is still marked as linked")
+        } else if len != 2 || values != [1, 3, 0] {
+            Err("removing the middle element did not link its neighbours")
+        } else if front != Some(first) || back != Some(third) || list.pop_front().is_some() {
+            Err("the elements were not popped in order")
+        } else {
+            Ok(())
+        }
+    }
+}
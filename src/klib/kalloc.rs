@@ -0,0 +1,123 @@
+//! Raw, zeroed allocations from the kernel heap, for buffers that are sized at run time and not
+//! owned by any Rust type. Every `kalloc` must be paired with a `kfree` of the same size and
+//! alignment.
+
+use alloc::allocator::{Alloc, Layout};
+use core::ptr;
+
+/// The largest allocation `kalloc` hands out. Anything bigger should come straight from the frame
+/// allocator rather than the heap.
+pub const KALLOC_MAX: usize = 100 * 1024;
+
+/// Allocate `size` zeroed bytes aligned to `align`, which must be a power of two.
+pub fn kalloc(size: usize, align: usize) -> Result<*mut u8, &'static str> {
+    if size == 0 {
+        return Err("zero sized allocation");
+    } else if size > KALLOC_MAX {
+        return Err("allocation is too large");
+    }
+
+    let layout = Layout::from_size_align(size, align).ok_or("alignment is not a power of two")?;
+
+    let mut heap = &::HEAP_ALLOCATOR;
+    let allocation = unsafe { heap.alloc(layout) }.map_err(|_| "out of memory")?;
+    unsafe { ptr::write_bytes(allocation, 0, size) };
+
+    Ok(allocation)
+}
+
+/// Free an allocation from `kalloc`. Freeing a null pointer does nothing.
+///
+/// # Safety
+///
+/// `ptr` must have come from `kalloc(size, align)`, with the same `size` and `align`, and not have
+/// been freed yet.
+pub unsafe fn kfree(ptr: *mut u8, size: usize, align: usize) {
+    if ptr.is_null() {
+        return;
+    }
+
+    let layout = Layout::from_size_align(size, align).expect("kfree: invalid layout");
+    let mut heap = &::HEAP_ALLOCATOR;
+    heap.dealloc(ptr, layout);
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "kalloc_round_trip",
+            run: kalloc_round_trip,
+        },
+        KTest {
+            name: "kalloc_layouts",
+            run: kalloc_layouts,
+        },
+    ];
+
+    fn kalloc_round_trip() -> Result<(), &'static str> {
+        use arch::memory::heap_allocator::alloc_stats;
+        use klib::{kalloc, kfree};
+        use core::slice;
+
+        let layouts = [(1, 1), (24, 8), (100, 16), (4096, 4096), (9000, 64)];
+        let used_before = alloc_stats().used;
+
+        let mut blocks = [0 as *mut u8; 5];
+        let mut result = Ok(());
+        for (block, &(size, align)) in blocks.iter_mut().zip(layouts.iter()) {
+            *block = match kalloc(size, align) {
+                Ok(block) => block,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            };
+            if *block as usize % align != 0 {
+                result = Err("an allocation is not aligned");
+            }
+
+            let bytes = unsafe { slice::from_raw_parts_mut(*block, size) };
+            if bytes.iter().any(|&byte| byte != 0) {
+                result = Err("an allocation was not zeroed");
+            }
+            for byte in bytes.iter_mut() {
+                *byte = size as u8;
+            }
+        }
+
+        // Check nothing overlapped before handing everything back.
+        for (&block, &(size, align)) in blocks.iter().zip(layouts.iter()) {
+            if !block.is_null() {
+                let bytes = unsafe { slice::from_raw_parts(block, size) };
+                if bytes.iter().any(|&byte| byte != size as u8) {
+                    result = Err("allocations overlap");
+                }
+                unsafe { kfree(block, size, align) };
+            }
+        }
+
+        if result.is_ok() && alloc_stats().used != used_before {
+            result = Err("freeing did not give the memory back");
+        }
+        result
+    }
+
+    fn kalloc_layouts() -> Result<(), &'static str> {
+        use klib::kalloc;
+        use klib::kalloc::KALLOC_MAX;
+
+        // Each of these must be refused without panicking.
+        let invalid = [(16, 3), (16, 0), (16, 24), (0, 8), (KALLOC_MAX + 1, 8)];
+
+        for &(size, align) in invalid.iter() {
+            if kalloc(size, align).is_ok() {
+                return Err("an invalid layout was allocated");
+            }
+        }
+
+        Ok(())
+    }
+}
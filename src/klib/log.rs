@@ -0,0 +1,63 @@
+//! Logging helpers for error propagation.
+
+/// Evaluate a `Result`, returning the `Ok` value. On `Err`, log the error along with the file and
+/// line of the call site, then return it early from the enclosing function (converted with
+/// `From`, like `?`). An optional message can be given to describe what failed.
+macro_rules! log_err {
+    ($result:expr) => (match $result {
+        Ok(value) => value,
+        Err(err) => {
+            println!("[ ERR ] {}:{}: {:?}", file!(), line!(), err);
+            return Err(::core::convert::From::from(err));
+        }
+    });
+    ($result:expr, $msg:expr) => (match $result {
+        Ok(value) => value,
+        Err(err) => {
+            println!("[ ERR ] {}:{}: {}: {:?}", file!(), line!(), $msg, err);
+            return Err(::core::convert::From::from(err));
+        }
+    });
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "log_err",
+            run: log_err,
+        },
+    ];
+
+    /// Fail through `log_err!`, and set `line` to the line it was called from.
+    fn fail(line: &mut u32) -> Result<(), &'static str> {
+        *line = line!() + 1;
+        let () = log_err!(Err::<(), _>("failure"), "ktest");
+        Ok(())
+    }
+
+    fn log_err() -> Result<(), &'static str> {
+        use device::console;
+        use device::vga::buffer::{BUFFER_HEIGHT, SCREEN};
+
+        let mirror = console::mirror();
+        console::set_mirror(true);
+
+        let mut line = 0;
+        let result = fail(&mut line);
+        let expected = format!("[ ERR ] {}:{}: ktest: \"failure\"", file!(), line);
+        let logged = SCREEN.lock().chars()[BUFFER_HEIGHT - 2].starts_with(expected.as_bytes());
+
+        console::set_mirror(mirror);
+
+        if result != Err("failure") {
+            Err("the error was not returned")
+        } else if !logged {
+            Err("the error was not logged with its call site")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,16 @@
+//! Kernel support library. Small, architecture-independent helpers shared by the rest of the
+//! kernel.
+
+#[macro_use]
+pub mod log;
+pub mod ring_buffer;
+pub mod arena;
+pub mod intrusive_list;
+pub mod kalloc;
+pub mod checksum;
+
+pub use self::ring_buffer::RingBuffer;
+pub use self::arena::Arena;
+pub use self::intrusive_list::{IntrusiveList, Linked, ListNode};
+pub use self::kalloc::{kalloc, kfree};
+pub use self::checksum::{crc32, crc32_update, internet_checksum};
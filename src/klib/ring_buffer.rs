@@ -0,0 +1,129 @@
+//! A fixed-capacity FIFO ring buffer, for passing data from interrupt handlers to the rest of the
+//! kernel without allocating.
+
+/// The number of elements a `RingBuffer` can hold.
+pub const RING_BUFFER_SIZE: usize = 128;
+
+pub struct RingBuffer<T: Copy> {
+    buffer: [T; RING_BUFFER_SIZE],
+    /// Index of the oldest element.
+    head: usize,
+    /// The number of elements in the buffer.
+    len: usize,
+}
+
+impl<T: Copy> RingBuffer<T> {
+    /// Create an empty buffer. `fill` is only used to initialise the backing array.
+    pub fn new(fill: T) -> RingBuffer<T> {
+        RingBuffer {
+            buffer: [fill; RING_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Add an element to the back of the buffer. Returns `false`, dropping `value`, if the buffer
+    /// is full.
+    pub fn push(&mut self, value: T) -> bool {
+        if self.is_full() {
+            return false;
+        }
+
+        let tail = (self.head + self.len) % RING_BUFFER_SIZE;
+        self.buffer[tail] = value;
+        self.len += 1;
+        true
+    }
+
+    /// Remove the element at the front of the buffer.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let value = self.buffer[self.head];
+        self.head = (self.head + 1) % RING_BUFFER_SIZE;
+        self.len -= 1;
+        Some(value)
+    }
+
+    /// Return the element at the front of the buffer without removing it.
+    pub fn peek(&self) -> Option<T> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.buffer[self.head])
+        }
+    }
+
+    /// Check if `value` is anywhere in the buffer.
+    pub fn contains(&self, value: &T) -> bool
+    where
+        T: PartialEq,
+    {
+        (0..self.len).any(|i| self.buffer[(self.head + i) % RING_BUFFER_SIZE] == *value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == RING_BUFFER_SIZE
+    }
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "ring_buffer",
+            run: ring_buffer,
+        },
+    ];
+
+    fn ring_buffer() -> Result<(), &'static str> {
+        use klib::ring_buffer::{RingBuffer, RING_BUFFER_SIZE};
+
+        let mut buffer = RingBuffer::new(0usize);
+        for i in 0..RING_BUFFER_SIZE {
+            if !buffer.push(i) {
+                return Err("push failed before the buffer was full");
+            }
+        }
+        if buffer.push(RING_BUFFER_SIZE) {
+            return Err("push succeeded on a full buffer");
+        }
+
+        if buffer.peek() != Some(0) || !buffer.contains(&(RING_BUFFER_SIZE - 1)) {
+            return Err("peek or contains did not see the elements");
+        }
+
+        for i in 0..RING_BUFFER_SIZE {
+            if buffer.pop() != Some(i) {
+                return Err("elements came out in the wrong order");
+            }
+        }
+
+        // Wrap around the end of the backing array.
+        buffer.push(1);
+        buffer.push(2);
+        if buffer.contains(&0) || !buffer.contains(&2) || buffer.peek() != Some(1) {
+            return Err("contains looked at elements that were popped");
+        }
+        buffer.pop();
+        buffer.pop();
+
+        if buffer.peek().is_some() || buffer.pop().is_some() {
+            Err("pop succeeded on an empty buffer")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Self-tests that can be run on a booted kernel. There is no shell yet, so they are run at the end
+//! of init when the `ktest` kernel argument is given: `ktest` runs all of them, and `ktest=name`
+//! only the named one.
+//!
+//! Each test lives in a `tests` module at the bottom of the file it covers, which lists them in a
+//! `TESTS` table. This module collects the tables, runs them and holds the fixtures they share.
+
+use alloc::String;
+use arch::interrupts::disable_interrupts_and_then;
+use arch::without_interrupts;
+use arch::memory::{self, Frame};
+use arch::memory::paging::{ActivePageTable, InactivePageTable, Page, PhysicalAddress,
+                           VirtualAddress};
+use arch::memory::paging::temporary_page::TemporaryPage;
+use core::fmt::Debug;
+use core::slice;
+use task::{ProcessId, Scheduling, SCHEDULER};
+
+/// A self-test. It returns a description of what went wrong on failure.
+pub struct KTest {
+    pub name: &'static str,
+    pub run: fn() -> Result<(), &'static str>,
+}
+
+/// The tests of every module that has any.
+static SUITES: &[&[KTest]] = &[
+    ::macros::tests::TESTS,
+    ::klib::log::tests::TESTS,
+    ::klib::ring_buffer::tests::TESTS,
+    ::klib::intrusive_list::tests::TESTS,
+    ::klib::arena::tests::TESTS,
+    ::klib::kalloc::tests::TESTS,
+    ::klib::checksum::tests::TESTS,
+    ::arch::memory::heap_allocator::tests::TESTS,
+    ::arch::memory::tests::TESTS,
+    ::arch::memory::area_frame_allocator::tests::TESTS,
+    ::arch::memory::frame_bitmap::tests::TESTS,
+    ::arch::memory::refcount::tests::TESTS,
+    ::arch::memory::stack_allocator::tests::TESTS,
+    ::arch::memory::paging::tests::TESTS,
+    ::arch::memory::paging::mapper::tests::TESTS,
+    ::arch::memory::paging::entry::tests::TESTS,
+    ::arch::multiboot::tests::TESTS,
+    ::arch::cpuid::tests::TESTS,
+    ::arch::percpu::tests::TESTS,
+    ::arch::interrupts::tests::TESTS,
+    ::arch::interrupts::gdt::tests::TESTS,
+    ::arch::interrupts::guard::tests::TESTS,
+    ::arch::interrupts::utils::tests::TESTS,
+    ::arch::interrupts::softirq::tests::TESTS,
+    ::arch::interrupts::frame::tests::TESTS,
+    ::arch::interrupts::exceptions::tests::TESTS,
+    ::acpi::tests::TESTS,
+    ::acpi::rsdp::tests::TESTS,
+    ::acpi::fadt::tests::TESTS,
+    ::acpi::pm::tests::TESTS,
+    ::device::driver::tests::TESTS,
+    ::device::hpet::tests::TESTS,
+    ::device::pit::tests::TESTS,
+    ::device::pci::tests::TESTS,
+    ::device::ata::tests::TESTS,
+    ::device::io::cpuio::tests::TESTS,
+    ::device::io::mmio::tests::TESTS,
+    ::device::ps2::tests::TESTS,
+    ::device::mouse::tests::TESTS,
+    ::device::keyboard::ps2_keyboard::tests::TESTS,
+    ::device::serial::tests::TESTS,
+    ::device::console::tests::TESTS,
+    ::device::vga::buffer::tests::TESTS,
+    ::device::vga::vga::tests::TESTS,
+    ::fs::procfs::tests::TESTS,
+    ::task::tests::TESTS,
+    ::task::coop_sched::tests::TESTS,
+    ::task::wait_queue::tests::TESTS,
+];
+
+/// Tests that end the session, and so are only run when asked for by name. They check how the
+/// kernel goes down, from outside QEMU.
+static FATAL_TESTS: &[&[KTest]] = &[
+    ::arch::interrupts::fatal::tests::FATAL_TESTS,
+    ::arch::interrupts::guard::tests::FATAL_TESTS,
+    ::arch::memory::heap_allocator::tests::FATAL_TESTS,
+    ::arch::memory::tests::FATAL_TESTS,
+    ::macros::tests::FATAL_TESTS,
+];
+
+/// Run every test whose name matches `filter`, or all of them if there is no filter, printing a
+/// line per test. Tests in `FATAL_TESTS` only run if named. Returns the number of tests that
+/// failed.
+pub fn run(filter: Option<&str>) -> usize {
+    let mut ran = 0;
+    let mut failed = 0;
+
+    let tests = SUITES
+        .iter()
+        .flat_map(|suite| suite.iter())
+        .filter(|test| filter.map_or(true, |name| name == test.name));
+    let fatal = FATAL_TESTS
+        .iter()
+        .flat_map(|suite| suite.iter())
+        .filter(|test| filter == Some(test.name));
+    for test in tests.chain(fatal) {
+        ran += 1;
+        match (test.run)() {
+            Ok(()) => println!("[ ktest ] {} ... ok", test.name),
+            Err(err) => {
+                failed += 1;
+                println!("[ ktest ] {} ... FAILED: {}", test.name, err);
+            }
+        }
+    }
+
+    if ran == 0 {
+        println!("[ ktest ] No test named {}", filter.unwrap_or(""));
+    } else {
+        println!("[ ktest ] {} passed, {} failed", ran - failed, failed);
+    }
+
+    failed
+}
+
+/// Run the tests requested on the kernel command line, if any.
+pub fn run_from_args() {
+    let args = ::arch::args::get();
+
+    if let Some(name) = args.get("ktest") {
+        run(Some(name));
+    } else if args.has("ktest") {
+        run(None);
+    }
+}
+
+/// The message `kassert_eq!` and `kassert_ne!` print when `left op right` does not hold at
+/// `location`, a file, line and column.
+pub fn assertion_message(
+    op: &str,
+    left: &Debug,
+    right: &Debug,
+    location: (&str, u32, u32),
+) -> String {
+    let (file, line, column) = location;
+    format!(
+        "[ FAIL ] {}:{}:{}: assertion failed: `(left {} right)`\n  left: `{:?}`,\n right: `{:?}`",
+        file, line, column, op, left, right
+    )
+}
+
+/// The start of a P4 slot nothing else maps anything in, for tests that need a scratch mapping in
+/// the active table.
+pub const SCRATCH_ADDRESS: usize = 0xffff_fd00_0000_0000;
+
+/// The start of a P4 slot the kernel does not use, for user mappings in a new address space.
+pub const USER_ADDRESS: usize = 0x0000_4000_0000_0000;
+
+/// The frame containing `address`.
+pub fn frame_at(address: usize) -> Frame {
+    Frame::containing_address(PhysicalAddress::new(address))
+}
+
+/// A temporary page for editing inactive tables, on the page `paging::init` used for the same.
+pub fn temporary_page() -> TemporaryPage {
+    TemporaryPage::new(Page::containing_address(VirtualAddress::new(0xcafe_babe_000)))
+}
+
+/// An empty address space in a newly allocated P4 frame, mapping only itself recursively.
+pub fn new_table(
+    active_table: &mut ActivePageTable,
+    temporary_page: &mut TemporaryPage,
+) -> Result<InactivePageTable, &'static str> {
+    let p4_frame = memory::allocate_frames(1).ok_or("could not allocate a P4 frame")?;
+    Ok(InactivePageTable::new(p4_frame, active_table, temporary_page))
+}
+
+/// Lay out a multiboot information structure holding `tags`, each a type and its data, in
+/// `buffer`, and return its address for `multiboot2::load`.
+///
+/// # Panics
+///
+/// Panics if the structure does not fit in `buffer`.
+pub fn multiboot_info(buffer: &mut [u64], tags: &[(u32, &[u8])]) -> usize {
+    fn put(bytes: &mut [u8], offset: usize, value: u32) {
+        for i in 0..4 {
+            bytes[offset + i] = (value >> (i * 8)) as u8;
+        }
+    }
+
+    let len = buffer.len() * 8;
+    let bytes = unsafe { slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, len) };
+
+    // Skip `total_size` and `reserved`, and pad each tag to 8 bytes. The list ends with a tag of
+    // type 0.
+    let mut offset = 8;
+    for &(typ, data) in tags.iter().chain([(0, &[][..])].iter()) {
+        put(bytes, offset, typ);
+        put(bytes, offset + 4, 8 + data.len() as u32);
+        bytes[offset + 8..offset + 8 + data.len()].copy_from_slice(data);
+        offset = (offset + 8 + data.len() + 7) & !7;
+    }
+    put(bytes, 0, offset as u32);
+
+    bytes.as_ptr() as usize
+}
+
+/// Make `bytes` arrive on COM1 by looping them back through the port, and run the receive
+/// interrupt handler on them so that they are waiting in its buffer.
+pub fn serial_receive(bytes: &[u8]) {
+    use device::serial::{self, COM1};
+
+    without_interrupts(|| {
+        COM1.lock().feed(bytes);
+        serial::handle_interrupt();
+    });
+}
+
+/// Whether `pid` is still in the task table.
+pub fn alive(pid: ProcessId) -> bool {
+    SCHEDULER.stats().iter().any(|stats| stats.pid == pid)
+}
+
+/// Give way to other tasks until `done` holds, at most 100 times. Returns whether it held.
+pub fn yield_until<F: Fn() -> bool>(done: F) -> bool {
+    for _ in 0..100 {
+        if done() {
+            return true;
+        }
+        disable_interrupts_and_then(|| unsafe { SCHEDULER.resched() });
+    }
+
+    done()
+}
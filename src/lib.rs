@@ -33,11 +33,16 @@ extern crate heapless;
 
 #[macro_use]
 mod macros;
+#[macro_use]
+pub mod klib;
 pub mod device;
 pub mod task;
 pub mod syscall;
 pub mod arch;
 pub mod acpi;
+pub mod fs;
+pub mod ktest;
+pub mod top;
 mod runtime_glue;
 
 pub use runtime_glue::*;
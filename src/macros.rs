@@ -1,4 +1,17 @@
+/// Print to the console: COM1, and the screen unless mirroring has been turned off.
 macro_rules! print {
+    ($($arg:tt)*) => ({
+        ::device::console::print(format_args!($($arg)*));
+    });
+}
+
+macro_rules! println {
+    ($fmt:expr) => (print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
+}
+
+/// Print to COM1 only, whether or not the console is mirrored to the screen.
+macro_rules! serial_print {
     ($($arg:tt)*) => ({
         use device::serial;
         use core::fmt::Write;
@@ -7,9 +20,9 @@ macro_rules! print {
     });
 }
 
-macro_rules! println {
-    ($fmt:expr) => (print!(concat!($fmt, "\n")));
-    ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
+macro_rules! serial_println {
+    ($fmt:expr) => (serial_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => (serial_print!(concat!($fmt, "\n"), $($arg)*));
 }
 
 macro_rules! format {
@@ -29,3 +42,77 @@ macro_rules! tty_switch {
         switch($x);
     });
 }
+
+/// Assert that two expressions are equal. On failure, print both values and the location of the
+/// assertion to serial, and exit QEMU with the failure code.
+macro_rules! kassert_eq {
+    ($left:expr, $right:expr) => ({
+        match (&$left, &$right) {
+            (left_val, right_val) => {
+                if !(*left_val == *right_val) {
+                    let location = (file!(), line!(), column!());
+                    println!("{}", ::ktest::assertion_message("==", left_val, right_val, location));
+                    ::device::qemu::exit(::device::qemu::ExitCode::Failure);
+                }
+            }
+        }
+    });
+}
+
+/// Assert that two expressions are not equal. On failure, print both values and the location of
+/// the assertion to serial, and exit QEMU with the failure code.
+macro_rules! kassert_ne {
+    ($left:expr, $right:expr) => ({
+        match (&$left, &$right) {
+            (left_val, right_val) => {
+                if *left_val == *right_val {
+                    let location = (file!(), line!(), column!());
+                    println!("{}", ::ktest::assertion_message("!=", left_val, right_val, location));
+                    ::device::qemu::exit(::device::qemu::ExitCode::Failure);
+                }
+            }
+        }
+    });
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "kassert_message",
+            run: kassert_message,
+        },
+    ];
+
+    pub const FATAL_TESTS: &[KTest] = &[
+        KTest {
+            name: "kassert_eq_exit",
+            run: kassert_eq_exit,
+        },
+    ];
+
+    fn kassert_message() -> Result<(), &'static str> {
+        use ktest::assertion_message;
+
+        kassert_eq!(1 + 1, 2);
+        kassert_ne!(1 + 1, 3);
+
+        let message = assertion_message("==", &(1 + 1), &"three", ("src/macros.rs", 12, 5));
+        if !message.contains("src/macros.rs:12:5") {
+            Err("the assertion message does not give its location")
+        } else if !message.contains("left: `2`") || !message.contains("right: `\"three\"`") {
+            Err("the assertion message does not print both operands")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Should print `left: `2`` and `right: `3`` with this location, and exit QEMU with the
+    /// failure code. Run with `ktest=kassert_eq_exit`.
+    fn kassert_eq_exit() -> Result<(), &'static str> {
+        kassert_eq!(1 + 1, 3);
+        Err("a failing kassert_eq! returned")
+    }
+}
@@ -11,7 +11,9 @@ pub extern "C" fn eh_personality() {}
 pub extern "C" fn panic_fmt(fmt: core::fmt::Arguments, file: &'static str, line: u32) -> ! {
     println!("\n\nPANIC in {} at line {}:", file, line);
     println!("    {}", fmt);
-    loop {}
+
+    unsafe { asm!("cli" :::: "volatile") };
+    ::arch::interrupts::fatal::die()
 }
 
 #[allow(non_snake_case)]
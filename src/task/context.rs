@@ -1,3 +1,5 @@
+use core::mem;
+
 #[derive(Clone, Debug)]
 /// Register context.
 pub struct Context {
@@ -10,6 +12,9 @@ pub struct Context {
     r13: usize,
     r14: usize,
     r15: usize,
+    /// Where `timer_entry` saved every register and the interrupt frame when the timer switched
+    /// this context out, or 0 if it was switched out by `switch_to`.
+    frame: usize,
 }
 
 impl Context {
@@ -25,6 +30,7 @@ impl Context {
             r13: 0,
             r14: 0,
             r15: 0,
+            frame: 0,
         }
     }
 
@@ -57,6 +63,47 @@ impl Context {
         asm!("mov rbp, $0" : : "r"(next.rbp) : "memory" : "intel", "volatile");
     }
 
+    /// Switch to `next`, which the timer switched out, by restoring the registers and interrupt
+    /// frame it saved at `frame` and returning from that interrupt. This context is saved as by
+    /// `switch_to`, so it resumes by returning from this call.
+    #[naked]
+    #[inline(never)]
+    pub unsafe extern "C" fn switch_to_frame(&mut self, next: &mut Context, frame: usize) {
+        asm!("pushfq ; pop $0" : "=r"(self.rflags) : : "memory" : "intel", "volatile");
+
+        asm!("mov $0, cr3" : "=r"(self.cr3) : : "memory" : "intel", "volatile");
+        asm!("mov $0, rbx" : "=r"(self.rbx) : : "memory" : "intel", "volatile");
+        asm!("mov $0, r12" : "=r"(self.r12) : : "memory" : "intel", "volatile");
+        asm!("mov $0, r13" : "=r"(self.r13) : : "memory" : "intel", "volatile");
+        asm!("mov $0, r14" : "=r"(self.r14) : : "memory" : "intel", "volatile");
+        asm!("mov $0, r15" : "=r"(self.r15) : : "memory" : "intel", "volatile");
+        asm!("mov $0, rsp" : "=r"(self.rsp) : : "memory" : "intel", "volatile");
+        asm!("mov $0, rbp" : "=r"(self.rbp) : : "memory" : "intel", "volatile");
+
+        if next.cr3 != self.cr3 {
+            asm!("mov cr3, $0" : : "r"(next.cr3) : "memory" : "intel", "volatile");
+        }
+
+        asm!("mov rsp, $0 ; jmp interrupt_return" : : "r"(frame) : "memory" : "intel", "volatile");
+    }
+
+    /// Record that the timer switched this context out with its registers saved at `frame`, and
+    /// load the page table of `next`. The timer then returns into `next` instead.
+    pub unsafe fn save_frame(&mut self, frame: usize, next: &Context) {
+        asm!("mov $0, cr3" : "=r"(self.cr3) : : "memory" : "intel", "volatile");
+        self.frame = frame;
+
+        if next.cr3 != self.cr3 {
+            asm!("mov cr3, $0" : : "r"(next.cr3) : "memory" : "intel", "volatile");
+        }
+    }
+
+    /// Return where the timer saved this context's registers, or 0 if it was switched out by
+    /// `switch_to`, and forget it, since the frame is used up by resuming.
+    pub fn take_frame(&mut self) -> usize {
+        mem::replace(&mut self.frame, 0)
+    }
+
     /// Set the active page table of this context.
     pub fn set_page_table(&mut self, address: usize) {
         self.cr3 = address;
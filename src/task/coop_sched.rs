@@ -1,12 +1,16 @@
-use alloc::VecDeque;
 use alloc::vec::Vec;
 use alloc::String;
 use core::mem;
 use core::ops::DerefMut;
 use core::sync::atomic::{AtomicUsize, Ordering};
-use task::{Process, ProcessId, ProcessList, Scheduling, State, INITIAL_STACK};
+use task::{JoinError, Process, ProcessId, ProcessList, ProcessStats, Scheduling, State, EXITED,
+           INITIAL_STACK, KERNEL_STACK_PAGES};
 use task::process;
-use spin::RwLock;
+use arch::memory::{self, STACK_CANARY};
+use arch::interrupts;
+use arch::without_interrupts;
+use klib::{IntrusiveList, RingBuffer};
+use spin::{Mutex, RwLock};
 
 /// Global kernel scheduler type.
 pub type Scheduler = CoopScheduler;
@@ -16,7 +20,16 @@ pub type Scheduler = CoopScheduler;
 pub struct CoopScheduler {
     current_pid: AtomicUsize,
     task_table: RwLock<ProcessList>,
-    ready_list: RwLock<VecDeque<ProcessId>>,
+    /// Processes ready to run, linked through their `ready_node`. Processes live behind an `Arc`
+    /// in the task table, so they stay in place while they are on this list. A process must be
+    /// taken off this list before it is removed from the task table.
+    ready_list: RwLock<IntrusiveList<Process>>,
+    /// Processes woken by `wake`, to be put on the ready list the next time `resched` runs. Only
+    /// locked with interrupts disabled, so that interrupt handlers can wake processes without
+    /// touching the task table or the ready list, which are held with interrupts enabled.
+    woken: Mutex<RingBuffer<ProcessId>>,
+    /// Timer ticks charged to the null process.
+    idle_ticks: AtomicUsize,
 }
 
 impl Scheduling for CoopScheduler {
@@ -25,6 +38,9 @@ impl Scheduling for CoopScheduler {
     fn create(&self, func: extern "C" fn(), name: String) -> Result<ProcessId, i16> {
         use arch::memory::paging;
 
+        // Recycle the stacks of processes that have exited since the last one was created.
+        self.reap();
+
         let mut stack: Vec<usize> = vec![0; INITIAL_STACK];
 
         let proc_top: usize = stack.len() - 3;
@@ -49,13 +65,27 @@ impl Scheduling for CoopScheduler {
             stack[proc_top + i] = *val;
         }
 
+        // The stack grows down, so an overflow clobbers the first element.
+        stack[0] = STACK_CANARY;
+
+        let kernel_stack =
+            memory::with_controller(|controller| controller.alloc_stack(KERNEL_STACK_PAGES));
+        let kernel_stack = match kernel_stack {
+            Some(kernel_stack) => kernel_stack,
+            None => {
+                println!("[ ERR ] Could not allocate a kernel stack for {}", name);
+                return Err(-1);
+            }
+        };
+
         let mut task_table_lock = self.task_table.write();
 
-        let proc_lock = task_table_lock.add()?;
+        let proc_lock = log_err!(task_table_lock.add(), "task table full");
         {
             let mut process = proc_lock.write();
 
             process.stack = Some(stack);
+            process.kernel_stack = Some(kernel_stack);
             process.name = name;
 
             // Create a new page table. This saves the address placed in cr3 after page table
@@ -76,10 +106,9 @@ impl Scheduling for CoopScheduler {
         ProcessId(self.current_pid.load(Ordering::SeqCst))
     }
 
-    /// Kill the process. We do this by marking it as free in the task table.
-    /// To free memory held by the process, we drop the String that holds the process name,
-    /// and mark the Option stack as None - this causes the memory held by the Some() to be
-    /// dropped.
+    /// Kill the process. We do this by marking it as free in the task table, and waking anything
+    /// joining it. A process killing itself is still running on its stacks, so they are only
+    /// freed by `reap` once something else runs.
     fn kill(&self, id: ProcessId) {
         {
             let task_table_lock = self.task_table.read();
@@ -89,27 +118,74 @@ impl Scheduling for CoopScheduler {
                 .write();
 
             proc_lock.set_state(State::Free);
-            proc_lock.stack = None;
+            if proc_lock.exit_code.is_none() {
+                proc_lock.exit_code = Some(-1);
+            }
+
+            if proc_lock.ready_node.is_linked() {
+                let process = proc_lock.deref_mut() as *mut Process;
+                unsafe { self.ready_list.write().remove(process) };
+            }
             drop(&mut proc_lock.name);
         }
 
+        EXITED.wake_all();
+
         unsafe {
             self.resched();
         }
     }
 
-    /// Mark a process as ready which enables it to be ran under resched().
+    /// Mark a process as ready which enables it to be ran under resched(). Readying a process
+    /// that is already on the ready list does nothing.
     fn ready(&self, id: ProcessId) {
-        self.ready_list.write().push_back(id);
+        let task_table_lock = self.task_table.read();
+        let mut proc_lock = task_table_lock
+            .get(id)
+            .expect("Cannot ready a non-existent process")
+            .write();
+
+        if !proc_lock.ready_node.is_linked() {
+            let process = proc_lock.deref_mut() as *mut Process;
+            unsafe { self.ready_list.write().push_back(process) };
+        }
+    }
+
+    /// Mark a process as blocked. It is not put back on the ready list when it is switched away
+    /// from, and only runs again once something calls ready() on it.
+    fn block(&self, id: ProcessId) {
+        let task_table_lock = self.task_table.read();
+        let mut proc_lock = task_table_lock
+            .get(id)
+            .expect("Cannot block a non-existent process")
+            .write();
+
+        proc_lock.set_state(State::Blocked);
     }
 
     /// Perform a context switch to the new process. This method will deadlock if any software
     /// locks are still held - it is therefore important to scope locking of data structures to
     /// ensure that these locks will be dropped.
     unsafe fn resched(&self) {
+        self.switch(0);
+    }
+}
+
+impl CoopScheduler {
+    /// Switch to the next ready process, as `resched`. `frame` is where `timer_entry` saved the
+    /// registers of the code the timer interrupted, or 0 if we were not called from the timer.
+    /// Returns the frame the timer should return into.
+    ///
+    /// A process the timer switched out has its registers saved in such a frame. From the timer,
+    /// we switch to it by returning its frame, and from anywhere else by jumping into the frame.
+    /// A process that called `resched` itself is switched to with `switch_to`, and the timer
+    /// then returns into its own `frame` once the interrupted process runs again.
+    unsafe fn switch(&self, frame: usize) -> usize {
+        self.ready_woken();
+
         {
             if self.ready_list.read().is_empty() {
-                return;
+                return frame;
             }
         }
 
@@ -130,10 +206,15 @@ impl Scheduling for CoopScheduler {
 
             if prev.state == State::Current {
                 prev.set_state(State::Ready);
-                ready_list_lock.push_back(curr_id);
+                if !prev.ready_node.is_linked() {
+                    ready_list_lock.push_back(prev.deref_mut() as *mut Process);
+                }
             }
 
-            if let Some(next_id) = ready_list_lock.pop_front() {
+            if let Some(next) = ready_list_lock.pop_front() {
+                // Everything on the ready list is a live process in the task table.
+                let next_id = (*next).pid;
+
                 if next_id != self.get_id() {
                     let mut next = task_table_lock
                         .get(next_id)
@@ -147,6 +228,9 @@ impl Scheduling for CoopScheduler {
                     // Save process pointers for out of scope context switch
                     prev_ptr = prev.deref_mut() as *mut Process;
                     next_ptr = next.deref_mut() as *mut Process;
+                } else {
+                    // We were the next to run, so we keep running.
+                    prev.set_state(State::Current);
                 }
             }
         }
@@ -160,19 +244,668 @@ impl Scheduling for CoopScheduler {
             let prev: &mut Process = &mut *prev_ptr;
             let next: &mut Process = &mut *next_ptr;
 
-            prev.ctx.switch_to(&mut next.ctx);
+            if !prev.check_canary() {
+                panic!(
+                    "Stack overflow in process {:?} ({}): canary clobbered",
+                    prev.pid, prev.name
+                );
+            }
+
+            // Interrupts and syscalls from ring 3 must land on the new process's kernel stack.
+            if let Some(ref kernel_stack) = next.kernel_stack {
+                interrupts::set_kernel_stack(kernel_stack.top());
+            }
+
+            let next_frame = next.ctx.take_frame();
+            if next_frame == 0 {
+                prev.ctx.switch_to(&mut next.ctx);
+            } else if frame == 0 {
+                prev.ctx.switch_to_frame(&mut next.ctx, next_frame);
+            } else {
+                prev.ctx.save_frame(frame, &next.ctx);
+                return next_frame;
+            }
         }
+
+        frame
     }
-}
 
-impl CoopScheduler {
     /// Initialise the cooperative scheduler. This sets the current PID as the null kernel process,
     /// and creates an empty task table and ready list.
     pub fn new() -> Self {
         CoopScheduler {
             current_pid: AtomicUsize::new(ProcessId::NULL_PROC.inner()),
             task_table: RwLock::new(ProcessList::new()),
-            ready_list: RwLock::new(VecDeque::<ProcessId>::new()),
+            ready_list: RwLock::new(IntrusiveList::new()),
+            woken: Mutex::new(RingBuffer::new(ProcessId::NULL_PROC)),
+            idle_ticks: AtomicUsize::new(0),
+        }
+    }
+    /// Create a kernel thread that runs `entry`, and put it on the ready list. The thread is
+    /// killed, and its stacks freed, once `entry` returns.
+    pub fn spawn(&self, name: &str, entry: fn()) -> Result<ProcessId, i16> {
+        let pid = self.create(thread_start, String::from(name))?;
+
+        {
+            let task_table_lock = self.task_table.read();
+            let mut process = task_table_lock
+                .get(pid)
+                .expect("Spawned process is not in the task table")
+                .write();
+            process.entry = Some(entry);
+        }
+
+        self.ready(pid);
+        Ok(pid)
+    }
+
+    /// Ready process `id` the next time the scheduler runs, rather than now. This only queues the
+    /// process, so it is safe to call from an interrupt handler. Returns `false` if too many
+    /// wake-ups are pending already.
+    pub fn wake(&self, id: ProcessId) -> bool {
+        without_interrupts(|| self.woken.lock().push(id))
+    }
+
+    /// Put the processes queued by `wake` on the ready list. Processes that have been killed or
+    /// joined since are skipped.
+    fn ready_woken(&self) {
+        while let Some(id) = without_interrupts(|| self.woken.lock().pop()) {
+            let task_table_lock = self.task_table.read();
+            if let Some(process) = task_table_lock.get(id) {
+                let mut proc_lock = process.write();
+                if proc_lock.state != State::Free && !proc_lock.ready_node.is_linked() {
+                    let process = proc_lock.deref_mut() as *mut Process;
+                    unsafe { self.ready_list.write().push_back(process) };
+                }
+            }
+        }
+    }
+
+    /// Mark process `id` as current again if it blocked but `resched` found nothing else to run,
+    /// so that it does not keep running in the blocked state.
+    pub fn resume(&self, id: ProcessId) {
+        if let Some(process) = self.task_table.read().get(id) {
+            let mut process = process.write();
+            if process.state == State::Blocked {
+                process.set_state(State::Current);
+            }
+        }
+    }
+
+    /// Free the stacks of every process that has been killed, other than the current one, which
+    /// may still be running on them. The processes stay in the task table, with their exit codes.
+    /// This takes the heap and memory controller locks, so it must not be called from an
+    /// interrupt handler.
+    pub fn reap(&self) {
+        let mut stacks = Vec::new();
+
+        {
+            let current = self.get_id();
+            let task_table_lock = self.task_table.read();
+            for (&pid, process) in task_table_lock.iter() {
+                let mut process = process.write();
+                if pid != current && process.state == State::Free {
+                    stacks.push((process.stack.take(), process.kernel_stack.take()));
+                }
+            }
+        }
+
+        // Free them with the task table unlocked, since freeing takes locks of its own.
+        for (stack, kernel_stack) in stacks {
+            drop(stack);
+            if let Some(kernel_stack) = kernel_stack {
+                memory::with_controller(|controller| controller.dealloc_stack(kernel_stack));
+            }
+        }
+    }
+
+    /// Record `code` as the exit code of process `id`, for when it is killed.
+    pub fn set_exit_code(&self, id: ProcessId, code: i32) {
+        if let Some(process) = self.task_table.read().get(id) {
+            process.write().exit_code = Some(code);
+        }
+    }
+
+    /// Return the exit code of process `id`, or `None` if it is still running or has been joined.
+    pub fn exit_code(&self, id: ProcessId) -> Option<i32> {
+        self.task_table
+            .read()
+            .get(id)
+            .and_then(|process| process.read().exit_code)
+    }
+
+    /// Check whether process `id` has been killed. Processes that are not in the task table count
+    /// as killed.
+    pub fn has_exited(&self, id: ProcessId) -> bool {
+        self.task_table
+            .read()
+            .get(id)
+            .map_or(true, |process| process.read().state == State::Free)
+    }
+
+    /// Take process `id` out of the task table once it has been killed, freeing whatever `reap`
+    /// has not already, and return its exit code. Returns `None`, leaving the process where it is,
+    /// if it does not exist or is still alive.
+    pub fn remove_exited(&self, id: ProcessId) -> Option<i32> {
+        let removed = {
+            let mut task_table_lock = self.task_table.write();
+            let exited = task_table_lock
+                .get(id)
+                .map_or(false, |process| process.read().state == State::Free);
+            if !exited {
+                return None;
+            }
+            task_table_lock.remove(id)?
+        };
+
+        // A killed process is off the ready list and never runs again, so the stacks are unused.
+        let (code, kernel_stack) = {
+            let mut process = removed.write();
+            (process.exit_code, process.kernel_stack.take())
+        };
+        if let Some(kernel_stack) = kernel_stack {
+            memory::with_controller(|controller| controller.dealloc_stack(kernel_stack));
+        }
+
+        code
+    }
+
+    /// Mark process `id` as joined, failing if it does not exist or already has been.
+    pub fn claim_join(&self, id: ProcessId) -> Result<(), JoinError> {
+        let task_table_lock = self.task_table.read();
+        let mut process = task_table_lock
+            .get(id)
+            .ok_or(JoinError::NoSuchTask)?
+            .write();
+
+        if process.joined {
+            return Err(JoinError::AlreadyJoined);
+        }
+        process.joined = true;
+
+        Ok(())
+    }
+
+    /// Switch to the next ready process from the timer interrupt, whose registers `timer_entry`
+    /// saved at `frame`. The interrupted code may be holding the task table or the ready list,
+    /// and waiting for them with interrupts disabled would never end, so if either is taken the
+    /// switch is skipped until the next time slice and `None` is returned. Otherwise, returns the
+    /// frame the timer should return into. Interrupts must be disabled.
+    pub unsafe fn preempt(&self, frame: usize) -> Option<usize> {
+        // Every process lock is taken under the task table, so a free table means they are too.
+        let free = self.task_table.try_write().is_some() && self.ready_list.try_write().is_some();
+        if free {
+            Some(self.switch(frame))
+        } else {
+            None
+        }
+    }
+
+    /// Charge a timer tick to the current process. This is called from the timer handler.
+    pub fn tick(&self) {
+        self.charge_tick(self.get_id());
+    }
+
+    /// Charge a timer tick to the process `id`. Ticks of the null process, which only runs when
+    /// nothing else is ready, are counted as idle time rather than as its runtime, so that it does
+    /// not show up as the busiest process. The task table may be held by the code the timer
+    /// interrupted, so its locks are only tried, and a tick that finds them taken is dropped.
+    pub fn charge_tick(&self, id: ProcessId) {
+        if id == ProcessId::NULL_PROC {
+            self.idle_ticks.fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+
+        if let Some(task_table) = self.task_table.try_read() {
+            if let Some(process) = task_table.get(id) {
+                if let Some(mut process) = process.try_write() {
+                    process.runtime_ticks += 1;
+                }
+            }
+        }
+    }
+
+    /// Return the number of timer ticks that landed while nothing but the null process could run.
+    pub fn idle_ticks(&self) -> usize {
+        self.idle_ticks.load(Ordering::SeqCst)
+    }
+
+    /// Return a snapshot of every live process, in PID order.
+    pub fn stats(&self) -> Vec<ProcessStats> {
+        self.task_table
+            .read()
+            .iter()
+            .map(|(_, process)| process.read().stats())
+            .filter(|stats| stats.state != State::Free)
+            .collect()
+    }
+}
+
+/// Where every thread from `spawn` starts. It exits with code 0 once the thread's entry returns.
+extern "C" fn thread_start() {
+    use task::SCHEDULER;
+
+    // A new context starts with interrupts disabled.
+    interrupts::enable_interrupts();
+
+    let entry = {
+        let task_table_lock = SCHEDULER.task_table.read();
+        task_table_lock
+            .get(SCHEDULER.get_id())
+            .and_then(|process| process.read().entry)
+    };
+
+    if let Some(entry) = entry {
+        entry();
+    }
+
+    ::task::exit(0);
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT,
+                             ATOMIC_USIZE_INIT};
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "task_runtime",
+            run: task_runtime,
+        },
+        KTest {
+            name: "preemption",
+            run: preemption,
+        },
+        KTest {
+            name: "preempt_registers",
+            run: preempt_registers,
+        },
+        KTest {
+            name: "wake_locked",
+            run: wake_locked,
+        },
+        KTest {
+            name: "kernel_stacks",
+            run: kernel_stacks,
+        },
+    ];
+
+    fn task_runtime() -> Result<(), &'static str> {
+        use alloc::String;
+        use arch::without_interrupts;
+        use device::pit;
+        use task::{ProcessId, Scheduling, SCHEDULER};
+
+        const TICKS: usize = 5;
+
+        // Never readied, so it only exists to have ticks charged to it.
+        extern "C" fn never_run() {}
+
+        let pid = SCHEDULER
+            .create(never_run, String::from("ktest_runtime"))
+            .map_err(|_| "could not create a process")?;
+
+        for _ in 0..TICKS {
+            SCHEDULER.charge_tick(pid);
+        }
+
+        let runtime = |pid: ProcessId| SCHEDULER.stats().into_iter().find(|stats| stats.pid == pid);
+        let stats = runtime(pid);
+
+        // Keep the timer from charging idle ticks of its own in between.
+        let (idle_before, idle_after) = without_interrupts(|| {
+            let before = SCHEDULER.idle_ticks();
+            SCHEDULER.charge_tick(ProcessId::NULL_PROC);
+            (before, SCHEDULER.idle_ticks())
+        });
+        let null_runtime = runtime(ProcessId::NULL_PROC).map(|stats| stats.runtime_ticks);
+
+        SCHEDULER.kill(pid);
+
+        match stats {
+            None => Err("the process is not in the task table"),
+            Some(ref stats) if stats.runtime_ticks != TICKS => {
+                Err("the process does not report the ticks it was charged")
+            }
+            Some(ref stats) if stats.cpu_time_ms != pit::ticks_to_ms(TICKS) => {
+                Err("the CPU time does not match the ticks")
+            }
+            Some(_) if idle_after != idle_before + 1 => Err("an idle tick was not counted as idle"),
+            Some(_) if null_runtime != Some(0) => {
+                Err("idle ticks were charged to the null process")
+            }
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// How many steps `busy_thread` has taken, and the sum it ended with.
+    static BUSY_STEPS: AtomicUsize = ATOMIC_USIZE_INIT;
+    static BUSY_SUM: AtomicUsize = ATOMIC_USIZE_INIT;
+    /// Set once `preemption` has seen `busy_thread` part way through, so that it can finish.
+    static BUSY_SEEN: AtomicBool = ATOMIC_BOOL_INIT;
+
+    fn busy_step(sum: usize, i: usize) -> usize {
+        sum.wrapping_mul(31).wrapping_add(i)
+    }
+
+    /// Spin without ever giving way, keeping the running sum in locals, until `preemption` has seen
+    /// the thread part way through, and then for as many steps again.
+    fn busy_thread() {
+        let mut sum = 0;
+        let mut i = 0;
+        let mut stop = usize::max_value();
+
+        while i < stop {
+            sum = busy_step(sum, i);
+            i += 1;
+            BUSY_STEPS.store(i, Ordering::SeqCst);
+
+            if stop == usize::max_value() && BUSY_SEEN.load(Ordering::SeqCst) {
+                stop = i * 2;
+            }
+        }
+
+        BUSY_SUM.store(sum, Ordering::SeqCst);
+    }
+
+    /// The test never gives way itself, so it only sees `busy_thread` part way through if the timer
+    /// switched the thread out, and the thread only finishes if the timer switched it back in. The
+    /// sum it finishes with shows that it picked up exactly where it was interrupted.
+    fn preemption() -> Result<(), &'static str> {
+        use arch::ARCH;
+        use device::pit;
+        use ktest::alive;
+        use task;
+
+        BUSY_STEPS.store(0, Ordering::SeqCst);
+        BUSY_SEEN.store(false, Ordering::SeqCst);
+
+        let pid = task::spawn("ktest_busy", busy_thread).map_err(|_| "could not spawn a thread")?;
+
+        let deadline = pit::uptime_ms() + 5000;
+        let mut seen = 0;
+        while alive(pid) && pit::uptime_ms() < deadline {
+            let steps = BUSY_STEPS.load(Ordering::SeqCst);
+            if seen == 0 && steps != 0 {
+                seen = steps;
+                BUSY_SEEN.store(true, Ordering::SeqCst);
+            }
+            ARCH.halt();
+        }
+
+        if alive(pid) {
+            // Let the thread finish whenever it next runs.
+            BUSY_SEEN.store(true, Ordering::SeqCst);
+            return Err("the timer did not switch the thread out and back in");
+        }
+
+        let steps = BUSY_STEPS.load(Ordering::SeqCst);
+        let expected = (0..steps).fold(0, busy_step);
+
+        if seen == 0 || steps <= seen {
+            Err("the thread was never seen part way through")
+        } else if BUSY_SUM.load(Ordering::SeqCst) != expected {
+            Err("the thread did not resume where it was switched out")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Set by `register_thread` just before it starts spinning, by `preempt_registers` to let it
+    /// stop, and by `register_thread` if its registers held their values throughout.
+    static REGISTERS_SPINNING: AtomicBool = ATOMIC_BOOL_INIT;
+    static REGISTERS_DONE: AtomicBool = ATOMIC_BOOL_INIT;
+    static REGISTERS_KEPT: AtomicBool = ATOMIC_BOOL_INIT;
+
+    /// Fill every general purpose register but RAX, RBP and RSP with a pattern, and spin without
+    /// touching them or giving way until `preempt_registers` is done, then check the patterns.
+    fn register_thread() {
+        let kept: usize;
+
+        REGISTERS_SPINNING.store(true, Ordering::SeqCst);
+        unsafe {
+            asm!("
+                mov rbx, 0x0101010101010101
+                mov rcx, 0x0202020202020202
+                mov rdx, 0x0303030303030303
+                mov rsi, 0x0404040404040404
+                mov rdi, 0x0505050505050505
+                mov r8, 0x0606060606060606
+                mov r9, 0x0707070707070707
+                mov r10, 0x0808080808080808
+                mov r11, 0x0909090909090909
+                mov r12, 0x0a0a0a0a0a0a0a0a
+                mov r13, 0x0b0b0b0b0b0b0b0b
+                mov r14, 0x0c0c0c0c0c0c0c0c
+                mov r15, 0x0d0d0d0d0d0d0d0d
+            1:
+                cmp byte ptr [rax], 0
+                je 1b
+
+                mov rax, 0x0101010101010101
+                cmp rbx, rax
+                jne 2f
+                mov rax, 0x0202020202020202
+                cmp rcx, rax
+                jne 2f
+                mov rax, 0x0303030303030303
+                cmp rdx, rax
+                jne 2f
+                mov rax, 0x0404040404040404
+                cmp rsi, rax
+                jne 2f
+                mov rax, 0x0505050505050505
+                cmp rdi, rax
+                jne 2f
+                mov rax, 0x0606060606060606
+                cmp r8, rax
+                jne 2f
+                mov rax, 0x0707070707070707
+                cmp r9, rax
+                jne 2f
+                mov rax, 0x0808080808080808
+                cmp r10, rax
+                jne 2f
+                mov rax, 0x0909090909090909
+                cmp r11, rax
+                jne 2f
+                mov rax, 0x0a0a0a0a0a0a0a0a
+                cmp r12, rax
+                jne 2f
+                mov rax, 0x0b0b0b0b0b0b0b0b
+                cmp r13, rax
+                jne 2f
+                mov rax, 0x0c0c0c0c0c0c0c0c
+                cmp r14, rax
+                jne 2f
+                mov rax, 0x0d0d0d0d0d0d0d0d
+                cmp r15, rax
+                jne 2f
+                mov rax, 1
+                jmp 3f
+            2:
+                xor rax, rax
+            3:"
+                : "={rax}"(kept)
+                : "{rax}"(&REGISTERS_DONE as *const AtomicBool)
+                : "rbx", "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+                  "r14", "r15", "cc", "memory"
+                : "intel", "volatile");
+        }
+
+        REGISTERS_KEPT.store(kept == 1, Ordering::SeqCst);
+    }
+
+    /// The thread spins with interrupts enabled and never gives way, so the test only runs again,
+    /// and the thread only finishes, if the timer switched it out and back in through
+    /// `timer_entry`. Every register it was interrupted with must come back as it was.
+    fn preempt_registers() -> Result<(), &'static str> {
+        use arch::ARCH;
+        use device::pit;
+        use ktest::alive;
+        use task;
+
+        REGISTERS_SPINNING.store(false, Ordering::SeqCst);
+        REGISTERS_DONE.store(false, Ordering::SeqCst);
+        REGISTERS_KEPT.store(false, Ordering::SeqCst);
+
+        let pid = task::spawn("ktest_registers", register_thread)
+            .map_err(|_| "could not spawn a thread")?;
+
+        let deadline = pit::uptime_ms() + 5000;
+        while !REGISTERS_SPINNING.load(Ordering::SeqCst) && pit::uptime_ms() < deadline {
+            ARCH.halt();
+        }
+        let spun = REGISTERS_SPINNING.load(Ordering::SeqCst);
+
+        // Let the thread be switched out and back in a few more times.
+        let switches = pit::uptime_ms() + 100;
+        while pit::uptime_ms() < switches {
+            ARCH.halt();
+        }
+
+        REGISTERS_DONE.store(true, Ordering::SeqCst);
+        while alive(pid) && pit::uptime_ms() < deadline {
+            ARCH.halt();
+        }
+
+        if !spun {
+            Err("the thread never ran")
+        } else if alive(pid) {
+            Err("the timer did not switch the thread back in")
+        } else if !REGISTERS_KEPT.load(Ordering::SeqCst) {
+            Err("a register changed while the thread was switched out")
+        } else {
+            Ok(())
+        }
+    }
+
+    lazy_static! {
+        static ref SLEEPERS: ::task::WaitQueue = ::task::WaitQueue::new();
+    }
+    /// Set once `sleeper` may stop waiting.
+    static SLEEPER_DONE: AtomicBool = ATOMIC_BOOL_INIT;
+
+    fn sleeper() {
+        SLEEPERS.wait_until(|| SLEEPER_DONE.load(Ordering::SeqCst));
+    }
+
+    /// Wake a thread the way an interrupt handler that interrupted the scheduler would, with the
+    /// task table held. The wake-up must not wait for the lock, and the thread must still run once
+    /// the lock is dropped. Waiting with nothing to wake us must leave us current.
+    fn wake_locked() -> Result<(), &'static str> {
+        use arch::without_interrupts;
+        use ktest::{alive, yield_until};
+        use task::{self, ProcessId, Scheduling, State, SCHEDULER};
+
+        let state = |pid: ProcessId| {
+            SCHEDULER
+                .stats()
+                .into_iter()
+                .find(|stats| stats.pid == pid)
+                .map(|stats| stats.state)
+        };
+
+        SLEEPER_DONE.store(false, Ordering::SeqCst);
+        let pid = task::spawn("ktest_sleeper", sleeper).map_err(|_| "could not spawn a thread")?;
+
+        if !yield_until(|| state(pid) == Some(State::Blocked)) {
+            SLEEPER_DONE.store(true, Ordering::SeqCst);
+            return Err("the thread never blocked on the queue");
+        }
+
+        SLEEPER_DONE.store(true, Ordering::SeqCst);
+        let woken = without_interrupts(|| {
+            let _task_table = SCHEDULER.task_table.write();
+            SLEEPERS.wake_one()
+        });
+        let exited = yield_until(|| !alive(pid));
+
+        SLEEPERS.wait_timeout(|| false, 10);
+        let current = state(SCHEDULER.get_id());
+
+        if !woken {
+            Err("the waiting thread was not woken")
+        } else if !exited {
+            Err("the woken thread never ran")
+        } else if current != Some(State::Current) {
+            Err("a wait that was not switched away from left us blocked")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The RSP0 each of `stack_thread_a` and `stack_thread_b` ran with, and whether they may exit.
+    static RSP0_A: AtomicUsize = ATOMIC_USIZE_INIT;
+    static RSP0_B: AtomicUsize = ATOMIC_USIZE_INIT;
+    static STACKS_DONE: AtomicBool = ATOMIC_BOOL_INIT;
+
+    /// Record RSP0, and stay alive until `kernel_stacks` has looked up the kernel stack.
+    fn record_rsp0(rsp0: &AtomicUsize) {
+        use arch::interrupts;
+use arch::without_interrupts;
+        use arch::ARCH;
+
+        rsp0.store(interrupts::kernel_stack(), Ordering::SeqCst);
+        while !STACKS_DONE.load(Ordering::SeqCst) {
+            ARCH.halt();
+        }
+    }
+
+    fn stack_thread_a() {
+        record_rsp0(&RSP0_A);
+    }
+
+    fn stack_thread_b() {
+        record_rsp0(&RSP0_B);
+    }
+
+    /// Each thread must have a kernel stack of its own, and RSP0 must point at the top of it
+    /// whenever the thread is switched in.
+    fn kernel_stacks() -> Result<(), &'static str> {
+        use ktest::{alive, yield_until};
+        use task::{self, ProcessId, SCHEDULER};
+
+        fn kernel_stack_top(pid: ProcessId) -> Option<usize> {
+            let task_table = SCHEDULER.task_table.read();
+            let process = task_table.get(pid)?.read();
+            let top = process.kernel_stack.as_ref().map(|stack| stack.top());
+            top
+        }
+
+        RSP0_A.store(0, Ordering::SeqCst);
+        RSP0_B.store(0, Ordering::SeqCst);
+        STACKS_DONE.store(false, Ordering::SeqCst);
+
+        let a = task::spawn("ktest_stack_a", stack_thread_a);
+        let b = task::spawn("ktest_stack_b", stack_thread_b);
+
+        let recorded = yield_until(|| {
+            RSP0_A.load(Ordering::SeqCst) != 0 && RSP0_B.load(Ordering::SeqCst) != 0
+        });
+        let tops = (a.ok().and_then(kernel_stack_top), b.ok().and_then(kernel_stack_top));
+
+        STACKS_DONE.store(true, Ordering::SeqCst);
+        let exited = yield_until(|| {
+            a.map_or(true, |pid| !alive(pid)) && b.map_or(true, |pid| !alive(pid))
+        });
+
+        let (top_a, top_b) = match tops {
+            (Some(top_a), Some(top_b)) => (top_a, top_b),
+            _ => return Err("the threads were not spawned with kernel stacks"),
+        };
+
+        if !recorded {
+            Err("the threads never ran")
+        } else if top_a == top_b {
+            Err("the threads share a kernel stack")
+        } else if RSP0_A.load(Ordering::SeqCst) != top_a || RSP0_B.load(Ordering::SeqCst) != top_b {
+            Err("switching to a thread did not point RSP0 at its kernel stack")
+        } else if !exited {
+            Err("the threads did not exit")
+        } else {
+            Ok(())
         }
     }
 }
@@ -2,12 +2,14 @@ pub mod context;
 pub mod process;
 pub mod proc_list;
 pub mod coop_sched;
+pub mod wait_queue;
 
 use self::coop_sched as scheduler;
 
-pub use self::process::{Process, ProcessId, State};
+pub use self::process::{Process, ProcessId, ProcessStats, State};
 pub use self::proc_list::ProcessList;
 pub use self::scheduler::Scheduler;
+pub use self::wait_queue::WaitQueue;
 use core::result::Result;
 use alloc::string::String;
 
@@ -17,6 +19,7 @@ pub trait Scheduling {
     fn get_id(&self) -> ProcessId;
     fn kill(&self, id: ProcessId);
     fn ready(&self, id: ProcessId);
+    fn block(&self, id: ProcessId);
     unsafe fn resched(&self);
 }
 
@@ -26,7 +29,189 @@ pub const MAX_PROCS: usize = usize::max_value() - 1;
 /// Initial size of vector stack.
 pub const INITIAL_STACK: usize = 1024;
 
+/// Size of each process's kernel stack, in pages.
+pub const KERNEL_STACK_PAGES: usize = 4;
+
 lazy_static! {
     /// Global kernel scheduler.
     pub static ref SCHEDULER: Scheduler = Scheduler::new();
 }
+
+/// Kernel threads are processes, so a thread is named by its PID.
+pub type TaskId = ProcessId;
+
+/// Reasons `join` can fail.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinError {
+    /// There is no task with that id.
+    NoSuchTask,
+    /// Someone else has already joined the task.
+    AlreadyJoined,
+    /// A task cannot wait for itself to exit.
+    JoinSelf,
+}
+
+lazy_static! {
+    /// Woken every time a process exits, for `join`.
+    static ref EXITED: WaitQueue = WaitQueue::new();
+}
+
+/// Start a kernel thread called `name` that runs `entry`. The thread exits with code 0, freeing
+/// its stacks, when `entry` returns.
+pub fn spawn(name: &str, entry: fn()) -> Result<TaskId, i16> {
+    use arch::interrupts::disable_interrupts_and_then;
+
+    disable_interrupts_and_then(|| SCHEDULER.spawn(name, entry))
+}
+
+/// End the current task with `code`, which is handed to whoever joins it.
+pub fn exit(code: i32) -> ! {
+    use arch::{Arch, ARCH};
+
+    let pid = SCHEDULER.get_id();
+
+    SCHEDULER.set_exit_code(pid, code);
+    SCHEDULER.kill(pid);
+
+    // Killing only switches away if something else was ready to run. This task is never put
+    // back on the ready list, so once something is, it does not return here.
+    loop {
+        unsafe { SCHEDULER.resched() };
+        ARCH.halt();
+    }
+}
+
+/// Wait for task `id` to exit and return its exit code. A task that has already exited returns
+/// straight away. Each task can only be joined once, after which it is taken out of the task
+/// table and its stacks are freed.
+pub fn join(id: TaskId) -> Result<i32, JoinError> {
+    if id == SCHEDULER.get_id() {
+        return Err(JoinError::JoinSelf);
+    }
+    SCHEDULER.claim_join(id)?;
+
+    // The exit code is set before the task is killed, so wait for the kill.
+    EXITED.wait_until(|| SCHEDULER.has_exited(id));
+
+    Ok(SCHEDULER.remove_exited(id).expect("Joined task has no exit code"))
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "spawn",
+            run: spawn,
+        },
+        KTest {
+            name: "join",
+            run: join,
+        },
+        KTest {
+            name: "reap",
+            run: reap,
+        },
+    ];
+
+    /// Bumped by the thread the `spawn` test starts.
+    static SPAWNED_RUNS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    fn spawned_thread() {
+        SPAWNED_RUNS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn spawn() -> Result<(), &'static str> {
+        use arch::memory;
+        use ktest::{alive, yield_until};
+        use task::{self, SCHEDULER};
+
+        let runs = SPAWNED_RUNS.load(Ordering::SeqCst);
+        let free_before = memory::stats().free;
+
+        let pid =
+            task::spawn("ktest_spawn", spawned_thread).map_err(|_| "could not spawn a thread")?;
+        let exited = yield_until(|| !alive(pid));
+
+        // The thread's stacks are freed once something else runs and reaps them.
+        SCHEDULER.reap();
+
+        if !exited {
+            Err("the thread did not exit")
+        } else if SPAWNED_RUNS.load(Ordering::SeqCst) != runs + 1 {
+            Err("the thread did not run exactly once")
+        } else if memory::stats().free < free_before {
+            Err("the frames of the thread's kernel stack were not freed")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn exit_with_42() {
+        ::task::exit(42);
+    }
+
+    fn exit_with_7() {
+        ::task::exit(7);
+    }
+
+    fn join() -> Result<(), &'static str> {
+        use ktest::yield_until;
+        use task::{self, JoinError, Scheduling, SCHEDULER};
+
+        let pid = task::spawn("ktest_join", exit_with_42).map_err(|_| "could not spawn a thread")?;
+        if task::join(pid) != Ok(42) {
+            return Err("the joiner did not receive the exit code");
+        }
+        if task::join(pid) != Err(JoinError::NoSuchTask) {
+            return Err("a joined task was left in the task table");
+        }
+
+        // Let the second thread finish before joining it.
+        let pid = task::spawn("ktest_join", exit_with_7).map_err(|_| "could not spawn a thread")?;
+        if !yield_until(|| SCHEDULER.exit_code(pid).is_some()) {
+            Err("the thread did not exit")
+        } else if task::join(pid) != Ok(7) {
+            Err("joining an exited task did not return its exit code")
+        } else if task::join(SCHEDULER.get_id()) != Err(JoinError::JoinSelf) {
+            Err("a task could join itself")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// An exited task keeps its exit code until it is joined, and is only removed then. Its
+    /// stacks are freed before that by `reap`.
+    fn reap() -> Result<(), &'static str> {
+        use arch::memory;
+        use ktest::{alive, yield_until};
+        use task::{self, JoinError, SCHEDULER};
+
+        let free_before = memory::stats().free;
+
+        let pid = task::spawn("ktest_reap", exit_with_42).map_err(|_| "could not spawn a thread")?;
+        if !yield_until(|| SCHEDULER.has_exited(pid)) {
+            return Err("the thread did not exit");
+        }
+
+        SCHEDULER.reap();
+        let kept = SCHEDULER.exit_code(pid);
+        let reaped = memory::stats().free >= free_before;
+
+        let code = task::join(pid);
+        let removed = SCHEDULER.exit_code(pid).is_none()
+            && SCHEDULER.claim_join(pid) == Err(JoinError::NoSuchTask);
+
+        if kept != Some(42) {
+            Err("the exit code was not kept until the task was joined")
+        } else if !reaped {
+            Err("the stacks of the exited task were not freed")
+        } else if code != Ok(42) || !removed || alive(pid) {
+            Err("joining did not return the exit code and remove the task")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use alloc::string::String;
 use alloc::vec::Vec;
 use task::context::Context;
+use klib::{Linked, ListNode};
+use arch::memory::Stack;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 /// Current state of the process.
@@ -13,6 +15,8 @@ pub enum State {
     Suspended,
     /// Process is ready to be ran by the scheduler.
     Ready,
+    /// Process is waiting on a `WaitQueue` and will not be ran until it is woken.
+    Blocked,
 }
 
 #[derive(Clone, Debug)]
@@ -32,7 +36,7 @@ impl ProcessId {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 /// A single process on the system.
 /// It has register context, id, name and an Optional process stack.
 pub struct Process {
@@ -42,6 +46,34 @@ pub struct Process {
     pub priority: Priority,
     pub ctx: Context,
     pub stack: Option<Vec<usize>>,
+    /// The stack the CPU switches to when this process enters the kernel from ring 3. The null
+    /// process never leaves ring 0, so it has none.
+    pub kernel_stack: Option<Stack>,
+    /// Links for the scheduler's ready queue.
+    pub ready_node: ListNode<Process>,
+    /// The function a kernel thread from `task::spawn` runs.
+    pub entry: Option<fn()>,
+    /// Set when the process exits, to the code passed to `task::exit`, or to -1 if it was killed
+    /// or returned without calling it.
+    pub exit_code: Option<i32>,
+    /// Whether something has called `task::join` on this process.
+    pub joined: bool,
+    /// Timer ticks that landed while this was the current process. Always 0 for the null
+    /// process, whose ticks are idle time and are counted by the scheduler instead.
+    pub runtime_ticks: usize,
+}
+
+/// A copy of the parts of a process that are reported to the user, taken so that the task table
+/// does not stay locked while they are shown.
+#[derive(Clone, Debug)]
+pub struct ProcessStats {
+    pub pid: ProcessId,
+    pub name: String,
+    pub state: State,
+    pub runtime_ticks: usize,
+    pub cpu_time_ms: usize,
+    /// Bytes of stack held by the process, counting its kernel stack.
+    pub memory: usize,
 }
 
 impl Process {
@@ -53,6 +85,12 @@ impl Process {
             priority: Priority(0),
             ctx: Context::new(),
             stack: None,
+            kernel_stack: None,
+            ready_node: ListNode::new(),
+            entry: None,
+            exit_code: None,
+            joined: false,
+            runtime_ticks: 0,
         }
     }
 
@@ -70,6 +108,54 @@ impl Process {
     pub fn set_stack(&mut self, addr: usize) {
         self.ctx.set_stack(addr);
     }
+
+    /// Return the CPU time this process has used, in milliseconds, at the timer's resolution.
+    pub fn cpu_time_ms(&self) -> usize {
+        ::device::pit::ticks_to_ms(self.runtime_ticks)
+    }
+
+    /// Return a snapshot of this process for reporting.
+    pub fn stats(&self) -> ProcessStats {
+        use core::mem;
+
+        let stack = self.stack
+            .as_ref()
+            .map_or(0, |stack| stack.len() * mem::size_of::<usize>());
+        let kernel_stack = self.kernel_stack
+            .as_ref()
+            .map_or(0, |stack| stack.top() - stack.bottom());
+
+        ProcessStats {
+            pid: self.pid,
+            name: self.name.clone(),
+            state: self.state.clone(),
+            runtime_ticks: self.runtime_ticks,
+            cpu_time_ms: self.cpu_time_ms(),
+            memory: stack + kernel_stack,
+        }
+    }
+
+    /// Check the canaries at the bottom of this process's stack and kernel stack. Processes
+    /// without stacks of their own, such as the null process, always pass.
+    pub fn check_canary(&self) -> bool {
+        use arch::memory::STACK_CANARY;
+
+        let stack = match self.stack {
+            Some(ref stack) if !stack.is_empty() => stack[0] == STACK_CANARY,
+            _ => true,
+        };
+        let kernel_stack = self.kernel_stack
+            .as_ref()
+            .map_or(true, |kernel_stack| kernel_stack.check_canary());
+
+        stack && kernel_stack
+    }
+}
+
+impl Linked for Process {
+    fn node(&mut self) -> &mut ListNode<Process> {
+        &mut self.ready_node
+    }
 }
 
 ///A returned process pops an instruction pointer off the stack then jumps to it.
@@ -0,0 +1,151 @@
+//! Blocking a process until an event, such as a device finishing a transfer, is signalled from an
+//! interrupt handler.
+
+use arch::{without_interrupts, Arch, ARCH};
+use klib::RingBuffer;
+use spin::Mutex;
+use task::{ProcessId, Scheduling, SCHEDULER};
+
+/// A list of processes waiting for a condition to become true.
+pub struct WaitQueue {
+    /// The processes waiting on this queue. Only locked with interrupts disabled, so that
+    /// `wake_one` and `wake_all` can be called from interrupt handlers. Nothing allocates while it
+    /// is held, since the allocator may be in use by the code an interrupt handler interrupted.
+    /// Waking only queues the process with the scheduler, so it never takes the scheduler's
+    /// locks.
+    waiting: Mutex<RingBuffer<ProcessId>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> WaitQueue {
+        WaitQueue {
+            waiting: Mutex::new(RingBuffer::new(ProcessId::NULL_PROC)),
+        }
+    }
+
+    /// Block the current process until `cond` returns true. `cond` is checked with interrupts
+    /// disabled before every sleep, so a wake-up between the check and the sleep is not lost. If
+    /// the queue is full, this polls `cond` on every interrupt instead of blocking.
+    pub fn wait_until<F: Fn() -> bool>(&self, cond: F) {
+        let pid = SCHEDULER.get_id();
+
+        loop {
+            let (done, queued) = without_interrupts(|| {
+                if cond() {
+                    return (true, false);
+                }
+
+                let queued = {
+                    let mut waiting = self.waiting.lock();
+                    waiting.contains(&pid) || waiting.push(pid)
+                };
+
+                if queued {
+                    SCHEDULER.block(pid);
+                    unsafe { SCHEDULER.resched() };
+                    // Does nothing if we were switched away from and have since been woken.
+                    SCHEDULER.resume(pid);
+                }
+
+                (false, queued)
+            });
+
+            if done {
+                return;
+            }
+
+            // If nothing else was ready to run we were not switched away from, and if the queue
+            // was full we never slept, so sleep until the next interrupt and look again.
+            if !queued || self.is_waiting(pid) {
+                ARCH.halt();
+            }
+        }
+    }
+
+    /// Block the current process until `cond` returns true or `ms` milliseconds have passed,
+    /// whichever comes first. The process is woken by either a signal on this queue or a timeout
+    /// registered with the PIT. Returns whether `cond` was met.
+    ///
+    /// If every PIT timeout slot is taken, this polls `cond` on every tick instead of blocking.
+    pub fn wait_timeout<F: Fn() -> bool>(&'static self, cond: F, ms: usize) -> bool {
+        use device::pit;
+
+        let deadline = pit::ticks() + pit::ms_to_ticks(ms);
+
+        if pit::wake_at(deadline, self) {
+            self.wait_until(|| cond() || pit::ticks() >= deadline);
+            pit::cancel_wake(deadline, self);
+        } else {
+            while !cond() && pit::ticks() < deadline {
+                ARCH.halt();
+            }
+        }
+
+        cond()
+    }
+
+    /// Wake the process that has been waiting the longest. It is put on the ready list the next
+    /// time the scheduler runs. Returns `false` if nothing was waiting, or if the scheduler has
+    /// too many wake-ups pending, in which case the process is left waiting.
+    pub fn wake_one(&self) -> bool {
+        without_interrupts(|| {
+            let mut waiting = self.waiting.lock();
+            match waiting.peek() {
+                Some(pid) if SCHEDULER.wake(pid) => {
+                    waiting.pop();
+                    true
+                }
+                _ => false,
+            }
+        })
+    }
+
+    /// Wake every waiting process.
+    pub fn wake_all(&self) {
+        while self.wake_one() {}
+    }
+
+    /// Check if `pid` is waiting on this queue.
+    fn is_waiting(&self, pid: ProcessId) -> bool {
+        without_interrupts(|| self.waiting.lock().contains(&pid))
+    }
+}
+
+/// Self-tests, run with the `ktest` kernel argument.
+pub mod tests {
+    use ktest::KTest;
+
+    pub const TESTS: &[KTest] = &[
+        KTest {
+            name: "wait_timeout",
+            run: wait_timeout,
+        },
+    ];
+
+    fn wait_timeout() -> Result<(), &'static str> {
+        use device::pit;
+        use task::WaitQueue;
+
+        lazy_static! {
+            static ref NEVER: WaitQueue = WaitQueue::new();
+        }
+
+        let timeout = pit::ms_to_ticks(50);
+
+        let before = pit::ticks();
+        let met = NEVER.wait_timeout(|| false, 50);
+        let waited = pit::ticks() - before;
+
+        if met {
+            Err("a condition that never holds was reported as met")
+        } else if waited < timeout {
+            Err("the wait returned before the timeout")
+        } else if waited > timeout + pit::ms_to_ticks(20) {
+            Err("the wait returned long after the timeout")
+        } else if !NEVER.wait_timeout(|| true, 50) {
+            Err("a condition that already holds was not reported as met")
+        } else {
+            Ok(())
+        }
+    }
+}
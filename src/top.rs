@@ -0,0 +1,135 @@
+//! A live summary of the system, in the style of `top`: uptime, memory, interrupts and the task
+//! list, redrawn every second until a key is pressed. There is no shell yet, so it is started at
+//! the end of init when the `top` kernel argument is given.
+
+use alloc::String;
+use core::fmt::Write;
+use device::vga::buffer::{self, Color, ColorCode, BUFFER_HEIGHT, BUFFER_WIDTH};
+
+/// How often the screen is redrawn, in milliseconds.
+const REFRESH_MS: usize = 1000;
+
+/// The row the task list starts on, below the summary and the column headings.
+const FIRST_TASK_ROW: usize = 5;
+
+const TEXT: ColorCode = ColorCode::new(Color::LightGray, Color::Black);
+const HEADING: ColorCode = ColorCode::new(Color::Black, Color::LightGray);
+
+/// Write `line` over the whole of `row`, padding it with spaces so nothing from the last frame
+/// is left behind.
+fn draw_line(row: usize, line: &mut String, color: ColorCode) {
+    while line.len() < BUFFER_WIDTH {
+        line.push(' ');
+    }
+    let _ = buffer::write_at(row, 0, line, color);
+    line.clear();
+}
+
+/// Draw one frame of the summary.
+fn draw() {
+    use arch::interrupts::guard;
+    use arch::memory::{self, PAGE_SIZE};
+    use arch::memory::heap_allocator::alloc_stats;
+    use device::pit;
+    use task::SCHEDULER;
+
+    let tasks = SCHEDULER.stats();
+    let seconds = pit::uptime_ms() / 1000;
+    let idle = pit::ticks_to_ms(SCHEDULER.idle_ticks());
+    let interrupts: usize = guard::counts().iter().sum();
+    let frames = memory::stats();
+    let heap = alloc_stats();
+
+    let mut line = String::new();
+
+    let _ = write!(
+        line,
+        "top - up {}:{:02}:{:02}, {} tasks, {} interrupts",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        tasks.len(),
+        interrupts
+    );
+    draw_line(0, &mut line, TEXT);
+
+    let _ = write!(
+        line,
+        "Mem: {} kB free, heap {} / {} kB used",
+        frames.free * PAGE_SIZE / 1024,
+        heap.used / 1024,
+        heap.heap_size / 1024
+    );
+    draw_line(1, &mut line, TEXT);
+
+    let _ = write!(
+        line,
+        "Idle: {}.{:02} s. Press any key to exit.",
+        idle / 1000,
+        idle % 1000 / 10
+    );
+    draw_line(2, &mut line, TEXT);
+    draw_line(3, &mut line, TEXT);
+
+    let _ = write!(
+        line,
+        "{:>6} {:<24} {:<10} {:>12} {:>10}",
+        "PID",
+        "NAME",
+        "STATE",
+        "CPU (s)",
+        "MEM (kB)"
+    );
+    draw_line(4, &mut line, HEADING);
+
+    let mut tasks = tasks.iter();
+    for row in FIRST_TASK_ROW..BUFFER_HEIGHT {
+        if let Some(task) = tasks.next() {
+            let ms = task.cpu_time_ms;
+            let _ = write!(
+                line,
+                "{:>6} {:<24} {:<10} {:>9}.{:02} {:>10}",
+                task.pid.inner(),
+                task.name,
+                format!("{:?}", task.state),
+                ms / 1000,
+                ms % 1000 / 10,
+                task.memory / 1024
+            );
+        }
+        draw_line(row, &mut line, TEXT);
+    }
+}
+
+/// Show the summary until a key is pressed on the keyboard or the serial line. Interrupts must be
+/// enabled.
+pub fn run() {
+    use arch::{Arch, ARCH};
+    use device::{console, pit};
+
+    // Keep log lines from scrolling the summary away.
+    let mirror = console::mirror();
+    console::set_mirror(false);
+
+    loop {
+        draw();
+
+        let deadline = pit::ticks() + pit::ms_to_ticks(REFRESH_MS);
+        while pit::ticks() < deadline {
+            if console::try_read_char().is_some() {
+                buffer::clear_screen();
+                console::set_mirror(mirror);
+                return;
+            }
+
+            ARCH.halt();
+        }
+    }
+}
+
+/// Start `top` if it was requested on the kernel command line.
+pub fn run_from_args() {
+    if ::arch::args::get().has("top") {
+        run();
+    }
+}